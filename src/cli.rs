@@ -74,6 +74,38 @@ struct Opts {
     /// gyro file path
     #[argh(option, short = 'g')]
     gyro_file: Option<String>,
+
+    /// render project files directly, without the render queue and Qt event loop
+    #[argh(switch)]
+    headless: bool,
+
+    /// print progress and results as JSON objects, one per line, instead of progress bars
+    #[argh(switch)]
+    json_progress: bool,
+
+    /// fail the render when the synchronization is doubtful at any sync point, instead of only printing a warning. Doesn't apply to --export-project
+    #[argh(switch)]
+    block_doubtful_sync: bool,
+
+    /// detect the IMU orientation of the inputs (or the gyro file) instead of rendering. The camera should be still and level at some point and do these motions, eg. "pan_left,tilt_down" or with time ranges in seconds "pan_left:2-3.5,tilt_down:5-6"
+    #[argh(option)]
+    detect_imu_orientation: Option<String>,
+
+    /// export the camera motion of project files instead of rendering: NukeChan, BlenderJson, Csv or AfterEffects. The file is saved next to the output video
+    #[argh(option)]
+    export_camera_motion: Option<String>,
+
+    /// check all lens profiles in a directory and print their errors and warnings, exits with code 1 if any profile has errors
+    #[argh(option)]
+    lint_lens_profiles: Option<String>,
+
+    /// load a smoothing algorithm from a dynamic library, can be used multiple times. Select it with the preset, eg. "{{ 'stabilization': {{ 'method_id': 'my_algorithm' }} }}"
+    #[argh(option)]
+    smoothing_plugin: Vec<String>,
+
+    /// stabilize raw RGBA frames of the project's video size from stdin using IMU samples received over UDP on this address, eg. "0.0.0.0:7777". Stabilized RGBA frames are written to stdout. See `live_stabilize` for details
+    #[argh(option)]
+    live_imu: Option<String>,
 }
 
 pub fn will_run_in_console() -> bool {
@@ -108,12 +140,23 @@ pub fn run(open_file: &mut String) -> bool {
             }
         }
 
+        if let Some(path) = opts.lint_lens_profiles {
+            lint_lens_profiles(&path, opts.json_progress);
+            return true;
+        }
+
         for file in videos.iter().chain(lens_profiles.iter()) {
             if !std::path::Path::new(&file).exists() {
                 log::error!("File {} doesn't exist.", file);
                 return true;
             }
         }
+        for path in &opts.smoothing_plugin {
+            if let Err(e) = smoothing::plugin::load(path) {
+                log::error!("Failed to load the smoothing plugin: {e}");
+                return true;
+            }
+        }
         let mut watching = opts.watch.as_ref().map(|x| !x.is_empty()).unwrap_or_default();
 
         if !watching {
@@ -131,6 +174,26 @@ pub fn run(open_file: &mut String) -> bool {
             if !presets.is_empty() { log::info!("Presets: {:?}", presets); }
         }
 
+        if let Some(motions) = opts.detect_imu_orientation {
+            detect_imu_orientation(&videos, opts.gyro_file.as_deref(), &presets, &motions, opts.json_progress);
+            return true;
+        }
+
+        if let Some(format) = opts.export_camera_motion {
+            export_camera_motion(&videos, &lens_profiles, &presets, &format, opts.overwrite, opts.json_progress);
+            return true;
+        }
+
+        if let Some(address) = opts.live_imu {
+            live_stabilize(&videos, &lens_profiles, &presets, &address);
+            return true;
+        }
+
+        if opts.headless {
+            run_headless(&videos, &lens_profiles, &presets, opts.out_params, opts.overwrite, opts.block_doubtful_sync, opts.json_progress);
+            return true;
+        }
+
         let m = MultiProgress::new();
         m.set_draw_target(indicatif::ProgressDrawTarget::hidden());
         let sty = ProgressStyle::with_template("[{bar:50.cyan/blue}] {pos:>5}/{len:5} {eta:11} {prefix:.magenta}\x1B[37;1m{msg}\x1B[0m")
@@ -148,7 +211,9 @@ pub fn run(open_file: &mut String) -> bool {
         let pbh0 = m.add(ProgressBar::new(1)); pbh0.set_style(ProgressStyle::with_template("{msg}").unwrap()); pbh0.set_message(" ");
         let pbh = m.add(ProgressBar::new(1)); pbh.set_style(ProgressStyle::with_template("{spinner:.green} {msg:73} Elapsed: {elapsed_precise}").unwrap().tick_strings(&spinner)); pbh.set_message("Queue"); pbh.enable_steady_tick(std::time::Duration::from_millis(70));
 
-        log::set_max_level(log::LevelFilter::Info);
+        let json_progress = opts.json_progress;
+        // Keep stdout clean for the JSON lines, warnings and errors still go to stderr
+        log::set_max_level(if json_progress { log::LevelFilter::Warn } else { log::LevelFilter::Info });
 
         let time = Instant::now();
         let mut queue_printed = false;
@@ -176,6 +241,7 @@ pub fn run(open_file: &mut String) -> bool {
         if opts.export_project > 0 {
             queue.export_project = opts.export_project;
         }
+        queue.block_doubtful_sync = opts.block_doubtful_sync;
 
         let mut pbs = HashMap::<u32, ProgressBar>::new();
 
@@ -209,10 +275,27 @@ pub fn run(open_file: &mut String) -> bool {
                     cpp!(unsafe [] { qApp->quit(); });
                 }
             });
-            connect!(queue_ptr, q, render_progress, |job_id: &u32, _progress: &f64, current_frame: &usize, total_frames: &usize, _finished: &bool, _start_time: &f64, _is_conversion: &bool| {
+            connect!(queue_ptr, q, render_progress, |job_id: &u32, progress: &f64, current_frame: &usize, total_frames: &usize, finished: &bool, start_time: &f64, is_conversion: &bool| {
                 let pb = pbs.get(job_id).unwrap();
                 let queue = &mut *queue.as_ptr();
                 let qi = queue.queue.borrow();
+                if json_progress {
+                    if *finished {
+                        if let Some(item) = qi.iter().find(|x| x.job_id == *job_id) {
+                            if item.error_string.is_empty() || item.error_string.to_string() == "uses_cpu" {
+                                let output = gyroflow_core::filesystem::get_file_url(&item.output_folder.to_string(), &item.output_filename.to_string(), false);
+                                json_event("finished", *job_id, serde_json::json!({
+                                    "output": output,
+                                    "output_path": gyroflow_core::filesystem::url_to_path(&output),
+                                    "total_frames": total_frames,
+                                }));
+                            }
+                        }
+                    } else if *current_frame > 0 {
+                        json_event("progress", *job_id, render_progress_json(*progress, *current_frame, *total_frames, *start_time, *is_conversion));
+                    }
+                    return;
+                }
                 if *current_frame >= *total_frames {
                     let mut ok = true;
                     for item in qi.iter() {
@@ -251,6 +334,17 @@ pub fn run(open_file: &mut String) -> bool {
                 pb.set_position(*current_frame as u64);
             });
             connect!(queue_ptr, q, processing_progress, |job_id: &u32, progress: &f64| {
+                if json_progress {
+                    json_event("sync_progress", *job_id, serde_json::json!({ "progress": progress }));
+                    if *progress >= 1.0 {
+                        let queue = &mut *queue.as_ptr();
+                        if let Some(stab) = queue.get_stab_for_job(*job_id) {
+                            json_event("sync_result", *job_id, sync_result_json(&stab));
+                            doubtful_sync_warning(*job_id, &stab);
+                        }
+                    }
+                    return;
+                }
                 let mut any_other_in_progress = false;
                 {
                     let queue = &mut *queue.as_ptr();
@@ -277,13 +371,33 @@ pub fn run(open_file: &mut String) -> bool {
                 }
             });
             connect!(queue_ptr, q, convert_format, |job_id: &u32, format: &QString, supported: &QString| {
+                if json_progress {
+                    json_event("error", *job_id, serde_json::json!({
+                        "kind": "ffmpeg.PixelFormatNotSupported",
+                        "message": format!("Pixel format {} is not supported", format.to_string()),
+                        "format": format.to_string(),
+                        "supported": supported.to_string().split(',').collect::<Vec<_>>(),
+                    }));
+                    return;
+                }
                 log::error!("[{:08x}] Pixel format {} is not supported. Supported are: {}", job_id, format.to_string(), supported.to_string());
             });
             connect!(queue_ptr, q, error, |job_id: &u32, text: &QString, arg: &QString, _callback: &QString| {
-                if opts.overwrite && text.to_string().starts_with("file_exists:") {
-                    let queue = &mut *queue.as_ptr();
+                let queue = &mut *queue.as_ptr();
+                let kind = queue.get_error_kind(*job_id);
+                if opts.overwrite && kind == "file_exists" {
                     queue.reset_job(*job_id);
-                    log::warn!("[{:08x}] File exists, overwriting: {}", job_id, text.to_string().strip_prefix("file_exists:").unwrap());
+                    let text = text.to_string();
+                    let details = text.strip_prefix("file_exists:").unwrap_or(&text);
+                    if json_progress {
+                        json_event("warning", *job_id, serde_json::json!({ "message": "File exists, overwriting", "details": details }));
+                    } else {
+                        log::warn!("[{:08x}] File exists, overwriting: {}", job_id, details);
+                    }
+                    return;
+                }
+                if json_progress {
+                    json_event("error", *job_id, queue_error_json(&kind, &text.to_string(), &arg.to_string()));
                     return;
                 }
                 log::error!("[{:08x}] Error: {}", job_id, text.to_string().replace("%1", &arg.to_string()));
@@ -296,6 +410,27 @@ pub fn run(open_file: &mut String) -> bool {
                 pb.set_style(sty.clone());
                 pb.set_message(fname);
                 pbs.insert(*job_id, pb);
+                if json_progress {
+                    let qi = queue.queue.borrow();
+                    if let Some(item) = qi.iter().find(|x| x.job_id == *job_id) {
+                        json_event("queued", *job_id, serde_json::json!({
+                            "input": item.input_file.to_string(),
+                            "output": gyroflow_core::filesystem::get_file_url(&item.output_folder.to_string(), &item.output_filename.to_string(), false),
+                            "export_settings": item.export_settings.to_string(),
+                            "total_frames": item.total_frames,
+                        }));
+                    }
+                }
+            });
+            connect!(queue_ptr, q, encoder_initialized, |job_id: &u32, encoder_name: &String| {
+                if json_progress {
+                    let queue = &mut *queue.as_ptr();
+                    let uses_cpu = queue.queue.borrow().iter().any(|x| x.job_id == *job_id && x.error_string.to_string() == "uses_cpu");
+                    json_event("encoder_initialized", *job_id, serde_json::json!({ "encoder": encoder_name }));
+                    if uses_cpu {
+                        json_event("warning", *job_id, serde_json::json!({ "message": format!("GPU encoding is not available, using {encoder_name}") }));
+                    }
+                }
             });
             connect!(queue_ptr, q, processing_done, |job_id: &u32, by_preset: &bool| {
                 let queue = &mut *queue.as_ptr();
@@ -375,7 +510,8 @@ fn detect_types(all_files: &[String]) -> (Vec<String>, Vec<String>, Vec<String>)
     let mut lens_profiles = Vec::new();
     let mut presets = Vec::new();
     for file in all_files {
-        if file.ends_with(".json") { // Lens profile
+        let is_imported_profile = || gyroflow_core::lens_profile_formats::has_supported_extension(file) && std::fs::read_to_string(file).is_ok_and(|x| gyroflow_core::lens_profile_formats::is_supported(file, &x));
+        if file.ends_with(".json") || is_imported_profile() { // Lens profile
             lens_profiles.push(file.clone());
         } else if file.ends_with(".gyroflow") {
             let video_path = || -> Option<String> {
@@ -488,18 +624,488 @@ fn setup_defaults(stab: Arc<StabilizationManager>, queue: &mut RenderQueue) -> s
             "audio_codec":           audio_codecs.get(settings.get("audioCodec").unwrap_or(&"0".into()).parse::<usize>().unwrap()).unwrap_or(&"AAC"),
             "interpolation":         interpolations.get(settings.get("interpolationMethod").unwrap_or(&"2".into()).parse::<usize>().unwrap()).unwrap_or(&"Lanczos4"),
         },
-        "synchronization": {
-            "initial_offset":     0,
-            "initial_offset_inv": false,
-            "search_size":        5,
-            "calc_initial_fast":  false,
-            "max_sync_points":    5,
-            "every_nth_frame":    1,
-            "time_per_syncpoint": 1,
-            "of_method":          2,
-            "offset_method":      2,
-            "auto_sync_points":   true,
+        "synchronization": default_sync_settings()
+    })
+}
+
+fn default_sync_settings() -> serde_json::Value {
+    serde_json::json!({
+        "initial_offset":     0,
+        "initial_offset_inv": false,
+        "search_size":        5,
+        "calc_initial_fast":  false,
+        "max_sync_points":    5,
+        "every_nth_frame":    1,
+        "time_per_syncpoint": 1,
+        "of_method":          2,
+        "offset_method":      2,
+        "auto_sync_points":   true,
+    })
+}
+
+fn run_headless(projects: &[String], lens_profiles: &[String], presets: &[String], out_params: Option<String>, overwrite: bool, block_doubtful_sync: bool, json_progress: bool) {
+    use rendering::headless::HeadlessRender;
+    use std::sync::atomic::AtomicBool;
+
+    log::set_max_level(if json_progress { log::LevelFilter::Warn } else { log::LevelFilter::Info });
+
+    let time = Instant::now();
+
+    rendering::init_log();
+    if let Some((name, _list_name)) = gyroflow_core::gpu::initialize_contexts() {
+        rendering::set_gpu_type_from_name(&name);
+    }
+
+    // Saved GUI settings are not used here, only the defaults and `--out-params`
+    let mut additional_data = serde_json::json!({
+        "output": {
+            "codec":             "H.264/AVC",
+            "codec_options":     "",
+            "use_gpu":           true,
+            "audio":             true,
+            "pixel_format":      "",
+            "keyframe_distance": 1,
+            "audio_codec":       "AAC",
+            "interpolation":     "Lanczos4",
+        },
+        "synchronization": default_sync_settings()
+    });
+    if let Some(outp) = out_params {
+        gyroflow_core::util::merge_json(additional_data.get_mut("output").unwrap(), &serde_json::from_str(&outp.replace('\'', "\"")).expect("Invalid json"));
+    }
+    let additional_data = additional_data.to_string();
+
+    let sty = ProgressStyle::with_template("[{bar:50.cyan/blue}] {pos:>5}/{len:5} {eta:11} {prefix:.magenta}\x1B[37;1m{msg}\x1B[0m")
+        .unwrap()
+        .with_key("eta", |state: &ProgressState, w: &mut dyn std::fmt::Write| write!(w, "ETA {:.1}s", state.eta().as_secs_f64()).unwrap())
+        .progress_chars("#>-");
+
+    let lens_profile_db = Arc::new(parking_lot::RwLock::new(gyroflow_core::lens_profile_database::LensProfileDatabase::default()));
+
+    for (i, project) in projects.iter().enumerate() {
+        let job_id = i as u32 + 1;
+        if !project.ends_with(".gyroflow") {
+            if json_progress {
+                json_event("error", job_id, serde_json::json!({ "kind": "not_a_project", "message": "Only .gyroflow projects can be rendered in headless mode", "input": project }));
+            } else {
+                log::error!("{} is not a project file, only .gyroflow projects can be rendered in headless mode.", project);
+            }
+            continue;
+        }
+        let mut job = match HeadlessRender::from_project(&path_to_url(project), &additional_data, "_stabilized", lens_profile_db.clone()) {
+            Ok(job) => job,
+            Err(e) => {
+                if json_progress {
+                    json_event("error", job_id, serde_json::json!({ "kind": e.kind(), "message": e.to_string(), "input": project }));
+                } else {
+                    log::error!("Failed to load {}: {}", project, e);
+                }
+                continue;
+            }
+        };
+        job.block_doubtful_sync = block_doubtful_sync;
+        if let Some(file) = lens_profiles.first() {
+            log::info!("Loading lens profile {}", file);
+            if let Err(e) = job.stab.load_lens_profile(file) {
+                if json_progress {
+                    json_event("error", job_id, serde_json::json!({ "kind": rendering::headless::HeadlessError::Core(e).kind(), "message": format!("Failed to load lens profile {file}") }));
+                } else {
+                    log::error!("Failed to load lens profile {}: {:?}", file, e);
+                }
+                continue;
+            }
+        }
+        let mut preset_failed = false;
+        for preset in presets {
+            log::info!("Applying preset {}", preset);
+            if let Err(e) = job.apply_preset(preset) {
+                if json_progress {
+                    json_event("error", job_id, serde_json::json!({ "kind": e.kind(), "message": format!("Failed to apply preset {preset}: {e}") }));
+                } else {
+                    log::error!("Failed to apply preset {}: {}", preset, e);
+                }
+                preset_failed = true;
+                break;
+            }
+        }
+        if preset_failed { continue; }
+        job.stab.recompute_blocking();
+
+        let total_frames = {
+            let params = job.stab.params.read();
+            (params.frame_count as f64 * params.get_trim_ratio()).ceil() as u64
+        };
+        if json_progress {
+            json_event("queued", job_id, serde_json::json!({
+                "input": job.stab.input_file.read().url,
+                "output": job.output_url(),
+                "export_settings": job.render_options.settings_string(job.stab.params.read().fps),
+                "total_frames": total_frames,
+            }));
         }
+
+        let pb = if json_progress { ProgressBar::hidden() } else { ProgressBar::new(total_frames) };
+        pb.set_style(sty.clone());
+        pb.set_message(job.render_options.output_filename.clone());
+
+        let start_time = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|x| x.as_millis() as f64).unwrap_or_default();
+        let pb2 = pb.clone();
+        let pb3 = pb.clone();
+        let stab = job.stab.clone();
+        let result = job.render(move |(progress, current_frame, total_frames, finished, is_conversion)| {
+            if json_progress {
+                if !finished && current_frame > 0 {
+                    json_event("progress", job_id, render_progress_json(progress, current_frame, total_frames, start_time, is_conversion));
+                }
+            } else {
+                pb2.set_length(total_frames as u64);
+                pb2.set_position(current_frame as u64);
+            }
+        }, move |progress| {
+            if json_progress {
+                json_event("sync_progress", job_id, serde_json::json!({ "progress": progress }));
+                if progress >= 1.0 {
+                    json_event("sync_result", job_id, sync_result_json(&stab));
+                    doubtful_sync_warning(job_id, &stab);
+                }
+            } else {
+                pb3.set_length(100);
+                pb3.set_position((progress * 100.0).round() as u64);
+            }
+        }, overwrite, Arc::new(AtomicBool::new(false)), Arc::new(AtomicBool::new(false)));
+
+        match result {
+            Ok(_) => {
+                pb.finish_with_message(format!("\x1B[1;32m{}\x1B[0m", pb.message())); // Green
+                if json_progress {
+                    json_event("finished", job_id, serde_json::json!({
+                        "output": job.output_url(),
+                        "output_path": gyroflow_core::filesystem::url_to_path(&job.output_url()),
+                        "total_frames": total_frames,
+                    }));
+                } else {
+                    log::info!("Rendered {}", job.output_url());
+                }
+            }
+            Err(e) => {
+                pb.abandon_with_message(format!("\x1B[1;31m{}\x1B[0m", pb.message())); // Red
+                if json_progress {
+                    json_event("error", job_id, serde_json::json!({ "kind": e.kind(), "message": e.to_string() }));
+                } else {
+                    log::error!("Failed to render {}: {}", project, e);
+                }
+            }
+        }
+    }
+
+    log::info!("Done in {:.3}s", time.elapsed().as_millis() as f64 / 1000.0);
+}
+
+fn export_camera_motion(projects: &[String], lens_profiles: &[String], presets: &[String], format: &str, overwrite: bool, json_progress: bool) {
+    use rendering::headless::{ HeadlessRender, HeadlessError };
+    use gyroflow_core::export::camera_motion::CameraMotionFormat;
+
+    log::set_max_level(if json_progress { log::LevelFilter::Warn } else { log::LevelFilter::Info });
+
+    let format = match format.parse::<CameraMotionFormat>() {
+        Ok(x) => x,
+        Err(_) => {
+            log::error!("Unknown camera motion format {}, supported are: NukeChan, BlenderJson, Csv, AfterEffects", format);
+            return;
+        }
+    };
+    let lens_profile_db = Arc::new(parking_lot::RwLock::new(gyroflow_core::lens_profile_database::LensProfileDatabase::default()));
+
+    for (i, project) in projects.iter().enumerate() {
+        let job_id = i as u32 + 1;
+        let result = (|| -> Result<String, HeadlessError> {
+            if !project.ends_with(".gyroflow") {
+                return Err(HeadlessError::NotAProject(project.clone()));
+            }
+            let job = HeadlessRender::from_project(&path_to_url(project), "{}", "_stabilized", lens_profile_db.clone())?;
+            if let Some(file) = lens_profiles.first() {
+                job.stab.load_lens_profile(file)?;
+            }
+            for preset in presets {
+                job.apply_preset(preset)?;
+            }
+            job.stab.recompute_blocking();
+
+            let url = gyroflow_core::filesystem::get_file_url(&job.render_options.output_folder, &gyroflow_core::filesystem::filename_with_extension(&job.render_options.output_filename, format.extension()), true);
+            if !overwrite && gyroflow_core::filesystem::exists(&url) {
+                return Err(HeadlessError::FileExists(url));
+            }
+            job.stab.export_camera_motion(&url, format)?;
+            Ok(url)
+        })();
+
+        match result {
+            Ok(url) => {
+                if json_progress {
+                    json_event("finished", job_id, serde_json::json!({ "output": url, "output_path": gyroflow_core::filesystem::url_to_path(&url) }));
+                } else {
+                    log::info!("Camera motion exported to {}", gyroflow_core::filesystem::display_url(&url));
+                }
+            }
+            Err(e) => {
+                if json_progress {
+                    json_event("error", job_id, serde_json::json!({ "kind": e.kind(), "message": e.to_string(), "input": project }));
+                } else {
+                    log::error!("Failed to export the camera motion of {}: {}", project, e);
+                }
+            }
+        }
+    }
+}
+
+// Live stabilization using the settings of a project file (lens profile, IMU orientation, smoothing etc).
+// IMU lines are `timestamp_ms,gx,gy,gz[,ax,ay,az]` (see `streaming::imu_source`), with the timestamp 0 at the first frame.
+// Frames are read from stdin at the project frame rate, so a recorded clip can be replayed with eg.
+//     ffmpeg -i clip.mp4 -f rawvideo -pix_fmt rgba - | gyroflow clip.gyroflow --live-imu 0.0.0.0:7777 > out.rgba
+// while a harness sends the IMU lines (`imu_source::format_line`) to port 7777.
+fn live_stabilize(projects: &[String], lens_profiles: &[String], presets: &[String], address: &str) {
+    use rendering::headless::{ HeadlessRender, HeadlessError };
+    use gyroflow_core::streaming::{ LiveStabilizer, imu_source::UdpImuSource };
+    use gyroflow_core::smoothing::look_ahead::LookAhead;
+    use gyroflow_core::stabilization::RGBA8;
+    use std::io::{ Read, Write };
+
+    let result = (|| -> Result<usize, HeadlessError> {
+        let project = projects.first().ok_or_else(|| HeadlessError::NotAProject(String::new()))?;
+        if !project.ends_with(".gyroflow") {
+            return Err(HeadlessError::NotAProject(project.clone()));
+        }
+        let lens_profile_db = Arc::new(parking_lot::RwLock::new(gyroflow_core::lens_profile_database::LensProfileDatabase::default()));
+        let job = HeadlessRender::from_project(&path_to_url(project), "{}", "_stabilized", lens_profile_db)?;
+        if let Some(file) = lens_profiles.first() {
+            job.stab.load_lens_profile(file)?;
+        }
+        for preset in presets {
+            job.apply_preset(preset)?;
+        }
+        let (video_size, fps) = {
+            let params = job.stab.params.read();
+            (params.video_size, params.fps)
+        };
+        job.stab.set_size(video_size.0, video_size.1);
+        job.stab.set_output_size(job.render_options.output_width, job.render_options.output_height);
+
+        let mut live = LiveStabilizer::new(job.stab.get_cloned(), LookAhead::default());
+        let mut source = UdpImuSource::bind(address, Some(std::time::Duration::from_millis(1))).map_err(GyroflowCoreError::from)?;
+        log::info!("Listening for IMU data on {address}, input frames {}x{} RGBA at {fps} fps, output {}x{}, latency {} ms",
+            video_size.0, video_size.1, job.render_options.output_width, job.render_options.output_height, live.latency_ms());
+
+        let frame_bytes = video_size.0 * 4 * video_size.1;
+        let mut stdin = std::io::stdin().lock();
+        let mut stdout = std::io::stdout().lock();
+        let mut write_frames = |live: &mut LiveStabilizer, flush: bool| -> Result<usize, HeadlessError> {
+            let mut written = 0;
+            for frame in live.process::<RGBA8>(flush) {
+                match frame {
+                    Ok(frame) => {
+                        stdout.write_all(&frame.data).map_err(GyroflowCoreError::from)?;
+                        written += 1;
+                    }
+                    Err(e) => log::error!("Failed to stabilize a frame: {e}")
+                }
+            }
+            stdout.flush().map_err(GyroflowCoreError::from)?;
+            Ok(written)
+        };
+
+        let mut frame_no = 0usize;
+        let mut written = 0;
+        let mut imu_open = true;
+        loop {
+            let mut data = vec![0u8; frame_bytes];
+            match stdin.read_exact(&mut data) {
+                Ok(_) => { }
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(GyroflowCoreError::from(e).into())
+            }
+            let timestamp_us = (frame_no as f64 * 1_000_000.0 / fps).round() as i64;
+            live.push_frame(timestamp_us, (video_size.0, video_size.1, video_size.0 * 4), data);
+            frame_no += 1;
+
+            if imu_open {
+                imu_open = live.read_imu(&mut source).map_err(GyroflowCoreError::from)?;
+            }
+            written += write_frames(&mut live, false)?;
+        }
+
+        // Wait for the IMU data of the last frames, until nothing arrives for a second
+        let mut last_received = Instant::now();
+        while imu_open && live.pending_frames() > 0 && last_received.elapsed().as_secs_f64() < 1.0 {
+            let last_ts = live.last_imu_timestamp_ms();
+            imu_open = live.read_imu(&mut source).map_err(GyroflowCoreError::from)?;
+            if live.last_imu_timestamp_ms() != last_ts {
+                last_received = Instant::now();
+            }
+            written += write_frames(&mut live, false)?;
+        }
+        written += write_frames(&mut live, true)?;
+        Ok(written)
+    })();
+
+    match result {
+        Ok(written) => log::info!("Stabilized {written} frames"),
+        Err(e) => log::error!("Live stabilization failed: {e}")
+    }
+}
+
+fn detect_imu_orientation(inputs: &[String], gyro_file: Option<&str>, presets: &[String], motions: &str, json_progress: bool) {
+    use std::sync::atomic::AtomicBool;
+    if json_progress { log::set_max_level(log::LevelFilter::Warn); }
+
+    let motions = match motions.split(',').map(|x| x.parse::<gyroflow_core::imu_orientation::KnownMotionSegment>()).collect::<Result<Vec<_>, _>>() {
+        Ok(x) => x,
+        Err(e) => {
+            if json_progress {
+                json_event("error", 0, serde_json::json!({ "kind": "invalid_motion", "message": e.to_string() }));
+            } else {
+                log::error!("{}", e);
+            }
+            return;
+        }
+    };
+
+    // Custom loggers can be read with the CSV mapping from a preset
+    let mut options = gyroflow_core::gyro_source::FileLoadOptions::default();
+    for preset in presets {
+        let data = if preset.starts_with('{') { Some(preset.clone()) } else { std::fs::read_to_string(preset).ok() };
+        let mapping = data.and_then(|x| serde_json::from_str::<serde_json::Value>(&x).ok())
+                          .and_then(|x| x.get("gyro_source")?.get("csv_mapping").cloned());
+        if let Some(mapping) = mapping {
+            match serde_json::from_value(mapping) {
+                Ok(mapping) => options.csv_mapping = Some(mapping),
+                Err(e) => log::error!("Invalid CSV mapping in {}: {}", preset, e)
+            }
+        }
+    }
+
+    for (i, input) in inputs.iter().enumerate() {
+        let job_id = i as u32 + 1;
+        let file = gyro_file.filter(|x| !x.is_empty()).unwrap_or(input);
+        let stab = StabilizationManager::default();
+        let result = stab.load_gyro_data(&path_to_url(file), false, &options, |_|(), Arc::new(AtomicBool::new(false)))
+                         .and_then(|_| stab.detect_imu_orientation(&motions));
+        match result {
+            Ok(candidates) => {
+                if json_progress {
+                    json_event("imu_orientation", job_id, serde_json::json!({ "input": file, "candidates": candidates }));
+                } else {
+                    log::info!("{}: detected IMU orientation {}", file, candidates[0].orientation);
+                    for c in candidates.iter().take(5) {
+                        log::info!("    {}  score: {:.3}  confidence: {:.1}%", c.orientation, c.score, c.confidence * 100.0);
+                    }
+                }
+            }
+            Err(e) => {
+                if json_progress {
+                    let message = e.to_string();
+                    json_event("error", job_id, serde_json::json!({ "kind": rendering::headless::HeadlessError::Core(e).kind(), "message": message, "input": file }));
+                } else {
+                    log::error!("{}: failed to detect IMU orientation: {}", file, e);
+                }
+            }
+        }
+    }
+}
+
+fn lint_lens_profiles(path: &str, json_progress: bool) {
+    use gyroflow_core::lens_profile_validation::Severity;
+    if json_progress { log::set_max_level(log::LevelFilter::Warn); }
+
+    if !std::path::Path::new(path).is_dir() {
+        if json_progress {
+            json_event("error", 0, serde_json::json!({ "kind": "not_found", "message": format!("Directory {path} doesn't exist") }));
+        } else {
+            log::error!("Directory {} doesn't exist.", path);
+        }
+        std::process::exit(1);
+    }
+
+    let reports = gyroflow_core::lens_profile_validation::lint_directory(std::path::Path::new(path));
+    let with_errors = reports.iter().filter(|x| x.has_errors()).count();
+    for report in &reports {
+        if json_progress {
+            json_event("lens_profile_issues", 0, serde_json::to_value(report).unwrap_or_default());
+        } else {
+            for issue in &report.issues {
+                match issue.severity {
+                    Severity::Error   => log::error!("{} ({}): {}: {}", report.path, report.identifier, issue.code, issue.message),
+                    Severity::Warning => log::warn!("{} ({}): {}: {}", report.path, report.identifier, issue.code, issue.message),
+                }
+            }
+        }
+    }
+    if json_progress {
+        json_event("lint_finished", 0, serde_json::json!({ "profiles_with_issues": reports.len(), "profiles_with_errors": with_errors }));
+    } else {
+        log::info!("{} profiles with issues, {} with errors", reports.len(), with_errors);
+    }
+    if with_errors > 0 {
+        std::process::exit(1);
+    }
+}
+
+fn json_event(event: &str, job_id: u32, data: serde_json::Value) {
+    use std::io::Write;
+    let mut obj = serde_json::json!({ "event": event, "job_id": job_id });
+    gyroflow_core::util::merge_json(&mut obj, &data);
+    let mut stdout = std::io::stdout().lock();
+    let _ = writeln!(stdout, "{}", obj);
+    let _ = stdout.flush();
+}
+
+fn render_progress_json(progress: f64, current_frame: usize, total_frames: usize, start_time_ms: f64, is_conversion: bool) -> serde_json::Value {
+    let now_ms = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|x| x.as_millis() as f64).unwrap_or_default();
+    let elapsed_s = if start_time_ms > 0.0 { (now_ms - start_time_ms).max(0.0) / 1000.0 } else { 0.0 };
+    let fps = if elapsed_s > 0.0 { current_frame as f64 / elapsed_s } else { 0.0 };
+    let eta_s = if fps > 0.0 { Some(total_frames.saturating_sub(current_frame) as f64 / fps) } else { None };
+    serde_json::json!({
+        "progress":      progress,
+        "current_frame": current_frame,
+        "total_frames":  total_frames,
+        "fps":           fps,
+        "elapsed_s":     elapsed_s,
+        "eta_s":         eta_s,
+        "is_conversion": is_conversion,
+    })
+}
+
+fn sync_result_json(stab: &StabilizationManager) -> serde_json::Value {
+    let gyro = stab.gyro.read();
+    let quality = gyro.get_offsets_quality();
+    let offsets = gyro.get_offsets().iter().map(|(ts, offset)| {
+        let mut item = serde_json::json!({ "timestamp_ms": *ts as f64 / 1000.0, "offset_ms": offset, "rejected": gyro.get_rejected_offsets().contains(ts) });
+        if let Some(q) = quality.get(ts) {
+            gyroflow_core::util::merge_json(&mut item, &serde_json::json!({
+                "features":       q.features,
+                "inlier_ratio":   q.inlier_ratio,
+                "peak_sharpness": q.peak_sharpness,
+                "trustworthy":    q.trustworthy,
+            }));
+        }
+        item
+    }).collect::<Vec<_>>();
+    serde_json::json!({ "offsets": offsets, "doubtful": gyro.doubtful_offsets() })
+}
+
+fn doubtful_sync_warning(job_id: u32, stab: &StabilizationManager) {
+    let doubtful = stab.gyro.read().doubtful_offsets();
+    if doubtful > 0 {
+        json_event("warning", job_id, serde_json::json!({ "kind": "doubtful_sync", "message": format!("Synchronization is doubtful at {doubtful} sync points"), "doubtful": doubtful }));
+    }
+}
+
+fn queue_error_json(kind: &str, text: &str, arg: &str) -> serde_json::Value {
+    // `arg` contains the error message followed by the ffmpeg log
+    let (message, log) = arg.split_once("\n\n").unwrap_or((arg, ""));
+    serde_json::json!({
+        "kind":    kind,
+        "message": if text.contains("%1") { text.replace("%1", message) } else { text.to_owned() },
+        "log":     log.trim(),
     })
 }
 
@@ -14,7 +14,6 @@ use qml_video_rs::video_item::MDKVideoItem;
 
 use crate::core;
 use crate::core::StabilizationManager;
-#[cfg(feature = "opencv")]
 use crate::core::calibration::LensCalibrator;
 use crate::core::synchronization::AutosyncProcess;
 use crate::core::stabilization::KernelParamsFlags;
@@ -35,6 +34,8 @@ struct OffsetItem {
     pub timestamp_us: i64,
     pub offset_ms: f64,
     pub linear_offset_ms: f64,
+    pub doubtful: bool,
+    pub rejected: bool,
 }
 
 #[derive(Default, SimpleListItem)]
@@ -73,8 +74,12 @@ pub struct Controller {
 
     telemetry_loaded: qt_signal!(is_main_video: bool, filename: QString, camera: QString, additional_data: QJsonObject),
     lens_profile_loaded: qt_signal!(lens_json: QString, filepath: QString, checksum: QString),
+    apply_lens_imu_transform: qt_method!(fn(&mut self)),
+    imu_transform_loaded: qt_signal!(orientation: QString, rotation: QJsonArray),
 
     set_smoothing_method: qt_method!(fn(&self, index: usize) -> QJsonArray),
+    get_smoothing_algs: qt_method!(fn(&self) -> QVariantList),
+    load_smoothing_plugin: qt_method!(fn(&mut self, url: QUrl) -> i32),
     get_smoothing_max_angles: qt_method!(fn(&self) -> QJsonArray),
     get_smoothing_status: qt_method!(fn(&self) -> QJsonArray),
     set_smoothing_param: qt_method!(fn(&self, name: QString, val: f64)),
@@ -90,6 +95,7 @@ pub struct Controller {
     remove_offset: qt_method!(fn(&self, timestamp_us: i64)),
     clear_offsets: qt_method!(fn(&self)),
     offset_at_video_timestamp: qt_method!(fn(&self, timestamp_us: i64) -> f64),
+    set_offset_model: qt_method!(fn(&self, model: i32)),
     offsets_model: qt_property!(RefCell<SimpleListModel<OffsetItem>>; NOTIFY offsets_updated),
     offsets_updated: qt_signal!(),
 
@@ -226,6 +232,15 @@ pub struct Controller {
 
     set_digital_lens_name: qt_method!(fn(&self, name: String)),
     set_digital_lens_param: qt_method!(fn(&self, index: usize, value: f64)),
+    set_calibration_target: qt_method!(fn(&self, name: String)),
+    set_calibration_grid_size: qt_method!(fn(&self, columns: usize, rows: usize)),
+    set_calibration_model: qt_method!(fn(&mut self, id: String)),
+    get_calibration_models: qt_method!(fn(&self) -> QJsonArray),
+    calibrate_imu: qt_method!(fn(&mut self)),
+    get_calibration_report: qt_method!(fn(&self) -> QJsonObject),
+    export_calibration_report: qt_method!(fn(&self, url: QUrl)),
+    reject_calibration_outliers: qt_method!(fn(&mut self)),
+    imu_calibrated: qt_signal!(result: QJsonObject),
 
     get_username: qt_method!(fn(&self) -> QString),
     clear_settings: qt_method!(fn(&self)),
@@ -377,6 +392,7 @@ impl Controller {
         sync_params.every_nth_frame     = sync_params.every_nth_frame.max(1);
 
         let for_rs = mode == "estimate_rolling_shutter";
+        let for_lens = mode == "estimate_lens";
 
         let every_nth_frame = sync_params.every_nth_frame;
 
@@ -391,7 +407,7 @@ impl Controller {
             this.chart_data_changed();
             this.sync_progress(percent, ready, total);
         });
-        let set_offsets = util::qt_queued_callback_mut(self, move |this, offsets: Vec<(f64, f64, f64)>| {
+        let set_offsets = util::qt_queued_callback_mut(self, move |this, offsets: Vec<(f64, f64, f64, synchronization::SyncQuality)>| {
             if for_rs {
                 if let Some(offs) = offsets.first() {
                     this.rolling_shutter_estimated(offs.1);
@@ -400,11 +416,13 @@ impl Controller {
                 let mut gyro = this.stabilizer.gyro.write();
                 gyro.prevent_recompute = true;
                 for x in offsets {
-                    ::log::info!("Setting offset at {:.4}: {:.4} (cost {:.4})", x.0, x.1, x.2);
+                    ::log::info!("Setting offset at {:.4}: {:.4} (cost {:.4}, features: {:.1}, inliers: {:.2}, sharpness: {:.2})", x.0, x.1, x.2, x.3.features, x.3.inlier_ratio, x.3.peak_sharpness);
+                    if !x.3.trustworthy { ::log::warn!("Sync point at {:.4} is doubtful", x.0); }
                     let new_ts = ((x.0 - x.1) * 1000.0) as i64;
                     // Remove existing offsets within 100ms range
                     gyro.remove_offsets_near(new_ts, 100.0);
                     gyro.set_offset(new_ts, x.1);
+                    gyro.set_offset_quality(new_ts, x.3);
                 }
                 gyro.prevent_recompute = false;
                 gyro.adjust_offsets();
@@ -418,6 +436,10 @@ impl Controller {
             ::log::info!("Setting orientation {}", &orientation);
             this.orientation_guessed(QString::from(orientation));
         });
+        let set_lens = util::qt_queued_callback_mut(self, move |this, lens_json: String| {
+            ::log::info!("Loading the estimated lens profile");
+            this.load_lens_profile(QString::from(lens_json));
+        });
         let err = util::qt_queued_callback_mut(self, |this, (msg, mut arg): (String, String)| {
             arg.push_str("\n\n");
             arg.push_str(&rendering::get_log());
@@ -440,10 +462,13 @@ impl Controller {
             sync.on_finished(move |arg| {
                 match arg {
                     Either::Left(offsets) => set_offsets(offsets),
+                    Either::Right(Some(lens)) if for_lens => set_lens(lens.0),
                     Either::Right(Some(orientation)) => set_orientation(orientation.0),
                     _=> ()
                 };
             });
+            let err2 = err.clone();
+            sync.on_error(move |e| err2(e));
 
             let ranges = sync.get_ranges();
             let cancel_flag = self.cancel_flag.clone();
@@ -641,11 +666,16 @@ impl Controller {
     }
 
     fn update_offset_model(&mut self) {
-        self.offsets_model = RefCell::new(self.stabilizer.gyro.read().get_offsets_plus_linear().iter().map(|(k, v)| OffsetItem {
+        let gyro = self.stabilizer.gyro.read();
+        let quality = gyro.get_offsets_quality();
+        self.offsets_model = RefCell::new(gyro.get_offsets_plus_linear().iter().map(|(k, v)| OffsetItem {
             timestamp_us: *k,
             offset_ms: v.0,
-            linear_offset_ms: v.1
+            linear_offset_ms: v.1,
+            doubtful: quality.get(k).map(|q| !q.trustworthy).unwrap_or_default(),
+            rejected: gyro.get_rejected_offsets().contains(k)
         }).collect());
+        drop(gyro);
 
         util::qt_queued_callback(self, |this, _| {
             this.offsets_updated();
@@ -809,6 +839,7 @@ impl Controller {
         }
     }
     fn load_lens_profile(&mut self, url_or_id: QString) {
+        let imu_transform = self.imu_transform();
         let (json, filepath, checksum) = {
             if let Err(e) = self.stabilizer.load_lens_profile(&url_or_id.to_string()) {
                 self.error(QString::from("An error occured: %1"), QString::from(e.to_string()), QString::default());
@@ -819,8 +850,27 @@ impl Controller {
         self.lens_loaded = true;
         self.lens_changed();
         self.lens_profile_loaded(QString::from(json), QString::from(filepath), QString::from(checksum));
+        if self.imu_transform() != imu_transform {
+            self.imu_transform_changed();
+        }
         self.request_recompute();
     }
+    /// Uses the IMU orientation and rotation from the lens profile even if the video or project already has them
+    fn apply_lens_imu_transform(&mut self) {
+        if self.stabilizer.apply_lens_imu_transform() {
+            self.imu_transform_changed();
+            self.chart_data_changed();
+            self.request_recompute();
+        }
+    }
+    fn imu_transform(&self) -> (Option<String>, Option<[f64; 3]>) {
+        let gyro = self.stabilizer.gyro.read();
+        (gyro.imu_orientation.clone(), gyro.imu_rotation_angles)
+    }
+    fn imu_transform_changed(&mut self) {
+        let (orientation, rotation) = self.imu_transform();
+        self.imu_transform_loaded(QString::from(orientation.unwrap_or_default()), util::serde_json_to_qt_array(&serde_json::json!(rotation.unwrap_or_default())));
+    }
     fn load_default_preset(&mut self) {
         // Assumes regular filesystem
         let local_path = gyroflow_core::lens_profile_database::LensProfileDatabase::get_path().join("default.gyroflow");
@@ -1145,6 +1195,17 @@ impl Controller {
     pub fn get_smoothing_algs(&self) -> QVariantList {
         self.stabilizer.get_smoothing_algs().into_iter().map(QString::from).collect()
     }
+    /// Returns the index of the loaded algorithm in `get_smoothing_algs`, or -1 if the plugin couldn't be loaded
+    fn load_smoothing_plugin(&mut self, url: QUrl) -> i32 {
+        let path = filesystem::url_to_path(&util::qurl_to_encoded(url));
+        match self.stabilizer.load_smoothing_plugin(&path) {
+            Ok(id) => self.stabilizer.smoothing.read().get_ids().iter().position(|x| *x == id).map(|x| x as i32).unwrap_or(-1),
+            Err(e) => {
+                self.error(QString::from("An error occured: %1"), QString::from(e.to_string()), QString::default());
+                -1
+            }
+        }
+    }
     fn get_smoothing_status(&self) -> QJsonArray {
         util::serde_json_to_qt_array(&self.stabilizer.get_smoothing_status())
     }
@@ -1356,6 +1417,8 @@ impl Controller {
     wrap_simple_method!(set_show_optical_flow,      v: bool);
     wrap_simple_method!(set_digital_lens_name,      v: String; recompute);
     wrap_simple_method!(set_digital_lens_param,     i: usize, v: f64; recompute);
+    wrap_simple_method!(set_calibration_target,     v: String);
+    wrap_simple_method!(set_calibration_grid_size,  columns: usize, rows: usize);
     wrap_simple_method!(set_fov_overview,       v: bool; recompute);
     wrap_simple_method!(set_show_safe_area,     v: bool; recompute);
     wrap_simple_method!(set_fov,                v: f64; recompute; chart_data_changed);
@@ -1385,6 +1448,7 @@ impl Controller {
     wrap_simple_method!(set_offset, timestamp_us: i64, offset_ms: f64; recompute; update_offset_model);
     wrap_simple_method!(clear_offsets,; recompute; update_offset_model);
     wrap_simple_method!(remove_offset, timestamp_us: i64; recompute; update_offset_model);
+    wrap_simple_method!(set_offset_model, model: i32; recompute; update_offset_model);
 
     wrap_simple_method!(set_imu_lpf, v: f64; recompute; chart_data_changed);
     wrap_simple_method!(set_imu_rotation, pitch_deg: f64, roll_deg: f64, yaw_deg: f64; recompute; chart_data_changed);
@@ -1463,244 +1527,373 @@ impl Controller {
     }
 
     pub fn init_calibrator(&self) {
-        #[cfg(feature = "opencv")]
-        {
-            self.stabilizer.params.write().is_calibrator = true;
-            *self.stabilizer.lens_calibrator.write() = Some(LensCalibrator::new());
-            self.stabilizer.set_smoothing_method(2); // Plain 3D
-            self.stabilizer.set_smoothing_param("time_constant", 2.0);
-        }
+        self.stabilizer.params.write().is_calibrator = true;
+        *self.stabilizer.lens_calibrator.write() = Some(LensCalibrator::new());
+        self.stabilizer.set_smoothing_method(2); // Plain 3D
+        self.stabilizer.set_smoothing_param("time_constant", 2.0);
     }
 
     fn start_autocalibrate(&mut self, max_points: usize, every_nth_frame: usize, iterations: usize, max_sharpness: f64, custom_timestamp_ms: f64, no_marker: bool) {
-        #[cfg(feature = "opencv")]
-        {
-            rendering::clear_log();
+        rendering::clear_log();
 
-            self.calib_in_progress = true;
-            self.calib_in_progress_changed();
-            self.calib_progress(0.0, 0.0, 0, 0, 0, 0.0);
+        self.calib_in_progress = true;
+        self.calib_in_progress_changed();
+        self.calib_progress(0.0, 0.0, 0, 0, 0, 0.0);
 
-            let stab = self.stabilizer.clone();
+        let stab = self.stabilizer.clone();
 
-            let (fps, frame_count, trim_ranges_ms, trim_ratio, org_size, input_horizontal_stretch, input_vertical_stretch) = {
-                let params = stab.params.read();
-                let lens = stab.lens.read();
-                let input_horizontal_stretch = if lens.input_horizontal_stretch > 0.01 { lens.input_horizontal_stretch } else { 1.0 };
-                let input_vertical_stretch = if lens.input_vertical_stretch > 0.01 { lens.input_vertical_stretch } else { 1.0 };
-                (params.fps, params.frame_count, params.trim_ranges.iter().map(|x| (x.0 * params.duration_ms, x.1 * params.duration_ms)).collect(), params.get_trim_ratio(), params.video_size, input_horizontal_stretch, input_vertical_stretch)
-            };
+        let (fps, frame_count, trim_ranges_ms, trim_ratio, org_size, input_horizontal_stretch, input_vertical_stretch) = {
+            let params = stab.params.read();
+            let lens = stab.lens.read();
+            let input_horizontal_stretch = if lens.input_horizontal_stretch > 0.01 { lens.input_horizontal_stretch } else { 1.0 };
+            let input_vertical_stretch = if lens.input_vertical_stretch > 0.01 { lens.input_vertical_stretch } else { 1.0 };
+            (params.fps, params.frame_count, params.trim_ranges.iter().map(|x| (x.0 * params.duration_ms, x.1 * params.duration_ms)).collect(), params.get_trim_ratio(), params.video_size, input_horizontal_stretch, input_vertical_stretch)
+        };
 
-            let is_forced = custom_timestamp_ms > -0.5;
-            let ranges = if is_forced {
-                vec![(custom_timestamp_ms - 1.0, custom_timestamp_ms + 1.0)]
-            } else {
-                trim_ranges_ms
+        let is_forced = custom_timestamp_ms > -0.5;
+        let ranges = if is_forced {
+            vec![(custom_timestamp_ms - 1.0, custom_timestamp_ms + 1.0)]
+        } else {
+            trim_ranges_ms
+        };
+
+        let cal = stab.lens_calibrator.clone();
+        if max_points > 0 {
+            let mut lock = cal.write();
+            let cal = lock.as_mut().unwrap();
+            let saved: BTreeMap<i32, core::calibration::Detected> = {
+                let lock = cal.image_points.read();
+                cal.forced_frames.iter().filter_map(|f| Some((*f, lock.get(f)?.clone()))).collect()
             };
+            *cal.image_points.write() = saved;
+            cal.max_images = max_points;
+            cal.iterations = iterations;
+            cal.max_sharpness = max_sharpness;
+        }
 
-            let cal = stab.lens_calibrator.clone();
-            if max_points > 0 {
-                let mut lock = cal.write();
-                let cal = lock.as_mut().unwrap();
-                let saved: BTreeMap<i32, core::calibration::Detected> = {
-                    let lock = cal.image_points.read();
-                    cal.forced_frames.iter().filter_map(|f| Some((*f, lock.get(f)?.clone()))).collect()
-                };
-                *cal.image_points.write() = saved;
-                cal.max_images = max_points;
-                cal.iterations = iterations;
-                cal.max_sharpness = max_sharpness;
+        let progress = util::qt_queued_callback_mut(self, |this, (ready, total, good, rms, sharpness): (usize, usize, usize, f64, f64)| {
+            this.calib_in_progress = ready < total;
+            this.calib_in_progress_changed();
+            this.calib_progress(ready as f64 / total as f64, rms, ready, total, good, sharpness);
+            if rms > 0.0 {
+                this.update_calib_model();
             }
+        });
+        let err = util::qt_queued_callback_mut(self, |this, (msg, mut arg): (String, String)| {
+            arg.push_str("\n\n");
+            arg.push_str(&rendering::get_log());
 
-            let progress = util::qt_queued_callback_mut(self, |this, (ready, total, good, rms, sharpness): (usize, usize, usize, f64, f64)| {
-                this.calib_in_progress = ready < total;
-                this.calib_in_progress_changed();
-                this.calib_progress(ready as f64 / total as f64, rms, ready, total, good, sharpness);
-                if rms > 0.0 {
-                    this.update_calib_model();
-                }
-            });
-            let err = util::qt_queued_callback_mut(self, |this, (msg, mut arg): (String, String)| {
-                arg.push_str("\n\n");
-                arg.push_str(&rendering::get_log());
-
-                this.error(QString::from(msg), QString::from(arg), QString::default());
-
-                this.calib_in_progress = false;
-                this.calib_in_progress_changed();
-            });
+            this.error(QString::from(msg), QString::from(arg), QString::default());
 
-            self.cancel_flag.store(false, SeqCst);
-            let cancel_flag = self.cancel_flag.clone();
+            this.calib_in_progress = false;
+            this.calib_in_progress_changed();
+        });
 
-            let total = ((frame_count as f64 * trim_ratio) / every_nth_frame as f64) as usize;
-            let total_read = Arc::new(AtomicUsize::new(0));
-            let processed = Arc::new(AtomicUsize::new(0));
+        self.cancel_flag.store(false, SeqCst);
+        let cancel_flag = self.cancel_flag.clone();
 
-            let processing_resolution = self.processing_resolution;
+        let total = ((frame_count as f64 * trim_ratio) / every_nth_frame as f64) as usize;
+        let total_read = Arc::new(AtomicUsize::new(0));
+        let processed = Arc::new(AtomicUsize::new(0));
 
-            let input_file = stab.input_file.read().clone();
-            core::run_threaded(move || {
+        let processing_resolution = self.processing_resolution;
 
-                let mut decoder_options = ffmpeg_next::Dictionary::new();
-                if input_file.image_sequence_fps > 0.0 {
-                    let fps = rendering::fps_to_rational(input_file.image_sequence_fps);
-                    decoder_options.set("framerate", &format!("{}/{}", fps.numerator(), fps.denominator()));
-                }
-                if input_file.image_sequence_start > 0 {
-                    decoder_options.set("start_number", &format!("{}", input_file.image_sequence_start));
-                }
-                if processing_resolution > 0 {
-                    decoder_options.set("scale", &format!("{}x{}", (processing_resolution * 16) / 9, processing_resolution));
-                }
+        let input_file = stab.input_file.read().clone();
+        core::run_threaded(move || {
 
-                ::log::debug!("Decoder options: {:?}", decoder_options);
-                let gpu_decoding = *rendering::GPU_DECODING.read();
-                let fs_base = gyroflow_core::filesystem::get_engine_base();
-                match VideoProcessor::from_file(&fs_base, &input_file.url, gpu_decoding, 0, Some(decoder_options)) {
-                    Ok(mut proc) => {
-                        let progress = progress.clone();
-                        let err2 = err.clone();
-                        let cal = cal.clone();
-                        let total_read = total_read.clone();
-                        let processed = processed.clone();
-                        let cancel_flag2 = cancel_flag.clone();
+            let mut decoder_options = ffmpeg_next::Dictionary::new();
+            if input_file.image_sequence_fps > 0.0 {
+                let fps = rendering::fps_to_rational(input_file.image_sequence_fps);
+                decoder_options.set("framerate", &format!("{}/{}", fps.numerator(), fps.denominator()));
+            }
+            if input_file.image_sequence_start > 0 {
+                decoder_options.set("start_number", &format!("{}", input_file.image_sequence_start));
+            }
+            if processing_resolution > 0 {
+                decoder_options.set("scale", &format!("{}x{}", (processing_resolution * 16) / 9, processing_resolution));
+            }
 
-                        proc.on_frame(move |timestamp_us, input_frame, _output_frame, converter, _rate_control| {
-                            let frame = core::frame_at_timestamp(timestamp_us as f64 / 1000.0, fps);
+            ::log::debug!("Decoder options: {:?}", decoder_options);
+            let gpu_decoding = *rendering::GPU_DECODING.read();
+            let fs_base = gyroflow_core::filesystem::get_engine_base();
+            match VideoProcessor::from_file(&fs_base, &input_file.url, gpu_decoding, 0, Some(decoder_options)) {
+                Ok(mut proc) => {
+                    let progress = progress.clone();
+                    let err2 = err.clone();
+                    let cal = cal.clone();
+                    let total_read = total_read.clone();
+                    let processed = processed.clone();
+                    let cancel_flag2 = cancel_flag.clone();
+
+                    proc.on_frame(move |timestamp_us, input_frame, _output_frame, converter, _rate_control| {
+                        let frame = core::frame_at_timestamp(timestamp_us as f64 / 1000.0, fps);
+
+                        if is_forced && total_read.load(SeqCst) > 0 {
+                            return Ok(());
+                        }
 
-                            if is_forced && total_read.load(SeqCst) > 0 {
-                                return Ok(());
+                        if (frame % every_nth_frame as i32) == 0 {
+                            let mut width = (input_frame.width() as f64 * input_horizontal_stretch).round() as u32;
+                            let mut height = (input_frame.height() as f64 * input_vertical_stretch).round() as u32;
+                            let org_size = (org_size.0 as u32, org_size.1 as u32);
+                            let mut pt_scale = 1.0;
+                            if processing_resolution > 0 && height > processing_resolution as u32 {
+                                pt_scale = height as f32 / processing_resolution as f32;
+                                width = (width as f32 / pt_scale).round() as u32;
+                                height = (height as f32 / pt_scale).round() as u32;
                             }
+                            match converter.scale(input_frame, ffmpeg_next::format::Pixel::GRAY8, width, height) {
+                                Ok(mut small_frame) => {
+                                    let (width, height, stride, pixels) = (small_frame.plane_width(0), small_frame.plane_height(0), small_frame.stride(0), small_frame.data_mut(0));
+
+                                    total_read.fetch_add(1, SeqCst);
+                                    let mut lock = cal.write();
+                                    let cal = lock.as_mut().unwrap();
+                                    if is_forced {
+                                        cal.forced_frames.insert(frame);
+                                    }
+                                    cal.no_marker = no_marker;
 
-                            if (frame % every_nth_frame as i32) == 0 {
-                                let mut width = (input_frame.width() as f64 * input_horizontal_stretch).round() as u32;
-                                let mut height = (input_frame.height() as f64 * input_vertical_stretch).round() as u32;
-                                let org_size = (org_size.0 as u32, org_size.1 as u32);
-                                let mut pt_scale = 1.0;
-                                if processing_resolution > 0 && height > processing_resolution as u32 {
-                                    pt_scale = height as f32 / processing_resolution as f32;
-                                    width = (width as f32 / pt_scale).round() as u32;
-                                    height = (height as f32 / pt_scale).round() as u32;
-                                }
-                                match converter.scale(input_frame, ffmpeg_next::format::Pixel::GRAY8, width, height) {
-                                    Ok(mut small_frame) => {
-                                        let (width, height, stride, pixels) = (small_frame.plane_width(0), small_frame.plane_height(0), small_frame.stride(0), small_frame.data_mut(0));
-
-                                        total_read.fetch_add(1, SeqCst);
-                                        let mut lock = cal.write();
-                                        let cal = lock.as_mut().unwrap();
-                                        if is_forced {
-                                            cal.forced_frames.insert(frame);
-                                        }
-                                        cal.no_marker = no_marker;
-
-                                        let (w, h) = org_size;
-                                        if w > 0 && h > 0 {
-                                            pt_scale = h as f32 / height as f32;
-                                        }
-                                        cal.feed_frame(timestamp_us, frame, (width, height), org_size, stride, pt_scale, pixels, cancel_flag2.clone(), total, processed.clone(), progress.clone());
-                                    },
-                                    Err(e) => {
-                                        err2(("An error occured: %1".to_string(), e.to_string()))
+                                    let (w, h) = org_size;
+                                    if w > 0 && h > 0 {
+                                        pt_scale = h as f32 / height as f32;
                                     }
+                                    cal.feed_frame(timestamp_us, frame, (width, height), org_size, stride, pt_scale, pixels, cancel_flag2.clone(), total, processed.clone(), progress.clone());
+                                },
+                                Err(e) => {
+                                    err2(("An error occured: %1".to_string(), e.to_string()))
                                 }
                             }
-                            Ok(())
-                        });
-                        if let Err(e) = proc.start_decoder_only(ranges, cancel_flag.clone()) {
-                            err(("An error occured: %1".to_string(), e.to_string()));
                         }
-                    }
-                    Err(error) => {
-                        err(("An error occured: %1".to_string(), error.to_string()));
+                        Ok(())
+                    });
+                    if let Err(e) = proc.start_decoder_only(ranges, cancel_flag.clone()) {
+                        err(("An error occured: %1".to_string(), e.to_string()));
                     }
                 }
-                // Don't lock the UI trying to draw chessboards while we calibrate
-                stab.params.write().is_calibrator = false;
-
-                while processed.load(SeqCst) < total_read.load(SeqCst) {
-                    std::thread::sleep(std::time::Duration::from_millis(500));
+                Err(error) => {
+                    err(("An error occured: %1".to_string(), error.to_string()));
                 }
+            }
+            // Don't lock the UI trying to draw chessboards while we calibrate
+            stab.params.write().is_calibrator = false;
 
-                let mut lock = cal.write();
-                let cal = lock.as_mut().unwrap();
-                if let Err(e) = cal.calibrate(is_forced) {
-                    err(("An error occured: %1".to_string(), format!("{:?}", e)));
-                } else {
-                    if cal.rms < 100.0 {
-                        stab.lens.write().set_from_calibrator(cal);
-                    }
-                    ::log::debug!("rms: {}, used_frames: {:?}, camera_matrix: {}, coefficients: {}", cal.rms, cal.used_points.keys(), cal.k, cal.d);
+            while processed.load(SeqCst) < total_read.load(SeqCst) {
+                std::thread::sleep(std::time::Duration::from_millis(500));
+            }
+
+            let mut lock = cal.write();
+            let cal = lock.as_mut().unwrap();
+            if let Err(e) = cal.calibrate(is_forced) {
+                err(("An error occured: %1".to_string(), format!("{:?}", e)));
+            } else {
+                if cal.rms < 100.0 {
+                    stab.lens.write().set_from_calibrator(cal);
                 }
+                ::log::debug!("rms: {}, used_frames: {:?}, camera_matrix: {}, coefficients: {:?}", cal.rms, cal.used_points.keys(), cal.k, cal.d);
+            }
 
-                let good = cal.image_points.read().len();
-                progress((total, total, good, cal.rms, *cal.sum_sharpness.read() / good.max(1) as f64));
+            let good = cal.image_points.read().len();
+            progress((total, total, good, cal.rms, *cal.sum_sharpness.read() / good.max(1) as f64));
 
-                stab.params.write().is_calibrator = true;
-            });
-        }
+            stab.params.write().is_calibrator = true;
+        });
     }
 
     fn update_calib_model(&mut self) {
-        #[cfg(feature = "opencv")]
-        {
-            let cal = self.stabilizer.lens_calibrator.clone();
+        let cal = self.stabilizer.lens_calibrator.clone();
 
-            let used_points = cal.read().as_ref().map(|x| x.used_points.clone()).unwrap_or_default();
+        let used_points = cal.read().as_ref().map(|x| x.used_points.clone()).unwrap_or_default();
 
-            self.calib_model = RefCell::new(used_points.values().map(|v| CalibrationItem {
-                timestamp_us: v.timestamp_us,
-                sharpness: v.avg_sharpness,
-                is_forced: v.is_forced
-            }).collect());
+        self.calib_model = RefCell::new(used_points.values().map(|v| CalibrationItem {
+            timestamp_us: v.timestamp_us,
+            sharpness: v.avg_sharpness,
+            is_forced: v.is_forced
+        }).collect());
 
-            util::qt_queued_callback(self, |this, _| {
-                this.calib_model_updated();
-            })(());
-        }
+        util::qt_queued_callback(self, |this, _| {
+            this.calib_model_updated();
+        })(());
     }
 
     fn add_calibration_point(&mut self, timestamp_us: i64, no_marker: bool) {
         self.start_autocalibrate(0, 1, 1, 1000.0, timestamp_us as f64 / 1000.0, no_marker);
     }
     fn remove_calibration_point(&mut self, timestamp_us: i64) {
-        #[cfg(feature = "opencv")]
+        let cal = self.stabilizer.lens_calibrator.clone();
+        let mut rms = 0.0;
         {
-            let cal = self.stabilizer.lens_calibrator.clone();
-            let mut rms = 0.0;
-            {
-                let mut lock = cal.write();
-                let cal = lock.as_mut().unwrap();
-                let mut frame_to_remove = None;
-                for x in &cal.used_points {
-                    if x.1.timestamp_us == timestamp_us {
-                        frame_to_remove = Some(*x.0);
-                        break;
+            let mut lock = cal.write();
+            let cal = lock.as_mut().unwrap();
+            let mut frame_to_remove = None;
+            for x in &cal.used_points {
+                if x.1.timestamp_us == timestamp_us {
+                    frame_to_remove = Some(*x.0);
+                    break;
+                }
+            }
+            if let Some(f) = frame_to_remove {
+                cal.forced_frames.remove(&f);
+                cal.used_points.remove(&f);
+            }
+            if cal.calibrate(true).is_ok() {
+                rms = cal.rms;
+                self.stabilizer.lens.write().set_from_calibrator(cal);
+                ::log::debug!("rms: {}, used_frames: {:?}, camera_matrix: {}, coefficients: {:?}", cal.rms, cal.used_points.keys(), cal.k, cal.d);
+            }
+        }
+        self.update_calib_model();
+        if rms > 0.0 {
+            self.calib_progress(1.0, rms, 1, 1, 1, 0.0);
+        }
+    }
+
+    fn set_calibration_model(&mut self, id: String) {
+        let mut progress = None;
+        if let Some(ref mut cal) = *self.stabilizer.lens_calibrator.write() {
+            cal.set_distortion_model(if id.is_empty() { None } else { Some(id) });
+            if cal.rms > 0.0 && cal.rms < 100.0 {
+                let good = cal.image_points.read().len();
+                progress = Some((cal.rms, good, *cal.sum_sharpness.read() / good.max(1) as f64));
+                self.stabilizer.lens.write().set_from_calibrator(cal);
+            }
+        }
+        if let Some((rms, good, sharpness)) = progress {
+            self.calib_progress(1.0, rms, 1, 1, good, sharpness);
+            self.request_recompute();
+        }
+    }
+    fn get_calibration_models(&self) -> QJsonArray {
+        let models = self.stabilizer.lens_calibrator.read().as_ref().map(|cal| {
+            cal.model_results.iter().map(|x| serde_json::json!({
+                "id": x.model,
+                "name": core::stabilization::distortion_models::DistortionModel::from_name(&x.model).name(),
+                "rms": x.rms
+            })).collect::<Vec<_>>()
+        }).unwrap_or_default();
+        util::serde_json_to_qt_array(&serde_json::Value::Array(models))
+    }
+
+    fn get_calibration_report(&self) -> QJsonObject {
+        let report = self.stabilizer.lens_calibrator.read().as_ref().and_then(|cal| cal.report().ok());
+        if let Some(report) = report {
+            if let Ok(mut v) = serde_json::to_value(&report) {
+                // Residuals of every point are only in the exported report
+                if let Some(frames) = v.get_mut("frames").and_then(|x| x.as_array_mut()) {
+                    for frame in frames {
+                        if let Some(obj) = frame.as_object_mut() { obj.remove("residuals"); }
                     }
                 }
-                if let Some(f) = frame_to_remove {
-                    cal.forced_frames.remove(&f);
-                    cal.used_points.remove(&f);
+                return util::serde_json_to_qt_object(&v);
+            }
+        }
+        QJsonObject::default()
+    }
+    fn export_calibration_report(&self, url: QUrl) {
+        let url = util::qurl_to_encoded(url);
+        let report = self.stabilizer.lens_calibrator.read().as_ref().map(|cal| cal.report());
+        match report {
+            Some(Ok(report)) => {
+                if let Err(e) = report.save_to_file(&url) {
+                    self.error(QString::from("An error occured: %1"), QString::from(format!("{:?}", e)), QString::default());
                 }
-                if cal.calibrate(true).is_ok() {
-                    rms = cal.rms;
-                    self.stabilizer.lens.write().set_from_calibrator(cal);
-                    ::log::debug!("rms: {}, used_frames: {:?}, camera_matrix: {}, coefficients: {}", cal.rms, cal.used_points.keys(), cal.k, cal.d);
+            },
+            Some(Err(e)) => { self.error(QString::from("An error occured: %1"), QString::from(format!("{:?}", e)), QString::default()); },
+            None => { }
+        }
+    }
+    fn reject_calibration_outliers(&mut self) {
+        let stab = self.stabilizer.clone();
+
+        let finished = util::qt_queued_callback_mut(self, |this, (rms, good, sharpness): (f64, usize, f64)| {
+            this.calib_in_progress = false;
+            this.calib_in_progress_changed();
+            this.update_calib_model();
+            this.calib_progress(1.0, rms, 1, 1, good, sharpness);
+            this.request_recompute();
+        });
+        let err = util::qt_queued_callback_mut(self, |this, (msg, arg): (String, String)| {
+            this.error(QString::from(msg), QString::from(arg), QString::default());
+
+            this.calib_in_progress = false;
+            this.calib_in_progress_changed();
+        });
+
+        self.calib_in_progress = true;
+        self.calib_in_progress_changed();
+
+        core::run_threaded(move || {
+            let mut lock = stab.lens_calibrator.write();
+            let Some(cal) = lock.as_mut() else { return; };
+            match cal.reject_outliers() {
+                Ok(rejected) => {
+                    ::log::info!("Rejected frames: {:?}, rms: {}", rejected, cal.rms);
+                    if !rejected.is_empty() {
+                        stab.lens.write().set_from_calibrator(cal);
+                    }
+                    let good = cal.image_points.read().len();
+                    finished((cal.rms, good, *cal.sum_sharpness.read() / good.max(1) as f64));
+                },
+                Err(e) => {
+                    err(("An error occured: %1".to_string(), format!("{:?}", e)));
                 }
             }
-            self.update_calib_model();
-            if rms > 0.0 {
-                self.calib_progress(1.0, rms, 1, 1, 1, 0.0);
+        });
+    }
+
+    fn calibrate_imu(&mut self) {
+        let stab = self.stabilizer.clone();
+
+        let finished = util::qt_queued_callback_mut(self, |this, result: serde_json::Value| {
+            this.calib_in_progress = false;
+            this.calib_in_progress_changed();
+            this.update_calib_model();
+            this.imu_calibrated(util::serde_json_to_qt_object(&result));
+            this.request_recompute();
+        });
+        let err = util::qt_queued_callback_mut(self, |this, (msg, mut arg): (String, String)| {
+            arg.push_str("\n\n");
+            arg.push_str(&rendering::get_log());
+
+            this.error(QString::from(msg), QString::from(arg), QString::default());
+
+            this.calib_in_progress = false;
+            this.calib_in_progress_changed();
+        });
+
+        self.calib_in_progress = true;
+        self.calib_in_progress_changed();
+
+        core::run_threaded(move || {
+            let gyro = stab.gyro.read().file_metadata.raw_imu.clone();
+            let mut lock = stab.lens_calibrator.write();
+            let Some(cal) = lock.as_mut() else { return; };
+            match cal.calibrate_imu(&gyro) {
+                Ok(()) => {
+                    stab.lens.write().set_from_calibrator(cal);
+                    if let Some(x) = &cal.imu_calibration {
+                        finished(serde_json::json!({
+                            "rms":                x.rms,
+                            "gyro_rms":           x.gyro_rms,
+                            "imu_orientation":    x.imu_orientation,
+                            "imu_rotation":       x.imu_rotation,
+                            "offset":             x.offset,
+                            "frame_readout_time": x.frame_readout_time,
+                            "views":              x.views
+                        }));
+                    }
+                },
+                Err(e) => {
+                    err(("An error occured: %1".to_string(), format!("{:?}", e)));
+                }
             }
-        }
+        });
     }
 
     fn export_lens_profile_filename(&self, info: QJsonObject) -> QString {
         let info_json = info.to_json().to_string();
 
         if let Ok(mut profile) = core::lens_profile::LensProfile::from_json(&info_json) {
-            #[cfg(feature = "opencv")]
             if let Some(ref cal) = *self.stabilizer.lens_calibrator.read() {
                 profile.set_from_calibrator(cal);
             }
@@ -1718,15 +1911,16 @@ impl Controller {
 
         match core::lens_profile::LensProfile::from_json(&info_json) {
             Ok(mut profile) => {
-                #[cfg(feature = "opencv")]
                 if let Some(ref cal) = *self.stabilizer.lens_calibrator.read() {
                     profile.set_from_calibrator(cal);
                 }
 
                 match profile.save_to_file(&url) {
-                    Ok(json) => {
-                        ::log::debug!("Lens profile json: {}", json);
-                        if upload {
+                    Ok(data) => {
+                        ::log::debug!("Lens profile: {}", data);
+                        // Exported formats are refitted, the database gets the original calibration
+                        let json = if core::lens_profile_formats::is_exportable(&url) { profile.get_json().unwrap_or_default() } else { data };
+                        if upload && !json.is_empty() {
                             core::run_threaded(move || {
                                 if let Ok(Ok(body)) = ureq::post("https://api.gyroflow.xyz/upload_profile").set("Content-Type", "application/json; charset=utf-8").send_string(&json).map(|x| x.into_string()) {
                                     ::log::debug!("Lens profile uploaded: {}", body.as_str());
@@ -0,0 +1,474 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Chessboard detection without OpenCV:
+//   - X-junctions are found with the ChESS detector (Bennett & Lasenby, "ChESS - Quick and Robust Detection of Chess-board Features")
+//   - the strongest corners are used as seeds and the grid is grown from them, predicting every next corner from the already found ones
+//   - the corners are refined to subpixel accuracy the same way as `cv::cornerSubPix`
+// The corners are returned in the same layout as `findChessboardCornersSB`: row by row, `columns` corners in each row.
+// The markers of our calibration target are not used, so the first corner is chosen only from the orientation of the board in the image.
+// Partial boards (`CalibrationTarget::PartialChessboard`) are returned with their own grid coordinates, see `find_partial_chessboard`.
+
+use std::collections::{ HashMap, VecDeque };
+
+const MAX_CANDIDATES: usize = 2000;
+const MAX_SEEDS: usize = 20;
+
+/// Detected points by their integer grid coordinates
+pub type Grid = HashMap<(i32, i32), (f32, f32)>;
+/// Corner position in the image and its grid coordinates
+pub type GridCorner = ((f32, f32), (i32, i32));
+
+pub struct GrayImage {
+    data: Vec<f32>,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl GrayImage {
+    pub fn new(pixels: &[u8], width: usize, height: usize, stride: usize) -> Self {
+        let mut data = Vec::with_capacity(width * height);
+        for y in 0..height {
+            data.extend(pixels[y * stride..y * stride + width].iter().map(|&x| x as f32));
+        }
+        Self { data, width, height }
+    }
+
+    #[inline]
+    fn at(&self, x: usize, y: usize) -> f32 {
+        self.data[y * self.width + x]
+    }
+
+    /// Bilinear sample, clamped to the image
+    pub fn sample(&self, x: f32, y: f32) -> f32 {
+        let x = x.max(0.0).min((self.width - 1) as f32);
+        let y = y.max(0.0).min((self.height - 1) as f32);
+        let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+        let (x1, y1) = ((x0 + 1).min(self.width - 1), (y0 + 1).min(self.height - 1));
+        let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+        let top    = self.at(x0, y0) * (1.0 - fx) + self.at(x1, y0) * fx;
+        let bottom = self.at(x0, y1) * (1.0 - fx) + self.at(x1, y1) * fx;
+        top * (1.0 - fy) + bottom * fy
+    }
+
+    fn gradient(&self, x: f32, y: f32) -> (f32, f32) {
+        (
+            (self.sample(x + 1.0, y) - self.sample(x - 1.0, y)) * 0.5,
+            (self.sample(x, y + 1.0) - self.sample(x, y - 1.0)) * 0.5
+        )
+    }
+
+    /// 3x3 box blur
+    pub fn blur(&self) -> Self {
+        let (w, h) = (self.width, self.height);
+        let mut tmp = vec![0.0; w * h];
+        for y in 0..h {
+            for x in 0..w {
+                tmp[y * w + x] = (self.at(x.saturating_sub(1), y) + self.at(x, y) + self.at((x + 1).min(w - 1), y)) / 3.0;
+            }
+        }
+        let mut data = vec![0.0; w * h];
+        for y in 0..h {
+            for x in 0..w {
+                data[y * w + x] = (tmp[y.saturating_sub(1) * w + x] + tmp[y * w + x] + tmp[(y + 1).min(h - 1) * w + x]) / 3.0;
+            }
+        }
+        Self { data, width: w, height: h }
+    }
+}
+
+/// Returns `columns * rows` inner corners of the chessboard, or `None` if the whole board wasn't found
+pub fn find_chessboard_corners(img: &GrayImage, columns: usize, rows: usize) -> Option<Vec<(f32, f32)>> {
+    if columns < 2 || rows < 2 || img.width < 16 || img.height < 16 { return None; }
+
+    let radius = ring_radius(img);
+    let img = img.blur();
+    let candidates = find_x_corners(&img, radius);
+    if candidates.len() < columns * rows { return None; }
+
+    let points: Vec<(f32, f32)> = candidates.iter().map(|x| x.0).collect();
+    for seed in 0..candidates.len().min(MAX_SEEDS) {
+        if let Some(board) = grow_grid(&points, seed, (columns + 2) * (rows + 2)).and_then(|grid| full_board(&grid, columns, rows)) {
+            return Some(board.into_iter().map(|pt| refine_corner(&img, pt, radius as f32)).collect());
+        }
+    }
+    None
+}
+
+/// The biggest part of a `columns` x `rows` board with at least `min_corners` corners.
+/// Returns the corners with their grid coordinates, starting from 0 with the same orientation rules as `find_chessboard_corners`
+pub fn find_partial_chessboard(img: &GrayImage, columns: usize, rows: usize, min_corners: usize) -> Option<Vec<GridCorner>> {
+    if columns < 2 || rows < 2 || img.width < 16 || img.height < 16 { return None; }
+
+    let radius = ring_radius(img);
+    let img = img.blur();
+    let candidates = find_x_corners(&img, radius);
+    let points: Vec<(f32, f32)> = candidates.iter().map(|x| x.0).collect();
+
+    let fits = |grid: &Grid| -> bool {
+        let span = |f: fn(&(i32, i32)) -> i32| grid.keys().map(f).max().unwrap_or_default() - grid.keys().map(f).min().unwrap_or_default() + 1;
+        let (w, h) = (span(|x| x.0) as usize, span(|x| x.1) as usize);
+        // Degenerate grids (single rows or columns) don't constrain the board pose
+        w >= 3 && h >= 3 && ((w <= columns && h <= rows) || (w <= rows && h <= columns))
+    };
+    let grid = (0..points.len().min(MAX_SEEDS))
+        .filter_map(|seed| grow_grid(&points, seed, columns * rows))
+        .filter(fits)
+        .max_by_key(|grid| grid.len())?;
+    if grid.len() < min_corners { return None; }
+
+    Some(oriented_cells(&grid).into_iter().map(|(pt, cell)| (refine_corner(&img, pt, radius as f32), cell)).collect())
+}
+
+/// Average width (in pixels) of the black-white transitions along the edges between the corners, similar to `cv::estimateChessboardSharpness`.
+/// Lower is sharper
+pub fn estimate_sharpness(img: &GrayImage, columns: usize, rows: usize, corners: &[(f32, f32)]) -> f64 {
+    if corners.len() != columns * rows { return 100.0; }
+
+    let cells: Vec<GridCorner> = corners.iter().enumerate().map(|(i, pt)| (*pt, ((i % columns) as i32, (i / columns) as i32))).collect();
+    estimate_grid_sharpness(img, &cells)
+}
+
+/// Same as `estimate_sharpness`, for corners with grid coordinates
+pub fn estimate_grid_sharpness(img: &GrayImage, cells: &[GridCorner]) -> f64 {
+    let grid: Grid = cells.iter().map(|(pt, cell)| (*cell, *pt)).collect();
+    let mut sum = 0.0;
+    let mut count = 0;
+    for ((i, j), pt) in &grid {
+        for neighbour in [(i + 1, *j), (*i, j + 1)] {
+            if let Some(width) = grid.get(&neighbour).and_then(|other| edge_width(img, *pt, *other)) {
+                sum += width;
+                count += 1;
+            }
+        }
+    }
+    if count == 0 { return 100.0; }
+    sum / count as f64
+}
+
+fn ring_radius(img: &GrayImage) -> usize {
+    (img.width.min(img.height) / 150).clamp(4, 10)
+}
+
+// ChESS response for every pixel, then non-maximum suppression. Returns the corners sorted by strength
+fn find_x_corners(img: &GrayImage, radius: usize) -> Vec<((f32, f32), f32)> {
+    let (w, h) = (img.width, img.height);
+    let ring: Vec<(isize, isize)> = (0..16).map(|i| {
+        let a = i as f64 * std::f64::consts::PI / 8.0;
+        ((a.cos() * radius as f64).round() as isize, (a.sin() * radius as f64).round() as isize)
+    }).collect();
+
+    let border = radius + 1;
+    let mut response = vec![0.0f32; w * h];
+    let mut max_response = 0.0f32;
+    for y in border..h - border {
+        for x in border..w - border {
+            let mut s = [0.0f32; 16];
+            for (i, (dx, dy)) in ring.iter().enumerate() {
+                s[i] = img.at((x as isize + dx) as usize, (y as isize + dy) as usize);
+            }
+            let sum_response: f32 = (0..4).map(|n| (s[n] + s[n + 8] - s[n + 4] - s[n + 12]).abs()).sum();
+            let diff_response: f32 = (0..8).map(|n| (s[n] - s[n + 8]).abs()).sum();
+            let ring_mean = s.iter().sum::<f32>() / 16.0;
+            let local_mean = (img.at(x, y) * 4.0 + img.at(x - 1, y) + img.at(x + 1, y) + img.at(x, y - 1) + img.at(x, y + 1)) / 8.0;
+            let r = sum_response - diff_response - 16.0 * (ring_mean - local_mean).abs();
+            if r > 0.0 {
+                response[y * w + x] = r;
+                max_response = max_response.max(r);
+            }
+        }
+    }
+    if max_response <= 0.0 { return Vec::new(); }
+
+    let threshold = max_response * 0.1;
+    let nms = radius as isize;
+    let mut ret = Vec::new();
+    for y in border..h - border {
+        for x in border..w - border {
+            let r = response[y * w + x];
+            if r < threshold { continue; }
+            let mut is_max = true;
+            'nms: for dy in -nms..=nms {
+                for dx in -nms..=nms {
+                    let (nx, ny) = (x as isize + dx, y as isize + dy);
+                    if (dx != 0 || dy != 0) && nx >= 0 && ny >= 0 && (nx as usize) < w && (ny as usize) < h {
+                        let other = response[ny as usize * w + nx as usize];
+                        // Ties are resolved by position, so a plateau gives only one corner
+                        if other > r || (other == r && (dy, dx) < (0, 0)) {
+                            is_max = false;
+                            break 'nms;
+                        }
+                    }
+                }
+            }
+            if is_max {
+                ret.push(((x as f32, y as f32), r));
+            }
+        }
+    }
+    ret.sort_by(|a, b| b.1.total_cmp(&a.1));
+    ret.truncate(MAX_CANDIDATES);
+    ret
+}
+
+fn dist2(a: (f32, f32), b: (f32, f32)) -> f32 {
+    (a.0 - b.0).powi(2) + (a.1 - b.1).powi(2)
+}
+
+fn nearest(points: &[(f32, f32)], used: &[bool], pt: (f32, f32), max_dist: f32) -> Option<usize> {
+    points.iter().enumerate()
+        .filter(|(i, p)| !used[*i] && dist2(**p, pt) < max_dist * max_dist)
+        .min_by(|a, b| dist2(*a.1, pt).total_cmp(&dist2(*b.1, pt)))
+        .map(|x| x.0)
+}
+
+/// Grows a square grid from `points[seed]`, its two nearest neighbours in roughly perpendicular directions define the initial axes.
+/// Returns `None` if the grid has more than `max_cells` points
+pub fn grow_grid(points: &[(f32, f32)], seed: usize, max_cells: usize) -> Option<Grid> {
+    let origin = points[seed];
+    let mut by_distance: Vec<usize> = (0..points.len()).filter(|&i| i != seed).collect();
+    by_distance.sort_by(|&a, &b| dist2(points[a], origin).total_cmp(&dist2(points[b], origin)));
+    by_distance.truncate(8);
+
+    // Two nearest neighbours in roughly perpendicular directions define the initial grid axes
+    let first = *by_distance.first()?;
+    let u = (points[first].0 - origin.0, points[first].1 - origin.1);
+    let u_len = dist2(points[first], origin).sqrt();
+    let second = *by_distance.iter().skip(1).find(|&&i| {
+        let v = (points[i].0 - origin.0, points[i].1 - origin.1);
+        let v_len = dist2(points[i], origin).sqrt();
+        let cos = (u.0 * v.0 + u.1 * v.1) / (u_len * v_len);
+        cos.abs() < 0.5 && v_len / u_len < 2.0
+    })?;
+    let v = (points[second].0 - origin.0, points[second].1 - origin.1);
+
+    let mut grid = Grid::new();
+    let mut used = vec![false; points.len()];
+    for (cell, idx) in [((0, 0), seed), ((1, 0), first), ((0, 1), second)] {
+        grid.insert(cell, points[idx]);
+        used[idx] = true;
+    }
+
+    let mut queue: VecDeque<(i32, i32)> = [(0, 0), (1, 0), (0, 1)].into_iter().collect();
+    while let Some((i, j)) = queue.pop_front() {
+        let pt = grid[&(i, j)];
+        for (di, dj) in [(1, 0), (-1, 0), (0, 1), (0, -1)] {
+            let target = (i + di, j + dj);
+            if grid.contains_key(&target) { continue; }
+
+            // Continue the line if possible, otherwise use the parallel neighbour row or column, and the initial axes as the last resort
+            let (pi, pj) = (dj, di);
+            let step = if let Some(prev) = grid.get(&(i - di, j - dj)) {
+                (pt.0 - prev.0, pt.1 - prev.1)
+            } else if let (Some(a), Some(b)) = (grid.get(&(i + pi, j + pj)), grid.get(&(i + pi + di, j + pj + dj))) {
+                (b.0 - a.0, b.1 - a.1)
+            } else if let (Some(a), Some(b)) = (grid.get(&(i - pi, j - pj)), grid.get(&(i - pi + di, j - pj + dj))) {
+                (b.0 - a.0, b.1 - a.1)
+            } else if di != 0 {
+                (u.0 * di as f32, u.1 * di as f32)
+            } else {
+                (v.0 * dj as f32, v.1 * dj as f32)
+            };
+            let predicted = (pt.0 + step.0, pt.1 + step.1);
+            let radius = (step.0 * step.0 + step.1 * step.1).sqrt() * 0.35;
+            if let Some(idx) = nearest(points, &used, predicted, radius) {
+                used[idx] = true;
+                grid.insert(target, points[idx]);
+                queue.push_back(target);
+                if grid.len() > max_cells { return None; }
+            }
+        }
+    }
+    Some(grid)
+}
+
+// The only fully detected window of the board size, in either orientation
+fn full_board(grid: &Grid, columns: usize, rows: usize) -> Option<Vec<(f32, f32)>> {
+    let min_i = grid.keys().map(|x| x.0).min()?;
+    let max_i = grid.keys().map(|x| x.0).max()?;
+    let min_j = grid.keys().map(|x| x.1).min()?;
+    let max_j = grid.keys().map(|x| x.1).max()?;
+
+    let mut found = None;
+    for transposed in [false, true] {
+        let (w, h) = if transposed { (rows as i32, columns as i32) } else { (columns as i32, rows as i32) };
+        for j0 in min_j..=max_j - h + 1 {
+            for i0 in min_i..=max_i - w + 1 {
+                if (0..h).all(|j| (0..w).all(|i| grid.contains_key(&(i0 + i, j0 + j)))) {
+                    if found.is_some() { return None; }
+                    found = Some((i0, j0, transposed));
+                }
+            }
+        }
+    }
+    let (i0, j0, transposed) = found?;
+
+    let at = |x: usize, y: usize| -> (f32, f32) {
+        let (i, j) = if transposed { (y as i32, x as i32) } else { (x as i32, y as i32) };
+        grid[&(i0 + i, j0 + j)]
+    };
+    let mut ret: Vec<Vec<(f32, f32)>> = (0..rows).map(|y| (0..columns).map(|x| at(x, y)).collect()).collect();
+
+    // Rows go down and columns go right in the image, as long as the board isn't rotated more than 90 degrees
+    let first = ret[0][0];
+    let ex = (ret[0][columns - 1].0 - first.0, ret[0][columns - 1].1 - first.1);
+    let ey = (ret[rows - 1][0].0 - first.0, ret[rows - 1][0].1 - first.1);
+    if ex.0 * ey.1 - ex.1 * ey.0 < 0.0 {
+        ret.reverse();
+    }
+    let ex = ret[0][columns - 1].0 - ret[0][0].0;
+    if ex < 0.0 {
+        ret.reverse();
+        ret.iter_mut().for_each(|row| row.reverse());
+    }
+    Some(ret.into_iter().flatten().collect())
+}
+
+/// Grid coordinates starting from 0, with the first axis going right and the second going down in the image, sorted row by row
+pub fn oriented_cells(grid: &Grid) -> Vec<GridCorner> {
+    let mean_step = |di: i32, dj: i32| -> (f32, f32) {
+        let steps: Vec<(f32, f32)> = grid.iter().filter_map(|((i, j), pt)| {
+            let next = grid.get(&(i + di, j + dj))?;
+            Some((next.0 - pt.0, next.1 - pt.1))
+        }).collect();
+        let n = steps.len().max(1) as f32;
+        steps.iter().fold((0.0, 0.0), |a, s| (a.0 + s.0 / n, a.1 + s.1 / n))
+    };
+    let (mut ex, mut ey) = (mean_step(1, 0), mean_step(0, 1));
+    // The columns go along the axis which is closer to horizontal
+    let transpose = ex.0.abs() < ey.0.abs();
+    if transpose { std::mem::swap(&mut ex, &mut ey); }
+    let flip_j = ex.0 * ey.1 - ex.1 * ey.0 < 0.0;
+    let rotate = ex.0 < 0.0;
+    let (si, sj) = (if rotate { -1 } else { 1 }, if rotate != flip_j { -1 } else { 1 });
+
+    let cells: Vec<GridCorner> = grid.iter().map(|((i, j), pt)| {
+        let (i, j) = if transpose { (*j, *i) } else { (*i, *j) };
+        (*pt, (i * si, j * sj))
+    }).collect();
+    let min_i = cells.iter().map(|x| x.1.0).min().unwrap_or_default();
+    let min_j = cells.iter().map(|x| x.1.1).min().unwrap_or_default();
+    let mut ret: Vec<GridCorner> = cells.into_iter().map(|(pt, (i, j))| (pt, (i - min_i, j - min_j))).collect();
+    ret.sort_by_key(|(_, (i, j))| (*j, *i));
+    ret
+}
+
+// Iterative refinement like `cv::cornerSubPix`: the corner is the point where all gradients in the window are perpendicular to the vectors from it
+fn refine_corner(img: &GrayImage, pt: (f32, f32), half_window: f32) -> (f32, f32) {
+    let win = half_window.round() as i32;
+    let sigma2 = (half_window * half_window / 2.0).max(1.0);
+    let mut cur = pt;
+    for _ in 0..20 {
+        let (mut a, mut b, mut c, mut bx, mut by) = (0.0f64, 0.0f64, 0.0f64, 0.0f64, 0.0f64);
+        for dy in -win..=win {
+            for dx in -win..=win {
+                let (x, y) = (cur.0 + dx as f32, cur.1 + dy as f32);
+                let weight = (-((dx * dx + dy * dy) as f32) / (2.0 * sigma2)).exp() as f64;
+                let (gx, gy) = img.gradient(x, y);
+                let (gx, gy) = (gx as f64, gy as f64);
+                let (gxx, gxy, gyy) = (gx * gx * weight, gx * gy * weight, gy * gy * weight);
+                a += gxx; b += gxy; c += gyy;
+                bx += gxx * x as f64 + gxy * y as f64;
+                by += gxy * x as f64 + gyy * y as f64;
+            }
+        }
+        let det = a * c - b * b;
+        if det.abs() < 1e-9 { break; }
+        let new = (((c * bx - b * by) / det) as f32, ((a * by - b * bx) / det) as f32);
+        let moved = dist2(new, cur);
+        cur = new;
+        if moved < 0.0001 { break; }
+    }
+    if dist2(cur, pt) > half_window * half_window { pt } else { cur }
+}
+
+// 10%-90% transition width across the edge between two neighbouring corners, averaged over a few profiles along the edge
+fn edge_width(img: &GrayImage, a: (f32, f32), b: (f32, f32)) -> Option<f64> {
+    let len = dist2(a, b).sqrt();
+    if len < 4.0 { return None; }
+    let dir = ((b.0 - a.0) / len, (b.1 - a.1) / len);
+    let normal = (-dir.1, dir.0);
+    let half = len * 0.3;
+    let step = 0.25;
+    let samples = (half * 2.0 / step) as usize + 1;
+
+    let mut widths = Vec::new();
+    for t in [0.35, 0.5, 0.65] {
+        let center = (a.0 + (b.0 - a.0) * t, a.1 + (b.1 - a.1) * t);
+        let profile: Vec<f32> = (0..samples).map(|i| {
+            let s = -half + i as f32 * step;
+            img.sample(center.0 + normal.0 * s, center.1 + normal.1 * s)
+        }).collect();
+
+        if let Some(width) = transition_width(&profile, step) {
+            widths.push(width);
+        }
+    }
+    if widths.is_empty() { return None; }
+    Some(widths.iter().sum::<f64>() / widths.len() as f64)
+}
+
+/// Distance between the 10% and 90% levels of a single dark-bright (or bright-dark) transition, `step` is the distance between the samples
+pub fn transition_width(profile: &[f32], step: f32) -> Option<f64> {
+    let (min, max) = profile.iter().fold((f32::MAX, f32::MIN), |(lo, hi), &x| (lo.min(x), hi.max(x)));
+    if profile.len() < 2 || max - min < 10.0 { return None; }
+    let rising = profile[profile.len() - 1] > profile[0];
+    let level = |p: f32| min + (max - min) * p;
+    let crossing = |threshold: f32| -> Option<f32> {
+        profile.windows(2).position(|w| if rising { w[0] < threshold && w[1] >= threshold } else { w[0] > threshold && w[1] <= threshold })
+            .map(|i| i as f32 * step)
+    };
+    let (lo, hi) = if rising { (level(0.1), level(0.9)) } else { (level(0.9), level(0.1)) };
+    Some((crossing(hi)? - crossing(lo)?).abs() as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Renders a rotated board with antialiased edges and compares the detected corners with the exact ones
+    #[test]
+    fn detects_rotated_board() {
+        let (columns, rows) = (14, 8);
+        let (width, height) = (960, 540);
+        let (square, angle, origin) = (40.0f64, 0.2f64, (170.0f64, 90.0f64));
+        let to_image = |x: f64, y: f64| -> (f64, f64) {
+            (origin.0 + square * (x * angle.cos() - y * angle.sin()), origin.1 + square * (x * angle.sin() + y * angle.cos()))
+        };
+        let to_board = |u: f64, v: f64| -> (f64, f64) {
+            let (u, v) = ((u - origin.0) / square, (v - origin.1) / square);
+            (u * angle.cos() + v * angle.sin(), -u * angle.sin() + v * angle.cos())
+        };
+
+        let mut pixels = vec![0u8; width * height];
+        for y in 0..height {
+            for x in 0..width {
+                let mut sum = 0.0;
+                for s in 0..16 {
+                    let (bx, by) = to_board(x as f64 + (s % 4) as f64 / 4.0 - 0.375, y as f64 + (s / 4) as f64 / 4.0 - 0.375);
+                    sum += if bx < 0.0 || by < 0.0 || bx >= (columns + 1) as f64 || by >= (rows + 1) as f64 {
+                        230.0
+                    } else if (bx.floor() as i32 + by.floor() as i32) % 2 == 0 {
+                        20.0
+                    } else {
+                        230.0
+                    };
+                }
+                pixels[y * width + x] = (sum / 16.0) as u8;
+            }
+        }
+
+        let img = GrayImage::new(&pixels, width, height, width);
+        let corners = find_chessboard_corners(&img, columns, rows).expect("Chessboard not found");
+        assert_eq!(corners.len(), columns * rows);
+        for y in 0..rows {
+            for x in 0..columns {
+                let expected = to_image((x + 1) as f64, (y + 1) as f64);
+                let found = corners[y * columns + x];
+                let err = ((found.0 as f64 - expected.0).powi(2) + (found.1 as f64 - expected.1).powi(2)).sqrt();
+                assert!(err < 0.1, "corner {x}x{y}: expected {expected:?}, found {found:?}");
+            }
+        }
+        assert!(estimate_sharpness(&img, columns, rows, &corners) < 3.0);
+    }
+}
@@ -0,0 +1,231 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Asymmetric circle grid detection without OpenCV, for the same targets as `findCirclesGrid` with `CALIB_CB_ASYMMETRIC_GRID`:
+// `columns` dark circles in each of `rows` rows, every other row shifted by half of the spacing.
+//   - dark blobs are found with a local mean threshold and filtered by size and shape (filled ellipses)
+//   - the nearest neighbours of every circle are diagonal, so the circles form a square grid rotated by 45 degrees,
+//     which is grown the same way as the chessboard corners
+// The object points are the circle positions in units of half of the spacing within a row, so they don't depend on which row is the shifted one.
+
+use std::collections::HashMap;
+use super::chessboard::{ self, GrayImage, Grid };
+
+const MAX_SEEDS: usize = 20;
+
+/// Circle center in the image and its object point
+pub type Circle = ((f32, f32), (f64, f64));
+
+/// Returns the circle centers and their object points, row by row, or `None` if the whole grid wasn't found
+pub fn find_circles_grid(img: &GrayImage, columns: usize, rows: usize) -> Option<Vec<Circle>> {
+    if columns < 2 || rows < 2 { return None; }
+
+    let centers = find_blobs(img);
+    if centers.len() < columns * rows { return None; }
+
+    for seed in 0..centers.len().min(MAX_SEEDS) {
+        if let Some(grid) = chessboard::grow_grid(&centers, seed, (columns + 2) * (rows + 2) * 2) {
+            if let Some(ret) = extract_pattern(&grid, columns, rows) {
+                return Some(ret);
+            }
+        }
+    }
+    None
+}
+
+/// Average width of the circle edges, along the lines between the neighbouring circles. Lower is sharper
+pub fn estimate_sharpness(img: &GrayImage, circles: &[Circle]) -> f64 {
+    let mut sum = 0.0;
+    let mut count = 0;
+    for (a, obj_a) in circles {
+        // Diagonal neighbour in the next row
+        let neighbour = circles.iter().find(|(_, obj_b)| (obj_b.0 - obj_a.0 - 1.0).abs() < 0.1 && (obj_b.1 - obj_a.1 - 1.0).abs() < 0.1);
+        if let Some((b, _)) = neighbour {
+            // From the center of the circle to the middle between the circles
+            let step = 0.25;
+            let len = ((b.0 - a.0).powi(2) + (b.1 - a.1).powi(2)).sqrt() * 0.5;
+            let samples = (len / step) as usize + 1;
+            let profile: Vec<f32> = (0..samples).map(|i| {
+                let t = i as f32 * step / (len * 2.0);
+                img.sample(a.0 + (b.0 - a.0) * t, a.1 + (b.1 - a.1) * t)
+            }).collect();
+            if let Some(width) = chessboard::transition_width(&profile, step) {
+                sum += width;
+                count += 1;
+            }
+        }
+    }
+    if count == 0 { return 100.0; }
+    sum / count as f64
+}
+
+// Centers of dark, filled, roughly elliptical blobs, biggest first
+fn find_blobs(img: &GrayImage) -> Vec<(f32, f32)> {
+    let (w, h) = (img.width, img.height);
+    if w < 16 || h < 16 { return Vec::new(); }
+
+    // Integral image for the local mean
+    let mut integral = vec![0.0f64; (w + 1) * (h + 1)];
+    for y in 0..h {
+        let mut row = 0.0;
+        for x in 0..w {
+            row += img.sample(x as f32, y as f32) as f64;
+            integral[(y + 1) * (w + 1) + x + 1] = integral[y * (w + 1) + x + 1] + row;
+        }
+    }
+    let half = (w.min(h) / 10).max(8);
+    let dark: Vec<bool> = (0..w * h).map(|i| {
+        let (x, y) = (i % w, i / w);
+        let (x0, y0, x1, y1) = (x.saturating_sub(half), y.saturating_sub(half), (x + half + 1).min(w), (y + half + 1).min(h));
+        let sum = integral[y1 * (w + 1) + x1] - integral[y0 * (w + 1) + x1] - integral[y1 * (w + 1) + x0] + integral[y0 * (w + 1) + x0];
+        let mean = sum / ((x1 - x0) * (y1 - y0)) as f64;
+        (img.sample(x as f32, y as f32) as f64) < mean - 10.0
+    }).collect();
+
+    let max_area = (w * h) / 50;
+    let mut visited = vec![false; w * h];
+    let mut blobs = Vec::new();
+    let mut stack = Vec::new();
+    for start in 0..w * h {
+        if !dark[start] || visited[start] { continue; }
+        visited[start] = true;
+        stack.push(start);
+
+        let (mut area, mut sx, mut sy, mut sxx, mut syy, mut sxy) = (0usize, 0.0f64, 0.0f64, 0.0f64, 0.0f64, 0.0f64);
+        let mut touches_border = false;
+        while let Some(i) = stack.pop() {
+            let (x, y) = (i % w, i / w);
+            let (fx, fy) = (x as f64, y as f64);
+            area += 1;
+            sx += fx; sy += fy; sxx += fx * fx; syy += fy * fy; sxy += fx * fy;
+            if x == 0 || y == 0 || x == w - 1 || y == h - 1 {
+                touches_border = true;
+                continue;
+            }
+            for n in [i - 1, i + 1, i - w, i + w] {
+                if dark[n] && !visited[n] {
+                    visited[n] = true;
+                    stack.push(n);
+                }
+            }
+        }
+        if touches_border || area < 12 || area > max_area { continue; }
+
+        let n = area as f64;
+        let (cx, cy) = (sx / n, sy / n);
+        let (vxx, vyy, vxy) = (sxx / n - cx * cx, syy / n - cy * cy, sxy / n - cx * cy);
+        let tr = vxx + vyy;
+        let disc = ((vxx - vyy).powi(2) + 4.0 * vxy * vxy).sqrt();
+        let (l1, l2) = ((tr + disc) / 2.0, (tr - disc) / 2.0);
+        if l2 <= 0.0 || l2 / l1 < 0.1 { continue; }
+        // A filled ellipse has the area of 4π√(λ1λ2)
+        let fill = n / (4.0 * std::f64::consts::PI * (l1 * l2).sqrt());
+        if !(0.8..=1.2).contains(&fill) { continue; }
+
+        blobs.push(((cx as f32, cy as f32), area));
+    }
+    blobs.sort_by_key(|x| std::cmp::Reverse(x.1));
+    blobs.into_iter().map(|x| x.0).collect()
+}
+
+// In the rotated grid cell (a, b) is at (a + b, b - a) in half-spacing units. Finds the only complete asymmetric pattern in either orientation
+fn extract_pattern(grid: &Grid, columns: usize, rows: usize) -> Option<Vec<Circle>> {
+    let points: HashMap<(i32, i32), (f32, f32)> = grid.iter().map(|((a, b), pt)| ((a + b, b - a), *pt)).collect();
+
+    let min_x = points.keys().map(|x| x.0).min()?;
+    let max_x = points.keys().map(|x| x.0).max()?;
+    let min_y = points.keys().map(|x| x.1).min()?;
+    let max_y = points.keys().map(|x| x.1).max()?;
+
+    let (c, r) = (columns as i32, rows as i32);
+    let mut found = None;
+    for transposed in [false, true] {
+        for shift in [0, 1] {
+            // Position of the circle `j` in row `i` within the pattern
+            let pattern = |i: i32, j: i32| -> (i32, i32) { (2 * j + (i + shift) % 2, i) };
+            let position = |x0: i32, y0: i32, (along, across): (i32, i32)| -> (i32, i32) {
+                if transposed { (x0 + across, y0 + along) } else { (x0 + along, y0 + across) }
+            };
+            for y0 in min_y - 1..=max_y {
+                for x0 in min_x - 1..=max_x {
+                    if (0..r).all(|i| (0..c).all(|j| points.contains_key(&position(x0, y0, pattern(i, j))))) {
+                        if found.is_some() { return None; }
+                        found = Some((0..r).flat_map(|i| (0..c).map(move |j| (i, j))).map(|(i, j)| {
+                            let obj = pattern(i, j);
+                            (points[&position(x0, y0, obj)], (obj.0 as f64, obj.1 as f64))
+                        }).collect::<Vec<_>>());
+                    }
+                }
+            }
+        }
+    }
+    let mut ret = found?;
+    orient(&mut ret);
+    Some(ret)
+}
+
+// Flips the object points so that the rows go down and the columns go right in the image, same as `chessboard::oriented_cells`, and sorts them row by row
+fn orient(points: &mut [Circle]) {
+    // Affine fit of image = A * object + t
+    let mut ata = nalgebra::Matrix3::<f64>::zeros();
+    let mut atb = nalgebra::Matrix3x2::<f64>::zeros();
+    for (pt, obj) in points.iter() {
+        let v = nalgebra::Vector3::new(obj.0, obj.1, 1.0);
+        ata += v * v.transpose();
+        atb += v * nalgebra::RowVector2::new(pt.0 as f64, pt.1 as f64);
+    }
+    let Some(a) = ata.try_inverse().map(|inv| inv * atb) else { return; };
+    let (ex, ey) = ((a[(0, 0)], a[(0, 1)]), (a[(1, 0)], a[(1, 1)]));
+
+    let flip_y = ex.0 * ey.1 - ex.1 * ey.0 < 0.0;
+    let rotate = ex.0 < 0.0;
+    let max_x = points.iter().map(|x| x.1.0).fold(0.0, f64::max);
+    let max_y = points.iter().map(|x| x.1.1).fold(0.0, f64::max);
+    for (_, obj) in points.iter_mut() {
+        if rotate { obj.0 = max_x - obj.0; }
+        if rotate != flip_y { obj.1 = max_y - obj.1; }
+    }
+    points.sort_by(|a, b| (a.1.1, a.1.0).partial_cmp(&(b.1.1, b.1.0)).unwrap_or(std::cmp::Ordering::Equal));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Renders a rotated OpenCV asymmetric circle grid and compares the detected centers with the exact ones
+    #[test]
+    fn detects_rotated_grid() {
+        let (columns, rows) = (4, 11);
+        let (width, height) = (800, 600);
+        let (spacing, radius, angle, origin) = (50.0f64, 8.0f64, 0.3f64, (300.0f64, 60.0f64));
+        let to_image = |x: f64, y: f64| -> (f64, f64) {
+            let (x, y) = (x * spacing / 2.0, y * spacing / 2.0);
+            (origin.0 + x * angle.cos() - y * angle.sin(), origin.1 + x * angle.sin() + y * angle.cos())
+        };
+        let centers: Vec<(f64, f64)> = (0..rows).flat_map(|y| (0..columns).map(move |x| ((2 * x + y % 2) as f64, y as f64)))
+            .map(|(x, y)| to_image(x, y))
+            .collect();
+
+        let mut pixels = vec![0u8; width * height];
+        for y in 0..height {
+            for x in 0..width {
+                let mut sum = 0.0;
+                for s in 0..16 {
+                    let (px, py) = (x as f64 + (s % 4) as f64 / 4.0 - 0.375, y as f64 + (s / 4) as f64 / 4.0 - 0.375);
+                    sum += if centers.iter().any(|c| (px - c.0).powi(2) + (py - c.1).powi(2) < radius * radius) { 20.0 } else { 230.0 };
+                }
+                pixels[y * width + x] = (sum / 16.0) as u8;
+            }
+        }
+
+        let img = GrayImage::new(&pixels, width, height, width);
+        let circles = find_circles_grid(&img, columns, rows).expect("Circle grid not found");
+        assert_eq!(circles.len(), columns * rows);
+        for (found, obj) in &circles {
+            let expected = to_image(obj.0, obj.1);
+            let err = ((found.0 as f64 - expected.0).powi(2) + (found.1 as f64 - expected.1).powi(2)).sqrt();
+            assert!(err < 0.2, "circle {obj:?}: expected {expected:?}, found {found:?}");
+        }
+        assert!(estimate_sharpness(&img, &circles) < 3.0);
+    }
+}
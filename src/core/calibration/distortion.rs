@@ -0,0 +1,206 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Calibration of any physical `DistortionModel`, by minimizing the reprojection error of its `distort_point`.
+// The fisheye calibration is the starting point: it provides the board poses and the focal length (scaled for models with a different
+// projection near the center), then the intrinsics, the distortion coefficients and all board poses are refined together.
+// `distort_point` is computed in f32, the same as in the stabilization, so the numerical derivatives use a bigger step.
+
+use nalgebra::{ Matrix3, Vector3 };
+use super::fisheye::{ self, FisheyeCalibration };
+use super::reprojection::{ self, Reprojection, POSE };
+use crate::stabilization::{ KernelParams, distortion_models::DistortionModel };
+use crate::GyroflowCoreError;
+
+const MAX_ITERATIONS: u64 = 100;
+const POSE_ITERATIONS: u64 = 50;
+const DERIVATIVE_STEP: f64 = 1e-4;
+
+/// Distortion models which can be calibrated, the first one is the default
+pub const MODELS: &[&str] = &["opencv_fisheye", "opencv_standard", "poly3", "poly5", "ptlens", "insta360", "sony"];
+
+#[derive(Clone, Debug)]
+pub struct ModelCalibration {
+    /// `DistortionModel` id
+    pub model: String,
+    pub rms: f64,
+    pub k: Matrix3<f64>,
+    pub d: Vec<f64>,
+}
+
+struct ModelSetup {
+    /// Initial distortion coefficients, in the `KernelParams::k` order
+    coefficients: Vec<f64>,
+    /// Number of coefficients from the beginning which are optimized, the rest stays fixed
+    fitted: usize,
+    /// Focal length relative to the fisheye one, so the center of the image has the same scale
+    focal_scale: f64,
+}
+
+/// Projection of points in the camera coordinates with the intrinsics `[fx, fy, cx, cy, fitted coefficients...]`, the rest of the coefficients stays fixed
+pub struct Projection {
+    /// `None` is the OpenCV fisheye model of `fisheye`, computed in f64
+    model: Option<DistortionModel>,
+    fitted: usize,
+    fixed: Vec<f64>,
+    focal_scale: f64,
+}
+
+impl Projection {
+    /// `coefficients` are all distortion coefficients of `model` in the `KernelParams::k` order, eg. `ModelCalibration::d`
+    pub fn new(model: &str, coefficients: &[f64]) -> Option<Self> {
+        let (fitted, focal_scale) = if model == MODELS[0] {
+            (4, 1.0)
+        } else {
+            let setup = setup(model)?;
+            (setup.fitted, setup.focal_scale)
+        };
+        if coefficients.len() < fitted { return None; }
+        Some(Self {
+            model: (model != MODELS[0]).then(|| DistortionModel::from_name(model)),
+            fitted,
+            fixed: coefficients[fitted..].to_vec(),
+            focal_scale
+        })
+    }
+
+    pub fn num_intrinsics(&self) -> usize {
+        4 + self.fitted
+    }
+
+    pub fn intrinsics(&self, k: &Matrix3<f64>, coefficients: &[f64]) -> Vec<f64> {
+        let mut ret = vec![k[(0, 0)], k[(1, 1)], k[(0, 2)], k[(1, 2)]];
+        ret.extend_from_slice(&coefficients[..self.fitted]);
+        ret
+    }
+
+    pub fn camera_matrix(intrinsics: &[f64]) -> Matrix3<f64> {
+        Matrix3::new(intrinsics[0], 0.0, intrinsics[2],
+                     0.0, intrinsics[1], intrinsics[3],
+                     0.0, 0.0, 1.0)
+    }
+
+    /// All distortion coefficients
+    pub fn coefficients(&self, intrinsics: &[f64]) -> Vec<f64> {
+        let mut ret = intrinsics[4..4 + self.fitted].to_vec();
+        ret.extend_from_slice(&self.fixed);
+        ret
+    }
+
+    pub fn project(&self, intrinsics: &[f64], p: &Vector3<f64>) -> Option<(f64, f64)> {
+        let Some(model) = &self.model else {
+            return Some(fisheye::distort(intrinsics, p));
+        };
+        let mut params = KernelParams::default();
+        for (k, v) in params.k.iter_mut().zip(intrinsics[4..].iter().chain(self.fixed.iter())) {
+            *k = *v as f32;
+        }
+        let (x, y) = model.distort_point(p.x as f32, p.y as f32, p.z as f32, &params);
+        Some((intrinsics[0] * x as f64 + intrinsics[2], intrinsics[1] * y as f64 + intrinsics[3]))
+    }
+
+    /// Board pose of a single view with fixed intrinsics
+    pub fn estimate_pose(&self, intrinsics: &[f64], object_points: &[(f64, f64)], image_points: &[(f32, f32)]) -> Option<[f64; POSE]> {
+        let initial = fisheye::initial_pose(&[intrinsics[0] / self.focal_scale, intrinsics[1] / self.focal_scale, intrinsics[2], intrinsics[3]], object_points, image_points)?;
+
+        let (object_points, image_points) = (object_points.to_vec(), image_points.to_vec());
+        let (object_points, image_points) = ([&object_points], [&image_points]);
+        let project = |_: &[f64], pose: &[f64], object_point: (f64, f64)| -> Option<(f64, f64)> {
+            self.project(intrinsics, &reprojection::transform(pose, object_point)?)
+        };
+        let problem = Reprojection::new(&object_points, &image_points, 0, project).with_step(DERIVATIVE_STEP);
+        let (p, _) = reprojection::optimize(problem, initial.to_vec(), POSE_ITERATIONS).ok()?;
+        p.try_into().ok()
+    }
+}
+
+fn setup(model: &str) -> Option<ModelSetup> {
+    let (coefficients, fitted, focal_scale) = match model {
+        "opencv_standard" => (vec![0.0; 5], 5, 1.0), // k1, k2, p1, p2, k3
+        "poly3"           => (vec![0.0], 1, 1.0),
+        "poly5"           => (vec![0.0; 2], 2, 1.0),
+        "ptlens"          => (vec![0.0; 3], 3, 1.0),
+        // k1, k2, k3, p1, p2, xi. With xi = 1 the projection is tan(θ/2), ie. half of the fisheye scale in the center
+        "insta360"        => (vec![0.0, 0.0, 0.0, 0.0, 0.0, 1.0], 6, 2.0),
+        // Polynomial of θ and the post scale, which is fixed because it's the same as the focal length
+        "sony"            => (vec![1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 1.0], 6, 1.0),
+        _ => return None
+    };
+    Some(ModelSetup { coefficients, fitted, focal_scale })
+}
+
+/// Calibrates `model` with the same views as `initial`, which is the result of `fisheye::calibrate` with the same `object_points` and `image_points`
+pub fn calibrate(model: &str, object_points: &[Vec<(f64, f64)>], image_points: &[Vec<(f32, f32)>], initial: &FisheyeCalibration) -> Result<ModelCalibration, GyroflowCoreError> {
+    if model == "opencv_fisheye" {
+        return Ok(ModelCalibration { model: model.into(), rms: initial.rms, k: initial.k, d: initial.d.as_slice().to_vec() });
+    }
+    let setup = setup(model).ok_or_else(|| GyroflowCoreError::CalibrationError(format!("Distortion model {model} can't be calibrated")))?;
+    let projection = Projection::new(model, &setup.coefficients).ok_or_else(|| GyroflowCoreError::CalibrationError(format!("Distortion model {model} can't be calibrated")))?;
+
+    let (object_points, views) = reprojection::valid_views(object_points, image_points);
+    if views.len() < 2 || views.len() != initial.rvecs.len() {
+        return Err(GyroflowCoreError::CalibrationError("The fisheye calibration doesn't match the views".into()));
+    }
+
+    let project = |intrinsics: &[f64], pose: &[f64], object_point: (f64, f64)| -> Option<(f64, f64)> {
+        projection.project(intrinsics, &reprojection::transform(pose, object_point)?)
+    };
+
+    let k = &initial.k;
+    let mut param = vec![k[(0, 0)] * setup.focal_scale, k[(1, 1)] * setup.focal_scale, k[(0, 2)], k[(1, 2)]];
+    param.extend_from_slice(&setup.coefficients[..setup.fitted]);
+    let intrinsics = param.len();
+    for (r, t) in initial.rvecs.iter().zip(initial.tvecs.iter()) {
+        param.extend_from_slice(&[r.x, r.y, r.z, t.x, t.y, t.z]);
+    }
+
+    let problem = Reprojection::new(&object_points, &views, intrinsics, project).with_step(DERIVATIVE_STEP);
+    let (p, rms) = reprojection::optimize(problem, param, MAX_ITERATIONS)?;
+
+    Ok(ModelCalibration {
+        model: model.into(),
+        rms,
+        k: Projection::camera_matrix(&p),
+        d: projection.coefficients(&p[..intrinsics]),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::Vector3;
+
+    // Views projected with a known Poly5 lens. The fisheye calibration only approximates it, the Poly5 calibration has to recover it
+    #[test]
+    fn recovers_poly5_lens() {
+        let (f, c, d) = (1500.0f64, (960.0f64, 540.0f64), [-0.12f32, 0.03f32]);
+        let model = DistortionModel::from_name("poly5");
+        let mut params = KernelParams::default();
+        params.k[..2].copy_from_slice(&d);
+
+        let objp: Vec<(f64, f64)> = (0..8).flat_map(|y| (0..14).map(move |x| (x as f64, y as f64))).collect();
+        let poses = [
+            (Vector3::new( 0.10, -0.20,  0.05), Vector3::new(-7.0, -4.0, 14.0)),
+            (Vector3::new(-0.35,  0.30,  0.10), Vector3::new(-5.0, -5.0, 15.0)),
+            (Vector3::new( 0.40,  0.25, -0.20), Vector3::new(-9.0, -2.0, 16.0)),
+            (Vector3::new(-0.20, -0.45,  0.30), Vector3::new(-4.0, -6.0, 13.0)),
+            (Vector3::new( 0.30, -0.10,  0.40), Vector3::new(-6.0, -7.0, 17.0)),
+        ];
+        let image_points: Vec<Vec<(f32, f32)>> = poses.iter().map(|(r, t)| {
+            let pose = [r.x, r.y, r.z, t.x, t.y, t.z];
+            objp.iter().map(|p| {
+                let p = reprojection::transform(&pose, *p).unwrap();
+                let (x, y) = model.distort_point(p.x as f32, p.y as f32, p.z as f32, &params);
+                ((c.0 + x as f64 * f) as f32, (c.1 + y as f64 * f) as f32)
+            }).collect()
+        }).collect();
+        let object_points = vec![objp; poses.len()];
+
+        let fisheye = super::super::fisheye::calibrate(&object_points, &image_points, (1920, 1080)).unwrap();
+        let result = calibrate("poly5", &object_points, &image_points, &fisheye).unwrap();
+        assert!(result.rms < 0.05, "rms: {}, fisheye rms: {}", result.rms, fisheye.rms);
+        assert!((result.k[(0, 0)] - f).abs() < 1.0, "fx: {}", result.k[(0, 0)]);
+        assert!((result.k[(0, 2)] - c.0).abs() < 1.0, "cx: {}", result.k[(0, 2)]);
+        assert!((result.d[0] - d[0] as f64).abs() < 0.005 && (result.d[1] - d[1] as f64).abs() < 0.005, "d: {:?}", result.d);
+    }
+}
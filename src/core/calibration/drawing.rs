@@ -53,6 +53,37 @@ pub fn draw_chessboard_corners(org_width: usize, org_height: usize, w: usize, h:
     }
 }
 
+// Same as `draw_chessboard_corners`, but for a partial board or a circle grid, where every row can have a different number of points.
+// Points are sorted row by row, and the rows are the distinct `y` of the object points
+pub fn draw_detected(org_width: usize, org_height: usize, w: usize, h: usize, drawing: &mut DrawCanvas, points: &[(f32, f32)], object_points: &[(f64, f64)], inverted: bool) {
+    const LINE_COLORS: &[Color] = &[Color::Red, Color::Blue2, Color::Yellow2, Color::Green, Color::Blue3, Color::Blue, Color::Magenta];
+
+    let ratio_w = w as f32 / org_width as f32;
+    let ratio_h = h as f32 / org_height as f32;
+    let r = 10.0 * ratio_w;
+    let mut prev: Option<((f32, f32), f64)> = None;
+    let mut row = 0;
+    for (pt, obj) in points.iter().zip(object_points.iter()) {
+        let mut pt = ((pt.0 * ratio_w).round(), (pt.1 * ratio_h).round());
+        if inverted {
+            pt.1 = h as f32 - pt.1;
+        }
+        if let Some((_, prev_y)) = prev {
+            if (prev_y - obj.1).abs() > 1e-6 { row += 1; }
+        }
+        let color = LINE_COLORS[row % LINE_COLORS.len()];
+        if let Some((prev_pt, prev_y)) = prev {
+            if (prev_y - obj.1).abs() <= 1e-6 {
+                line(drawing, prev_pt, pt, color);
+            }
+        }
+        line(drawing, (pt.0 - r, pt.1 - r), (pt.0 + r, pt.1 + r), color);
+        line(drawing, (pt.0 - r, pt.1 + r), (pt.0 + r, pt.1 - r), color);
+        circle(drawing, pt, r + 1.0, color);
+        prev = Some((pt, obj.1));
+    }
+}
+
 fn line(drawing: &mut DrawCanvas, p1: (f32, f32), p2: (f32, f32), color: Color) {
     let points = line_drawing::Bresenham::new((p1.0 as isize, p1.1 as isize), (p2.0 as isize, p2.1 as isize));
     draw_pixels(drawing, color, points);
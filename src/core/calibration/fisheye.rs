@@ -0,0 +1,192 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Fisheye calibration without OpenCV, using the same camera model as `cv::fisheye::calibrate` with `CALIB_FIX_SKEW`:
+//     θ = atan(r),  θd = θ (1 + k1θ² + k2θ⁴ + k3θ⁶ + k4θ⁸),  u = fx θd/r x + cx,  v = fy θd/r y + cy
+// The initial guess is the same as in OpenCV (focal length of max(width, height) / π, no distortion and board poses from homographies),
+// then the intrinsics and all board poses are refined together with Levenberg-Marquardt.
+// The RMS is also computed the same way: square root of the mean squared reprojection error of all points, in pixels.
+
+use nalgebra::{ Matrix3, Rotation3, Vector3, Vector4 };
+use super::reprojection::{ self, Reprojection, POSE };
+use crate::GyroflowCoreError;
+
+const INTRINSICS: usize = 8; // fx, fy, cx, cy, k1, k2, k3, k4
+const MAX_ITERATIONS: u64 = 100;
+
+#[derive(Clone, Debug)]
+pub struct FisheyeCalibration {
+    pub rms: f64,
+    pub k: Matrix3<f64>,
+    pub d: Vector4<f64>,
+    /// Pose of the board in every view, as rotation vectors and translations in board units
+    pub rvecs: Vec<Vector3<f64>>,
+    pub tvecs: Vec<Vector3<f64>>,
+}
+
+/// Projects a point on the board (z = 0) to the image
+pub fn project_point(k: &Matrix3<f64>, d: &Vector4<f64>, rvec: &Vector3<f64>, tvec: &Vector3<f64>, object_point: (f64, f64)) -> Option<(f64, f64)> {
+    let intrinsics = [k[(0, 0)], k[(1, 1)], k[(0, 2)], k[(1, 2)], d[0], d[1], d[2], d[3]];
+    let pose = [rvec[0], rvec[1], rvec[2], tvec[0], tvec[1], tvec[2]];
+    project(&intrinsics, &pose, object_point)
+}
+
+fn project(intrinsics: &[f64], pose: &[f64], object_point: (f64, f64)) -> Option<(f64, f64)> {
+    Some(distort(intrinsics, &reprojection::transform(pose, object_point)?))
+}
+
+/// Projects a point in the camera coordinates with `[fx, fy, cx, cy, k1, k2, k3, k4]`
+pub fn distort(intrinsics: &[f64], p: &Vector3<f64>) -> (f64, f64) {
+    let (x, y) = (p.x / p.z, p.y / p.z);
+    let r = (x * x + y * y).sqrt();
+    let theta = r.atan();
+    let theta2 = theta * theta;
+    let [k1, k2, k3, k4] = [intrinsics[4], intrinsics[5], intrinsics[6], intrinsics[7]];
+    let theta_d = theta * (1.0 + theta2 * (k1 + theta2 * (k2 + theta2 * (k3 + theta2 * k4))));
+    let scale = if r > 1e-8 { theta_d / r } else { 1.0 };
+
+    (intrinsics[0] * x * scale + intrinsics[2], intrinsics[1] * y * scale + intrinsics[3])
+}
+
+/// `object_points` are the board points of every view in board units, `image_points` are the detected points in the same order.
+/// Views can have different numbers of points (eg. partially visible boards), views with less than 4 points or mismatched lengths are ignored
+pub fn calibrate(object_points: &[Vec<(f64, f64)>], image_points: &[Vec<(f32, f32)>], size: (usize, usize)) -> Result<FisheyeCalibration, GyroflowCoreError> {
+    let (object_points, views) = reprojection::valid_views(object_points, image_points);
+    if views.len() < 2 {
+        return Err(GyroflowCoreError::CalibrationError("At least 2 views of the board are required".into()));
+    }
+
+    let f = size.0.max(size.1) as f64 / std::f64::consts::PI;
+    let intrinsics = [f, f, size.0 as f64 / 2.0 - 0.5, size.1 as f64 / 2.0 - 0.5, 0.0, 0.0, 0.0, 0.0];
+
+    let mut param = intrinsics.to_vec();
+    for (obj, view) in object_points.iter().zip(views.iter()) {
+        let pose = initial_pose(&intrinsics, obj, view)
+            .ok_or_else(|| GyroflowCoreError::CalibrationError("Unable to estimate the board pose".into()))?;
+        param.extend_from_slice(&pose);
+    }
+
+    let problem = Reprojection::new(&object_points, &views, INTRINSICS, project);
+    let (p, rms) = reprojection::optimize(problem, param, MAX_ITERATIONS)?;
+
+    Ok(FisheyeCalibration {
+        rms,
+        k: Matrix3::new(p[0], 0.0, p[2],
+                        0.0, p[1], p[3],
+                        0.0, 0.0, 1.0),
+        d: Vector4::new(p[4], p[5], p[6], p[7]),
+        rvecs: (0..views.len()).map(|v| Vector3::from_column_slice(&p[INTRINSICS + v * POSE..INTRINSICS + v * POSE + 3])).collect(),
+        tvecs: (0..views.len()).map(|v| Vector3::from_column_slice(&p[INTRINSICS + v * POSE + 3..INTRINSICS + (v + 1) * POSE])).collect(),
+    })
+}
+
+/// Board pose from the homography between the board and the undistorted points (Zhang's method). Only `[fx, fy, cx, cy]` of `intrinsics` are used
+pub fn initial_pose(intrinsics: &[f64], object_points: &[(f64, f64)], image_points: &[(f32, f32)]) -> Option<[f64; POSE]> {
+    let normalized: Vec<(f64, f64)> = image_points.iter().map(|&(u, v)| {
+        let x = (u as f64 - intrinsics[2]) / intrinsics[0];
+        let y = (v as f64 - intrinsics[3]) / intrinsics[1];
+        let theta_d = (x * x + y * y).sqrt();
+        // No distortion yet, so θ = θd
+        let scale = if theta_d > 1e-8 { theta_d.min(1.5).tan() / theta_d } else { 1.0 };
+        (x * scale, y * scale)
+    }).collect();
+
+    let h = homography(object_points, &normalized)?;
+    let (h1, h2, h3) = (h.column(0).into_owned(), h.column(1).into_owned(), h.column(2).into_owned());
+    let mut lambda = 2.0 / (h1.norm() + h2.norm());
+    if !lambda.is_finite() { return None; }
+    if h3.z * lambda < 0.0 {
+        // The board has to be in front of the camera
+        lambda = -lambda;
+    }
+    let (r1, r2, t) = (h1 * lambda, h2 * lambda, h3 * lambda);
+    let r = Matrix3::from_columns(&[r1, r2, r1.cross(&r2)]);
+
+    // Closest rotation matrix
+    let svd = r.svd(true, true);
+    let (mut u, v_t) = (svd.u?, svd.v_t?);
+    if (u * v_t).determinant() < 0.0 {
+        u.set_column(2, &(-u.column(2)));
+    }
+    let rvec = Rotation3::from_matrix_unchecked(u * v_t).scaled_axis();
+    Some([rvec.x, rvec.y, rvec.z, t.x, t.y, t.z])
+}
+
+// Normalized DLT
+fn homography(src: &[(f64, f64)], dst: &[(f64, f64)]) -> Option<Matrix3<f64>> {
+    let normalization = |pts: &[(f64, f64)]| -> Matrix3<f64> {
+        let n = pts.len() as f64;
+        let (cx, cy) = pts.iter().fold((0.0, 0.0), |a, p| (a.0 + p.0 / n, a.1 + p.1 / n));
+        let mean_dist = pts.iter().map(|p| ((p.0 - cx).powi(2) + (p.1 - cy).powi(2)).sqrt()).sum::<f64>() / n;
+        let s = if mean_dist > 1e-12 { std::f64::consts::SQRT_2 / mean_dist } else { 1.0 };
+        Matrix3::new(s, 0.0, -s * cx,
+                     0.0, s, -s * cy,
+                     0.0, 0.0, 1.0)
+    };
+    let (ts, td) = (normalization(src), normalization(dst));
+
+    let mut ata = nalgebra::SMatrix::<f64, 9, 9>::zeros();
+    for (s, d) in src.iter().zip(dst.iter()) {
+        let s = ts * Vector3::new(s.0, s.1, 1.0);
+        let d = td * Vector3::new(d.0, d.1, 1.0);
+        let rows = [
+            nalgebra::SVector::<f64, 9>::from_column_slice(&[-s.x, -s.y, -1.0, 0.0, 0.0, 0.0, d.x * s.x, d.x * s.y, d.x]),
+            nalgebra::SVector::<f64, 9>::from_column_slice(&[0.0, 0.0, 0.0, -s.x, -s.y, -1.0, d.y * s.x, d.y * s.y, d.y]),
+        ];
+        for row in rows {
+            ata += row * row.transpose();
+        }
+    }
+    let eigen = ata.symmetric_eigen();
+    let (min_idx, _) = eigen.eigenvalues.iter().enumerate().min_by(|a, b| a.1.total_cmp(b.1))?;
+    let h = eigen.eigenvectors.column(min_idx);
+    let hn = Matrix3::new(h[0], h[1], h[2],
+                          h[3], h[4], h[5],
+                          h[6], h[7], h[8]);
+    Some(td.try_inverse()? * hn * ts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Intrinsics of a GoPro profile calibrated with `cv::fisheye::calibrate`. The views are projected with them,
+    // so the solver has to recover the OpenCV result and reach an RMS close to the added noise.
+    #[test]
+    fn recovers_opencv_fisheye_profile() {
+        let k = Matrix3::new(1174.57, 0.0, 1911.79,
+                             0.0, 1175.32, 1080.86,
+                             0.0, 0.0, 1.0);
+        let d = Vector4::new(0.0456, 0.0214, -0.0102, 0.0021);
+        let objp: Vec<(f64, f64)> = (0..8).flat_map(|y| (0..14).map(move |x| (x as f64, y as f64))).collect();
+
+        let poses = [
+            (Vector3::new( 0.10, -0.20,  0.05), Vector3::new(-7.0, -4.0, 12.0)),
+            (Vector3::new(-0.35,  0.30,  0.10), Vector3::new(-3.0, -5.0, 10.0)),
+            (Vector3::new( 0.40,  0.25, -0.20), Vector3::new(-12.0, -2.0, 11.0)),
+            (Vector3::new(-0.20, -0.50,  0.30), Vector3::new(-1.0, -7.0, 9.0)),
+            (Vector3::new( 0.55, -0.10,  0.40), Vector3::new(-9.0, -8.0, 13.0)),
+            (Vector3::new( 0.05,  0.60, -0.10), Vector3::new(-6.0, 0.0, 8.0)),
+        ];
+        // Deterministic noise of up to ±0.2 px
+        let mut seed = 1u32;
+        let mut noise = || { seed = seed.wrapping_mul(1103515245).wrapping_add(12345); ((seed >> 16) as f64 / 65535.0 - 0.5) * 0.4 };
+
+        let image_points: Vec<Vec<(f32, f32)>> = poses.iter().map(|(r, t)| {
+            objp.iter().map(|p| {
+                let (u, v) = project_point(&k, &d, r, t, *p).unwrap();
+                ((u + noise()) as f32, (v + noise()) as f32)
+            }).collect()
+        }).collect();
+
+        let result = calibrate(&vec![objp; poses.len()], &image_points, (3840, 2160)).unwrap();
+        assert!(result.rms < 0.2, "rms: {}", result.rms);
+        assert!((result.k[(0, 0)] - k[(0, 0)]).abs() < 2.0, "fx: {}", result.k[(0, 0)]);
+        assert!((result.k[(1, 1)] - k[(1, 1)]).abs() < 2.0, "fy: {}", result.k[(1, 1)]);
+        assert!((result.k[(0, 2)] - k[(0, 2)]).abs() < 2.0, "cx: {}", result.k[(0, 2)]);
+        assert!((result.k[(1, 2)] - k[(1, 2)]).abs() < 2.0, "cy: {}", result.k[(1, 2)]);
+        for i in 0..4 {
+            assert!((result.d[i] - d[i]).abs() < 0.01, "d: {:?}", result.d);
+        }
+    }
+}
@@ -0,0 +1,614 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Joint calibration of the lens and the IMU from a calibration video with gyro data, similar to the camera-IMU calibration of Kalibr.
+// Besides the lens intrinsics it estimates the rotation between the IMU and the camera, the gyro time offset and the rolling shutter readout time.
+//   1. The board pose of every view is estimated with the intrinsics of the lens calibration
+//   2. The camera angular velocity between consecutive views is compared with the mean gyro rate in the same interval. For every time offset
+//      in the search range the best orthogonal mapping between them has a closed form (Kabsch), the offset with the lowest error is the initial guess
+//   3. The intrinsics, the IMU rotation, the time offset, the readout time and all board poses are refined together with Levenberg-Marquardt.
+//      The residuals are the reprojection errors, with every point rotated by the gyro between the middle of the frame and the time its row was read,
+//      and the differences between the board rotations of consecutive views and the integrated gyro, converted to pixels with the focal length.
+// The camera angular velocity here is the rotation of the camera body. Gyroflow gyro is the opposite rotation of the image with X and Y swapped,
+// the same as the rotations estimated from the optical flow in `synchronization`.
+// The IMU rotation is split into the closest axis permutation (`imu_orientation`) and the remaining rotation (`imu_rotation_angles`), the same way `GyroSource` applies them.
+
+use argmin::core::{ Error, Executor, Jacobian, Operator, State };
+use nalgebra::{ DMatrix, DVector, Matrix3, Rotation3, UnitQuaternion, Vector3 };
+use super::distortion::{ ModelCalibration, Projection };
+use super::levenberg_marquardt::LevenbergMarquardt;
+use super::reprojection::{ self, POSE, BEHIND_CAMERA_ERROR };
+use crate::gyro_source::TimeIMU;
+use crate::GyroflowCoreError;
+
+const MAX_VIEWS: usize = 40;
+/// Longest interval between consecutive views which is compared with the gyro
+const MAX_PAIR_INTERVAL_MS: f64 = 1000.0;
+const MAX_OFFSET_MS: f64 = 1000.0;
+const OFFSET_STEP_MS: f64 = 1.0;
+/// Smallest ratio of the weakest and the strongest rotation axis. All axes are needed to find the orientation
+const MIN_EXCITATION: f64 = 0.05;
+const MAX_ITERATIONS: u64 = 100;
+const DERIVATIVE_STEP: f64 = 1e-4;
+const TOLERANCE: f64 = 1e-8;
+const GLOBAL: usize = 5; // IMU rotation vector, time offset, readout time
+
+/// Swaps X and Y
+const SWAP_XY: Matrix3<f64> = Matrix3::new(0.0, 1.0, 0.0,
+                                           1.0, 0.0, 0.0,
+                                           0.0, 0.0, 1.0);
+
+#[derive(Clone, Default, Debug)]
+pub struct ImuView {
+    /// Video timestamp of the middle of the frame
+    pub timestamp_ms: f64,
+    pub object_points: Vec<(f64, f64)>,
+    pub image_points: Vec<(f32, f32)>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ImuCalibration {
+    /// RMS reprojection error in pixels
+    pub rms: f64,
+    /// RMS difference between the board rotations of consecutive views and the integrated gyro, in degrees
+    pub gyro_rms: f64,
+    pub k: Matrix3<f64>,
+    pub d: Vec<f64>,
+    /// Same as `GyroSource::imu_orientation`
+    pub imu_orientation: String,
+    /// Pitch, roll and yaw in degrees, same as `GyroSource::imu_rotation_angles`
+    pub imu_rotation: [f64; 3],
+    /// Gyro offset in ms, same as the values of `GyroSource::offsets`
+    pub offset: f64,
+    /// Frame readout time in ms, negative is bottom to top
+    pub frame_readout_time: f64,
+    pub views: usize,
+}
+
+/// `initial` is the lens calibration, `gyro` is the raw gyro in deg/s before any orientation or rotation is applied
+pub fn calibrate(initial: &ModelCalibration, size: (usize, usize), views: &[ImuView], gyro: &[TimeIMU]) -> Result<ImuCalibration, GyroflowCoreError> {
+    let err = |msg: &str| GyroflowCoreError::CalibrationError(msg.into());
+
+    let gyro = Gyro::new(gyro);
+    if gyro.timestamps.len() < 2 {
+        return Err(err("The video doesn't contain gyro data"));
+    }
+    let projection = Projection::new(&initial.model, &initial.d).ok_or_else(|| err("Unsupported distortion model"))?;
+    let intrinsics = projection.intrinsics(&initial.k, &initial.d);
+
+    let mut views: Vec<&ImuView> = views.iter().filter(|x| x.object_points.len() == x.image_points.len() && x.object_points.len() >= 4).collect();
+    views.sort_by(|a, b| a.timestamp_ms.total_cmp(&b.timestamp_ms));
+    if views.len() > MAX_VIEWS {
+        let step = views.len() as f64 / MAX_VIEWS as f64;
+        views = (0..MAX_VIEWS).map(|i| views[(i as f64 * step) as usize]).collect();
+    }
+
+    // 1. Board poses
+    let mut poses = Vec::with_capacity(views.len());
+    views.retain(|view| {
+        let pose = projection.estimate_pose(&intrinsics, &view.object_points, &view.image_points);
+        poses.extend(pose);
+        pose.is_some()
+    });
+
+    let pairs: Vec<(usize, usize)> = (1..views.len())
+        .filter(|&i| views[i].timestamp_ms - views[i - 1].timestamp_ms > 0.0 && views[i].timestamp_ms - views[i - 1].timestamp_ms <= MAX_PAIR_INTERVAL_MS)
+        .map(|i| (i - 1, i))
+        .collect();
+    if pairs.len() < 3 {
+        return Err(err("Not enough consecutive views of the board"));
+    }
+
+    // 2. Initial time offset and IMU orientation
+    let rates: Vec<Vector3<f64>> = pairs.iter().map(|&(a, b)| {
+        let relative = pose_rotation(&poses[b]) * pose_rotation(&poses[a]).inverse();
+        -relative.scaled_axis() / ((views[b].timestamp_ms - views[a].timestamp_ms) / 1000.0)
+    }).collect();
+    let (offset, imu_to_camera) = initial_alignment(&gyro, &views, &pairs, &rates)?;
+
+    // The rotation of the body is the opposite of the image rotation in the gyroflow coordinates, with X and Y swapped
+    let orientation = -SWAP_XY * imu_to_camera;
+    let (permutation, imu_orientation) = closest_permutation(&orientation);
+    let rotation = Rotation3::from_matrix(&(orientation * permutation.transpose()));
+
+    // 3. Joint refinement
+    let mut param = intrinsics.clone();
+    param.extend_from_slice(rotation.scaled_axis().as_slice());
+    param.extend_from_slice(&[offset, 0.0]);
+    for pose in &poses {
+        param.extend_from_slice(pose);
+    }
+    let problem = Joint::new(&projection, &views, &pairs, &gyro, permutation, size.1 as f64, intrinsics.len(), intrinsics[0]);
+    let p = problem.optimize(param)?;
+
+    let residuals = problem.residuals(&p);
+    let reprojection_end = problem.offsets[views.len()];
+    let rms = (residuals.rows(0, reprojection_end).norm_squared() / (reprojection_end / 2).max(1) as f64).sqrt();
+    let gyro_rms = (residuals.rows(reprojection_end, residuals.len() - reprojection_end).norm_squared() / pairs.len() as f64).sqrt() / problem.rotation_weight;
+
+    let n = intrinsics.len();
+    let rotation = Rotation3::new(Vector3::new(p[n], p[n + 1], p[n + 2]));
+    // `GyroSource` builds the rotation with `Rotation3::from_euler_angles(yaw, pitch, roll)`
+    let (yaw, pitch, roll) = rotation.euler_angles();
+
+    Ok(ImuCalibration {
+        rms,
+        gyro_rms: gyro_rms.to_degrees(),
+        k: Projection::camera_matrix(&p),
+        d: projection.coefficients(&p[..n]),
+        imu_orientation,
+        imu_rotation: [pitch.to_degrees(), roll.to_degrees(), yaw.to_degrees()],
+        offset: p[n + 3],
+        frame_readout_time: p[n + 4],
+        views: views.len(),
+    })
+}
+
+fn pose_rotation(pose: &[f64]) -> Rotation3<f64> {
+    Rotation3::new(Vector3::new(pose[0], pose[1], pose[2]))
+}
+
+// Returns the offset and the orthogonal matrix which maps the gyro to the camera angular velocity
+fn initial_alignment(gyro: &Gyro, views: &[&ImuView], pairs: &[(usize, usize)], rates: &[Vector3<f64>]) -> Result<(f64, Matrix3<f64>), GyroflowCoreError> {
+    let mut best = (f64::MAX, 0.0, Matrix3::zeros(), Vector3::zeros()); // cost, offset, matrix, singular values
+    let steps = (MAX_OFFSET_MS / OFFSET_STEP_MS) as i64;
+    for step in -steps..=steps {
+        let offset = step as f64 * OFFSET_STEP_MS;
+        let (mut h, mut sum_sq, mut count) = (Matrix3::zeros(), 0.0, 0);
+        for (&(a, b), rate) in pairs.iter().zip(rates.iter()) {
+            if let Some(g) = gyro.mean_rate(views[a].timestamp_ms - offset, views[b].timestamp_ms - offset) {
+                h += rate * g.transpose();
+                sum_sq += rate.norm_squared() + g.norm_squared();
+                count += 1;
+            }
+        }
+        if count < 3 || count * 4 < pairs.len() * 3 { continue; }
+
+        let svd = h.svd(true, true);
+        let (Some(u), Some(v_t)) = (svd.u, svd.v_t) else { continue; };
+        // Sum of squared differences with the best orthogonal matrix
+        let cost = (sum_sq - 2.0 * svd.singular_values.sum()) / count as f64;
+        if cost < best.0 {
+            best = (cost, offset, u * v_t, svd.singular_values);
+        }
+    }
+    let (cost, offset, matrix, singular_values) = best;
+    if cost == f64::MAX {
+        return Err(GyroflowCoreError::CalibrationError("Gyro data doesn't cover the views of the board".into()));
+    }
+    if singular_values.max() <= 0.0 || singular_values.min() / singular_values.max() < MIN_EXCITATION {
+        return Err(GyroflowCoreError::CalibrationError("The camera has to be rotated around all axes".into()));
+    }
+    Ok((offset, matrix))
+}
+
+// Closest signed axis permutation with the same determinant, and its `imu_orientation` string
+fn closest_permutation(m: &Matrix3<f64>) -> (Matrix3<f64>, String) {
+    const PERMUTATIONS: [[usize; 3]; 6] = [[0, 1, 2], [0, 2, 1], [1, 0, 2], [1, 2, 0], [2, 0, 1], [2, 1, 0]];
+    let det = m.determinant();
+    let mut best = (f64::MIN, Matrix3::identity(), String::new());
+    for axes in PERMUTATIONS {
+        for signs in 0..8 {
+            let mut p = Matrix3::zeros();
+            let mut name = String::new();
+            for (row, &axis) in axes.iter().enumerate() {
+                let positive = signs & (1 << row) == 0;
+                p[(row, axis)] = if positive { 1.0 } else { -1.0 };
+                let c = b"XYZ"[axis] as char;
+                name.push(if positive { c } else { c.to_ascii_lowercase() });
+            }
+            if p.determinant() * det <= 0.0 { continue; }
+            let score = (m.transpose() * p).trace();
+            if score > best.0 {
+                best = (score, p, name);
+            }
+        }
+    }
+    (best.1, best.2)
+}
+
+// Raw gyro in rad/s
+struct Gyro {
+    timestamps: Vec<f64>,
+    rates: Vec<Vector3<f64>>,
+    /// Integral of the rates, for the mean rate in an interval
+    integral: Vec<Vector3<f64>>,
+    /// Orientation integrated from the rates, and from the negated rates
+    orientations: [Vec<UnitQuaternion<f64>>; 2],
+}
+
+impl Gyro {
+    fn new(imu: &[TimeIMU]) -> Self {
+        let mut ret = Self { timestamps: Vec::new(), rates: Vec::new(), integral: Vec::new(), orientations: [Vec::new(), Vec::new()] };
+        for x in imu {
+            let Some(g) = x.gyro else { continue; };
+            let rate = Vector3::new(g[0], g[1], g[2]).map(f64::to_radians);
+            match ret.timestamps.last() {
+                None => {
+                    ret.integral.push(Vector3::zeros());
+                    ret.orientations[0].push(UnitQuaternion::identity());
+                    ret.orientations[1].push(UnitQuaternion::identity());
+                },
+                Some(&prev) if x.timestamp_ms > prev => {
+                    let step = (ret.rates.last().unwrap() + rate) / 2.0 * ((x.timestamp_ms - prev) / 1000.0);
+                    ret.integral.push(ret.integral.last().unwrap() + step);
+                    for (orientations, sign) in ret.orientations.iter_mut().zip([1.0, -1.0]) {
+                        orientations.push(orientations.last().unwrap() * UnitQuaternion::from_scaled_axis(step * sign));
+                    }
+                },
+                _ => continue
+            }
+            ret.timestamps.push(x.timestamp_ms);
+            ret.rates.push(rate);
+        }
+        ret
+    }
+
+    // Index of the sample before `timestamp_ms` and the position between it and the next one
+    fn locate(&self, timestamp_ms: f64) -> Option<(usize, f64)> {
+        let len = self.timestamps.len();
+        if len < 2 || timestamp_ms < self.timestamps[0] || timestamp_ms > self.timestamps[len - 1] { return None; }
+        let i = self.timestamps.partition_point(|x| *x <= timestamp_ms).clamp(1, len - 1) - 1;
+        Some((i, (timestamp_ms - self.timestamps[i]) / (self.timestamps[i + 1] - self.timestamps[i])))
+    }
+
+    fn rate(&self, timestamp_ms: f64) -> Option<Vector3<f64>> {
+        let (i, t) = self.locate(timestamp_ms)?;
+        Some(self.rates[i].lerp(&self.rates[i + 1], t))
+    }
+
+    fn mean_rate(&self, from_ms: f64, to_ms: f64) -> Option<Vector3<f64>> {
+        let (a, ta) = self.locate(from_ms)?;
+        let (b, tb) = self.locate(to_ms)?;
+        let integral = self.integral[b].lerp(&self.integral[b + 1], tb) - self.integral[a].lerp(&self.integral[a + 1], ta);
+        Some(integral / ((to_ms - from_ms) / 1000.0))
+    }
+
+    /// Rotation of the IMU between the timestamps, in the IMU coordinates at `from_ms`
+    fn rotation(&self, from_ms: f64, to_ms: f64, negated: bool) -> Option<UnitQuaternion<f64>> {
+        let orientations = &self.orientations[negated as usize];
+        let orientation = |timestamp_ms: f64| -> Option<UnitQuaternion<f64>> {
+            let (i, t) = self.locate(timestamp_ms)?;
+            Some(orientations[i].try_slerp(&orientations[i + 1], t, 1e-9).unwrap_or(orientations[i]))
+        };
+        Some(orientation(from_ms)?.inverse() * orientation(to_ms)?)
+    }
+}
+
+// Parameters: intrinsics, IMU rotation vector, time offset (ms), readout time (ms) and the board pose of every view.
+// The residuals are in blocks: reprojection of every view, then the rotation difference of every pair of consecutive views
+struct Joint<'a> {
+    projection: &'a Projection,
+    views: &'a [&'a ImuView],
+    pairs: &'a [(usize, usize)],
+    gyro: &'a Gyro,
+    permutation: Matrix3<f64>,
+    height: f64,
+    intrinsics: usize,
+    /// Pixels per radian of the rotation residuals
+    rotation_weight: f64,
+    /// Index of the first residual of every block, and the total number of residuals at the end
+    offsets: Vec<usize>,
+    /// Pairs which contain each view
+    view_pairs: Vec<Vec<usize>>,
+}
+
+impl<'a> Joint<'a> {
+    #[allow(clippy::too_many_arguments)]
+    fn new(projection: &'a Projection, views: &'a [&'a ImuView], pairs: &'a [(usize, usize)], gyro: &'a Gyro, permutation: Matrix3<f64>, height: f64, intrinsics: usize, rotation_weight: f64) -> Self {
+        let mut offsets = vec![0];
+        for view in views {
+            offsets.push(offsets.last().unwrap() + view.image_points.len() * 2);
+        }
+        for _ in pairs {
+            offsets.push(offsets.last().unwrap() + 3);
+        }
+        let mut view_pairs = vec![Vec::new(); views.len()];
+        for (i, &(a, b)) in pairs.iter().enumerate() {
+            view_pairs[a].push(i);
+            view_pairs[b].push(i);
+        }
+        Self { projection, views, pairs, gyro, permutation, height, intrinsics, rotation_weight, offsets, view_pairs }
+    }
+
+    fn pose<'p>(&self, param: &'p [f64], view: usize) -> &'p [f64] {
+        let start = self.intrinsics + GLOBAL + view * POSE;
+        &param[start..start + POSE]
+    }
+
+    // Maps the raw gyro to the angular velocity of the camera body
+    fn imu_to_camera(&self, param: &[f64]) -> Matrix3<f64> {
+        let g = &param[self.intrinsics..];
+        -SWAP_XY * Rotation3::new(Vector3::new(g[0], g[1], g[2])).matrix() * self.permutation
+    }
+
+    fn view_residuals(&self, param: &[f64], view: usize, out: &mut [f64]) {
+        let (offset, readout) = (param[self.intrinsics + 3], param[self.intrinsics + 4]);
+        let intrinsics = &param[..self.intrinsics];
+        let pose = self.pose(param, view);
+        let v = self.views[view];
+        let rate = self.gyro.rate(v.timestamp_ms - offset).map(|x| self.imu_to_camera(param) * x).unwrap_or_default();
+
+        for (i, (obj, img)) in v.object_points.iter().zip(v.image_points.iter()).enumerate() {
+            // Rotation of the camera between the middle of the frame and the time the row was read, the board rotates the opposite way
+            let dt = readout * (img.1 as f64 / self.height - 0.5) / 1000.0;
+            let (u, v) = reprojection::transform(pose, *obj)
+                .map(|p| Rotation3::new(-rate * dt) * p)
+                .filter(|p| p.z > 1e-9)
+                .and_then(|p| self.projection.project(intrinsics, &p))
+                .filter(|(u, v)| u.is_finite() && v.is_finite())
+                .unwrap_or((img.0 as f64 + BEHIND_CAMERA_ERROR, img.1 as f64 + BEHIND_CAMERA_ERROR));
+            out[i * 2]     = u - img.0 as f64;
+            out[i * 2 + 1] = v - img.1 as f64;
+        }
+    }
+
+    fn pair_residuals(&self, param: &[f64], pair: usize, out: &mut [f64]) {
+        let (a, b) = self.pairs[pair];
+        let offset = param[self.intrinsics + 3];
+        let relative = pose_rotation(self.pose(param, b)) * pose_rotation(self.pose(param, a)).inverse();
+
+        // The integrated rotation can only be transformed by a rotation, so an improper mapping is split into a rotation and negated rates
+        let mut imu_to_camera = self.imu_to_camera(param);
+        let negated = imu_to_camera.determinant() < 0.0;
+        if negated { imu_to_camera = -imu_to_camera; }
+
+        let error = self.gyro.rotation(self.views[a].timestamp_ms - offset, self.views[b].timestamp_ms - offset, negated).map(|q| {
+            let body = imu_to_camera * q.to_rotation_matrix().matrix() * imu_to_camera.transpose();
+            // The board rotates the opposite way
+            (relative * Rotation3::from_matrix_unchecked(body)).scaled_axis()
+        }).unwrap_or_default();
+        for i in 0..3 {
+            out[i] = error[i] * self.rotation_weight;
+        }
+    }
+
+    fn block_residuals(&self, param: &[f64], block: usize, out: &mut [f64]) {
+        if block < self.views.len() {
+            self.view_residuals(param, block, out);
+        } else {
+            self.pair_residuals(param, block - self.views.len(), out);
+        }
+    }
+
+    fn residuals(&self, param: &[f64]) -> DVector<f64> {
+        let mut ret = DVector::zeros(*self.offsets.last().unwrap());
+        for block in 0..self.offsets.len() - 1 {
+            self.block_residuals(param, block, &mut ret.as_mut_slice()[self.offsets[block]..self.offsets[block + 1]]);
+        }
+        ret
+    }
+
+    fn optimize(&self, param: Vec<f64>) -> Result<Vec<f64>, GyroflowCoreError> {
+        let result = Executor::new(self, LevenbergMarquardt::new().with_tolerance(TOLERANCE))
+            .configure(|state| state.param(DVector::from_vec(param)).max_iters(MAX_ITERATIONS))
+            .run()
+            .map_err(|e| GyroflowCoreError::CalibrationError(e.to_string()))?;
+
+        let state = result.state();
+        match state.get_best_param() {
+            Some(best) if state.get_best_cost().is_finite() => Ok(best.as_slice().to_vec()),
+            _ => Err(GyroflowCoreError::CalibrationError("Calibration didn't converge".into()))
+        }
+    }
+}
+
+impl<'a> Operator for &Joint<'a> {
+    type Param = DVector<f64>;
+    type Output = DVector<f64>;
+
+    fn apply(&self, param: &Self::Param) -> Result<Self::Output, Error> {
+        Ok(self.residuals(param.as_slice()))
+    }
+}
+
+impl<'a> Jacobian for &Joint<'a> {
+    type Param = DVector<f64>;
+    type Jacobian = DMatrix<f64>;
+
+    // Central differences. The intrinsics and the IMU parameters affect all residuals, every pose only affects its view and its pairs
+    fn jacobian(&self, param: &Self::Param) -> Result<Self::Jacobian, Error> {
+        let views = self.views.len();
+        let mut ret = DMatrix::zeros(*self.offsets.last().unwrap(), param.len());
+        let mut p = param.as_slice().to_vec();
+
+        let differentiate = |p: &mut Vec<f64>, column: usize, block: usize, ret: &mut DMatrix<f64>| {
+            let range = self.offsets[block]..self.offsets[block + 1];
+            let (mut plus, mut minus) = (vec![0.0; range.len()], vec![0.0; range.len()]);
+            let org = p[column];
+            let h = DERIVATIVE_STEP * org.abs().max(1.0);
+            p[column] = org + h;
+            self.block_residuals(p, block, &mut plus);
+            p[column] = org - h;
+            self.block_residuals(p, block, &mut minus);
+            p[column] = org;
+            for (i, row) in range.enumerate() {
+                ret[(row, column)] = (plus[i] - minus[i]) / (2.0 * h);
+            }
+        };
+        for column in 0..self.intrinsics + GLOBAL {
+            for block in 0..self.offsets.len() - 1 {
+                differentiate(&mut p, column, block, &mut ret);
+            }
+        }
+        for view in 0..views {
+            for column in 0..POSE {
+                let column = self.intrinsics + GLOBAL + view * POSE + column;
+                differentiate(&mut p, column, view, &mut ret);
+                for pair in &self.view_pairs[view] {
+                    differentiate(&mut p, column, views + pair, &mut ret);
+                }
+            }
+        }
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::fisheye;
+
+    // Video of a static board with a rotating camera and a rolling shutter, and gyro from a rotated IMU with a time offset
+    #[test]
+    fn recovers_imu_extrinsics() {
+        let (size, f, c, d) = ((1920, 1080), 1000.0, (960.0, 540.0), [0.02, -0.01, 0.005, 0.0]);
+        let (orientation, angles, offset, readout) = ("yXZ", [2.0f64, -1.5f64, 3.0f64], 37.0, 20.0);
+
+        let permutation = Matrix3::new(0.0, -1.0, 0.0,
+                                       1.0, 0.0, 0.0,
+                                       0.0, 0.0, 1.0);
+        let rotation = Rotation3::from_euler_angles(angles[2].to_radians(), angles[0].to_radians(), angles[1].to_radians());
+        let imu_to_camera = -SWAP_XY * rotation.matrix() * permutation;
+
+        // Angular velocity of the camera body and its orientation, integrated at 1 kHz
+        let angular_velocity = |t: f64| Vector3::new(0.5 * (2.1 * t).sin(), 0.4 * (3.3 * t + 1.0).sin(), 0.4 * (1.7 * t + 2.0).sin());
+        let mut orientations = vec![UnitQuaternion::identity()];
+        for i in 0..8000 {
+            let t = i as f64 / 1000.0;
+            orientations.push(orientations[i] * UnitQuaternion::from_scaled_axis(angular_velocity(t + 0.0005) / 1000.0));
+        }
+        let orientation_at = |ms: f64| orientations[ms as usize].slerp(&orientations[ms as usize + 1], ms.fract());
+
+        let intrinsics = [f, f, c.0, c.1, d[0], d[1], d[2], d[3]];
+        let (board_rotation, board_position) = (Rotation3::new(Vector3::new(0.2, -0.3, 0.1)), Vector3::new(-3.5, -2.0, 9.0));
+        let objp: Vec<(f64, f64)> = (0..5).flat_map(|y| (0..8).map(move |x| (x as f64, y as f64))).collect();
+        let views: Vec<ImuView> = (0..24).map(|i| {
+            let timestamp_ms = 500.0 + i as f64 * 250.0;
+            let image_points = objp.iter().map(|p| {
+                let world = board_rotation * Vector3::new(p.0, p.1, 0.0) + board_position;
+                // The row depends on the time it was read
+                let mut pt = (0.0, c.1);
+                for _ in 0..5 {
+                    let t = timestamp_ms + readout * (pt.1 / size.1 as f64 - 0.5);
+                    pt = fisheye::distort(&intrinsics, &(orientation_at(t).inverse() * world));
+                }
+                (pt.0 as f32, pt.1 as f32)
+            }).collect();
+            ImuView { timestamp_ms, object_points: objp.clone(), image_points }
+        }).collect();
+
+        let gyro: Vec<TimeIMU> = (0..7000).step_by(5).map(|ms| {
+            let g = imu_to_camera.transpose() * angular_velocity((ms as f64 + offset) / 1000.0);
+            TimeIMU { timestamp_ms: ms as f64, gyro: Some([g.x.to_degrees(), g.y.to_degrees(), g.z.to_degrees()]), accl: None, magn: None }
+        }).collect();
+
+        let initial = ModelCalibration {
+            model: "opencv_fisheye".into(),
+            rms: 1.0,
+            k: Matrix3::new(f + 10.0, 0.0, c.0 - 5.0,
+                            0.0, f + 10.0, c.1 + 5.0,
+                            0.0, 0.0, 1.0),
+            d: d.to_vec()
+        };
+        let result = calibrate(&initial, size, &views, &gyro).unwrap();
+        assert_eq!(result.imu_orientation, orientation);
+        for (found, expected) in result.imu_rotation.iter().zip(angles.iter()) {
+            assert!((found - expected).abs() < 0.2, "rotation: {:?}", result.imu_rotation);
+        }
+        assert!((result.offset - offset).abs() < 1.0, "offset: {}", result.offset);
+        assert!((result.frame_readout_time - readout).abs() < 1.0, "readout: {}", result.frame_readout_time);
+        assert!((result.k[(0, 0)] - f).abs() < 1.0, "fx: {}", result.k[(0, 0)]);
+        assert!(result.rms < 0.1, "rms: {}", result.rms);
+    }
+    // Independent of the conventions used by the solver: the video and the gyro come from a camera with an arbitrarily mounted IMU,
+    // and the calibrated lens profile is loaded into `StabilizationManager`. Stabilized to the orientation of the first view,
+    // the static board has to stay in the same place in every view.
+    #[test]
+    fn calibrated_profile_stabilizes() {
+        let (size, f, c, d) = ((1920, 1080), 900.0, (955.0, 545.0), [0.03, -0.02, 0.01, 0.0]);
+        let (offset, readout) = (-23.0, 15.0);
+        let imu_mounting = Rotation3::from_euler_angles(0.3, -1.2, 2.0);
+
+        let angular_velocity = |t: f64| Vector3::new(0.4 * (1.9 * t).sin(), 0.5 * (2.7 * t + 0.5).sin(), 0.3 * (1.3 * t + 1.5).sin());
+        let mut orientations = vec![UnitQuaternion::identity()];
+        for i in 0..8000 {
+            let t = i as f64 / 1000.0;
+            orientations.push(orientations[i] * UnitQuaternion::from_scaled_axis(angular_velocity(t + 0.0005) / 1000.0));
+        }
+        let orientation_at = |ms: f64| orientations[ms as usize].slerp(&orientations[ms as usize + 1], ms.fract());
+
+        let intrinsics = [f, f, c.0, c.1, d[0], d[1], d[2], d[3]];
+        let (board_rotation, board_position) = (Rotation3::new(Vector3::new(-0.1, 0.25, 0.05)), Vector3::new(-3.5, -2.0, 8.0));
+        let objp: Vec<(f64, f64)> = (0..5).flat_map(|y| (0..8).map(move |x| (x as f64, y as f64))).collect();
+        let views: Vec<ImuView> = (0..24).map(|i| {
+            let timestamp_ms = 500.0 + i as f64 * 250.0;
+            let image_points = objp.iter().map(|p| {
+                let world = board_rotation * Vector3::new(p.0, p.1, 0.0) + board_position;
+                let mut pt = (0.0, c.1);
+                for _ in 0..5 {
+                    let t = timestamp_ms + readout * (pt.1 / size.1 as f64 - 0.5);
+                    pt = fisheye::distort(&intrinsics, &(orientation_at(t).inverse() * world));
+                }
+                (pt.0 as f32, pt.1 as f32)
+            }).collect();
+            ImuView { timestamp_ms, object_points: objp.clone(), image_points }
+        }).collect();
+
+        // The angular velocity of the camera in the IMU axes, in deg/s
+        let gyro: Vec<TimeIMU> = (0..7000).step_by(5).map(|ms| {
+            let g = imu_mounting * angular_velocity((ms as f64 + offset) / 1000.0);
+            TimeIMU { timestamp_ms: ms as f64, gyro: Some([g.x.to_degrees(), g.y.to_degrees(), g.z.to_degrees()]), accl: None, magn: None }
+        }).collect();
+
+        let initial = ModelCalibration {
+            model: "opencv_fisheye".into(),
+            rms: 1.0,
+            k: Matrix3::new(f - 8.0, 0.0, c.0 + 4.0,
+                            0.0, f - 8.0, c.1 - 4.0,
+                            0.0, 0.0, 1.0),
+            d: d.to_vec()
+        };
+        let result = calibrate(&initial, size, &views, &gyro).unwrap();
+
+        let stab = crate::StabilizationManager::with_test_gyro(7000.0, 4.0, size, gyro);
+        stab.set_offset(0, result.offset);
+        stab.set_frame_readout_time(result.frame_readout_time);
+        let profile = serde_json::json!({
+            "calibrator_version": "1.6.0",
+            "calib_dimension": { "w": size.0, "h": size.1 },
+            "fisheye_params": {
+                "RMS_error": result.rms,
+                "camera_matrix": result.k.row_iter().map(|x| [x[0], x[1], x[2]]).collect::<Vec<_>>(),
+                "distortion_coeffs": result.d
+            },
+            "sync_settings": { "imu_orientation": result.imu_orientation, "imu_rotation": result.imu_rotation }
+        });
+        stab.load_lens_profile(&profile.to_string()).unwrap();
+        assert_eq!(stab.gyro.read().imu_orientation.as_deref(), Some(result.imu_orientation.as_str()));
+        stab.lock_smoothed_orientation(views[0].timestamp_ms);
+        let compute_params = crate::stabilization::ComputeParams::from_manager(&stab);
+
+        let stabilized: Vec<Vec<(f32, f32)>> = views.iter().map(|view| {
+            crate::stabilization::undistort_points_with_rolling_shutter(&view.image_points, view.timestamp_ms, &compute_params, 1.0, false)
+        }).collect();
+        for view in &stabilized[1..] {
+            for (p, p0) in view.iter().zip(stabilized[0].iter()) {
+                let diff = ((p.0 - p0.0).powi(2) + (p.1 - p0.1).powi(2)).sqrt();
+                assert!(diff < 1.0, "{p:?} should be at {p0:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn lens_profile_keeps_project_orientation() {
+        let profile = serde_json::json!({
+            "calibrator_version": "1.6.0",
+            "calib_dimension": { "w": 1920, "h": 1080 },
+            "fisheye_params": {
+                "camera_matrix": [[1000.0, 0.0, 960.0], [0.0, 1000.0, 540.0], [0.0, 0.0, 1.0]],
+                "distortion_coeffs": [0.0, 0.0, 0.0, 0.0]
+            },
+            "sync_settings": { "imu_orientation": "XYZ", "imu_rotation": [1.0, 2.0, 3.0] }
+        });
+        let stab = crate::StabilizationManager::default();
+        stab.gyro.write().imu_orientation = Some("YxZ".into());
+        stab.load_lens_profile(&profile.to_string()).unwrap();
+        assert_eq!(stab.gyro.read().imu_orientation.as_deref(), Some("YxZ"));
+        assert_eq!(stab.gyro.read().imu_rotation_angles, None);
+
+        assert!(stab.apply_lens_imu_transform());
+        assert_eq!(stab.gyro.read().imu_orientation.as_deref(), Some("XYZ"));
+        assert_eq!(stab.gyro.read().imu_rotation_angles, Some([1.0, 2.0, 3.0]));
+    }
+}
\ No newline at end of file
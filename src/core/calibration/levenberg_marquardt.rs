@@ -0,0 +1,92 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Levenberg-Marquardt solver for argmin, which only provides Gauss-Newton for nonlinear least squares.
+// The damping is scaled by the diagonal of JᵀJ, so parameters of very different magnitudes (eg. focal length and distortion coefficients)
+// converge equally well. The cost is the sum of squared residuals.
+
+use argmin::core::{ Error, IterState, Jacobian, Operator, Problem, Solver, TerminationReason, TerminationStatus, KV };
+use nalgebra::{ DMatrix, DVector };
+
+pub type LmState = IterState<DVector<f64>, (), DMatrix<f64>, (), DVector<f64>, f64>;
+
+const MAX_DAMPING_STEPS: usize = 10;
+
+#[derive(Clone, Debug)]
+pub struct LevenbergMarquardt {
+    lambda: f64,
+    tolerance: f64,
+    converged: bool,
+}
+
+impl Default for LevenbergMarquardt {
+    fn default() -> Self {
+        Self {
+            lambda: 1e-3,
+            tolerance: 1e-10,
+            converged: false,
+        }
+    }
+}
+
+impl LevenbergMarquardt {
+    pub fn new() -> Self { Self::default() }
+
+    /// Stop when a step reduces the cost by less than `tolerance` (relative)
+    pub fn with_tolerance(mut self, tolerance: f64) -> Self {
+        self.tolerance = tolerance;
+        self
+    }
+}
+
+impl<O> Solver<O, LmState> for LevenbergMarquardt
+where O: Operator<Param = DVector<f64>, Output = DVector<f64>> + Jacobian<Param = DVector<f64>, Jacobian = DMatrix<f64>> {
+    const NAME: &'static str = "Levenberg-Marquardt";
+
+    fn init(&mut self, problem: &mut Problem<O>, mut state: LmState) -> Result<(LmState, Option<KV>), Error> {
+        let param = state.take_param().ok_or_else(|| Error::msg("Levenberg-Marquardt requires an initial parameter vector"))?;
+        let residuals = problem.apply(&param)?;
+        let cost = residuals.norm_squared();
+        Ok((state.param(param).residuals(residuals).cost(cost), None))
+    }
+
+    fn next_iter(&mut self, problem: &mut Problem<O>, mut state: LmState) -> Result<(LmState, Option<KV>), Error> {
+        let param = state.take_param().ok_or_else(|| Error::msg("Levenberg-Marquardt: parameters not set"))?;
+        let residuals = state.take_residuals().ok_or_else(|| Error::msg("Levenberg-Marquardt: residuals not set"))?;
+        let cost = state.get_cost();
+
+        let jacobian = problem.jacobian(&param)?;
+        let jtj = jacobian.tr_mul(&jacobian);
+        let gradient = jacobian.tr_mul(&residuals);
+
+        for _ in 0..MAX_DAMPING_STEPS {
+            let mut a = jtj.clone();
+            for i in 0..a.nrows() {
+                a[(i, i)] += self.lambda * jtj[(i, i)].max(1e-9);
+            }
+            if let Some(cholesky) = a.cholesky() {
+                let new_param = &param - cholesky.solve(&gradient);
+                let new_residuals = problem.apply(&new_param)?;
+                let new_cost = new_residuals.norm_squared();
+                if new_cost.is_finite() && new_cost < cost {
+                    self.lambda = (self.lambda / 10.0).max(1e-12);
+                    self.converged = cost - new_cost <= self.tolerance * cost;
+                    return Ok((state.param(new_param).residuals(new_residuals).cost(new_cost), None));
+                }
+            }
+            self.lambda *= 10.0;
+        }
+
+        // No step reduces the cost anymore
+        self.converged = true;
+        Ok((state.param(param).residuals(residuals).cost(cost), None))
+    }
+
+    fn terminate(&mut self, _state: &LmState) -> TerminationStatus {
+        if self.converged {
+            TerminationStatus::Terminated(TerminationReason::SolverConverged)
+        } else {
+            TerminationStatus::NotTerminated
+        }
+    }
+}
@@ -4,6 +4,11 @@
 /// The basic idea here is to find chessboard every 10 frames and save all points to a map.
 /// Then we pick a random 10 frames from that map and calculate the calibration.
 /// Repeat that 1000 times, with new random set of frames each time and return the set which resulted in the lowest RMS
+/// Without the `use-opencv` feature, the chessboard detection and the fisheye calibration are done by `chessboard` and `fisheye`.
+/// Partial chessboard and asymmetric circle grid targets are always detected by `target`.
+/// The frames with the lowest fisheye RMS are then used to calibrate every model from `distortion::MODELS`, and the selected one is applied.
+/// `report` shows the error of every used frame and the coverage of the image, and frames with much higher error can be rejected.
+/// If the video has gyro data, `imu` can then calibrate the lens together with the IMU orientation, gyro offset and rolling shutter from all detected frames.
 
 #[cfg(feature = "use-opencv")]
 use opencv::{
@@ -13,20 +18,39 @@ use opencv::{
 };
 
 use rand::prelude::IteratorRandom;
-use std::{ ffi::c_void, collections::{ BTreeSet, BTreeMap, HashSet } };
+#[cfg(feature = "use-opencv")]
+use std::ffi::c_void;
+use std::collections::{ BTreeSet, BTreeMap, HashSet };
 use std::sync::atomic::{ AtomicBool, AtomicUsize, Ordering::SeqCst };
 use std::sync::Arc;
 use nalgebra::{ Matrix3, Vector4 };
 use parking_lot::RwLock;
-use rayon::iter::{ ParallelIterator, IntoParallelIterator };
+use rayon::iter::{ ParallelIterator, IntoParallelIterator, IntoParallelRefIterator };
 
 use crate::stabilization::distortion_models::DistortionModel;
+use crate::gyro_source::TimeIMU;
+use crate::GyroflowCoreError;
 
 pub mod drawing;
+pub mod chessboard;
+pub mod circles;
+pub mod distortion;
+pub mod fisheye;
+pub mod imu;
+pub mod levenberg_marquardt;
+pub mod report;
+pub mod reprojection;
+pub mod self_calibration;
+pub mod target;
+
+pub use target::CalibrationTarget;
 
 #[derive(Clone, Default, Debug)]
 pub struct Detected {
     pub points: Vec<(f32, f32)>,
+    /// Position of every point on the board, empty means the whole board (`LensCalibrator::objp`)
+    pub object_points: Vec<(f64, f64)>,
+    pub target: CalibrationTarget,
     pub frame: i32,
     pub timestamp_us: i64,
     pub avg_sharpness: f64,
@@ -48,7 +72,7 @@ pub struct LensCalibrator {
     pub objp: Vec<(f64, f64)>,
 
     pub k: Matrix3<f64>,
-    pub d: Vector4<f64>,
+    pub d: Vec<f64>,
 
     pub sum_sharpness: Arc<RwLock<f64>>,
     pub r_limit: f64,
@@ -56,11 +80,19 @@ pub struct LensCalibrator {
     pub forced_frames: HashSet<i32>,
 
     pub no_marker: bool,
+    pub target: CalibrationTarget,
 
     pub digital_lens: Option<String>,
     pub digital_lens_params: Option<Vec<f64>>,
     pub asymmetrical: bool,
 
+    /// `DistortionModel` id, `None` is OpenCV fisheye
+    pub distortion_model: Option<String>,
+    /// Calibration of every model from `distortion::MODELS` with the used frames
+    pub model_results: Vec<distortion::ModelCalibration>,
+    /// Joint calibration of the lens and the IMU
+    pub imu_calibration: Option<imu::ImuCalibration>,
+
     pub all_matches: Arc<RwLock<BTreeMap<i32, Detected>>>, // frame, Detected
     pub image_points: Arc<RwLock<BTreeMap<i32, Detected>>>, // frame, Detected
     pub used_points: BTreeMap<i32, Detected> // frame, Detected
@@ -87,19 +119,130 @@ impl LensCalibrator {
             ..Default::default()
         };
 
-        for y in 0..ret.rows {
-            for x in 0..ret.columns {
-                ret.objp.push((x as f64, y as f64));
-            }
-        }
+        ret.objp = ret.target.object_points(ret.columns, ret.rows);
 
         ret
     }
 
+    pub fn set_target(&mut self, target: CalibrationTarget) {
+        if self.target != target {
+            self.target = target;
+            self.objp = target.object_points(self.columns, self.rows);
+            self.clear();
+        }
+    }
+    /// Inner corners of the chessboard-based targets, or circles of the circle grid
+    pub fn set_grid_size(&mut self, columns: usize, rows: usize) {
+        if (self.columns, self.rows) != (columns, rows) && columns > 1 && rows > 1 {
+            (self.columns, self.rows) = (columns, rows);
+            self.objp = self.target.object_points(columns, rows);
+            self.clear();
+        }
+    }
+
+    pub fn distortion_model_id(&self) -> &str {
+        self.distortion_model.as_deref().unwrap_or(distortion::MODELS[0])
+    }
+
+    /// Selects the model and applies its result if it was already calibrated
+    pub fn set_distortion_model(&mut self, model: Option<String>) {
+        self.distortion_model = model;
+        self.apply_distortion_model();
+    }
+
+    fn apply_distortion_model(&mut self) {
+        let id = self.distortion_model_id();
+        if let Some(result) = self.model_results.iter().find(|x| x.model == id).cloned() {
+            self.k = result.k;
+            self.d = result.d;
+            self.rms = result.rms;
+        }
+    }
+
+    // Starts from the fisheye calibration of the used frames, which is also the result for `opencv_fisheye`
+    fn calibrate_models(&mut self, size: (usize, usize), fisheye_result: (f64, Matrix3<f64>, Vector4<f64>)) {
+        let frames: Vec<&Detected> = self.used_points.values().collect();
+        let imgpoints: Vec<Vec<(f32, f32)>> = frames.iter().map(|x| x.points.clone()).collect();
+        let objpoints: Vec<Vec<(f64, f64)>> = frames.iter().map(|x| if x.object_points.is_empty() { self.objp.clone() } else { x.object_points.clone() }).collect();
+
+        let (rms, k, d) = fisheye_result;
+        self.model_results = vec![distortion::ModelCalibration { model: distortion::MODELS[0].into(), rms, k, d: d.as_slice().to_vec() }];
+        match fisheye::calibrate(&objpoints, &imgpoints, size) {
+            Ok(initial) => {
+                let results: Vec<distortion::ModelCalibration> = distortion::MODELS[1..].par_iter().filter_map(|model| {
+                    distortion::calibrate(model, &objpoints, &imgpoints, &initial)
+                        .map_err(|e| log::warn!("Failed to calibrate {}: {:?}", model, e))
+                        .ok()
+                }).collect();
+                self.model_results.extend(results);
+            },
+            Err(e) => {
+                log::warn!("Failed to calibrate the distortion models: {:?}", e);
+            }
+        }
+        for x in &self.model_results {
+            log::info!("{}: RMS {:.5}", x.model, x.rms);
+        }
+    }
+
     pub fn clear(&mut self) {
         self.all_matches.write().clear();
         self.image_points.write().clear();
         self.used_points.clear();
+        self.imu_calibration = None;
+    }
+
+    /// Quality report of the current calibration
+    pub fn report(&self) -> Result<report::CalibrationReport, GyroflowCoreError> {
+        let frames: Vec<&Detected> = self.used_points.values().collect();
+        report::build(self.distortion_model_id(), &self.k, &self.d, (self.width, self.height), &frames, &self.objp)
+    }
+
+    /// Removes the used frames with much higher reprojection error than the others and calibrates again with the remaining ones.
+    /// Returns the removed frames
+    pub fn reject_outliers(&mut self) -> Result<Vec<i32>, GyroflowCoreError> {
+        let outliers = self.report()?.outliers;
+        if outliers.is_empty() {
+            return Ok(outliers);
+        }
+        {
+            let mut image_points = self.image_points.write();
+            for frame in &outliers {
+                self.used_points.remove(frame);
+                image_points.remove(frame);
+            }
+        }
+        self.calibrate(true)?;
+        Ok(outliers)
+    }
+
+    /// Calibrates the selected model together with the IMU, using all detected frames and the current calibration as the initial guess.
+    /// `gyro` is the raw gyro of the video, before any orientation or rotation is applied
+    pub fn calibrate_imu(&mut self, gyro: &[TimeIMU]) -> Result<(), GyroflowCoreError> {
+        if self.rms <= 0.0 || self.rms >= 100.0 {
+            return Err(GyroflowCoreError::CalibrationError("The lens has to be calibrated first".into()));
+        }
+        let views: Vec<imu::ImuView> = self.image_points.read().values().map(|x| imu::ImuView {
+            timestamp_ms: x.timestamp_us as f64 / 1000.0,
+            object_points: if x.object_points.is_empty() { self.objp.clone() } else { x.object_points.clone() },
+            image_points: x.points.clone()
+        }).collect();
+        let initial = distortion::ModelCalibration { model: self.distortion_model_id().into(), rms: self.rms, k: self.k, d: self.d.clone() };
+
+        let result = imu::calibrate(&initial, (self.width, self.height), &views, gyro)?;
+        log::info!("IMU calibration from {} views: RMS {:.5}, gyro RMS {:.3} deg, orientation {}, rotation {:?}, offset {:.2} ms, readout {:.2} ms",
+            result.views, result.rms, result.gyro_rms, result.imu_orientation, result.imu_rotation, result.offset, result.frame_readout_time);
+
+        self.k = result.k;
+        self.d = result.d.clone();
+        self.rms = result.rms;
+        if let Some(model) = self.model_results.iter_mut().find(|x| x.model == initial.model) {
+            model.k = result.k;
+            model.d = result.d.clone();
+            model.rms = result.rms;
+        }
+        self.imu_calibration = Some(result);
+        Ok(())
     }
 
     pub fn feed_frame<F>(&mut self, timestamp_us: i64, frame: i32, size: (u32, u32), org_size: (u32, u32), stride: usize, pt_scale: f32, pixels: &[u8], cancel_flag: Arc<AtomicBool>, total: usize, processed_imgs: Arc<AtomicUsize>, progress: F)
@@ -107,7 +250,7 @@ impl LensCalibrator {
 
         self.width = org_size.0 as usize;
         self.height = org_size.1 as usize;
-        let grid_size = Size::new(self.columns as i32, self.rows as i32);
+        let grid_size = (self.columns, self.rows);
         let max_sharpness = self.max_sharpness;
 
         let mut pixels = pixels.to_vec();
@@ -119,6 +262,8 @@ impl LensCalibrator {
         let digital_lens = self.digital_lens.as_ref().map(|x| DistortionModel::from_name(&x));
         let digital_lens_params_opt = self.digital_lens_params.clone();
         let no_marker = self.no_marker;
+        let target = self.target;
+        let objp = self.objp.clone();
 
         if let Some(detected) = all_matches.read().get(&frame) {
             if detected.avg_sharpness < max_sharpness {
@@ -130,9 +275,9 @@ impl LensCalibrator {
         }
 
         crate::run_threaded(move || {
-            let avg_sharpness = (|| -> Result<f64, opencv::Error> {
+            let avg_sharpness = (|| -> Option<f64> {
                 if cancel_flag.load(std::sync::atomic::Ordering::Relaxed) {
-                    return Ok(0.0);
+                    return Some(0.0);
                 }
 
                 // Apply contrast and brightness
@@ -142,70 +287,57 @@ impl LensCalibrator {
                     *px = (*px as f64 * contrast + brightness).min(255.0) as u8;
                 }
 
-                let inp1 = unsafe { Mat::new_size_with_data_unsafe(Size::new(size.0 as i32, size.1 as i32), CV_8UC1, pixels.as_ptr() as *mut c_void, stride as usize)? };
-                let mut inp = unsafe { Mat::new_size_with_data_unsafe(Size::new(size.0 as i32, size.1 as i32), CV_8UC1, pixels.as_ptr() as *mut c_void, stride as usize)? };
-
-                let _ = opencv::imgproc::equalize_hist(&inp1, &mut inp);
-
-                let mut corners = Mat::default();
-
-                let mut flags = CALIB_CB_MARKER;
-                if no_marker {
-                    flags = 0;
+                let (corners, object_points, avg_sharpness) = if target == CalibrationTarget::Chessboard {
+                    let (corners, avg_sharpness) = find_chessboard(&pixels, size, stride, grid_size, no_marker)?;
+                    (corners, objp, avg_sharpness)
+                } else {
+                    let img = chessboard::GrayImage::new(&pixels, size.0 as usize, size.1 as usize, stride);
+                    let detection = target.detect(&img, grid_size.0, grid_size.1)?;
+                    (detection.points, detection.object_points, detection.sharpness)
+                };
+                let mut points = Vec::with_capacity(corners.len());
+
+                let mut digital_lens_params = [0f32; 4];
+                if let Some(p) = digital_lens_params_opt {
+                    for (i, v) in p.iter().enumerate() {
+                        digital_lens_params[i] = *v as f32;
+                    }
                 }
-
-                if opencv::calib3d::find_chessboard_corners_sb(&inp, grid_size, &mut corners, flags)? {
-                    if corners.rows() > 0 {
-                        let sharpness = opencv::calib3d::estimate_chessboard_sharpness(&inp, grid_size, &corners, 0.8, false, &mut Mat::default()).unwrap_or_default();
-                        let avg_sharpness = *sharpness.get(0).unwrap_or(&100.0);
-                        let mut points = Vec::with_capacity(corners.rows() as usize);
-
-                        let mut digital_lens_params = [0f32; 4];
-                        if let Some(p) = digital_lens_params_opt {
-                            for (i, v) in p.iter().enumerate() {
-                                digital_lens_params[i] = *v as f32;
-                            }
-                        }
-                        // TODO more params
-                        let kernel_params = crate::stabilization::KernelParams {
-                            width : size.0 as i32,
-                            height: size.1 as i32,
-                            output_width: size.0 as i32,
-                            output_height: size.1 as i32,
-                            digital_lens_params,
-                            ..Default::default()
-                        };
-
-                        for (_pos, mut pt) in corners.iter::<Point2f>()? {
-                            if let Some(digital) = &digital_lens {
-                                if let Some(mut pt2) = digital.undistort_point((pt.x,  pt.y), &kernel_params) {
-                                    // TODO
-                                    // Move from center to the left, because we trim the right part making it 4:3
-                                    //pt2.0 -= 0.125; // (16-4) / (9-3) / 16
-
-                                    pt = Point2f::new(pt2.0, pt2.1);
-                                }
-                            }
-                            points.push((pt.x * pt_scale, pt.y * pt_scale));
-                        }
-                        log::debug!("avg sharpness: {:.5}, max: {:.5}", avg_sharpness, max_sharpness);
-                        if avg_sharpness < max_sharpness || is_forced {
-                            img_points.write().insert(frame, Detected { points: points.clone(), timestamp_us, frame, avg_sharpness, is_forced });
-                            *sum_sharpness.write() += avg_sharpness;
+                // TODO more params
+                let kernel_params = crate::stabilization::KernelParams {
+                    width : size.0 as i32,
+                    height: size.1 as i32,
+                    output_width: size.0 as i32,
+                    output_height: size.1 as i32,
+                    digital_lens_params,
+                    ..Default::default()
+                };
+
+                for mut pt in corners {
+                    if let Some(digital) = &digital_lens {
+                        if let Some(pt2) = digital.undistort_point(pt, &kernel_params) {
+                            // TODO
+                            // Move from center to the left, because we trim the right part making it 4:3
+                            //pt2.0 -= 0.125; // (16-4) / (9-3) / 16
+
+                            pt = pt2;
                         }
-                        all_matches.write().insert(frame, Detected { points, timestamp_us, avg_sharpness, frame, is_forced });
-                        return Ok(avg_sharpness);
                     }
+                    points.push((pt.0 * pt_scale, pt.1 * pt_scale));
                 }
-                Err(opencv::Error::new(0, "Chessboard not found".to_string()))
+                log::debug!("avg sharpness: {:.5}, max: {:.5}", avg_sharpness, max_sharpness);
+                if avg_sharpness < max_sharpness || is_forced {
+                    img_points.write().insert(frame, Detected { points: points.clone(), object_points: object_points.clone(), target, timestamp_us, frame, avg_sharpness, is_forced });
+                    *sum_sharpness.write() += avg_sharpness;
+                }
+                all_matches.write().insert(frame, Detected { points, object_points, target, timestamp_us, avg_sharpness, frame, is_forced });
+                Some(avg_sharpness)
             })();
             progress((processed_imgs.fetch_add(1, SeqCst) + 1, total, img_points.read().len(), 0.0, avg_sharpness.unwrap_or(0.0)));
         });
     }
 
-    pub fn calibrate(&mut self, only_used: bool) -> Result<(), opencv::Error> {
-        let calib_criteria = TermCriteria::new(TermCriteria_Type::EPS as i32 | TermCriteria_Type::COUNT as i32, 30, 1e-6)?;
-
+    pub fn calibrate(&mut self, only_used: bool) -> Result<(), GyroflowCoreError> {
         let found_frames: BTreeSet<i32> = if only_used {
             self.used_points.keys().copied().collect()
         } else {
@@ -220,7 +352,7 @@ impl LensCalibrator {
             // TODO
             //width = (width as f32 / 1.33333333).round() as i32;
         }
-        let size = Size::new(width, self.height as i32);
+        let size = (width as usize, self.height);
         let objp = self.objp.clone();
         let max_images = self.max_images;
         let forced_frames = self.forced_frames.clone();
@@ -264,29 +396,15 @@ impl LensCalibrator {
                 return (999.0000, Matrix3::<f64>::default(), Vector4::<f64>::default(), final_frames);
             }
 
-            let imgpoints = Vector::<Vector<Point2f>>::from_iter(
-                final_frames.iter().filter_map(|k| Some(Vector::from_iter(
-                    image_points.get(k)?.points.iter().map(|(x, y)| Point2f::new(*x as f32, *y as f32))
-                ))
-            ));
-            let objpoints = Vector::<Vector<Point3d>>::from_iter(
-                (0..imgpoints.len()).into_iter().map(|_| Vector::<Point3d>::from_iter(
-                    objp.iter().map(|(x, y)| Point3d::new(*x, *y, 0.0))
-                ))
-            );
-
-            let mut k  = Mat::default(); let mut d  = Mat::default();
-            let mut rv = Mat::default(); let mut tv = Mat::default();
-            // let mut nop = Mat::default();
-
-            // match opencv::calib3d::calibrate_camera_ro(&objpoints, &imgpoints, size, 13, &mut k, &mut d, &mut rv, &mut tv, &mut nop, Fisheye_CALIB_RECOMPUTE_EXTRINSIC | Fisheye_CALIB_FIX_SKEW, calib_criteria) {
-            match opencv::calib3d::calibrate(&objpoints, &imgpoints, size, &mut k, &mut d, &mut rv, &mut tv, Fisheye_CALIB_RECOMPUTE_EXTRINSIC | Fisheye_CALIB_FIX_SKEW, calib_criteria) {
-                Ok(rms) => {
-                    if let Ok(k) = cv_to_mat3(k) {
-                        if let Ok(d) = cv_to_vec4(d) {
-                            return (rms, k, d, final_frames);
-                        }
-                    }
+            let imgpoints: Vec<Vec<(f32, f32)>> = final_frames.iter().filter_map(|k| Some(image_points.get(k)?.points.clone())).collect();
+            let objpoints: Vec<Vec<(f64, f64)>> = final_frames.iter().filter_map(|k| {
+                let detected = image_points.get(k)?;
+                Some(if detected.object_points.is_empty() { objp.clone() } else { detected.object_points.clone() })
+            }).collect();
+
+            match calibrate_fisheye(&objpoints, &imgpoints, size) {
+                Ok((rms, k, d)) => {
+                    return (rms, k, d, final_frames);
                 },
                 Err(e) => {
                     log::warn!("Failed to calibrate! {:?}", e);
@@ -297,17 +415,91 @@ impl LensCalibrator {
 
         if let Some((rms, k, d, used_frames)) = result {
             self.k = k;
-            self.d = d;
+            self.d = d.as_slice().to_vec();
             self.rms = rms;
             self.used_points = used_frames.into_iter().filter_map(|f| Some((f, image_points.get(&f)?.clone()))).collect();
 
+            if rms < 100.0 {
+                self.calibrate_models(size, (rms, k, d));
+                self.apply_distortion_model();
+            } else {
+                self.model_results.clear();
+            }
+
             Ok(())
         } else {
-            Err(opencv::Error::new(0, "Unable to calibrate camera".to_string()))
+            Err(GyroflowCoreError::CalibrationError("Unable to calibrate camera".to_string()))
         }
     }
 }
 
+/// Returns the corners and the average sharpness
+#[cfg(feature = "use-opencv")]
+fn find_chessboard(pixels: &[u8], size: (u32, u32), stride: usize, grid_size: (usize, usize), no_marker: bool) -> Option<(Vec<(f32, f32)>, f64)> {
+    let result = || -> Result<Option<(Vec<(f32, f32)>, f64)>, opencv::Error> {
+        let grid_size = Size::new(grid_size.0 as i32, grid_size.1 as i32);
+        let inp1 = unsafe { Mat::new_size_with_data_unsafe(Size::new(size.0 as i32, size.1 as i32), CV_8UC1, pixels.as_ptr() as *mut c_void, stride as usize)? };
+        let mut inp = unsafe { Mat::new_size_with_data_unsafe(Size::new(size.0 as i32, size.1 as i32), CV_8UC1, pixels.as_ptr() as *mut c_void, stride as usize)? };
+
+        let _ = opencv::imgproc::equalize_hist(&inp1, &mut inp);
+
+        let mut corners = Mat::default();
+
+        let mut flags = CALIB_CB_MARKER;
+        if no_marker {
+            flags = 0;
+        }
+
+        if opencv::calib3d::find_chessboard_corners_sb(&inp, grid_size, &mut corners, flags)? && corners.rows() > 0 {
+            let sharpness = opencv::calib3d::estimate_chessboard_sharpness(&inp, grid_size, &corners, 0.8, false, &mut Mat::default()).unwrap_or_default();
+            let avg_sharpness = *sharpness.get(0).unwrap_or(&100.0);
+            let points = corners.iter::<Point2f>()?.map(|(_, pt)| (pt.x, pt.y)).collect();
+            return Ok(Some((points, avg_sharpness)));
+        }
+        Ok(None)
+    };
+    result().unwrap_or_else(|e| { log::warn!("Failed to find chessboard: {:?}", e); None })
+}
+
+#[cfg(not(feature = "use-opencv"))]
+fn find_chessboard(pixels: &[u8], size: (u32, u32), stride: usize, grid_size: (usize, usize), _no_marker: bool) -> Option<(Vec<(f32, f32)>, f64)> {
+    let img = chessboard::GrayImage::new(pixels, size.0 as usize, size.1 as usize, stride);
+    let corners = chessboard::find_chessboard_corners(&img, grid_size.0, grid_size.1)?;
+    let avg_sharpness = chessboard::estimate_sharpness(&img, grid_size.0, grid_size.1, &corners);
+    Some((corners, avg_sharpness))
+}
+
+#[cfg(feature = "use-opencv")]
+fn calibrate_fisheye(objpoints: &[Vec<(f64, f64)>], imgpoints: &[Vec<(f32, f32)>], size: (usize, usize)) -> Result<(f64, Matrix3<f64>, Vector4<f64>), opencv::Error> {
+    let calib_criteria = TermCriteria::new(TermCriteria_Type::EPS as i32 | TermCriteria_Type::COUNT as i32, 30, 1e-6)?;
+    let size = Size::new(size.0 as i32, size.1 as i32);
+
+    let imgpoints = Vector::<Vector<Point2f>>::from_iter(
+        imgpoints.iter().map(|points| Vector::from_iter(
+            points.iter().map(|(x, y)| Point2f::new(*x, *y))
+        ))
+    );
+    let objpoints = Vector::<Vector<Point3d>>::from_iter(
+        objpoints.iter().map(|points| Vector::<Point3d>::from_iter(
+            points.iter().map(|(x, y)| Point3d::new(*x, *y, 0.0))
+        ))
+    );
+
+    let mut k  = Mat::default(); let mut d  = Mat::default();
+    let mut rv = Mat::default(); let mut tv = Mat::default();
+    // let mut nop = Mat::default();
+
+    // let rms = opencv::calib3d::calibrate_camera_ro(&objpoints, &imgpoints, size, 13, &mut k, &mut d, &mut rv, &mut tv, &mut nop, Fisheye_CALIB_RECOMPUTE_EXTRINSIC | Fisheye_CALIB_FIX_SKEW, calib_criteria)?;
+    let rms = opencv::calib3d::calibrate(&objpoints, &imgpoints, size, &mut k, &mut d, &mut rv, &mut tv, Fisheye_CALIB_RECOMPUTE_EXTRINSIC | Fisheye_CALIB_FIX_SKEW, calib_criteria)?;
+    Ok((rms, cv_to_mat3(k)?, cv_to_vec4(d)?))
+}
+
+#[cfg(not(feature = "use-opencv"))]
+fn calibrate_fisheye(objpoints: &[Vec<(f64, f64)>], imgpoints: &[Vec<(f32, f32)>], size: (usize, usize)) -> Result<(f64, Matrix3<f64>, Vector4<f64>), GyroflowCoreError> {
+    let result = fisheye::calibrate(objpoints, imgpoints, size)?;
+    Ok((result.rms, result.k, result.d))
+}
+
 #[cfg(feature = "use-opencv")]
 fn cv_to_mat3(r1: Mat) -> Result<Matrix3<f64>, opencv::Error> {
     if r1.typ() != opencv::core::CV_64FC1 {
@@ -333,4 +525,127 @@ fn cv_to_vec4(v: Mat) -> Result<Vector4<f64>, opencv::Error> {
     ))
 }
 
-// https://github.com/Tangram-Vision/Tangram-Vision-Blog/blob/main/2021.05.28_CalibrationFromScratch/src/main.rs
\ No newline at end of file
+// https://github.com/Tangram-Vision/Tangram-Vision-Blog/blob/main/2021.05.28_CalibrationFromScratch/src/main.rs
+
+// With OpenCV available, the pure-Rust detector and solver are compared directly with `findChessboardCornersSB` and `fisheye::calibrate`
+#[cfg(all(test, feature = "use-opencv"))]
+mod tests {
+    use super::*;
+    use nalgebra::{ Rotation3, Vector3 };
+
+    // GoPro profile calibrated with `cv::fisheye::calibrate`, same as in `fisheye::tests`
+    fn gopro_intrinsics(scale: f64) -> (Matrix3<f64>, Vector4<f64>) {
+        (Matrix3::new(1174.57 * scale, 0.0, 1911.79 * scale,
+                      0.0, 1175.32 * scale, 1080.86 * scale,
+                      0.0, 0.0, 1.0),
+         Vector4::new(0.0456, 0.0214, -0.0102, 0.0021))
+    }
+
+    // Renders a board with `columns` x `rows` inner corners at integer board coordinates 1..=columns, 1..=rows, through the fisheye lens
+    fn render_board(k: &Matrix3<f64>, d: &Vector4<f64>, rvec: &Vector3<f64>, tvec: &Vector3<f64>, columns: usize, rows: usize, size: (usize, usize)) -> Vec<u8> {
+        let r_t = Rotation3::new(*rvec).inverse();
+        let t_board = r_t * tvec;
+        let mut pixels = vec![0u8; size.0 * size.1];
+        for y in 0..size.1 {
+            for x in 0..size.0 {
+                let mut sum = 0.0;
+                for s in 0..4 {
+                    let u = x as f64 + (s % 2) as f64 / 2.0 - 0.25;
+                    let v = y as f64 + (s / 2) as f64 / 2.0 - 0.25;
+                    let (xd, yd) = ((u - k[(0, 2)]) / k[(0, 0)], (v - k[(1, 2)]) / k[(1, 1)]);
+                    let theta_d = (xd * xd + yd * yd).sqrt();
+                    let mut theta = theta_d;
+                    for _ in 0..10 {
+                        let t2 = theta * theta;
+                        let f = theta * (1.0 + t2 * (d[0] + t2 * (d[1] + t2 * (d[2] + t2 * d[3])))) - theta_d;
+                        let df = 1.0 + t2 * (3.0 * d[0] + t2 * (5.0 * d[1] + t2 * (7.0 * d[2] + t2 * 9.0 * d[3])));
+                        theta -= f / df;
+                    }
+                    let scale = if theta_d > 1e-8 { theta.tan() / theta_d } else { 1.0 };
+                    let ray = r_t * Vector3::new(xd * scale, yd * scale, 1.0);
+                    let dist = t_board.z / ray.z;
+                    let (bx, by) = (ray.x * dist - t_board.x, ray.y * dist - t_board.y);
+                    sum += if theta >= std::f64::consts::FRAC_PI_2 || dist <= 0.0 || bx < 0.0 || by < 0.0 || bx >= (columns + 1) as f64 || by >= (rows + 1) as f64 {
+                        230.0
+                    } else if (bx.floor() as i32 + by.floor() as i32) % 2 == 0 {
+                        20.0
+                    } else {
+                        230.0
+                    };
+                }
+                pixels[y * size.0 + x] = (sum / 4.0) as u8;
+            }
+        }
+        pixels
+    }
+
+    #[test]
+    fn chessboard_matches_opencv() {
+        let (columns, rows) = (14, 8);
+        let size = (1920, 1080);
+        let (k, d) = gopro_intrinsics(0.5);
+        for (rvec, tvec) in [
+            (Vector3::new(0.15, -0.25, 0.05), Vector3::new(-7.5, -4.5, 12.0)),
+            (Vector3::new(-0.30, 0.35, -0.20), Vector3::new(-11.0, -2.0, 10.0)),
+        ] {
+            let pixels = render_board(&k, &d, &rvec, &tvec, columns, rows, size);
+            let (opencv_corners, _) = find_chessboard(&pixels, (size.0 as u32, size.1 as u32), size.0, (columns, rows), true).expect("OpenCV didn't find the chessboard");
+            let img = chessboard::GrayImage::new(&pixels, size.0, size.1, size.0);
+            let corners = chessboard::find_chessboard_corners(&img, columns, rows).expect("Chessboard not found");
+            assert_eq!(corners.len(), opencv_corners.len());
+
+            // The first corner can be chosen differently, so the corners are matched by position
+            let mut max_diff = 0.0f32;
+            let mut sum_diff = 0.0f32;
+            for c in &corners {
+                let diff = opencv_corners.iter().map(|o| ((c.0 - o.0).powi(2) + (c.1 - o.1).powi(2)).sqrt()).fold(f32::MAX, f32::min);
+                max_diff = max_diff.max(diff);
+                sum_diff += diff;
+            }
+            let mean_diff = sum_diff / corners.len() as f32;
+            assert!(mean_diff < 0.1 && max_diff < 0.3, "mean: {mean_diff}, max: {max_diff}");
+
+            // Both are close to the exact corners
+            for (x, y) in (1..=rows).flat_map(|y| (1..=columns).map(move |x| (x, y))) {
+                let (u, v) = fisheye::project_point(&k, &d, &rvec, &tvec, (x as f64, y as f64)).unwrap();
+                let diff = opencv_corners.iter().map(|o| ((o.0 as f64 - u).powi(2) + (o.1 as f64 - v).powi(2)).sqrt()).fold(f64::MAX, f64::min);
+                assert!(diff < 0.3, "OpenCV corner {x}x{y}: {diff}");
+            }
+        }
+    }
+
+    #[test]
+    fn fisheye_matches_opencv() {
+        let (k, d) = gopro_intrinsics(1.0);
+        let objp: Vec<(f64, f64)> = (0..8).flat_map(|y| (0..14).map(move |x| (x as f64, y as f64))).collect();
+        let poses = [
+            (Vector3::new( 0.10, -0.20,  0.05), Vector3::new(-7.0, -4.0, 12.0)),
+            (Vector3::new(-0.35,  0.30,  0.10), Vector3::new(-3.0, -5.0, 10.0)),
+            (Vector3::new( 0.40,  0.25, -0.20), Vector3::new(-12.0, -2.0, 11.0)),
+            (Vector3::new(-0.20, -0.50,  0.30), Vector3::new(-1.0, -7.0, 9.0)),
+            (Vector3::new( 0.55, -0.10,  0.40), Vector3::new(-9.0, -8.0, 13.0)),
+            (Vector3::new( 0.05,  0.60, -0.10), Vector3::new(-6.0, 0.0, 8.0)),
+        ];
+        let mut seed = 7u32;
+        let mut noise = || { seed = seed.wrapping_mul(1103515245).wrapping_add(12345); ((seed >> 16) as f64 / 65535.0 - 0.5) * 0.4 };
+        let image_points: Vec<Vec<(f32, f32)>> = poses.iter().map(|(r, t)| {
+            objp.iter().map(|p| {
+                let (u, v) = fisheye::project_point(&k, &d, r, t, *p).unwrap();
+                ((u + noise()) as f32, (v + noise()) as f32)
+            }).collect()
+        }).collect();
+        let object_points = vec![objp; poses.len()];
+
+        let (opencv_rms, opencv_k, opencv_d) = calibrate_fisheye(&object_points, &image_points, (3840, 2160)).unwrap();
+        let result = fisheye::calibrate(&object_points, &image_points, (3840, 2160)).unwrap();
+
+        // Same minimum of the same cost function
+        assert!((result.rms - opencv_rms).abs() < 0.01 * opencv_rms, "rms: {} vs OpenCV {opencv_rms}", result.rms);
+        for (i, j) in [(0, 0), (1, 1), (0, 2), (1, 2)] {
+            assert!((result.k[(i, j)] - opencv_k[(i, j)]).abs() < 0.5, "k: {} vs OpenCV {opencv_k}", result.k);
+        }
+        for i in 0..4 {
+            assert!((result.d[i] - opencv_d[i]).abs() < 1e-3, "d: {:?} vs OpenCV {opencv_d:?}", result.d);
+        }
+    }
+}
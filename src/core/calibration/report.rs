@@ -0,0 +1,300 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Quality report of a lens calibration: reprojection error of every used frame and every point, and how well the points cover the image.
+// The board pose of every frame is estimated with the final intrinsics, so the residuals are the same as in the calibration.
+// The image is divided into a grid, and the corners and edges without points are reported, because the distortion there is only extrapolated.
+
+use std::fmt::Write;
+use nalgebra::Matrix3;
+use serde::Serialize;
+use super::distortion::Projection;
+use super::reprojection;
+use super::Detected;
+use crate::GyroflowCoreError;
+
+pub const COVERAGE_COLUMNS: usize = 16;
+pub const COVERAGE_ROWS: usize = 9;
+/// Frames with the error higher than this times the median error are outliers
+const OUTLIER_FACTOR: f64 = 2.5;
+/// Frames with the error lower than this are never outliers
+const MIN_OUTLIER_ERROR: f64 = 1.0;
+const MIN_COVERAGE: f64 = 0.7;
+const MIN_EDGE_COVERAGE: f64 = 0.5;
+const MIN_FRAMES: usize = 10;
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct FrameReport {
+    pub frame: i32,
+    pub timestamp_us: i64,
+    pub is_forced: bool,
+    /// RMS reprojection error in pixels
+    pub rms: f64,
+    pub max_error: f64,
+    /// Detected point and the difference of the projected point from it, `[x, y, dx, dy]`
+    pub residuals: Vec<[f32; 4]>,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct CalibrationReport {
+    /// `DistortionModel` id
+    pub model: String,
+    pub width: usize,
+    pub height: usize,
+    /// RMS reprojection error of all frames in pixels
+    pub rms: f64,
+    pub frames: Vec<FrameReport>,
+    /// Number of points in every cell of the `COVERAGE_COLUMNS` x `COVERAGE_ROWS` grid over the image, row by row
+    pub coverage: Vec<Vec<usize>>,
+    /// Ratio of the cells with at least one point
+    pub coverage_ratio: f64,
+    /// Frames with much higher error than the others
+    pub outliers: Vec<i32>,
+    pub warnings: Vec<ReportWarning>,
+}
+
+/// `text` is in English with `%1`, `%2` placeholders for `args`, so the UI can translate it
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ReportWarning {
+    pub text: &'static str,
+    pub args: Vec<String>,
+}
+impl ReportWarning {
+    fn new(text: &'static str, args: Vec<String>) -> Self {
+        Self { text, args }
+    }
+}
+impl std::fmt::Display for ReportWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let mut text = self.text.to_owned();
+        for (i, arg) in self.args.iter().enumerate().rev() {
+            text = text.replace(&format!("%{}", i + 1), arg);
+        }
+        write!(f, "{text}")
+    }
+}
+
+/// `frames` are the frames used for the calibration, `objp` are the object points of frames which don't have their own
+pub fn build(model: &str, k: &Matrix3<f64>, d: &[f64], size: (usize, usize), frames: &[&Detected], objp: &[(f64, f64)]) -> Result<CalibrationReport, GyroflowCoreError> {
+    let projection = Projection::new(model, d).ok_or_else(|| GyroflowCoreError::CalibrationError(format!("Distortion model {model} can't be calibrated")))?;
+    let intrinsics = projection.intrinsics(k, d);
+
+    let mut report = CalibrationReport {
+        model: model.into(),
+        width: size.0,
+        height: size.1,
+        coverage: vec![vec![0; COVERAGE_COLUMNS]; COVERAGE_ROWS],
+        ..Default::default()
+    };
+
+    let (mut sum_sq, mut count) = (0.0, 0);
+    for detected in frames {
+        let object_points = if detected.object_points.is_empty() { objp } else { &detected.object_points[..] };
+        if object_points.len() != detected.points.len() { continue; }
+
+        for pt in &detected.points {
+            let column = ((pt.0 as f64 / size.0 as f64 * COVERAGE_COLUMNS as f64).max(0.0) as usize).min(COVERAGE_COLUMNS - 1);
+            let row    = ((pt.1 as f64 / size.1 as f64 * COVERAGE_ROWS    as f64).max(0.0) as usize).min(COVERAGE_ROWS - 1);
+            report.coverage[row][column] += 1;
+        }
+
+        let mut frame = FrameReport { frame: detected.frame, timestamp_us: detected.timestamp_us, is_forced: detected.is_forced, ..Default::default() };
+        if let Some(pose) = projection.estimate_pose(&intrinsics, object_points, &detected.points) {
+            let mut frame_sum_sq = 0.0;
+            for (obj, img) in object_points.iter().zip(detected.points.iter()) {
+                let (u, v) = reprojection::transform(&pose, *obj)
+                    .and_then(|p| projection.project(&intrinsics, &p))
+                    .filter(|(u, v)| u.is_finite() && v.is_finite())
+                    .unwrap_or((img.0 as f64 + reprojection::BEHIND_CAMERA_ERROR, img.1 as f64 + reprojection::BEHIND_CAMERA_ERROR));
+                let (dx, dy) = (u - img.0 as f64, v - img.1 as f64);
+                let error_sq = dx * dx + dy * dy;
+                frame_sum_sq += error_sq;
+                frame.max_error = frame.max_error.max(error_sq.sqrt());
+                frame.residuals.push([img.0, img.1, dx as f32, dy as f32]);
+            }
+            sum_sq += frame_sum_sq;
+            count += detected.points.len();
+            frame.rms = (frame_sum_sq / detected.points.len().max(1) as f64).sqrt();
+        } else {
+            frame.rms = reprojection::BEHIND_CAMERA_ERROR;
+            frame.max_error = reprojection::BEHIND_CAMERA_ERROR;
+        }
+        report.frames.push(frame);
+    }
+    if report.frames.is_empty() {
+        return Err(GyroflowCoreError::CalibrationError("No calibrated frames".into()));
+    }
+    report.rms = (sum_sq / count.max(1) as f64).sqrt();
+
+    let covered = report.coverage.iter().flatten().filter(|x| **x > 0).count();
+    report.coverage_ratio = covered as f64 / (COVERAGE_COLUMNS * COVERAGE_ROWS) as f64;
+
+    let mut errors: Vec<f64> = report.frames.iter().map(|x| x.rms).collect();
+    errors.sort_by(f64::total_cmp);
+    let threshold = (errors[errors.len() / 2] * OUTLIER_FACTOR).max(MIN_OUTLIER_ERROR);
+    report.outliers = report.frames.iter().filter(|x| !x.is_forced && x.rms > threshold).map(|x| x.frame).collect();
+
+    report.warnings = report.find_warnings();
+    Ok(report)
+}
+
+impl CalibrationReport {
+    fn find_warnings(&self) -> Vec<ReportWarning> {
+        let mut ret = Vec::new();
+        if self.frames.len() < MIN_FRAMES {
+            ret.push(ReportWarning::new("Only %1 frames were used for the calibration, at least %2 are recommended", vec![self.frames.len().to_string(), MIN_FRAMES.to_string()]));
+        }
+        if self.coverage_ratio < MIN_COVERAGE {
+            ret.push(ReportWarning::new("The calibration target covers only %1% of the image", vec![format!("{:.0}", self.coverage_ratio * 100.0)]));
+        }
+
+        let is_covered = |rows: std::ops::Range<usize>, columns: std::ops::Range<usize>| -> bool {
+            rows.into_iter().any(|r| columns.clone().any(|c| self.coverage[r][c] > 0))
+        };
+        let (corner_w, corner_h) = (COVERAGE_COLUMNS / 4, COVERAGE_ROWS / 3);
+        for (text, rows, columns) in [
+            ("The top left corner of the image isn't covered",     0..corner_h, 0..corner_w),
+            ("The top right corner of the image isn't covered",    0..corner_h, COVERAGE_COLUMNS - corner_w..COVERAGE_COLUMNS),
+            ("The bottom left corner of the image isn't covered",  COVERAGE_ROWS - corner_h..COVERAGE_ROWS, 0..corner_w),
+            ("The bottom right corner of the image isn't covered", COVERAGE_ROWS - corner_h..COVERAGE_ROWS, COVERAGE_COLUMNS - corner_w..COVERAGE_COLUMNS),
+        ] {
+            if !is_covered(rows, columns) {
+                ret.push(ReportWarning::new(text, Vec::new()));
+            }
+        }
+
+        let edge_coverage = |cells: Vec<usize>| -> f64 { cells.iter().filter(|x| **x > 0).count() as f64 / cells.len() as f64 };
+        for (text, cells) in [
+            ("The top edge of the image is poorly covered",    self.coverage[0].clone()),
+            ("The bottom edge of the image is poorly covered", self.coverage[COVERAGE_ROWS - 1].clone()),
+            ("The left edge of the image is poorly covered",   self.coverage.iter().map(|x| x[0]).collect()),
+            ("The right edge of the image is poorly covered",  self.coverage.iter().map(|x| x[COVERAGE_COLUMNS - 1]).collect()),
+        ] {
+            if edge_coverage(cells) < MIN_EDGE_COVERAGE {
+                ret.push(ReportWarning::new(text, Vec::new()));
+            }
+        }
+
+        for frame in self.frames.iter().filter(|x| self.outliers.contains(&x.frame)) {
+            ret.push(ReportWarning::new("Frame %1 has a high reprojection error (%2 px)", vec![frame.frame.to_string(), format!("{:.3}", frame.rms)]));
+        }
+        ret
+    }
+
+    pub fn to_json(&self) -> Result<String, GyroflowCoreError> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// Standalone HTML page with the summary, the coverage heatmap, the residual vectors and the frames
+    pub fn to_html(&self) -> String {
+        let mut html = String::new();
+        let _ = write!(html, "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Lens calibration report</title>\n<style>\n\
+            body {{ font-family: sans-serif; background: #1e1e1e; color: #ddd; margin: 20px; }}\n\
+            table {{ border-collapse: collapse; margin-bottom: 20px; }}\n\
+            td, th {{ border: 1px solid #444; padding: 4px 10px; text-align: right; }}\n\
+            .heatmap td {{ width: 40px; height: 30px; text-align: center; color: #fff; }}\n\
+            .outlier {{ color: #f41717; }}\n\
+            .warning {{ color: #f6a10c; }}\n\
+            </style>\n</head>\n<body>\n");
+
+        let _ = writeln!(html, "<h2>Lens calibration report</h2>\n<table>");
+        let _ = writeln!(html, "<tr><th>Distortion model</th><td>{}</td></tr>", self.model);
+        let _ = writeln!(html, "<tr><th>Resolution</th><td>{}x{}</td></tr>", self.width, self.height);
+        let _ = writeln!(html, "<tr><th>Reprojection error</th><td>{:.5} px</td></tr>", self.rms);
+        let _ = writeln!(html, "<tr><th>Frames</th><td>{}</td></tr>", self.frames.len());
+        let _ = writeln!(html, "<tr><th>Coverage</th><td>{:.0}%</td></tr>\n</table>", self.coverage_ratio * 100.0);
+
+        if !self.warnings.is_empty() {
+            let _ = writeln!(html, "<h3>Warnings</h3>\n<ul>");
+            for x in &self.warnings {
+                let _ = writeln!(html, "<li class=\"warning\">{x}</li>");
+            }
+            let _ = writeln!(html, "</ul>");
+        }
+
+        let max_count = self.coverage.iter().flatten().copied().max().unwrap_or(0).max(1);
+        let _ = writeln!(html, "<h3>Coverage</h3>\n<table class=\"heatmap\">");
+        for row in &self.coverage {
+            let _ = write!(html, "<tr>");
+            for count in row {
+                let alpha = *count as f64 / max_count as f64;
+                let color = if *count == 0 { "rgb(120, 20, 20)".to_string() } else { format!("rgba(26, 233, 33, {:.2})", 0.2 + alpha * 0.8) };
+                let _ = write!(html, "<td style=\"background: {color}\">{count}</td>");
+            }
+            let _ = writeln!(html, "</tr>");
+        }
+        let _ = writeln!(html, "</table>");
+
+        // Residuals are scaled so that the biggest one is 5% of the image width
+        let max_residual = self.frames.iter().flat_map(|x| x.residuals.iter()).map(|r| (r[2] * r[2] + r[3] * r[3]).sqrt()).fold(0.0f32, f32::max);
+        let scale = if max_residual > 0.0 { self.width as f32 * 0.05 / max_residual } else { 1.0 };
+        let _ = writeln!(html, "<h3>Residuals (scaled {scale:.1}x)</h3>");
+        let _ = writeln!(html, "<svg viewBox=\"0 0 {} {}\" style=\"width: 100%; max-width: 1200px; background: #111; border: 1px solid #444;\">", self.width, self.height);
+        for frame in &self.frames {
+            let color = if self.outliers.contains(&frame.frame) { "#f41717" } else { "#1ae921" };
+            for r in &frame.residuals {
+                let _ = writeln!(html, "<line x1=\"{:.1}\" y1=\"{:.1}\" x2=\"{:.1}\" y2=\"{:.1}\" stroke=\"{color}\" stroke-width=\"2\"/>", r[0], r[1], r[0] + r[2] * scale, r[1] + r[3] * scale);
+            }
+        }
+        let _ = writeln!(html, "</svg>");
+
+        let _ = writeln!(html, "<h3>Frames</h3>\n<table>\n<tr><th>Frame</th><th>Timestamp</th><th>RMS</th><th>Max error</th><th>Points</th></tr>");
+        for frame in &self.frames {
+            let class = if self.outliers.contains(&frame.frame) { " class=\"outlier\"" } else { "" };
+            let _ = writeln!(html, "<tr{class}><td>{}{}</td><td>{:.3} s</td><td>{:.3} px</td><td>{:.3} px</td><td>{}</td></tr>",
+                frame.frame, if frame.is_forced { " (manual)" } else { "" }, frame.timestamp_us as f64 / 1_000_000.0, frame.rms, frame.max_error, frame.residuals.len());
+        }
+        let _ = writeln!(html, "</table>\n</body>\n</html>");
+        html
+    }
+
+    /// Saves the report as HTML if the file name ends with .html, or as JSON otherwise
+    pub fn save_to_file(&self, url: &str) -> Result<(), GyroflowCoreError> {
+        let lower = url.to_ascii_lowercase();
+        let data = if lower.ends_with(".html") || lower.ends_with(".htm") { self.to_html() } else { self.to_json()? };
+        crate::filesystem::write(url, data.as_bytes())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::{ Vector3, Vector4 };
+    use super::super::fisheye;
+
+    // Frames of a fisheye lens in the middle of the image, with one frame moved by a few pixels
+    #[test]
+    fn finds_outliers_and_poor_coverage() {
+        let k = Matrix3::new(1000.0, 0.0, 960.0,
+                             0.0, 1000.0, 540.0,
+                             0.0, 0.0, 1.0);
+        let d = Vector4::new(0.02, -0.01, 0.005, 0.0);
+        let objp: Vec<(f64, f64)> = (0..8).flat_map(|y| (0..14).map(move |x| (x as f64, y as f64))).collect();
+        let frames: Vec<Detected> = (0..12).map(|i| {
+            let r = Vector3::new(0.1 * (i as f64).sin(), 0.1 * (i as f64).cos(), 0.05);
+            let t = Vector3::new(-6.5, -3.5, 18.0 + i as f64 * 0.2);
+            let shift = if i == 5 { 4.0 } else { 0.0 };
+            Detected {
+                points: objp.iter().map(|p| {
+                    let (u, v) = fisheye::project_point(&k, &d, &r, &t, *p).unwrap();
+                    ((u + shift * (p.0 - 6.5) / 6.5) as f32, v as f32)
+                }).collect(),
+                frame: i * 10,
+                timestamp_us: i as i64 * 333_333,
+                ..Default::default()
+            }
+        }).collect();
+        let frames: Vec<&Detected> = frames.iter().collect();
+
+        let report = build("opencv_fisheye", &k, d.as_slice(), (1920, 1080), &frames, &objp).unwrap();
+        assert_eq!(report.frames.len(), 12);
+        assert_eq!(report.outliers, vec![50]);
+        assert!(report.frames[0].rms < 0.01, "rms: {}", report.frames[0].rms);
+        assert!(report.coverage_ratio < MIN_COVERAGE, "coverage: {}", report.coverage_ratio);
+        assert!(report.warnings.iter().any(|x| x.to_string().contains("top left corner")), "{:?}", report.warnings);
+        let outlier = report.warnings.iter().find(|x| x.text == "Frame %1 has a high reprojection error (%2 px)").unwrap();
+        assert_eq!(outlier.to_string(), format!("Frame 50 has a high reprojection error ({:.3} px)", report.frames[5].rms));
+        assert!(report.to_html().contains("<svg"));
+    }
+}
@@ -0,0 +1,154 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Reprojection error of a planar board (z = 0) seen in several views, minimized by `LevenbergMarquardt` to calibrate a camera.
+// The parameters are the camera intrinsics, whose meaning is up to the projection function, followed by the board pose of every view
+// (rotation vector and translation in board units). The residuals are the differences between the projected and detected points, in pixels.
+
+use argmin::core::{ Error, Executor, Jacobian, Operator, State };
+use nalgebra::{ DMatrix, DVector, Rotation3, Vector3 };
+use super::levenberg_marquardt::LevenbergMarquardt;
+use crate::GyroflowCoreError;
+
+pub const POSE: usize = 6; // rotation vector, translation
+/// Residual of points which can't be projected
+pub const BEHIND_CAMERA_ERROR: f64 = 1000.0;
+
+/// Board points and detected points of the views which can be used for calibration
+pub type Views<'a> = (Vec<&'a Vec<(f64, f64)>>, Vec<&'a Vec<(f32, f32)>>);
+
+/// Skips views with mismatched lengths or less than 4 points
+pub fn valid_views<'a>(object_points: &'a [Vec<(f64, f64)>], image_points: &'a [Vec<(f32, f32)>]) -> Views<'a> {
+    object_points.iter().zip(image_points.iter())
+        .filter(|(obj, img)| obj.len() == img.len() && obj.len() >= 4)
+        .unzip()
+}
+
+/// Board point in the camera coordinates, or `None` if it's behind the camera
+pub fn transform(pose: &[f64], object_point: (f64, f64)) -> Option<Vector3<f64>> {
+    let rotation = Rotation3::new(Vector3::new(pose[0], pose[1], pose[2]));
+    let p = rotation * Vector3::new(object_point.0, object_point.1, 0.0) + Vector3::new(pose[3], pose[4], pose[5]);
+    if p.z <= 1e-9 { return None; }
+    Some(p)
+}
+
+pub struct Reprojection<'a, P> {
+    object_points: &'a [&'a Vec<(f64, f64)>],
+    image_points: &'a [&'a Vec<(f32, f32)>],
+    /// Index of the first residual of every view, and the total number of residuals at the end
+    offsets: Vec<usize>,
+    intrinsics: usize,
+    step: f64,
+    project: P,
+}
+
+impl<'a, P> Reprojection<'a, P>
+where P: Fn(&[f64], &[f64], (f64, f64)) -> Option<(f64, f64)> {
+    /// `project(intrinsics, pose, object_point)` returns the point in the image, `intrinsics` is the number of the intrinsic parameters
+    pub fn new(object_points: &'a [&'a Vec<(f64, f64)>], image_points: &'a [&'a Vec<(f32, f32)>], intrinsics: usize, project: P) -> Self {
+        let mut offsets = vec![0];
+        for view in image_points {
+            offsets.push(offsets.last().unwrap() + view.len() * 2);
+        }
+        Self { object_points, image_points, offsets, intrinsics, step: 1e-6, project }
+    }
+
+    /// Relative step of the numerical derivatives. Has to be bigger if the projection isn't computed in f64
+    pub fn with_step(mut self, step: f64) -> Self {
+        self.step = step;
+        self
+    }
+
+    pub fn num_points(&self) -> usize {
+        self.offsets.last().unwrap() / 2
+    }
+
+    pub fn pose<'p>(&self, param: &'p [f64], view: usize) -> &'p [f64] {
+        &param[self.intrinsics + view * POSE..self.intrinsics + (view + 1) * POSE]
+    }
+
+    fn view_residuals(&self, intrinsics: &[f64], pose: &[f64], view: usize, out: &mut [f64]) {
+        for (i, (obj, img)) in self.object_points[view].iter().zip(self.image_points[view].iter()).enumerate() {
+            let (u, v) = (self.project)(intrinsics, pose, *obj)
+                .filter(|(u, v)| u.is_finite() && v.is_finite())
+                .unwrap_or((img.0 as f64 + BEHIND_CAMERA_ERROR, img.1 as f64 + BEHIND_CAMERA_ERROR));
+            out[i * 2]     = u - img.0 as f64;
+            out[i * 2 + 1] = v - img.1 as f64;
+        }
+    }
+    fn residuals(&self, view: usize) -> std::ops::Range<usize> {
+        self.offsets[view]..self.offsets[view + 1]
+    }
+}
+
+impl<'a, P> Operator for Reprojection<'a, P>
+where P: Fn(&[f64], &[f64], (f64, f64)) -> Option<(f64, f64)> {
+    type Param = DVector<f64>;
+    type Output = DVector<f64>;
+
+    fn apply(&self, param: &Self::Param) -> Result<Self::Output, Error> {
+        let p = param.as_slice();
+        let mut ret = DVector::zeros(*self.offsets.last().unwrap());
+        for view in 0..self.image_points.len() {
+            self.view_residuals(&p[..self.intrinsics], self.pose(p, view), view, &mut ret.as_mut_slice()[self.residuals(view)]);
+        }
+        Ok(ret)
+    }
+}
+
+impl<'a, P> Jacobian for Reprojection<'a, P>
+where P: Fn(&[f64], &[f64], (f64, f64)) -> Option<(f64, f64)> {
+    type Param = DVector<f64>;
+    type Jacobian = DMatrix<f64>;
+
+    // Central differences. Every pose only affects the residuals of its own view, so only these are recomputed
+    fn jacobian(&self, param: &Self::Param) -> Result<Self::Jacobian, Error> {
+        let views = self.image_points.len();
+        let mut ret = DMatrix::zeros(*self.offsets.last().unwrap(), param.len());
+        let mut p = param.as_slice().to_vec();
+
+        let differentiate = |p: &mut Vec<f64>, column: usize, view: usize, ret: &mut DMatrix<f64>| {
+            let range = self.residuals(view);
+            let (mut plus, mut minus) = (vec![0.0; range.len()], vec![0.0; range.len()]);
+            let org = p[column];
+            let h = self.step * org.abs().max(1.0);
+            p[column] = org + h;
+            self.view_residuals(&p[..self.intrinsics], self.pose(p, view), view, &mut plus);
+            p[column] = org - h;
+            self.view_residuals(&p[..self.intrinsics], self.pose(p, view), view, &mut minus);
+            p[column] = org;
+            for (i, row) in range.enumerate() {
+                ret[(row, column)] = (plus[i] - minus[i]) / (2.0 * h);
+            }
+        };
+        for view in 0..views {
+            for column in 0..self.intrinsics {
+                differentiate(&mut p, column, view, &mut ret);
+            }
+            for column in 0..POSE {
+                differentiate(&mut p, self.intrinsics + view * POSE + column, view, &mut ret);
+            }
+        }
+        Ok(ret)
+    }
+}
+
+/// Refines `param` and returns the best parameters with their RMS reprojection error in pixels
+pub fn optimize<P>(problem: Reprojection<P>, param: Vec<f64>, max_iters: u64) -> Result<(Vec<f64>, f64), GyroflowCoreError>
+where P: Fn(&[f64], &[f64], (f64, f64)) -> Option<(f64, f64)> {
+    let num_points = problem.num_points().max(1);
+    let result = Executor::new(problem, LevenbergMarquardt::new())
+        .configure(|state| state.param(DVector::from_vec(param)).max_iters(max_iters))
+        .run()
+        .map_err(|e| GyroflowCoreError::CalibrationError(e.to_string()))?;
+
+    let state = result.state();
+    let best = state.get_best_param().cloned()
+        .ok_or_else(|| GyroflowCoreError::CalibrationError("Calibration didn't converge".into()))?;
+    // The cost is the sum of squared residuals
+    let rms = (state.get_best_cost() / num_points as f64).sqrt();
+    if !rms.is_finite() {
+        return Err(GyroflowCoreError::CalibrationError("Calibration didn't converge".into()));
+    }
+    Ok((best.as_slice().to_vec(), rms))
+}
@@ -0,0 +1,272 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Lens calibration without a calibration target, from the tracked features of a normal video and the synchronized gyro.
+// Between two frames the camera rotates by the gyro rotation, so every feature in the first frame predicts where it should be in the next one:
+// its ray is unprojected with the lens, rotated and projected again. The lens is the one for which the predicted and the tracked points agree the best.
+//   1. The initial focal length is the best one from a range of fields of view without distortion, compared by the median error
+//   2. The focal length and k1, k2 of the OpenCV fisheye model are refined with Levenberg-Marquardt
+//   3. Features with errors much bigger than the median (wrong matches, moving objects, parallax) are rejected and the lens is refined again
+// The principal point stays in the center of the image, rotations alone constrain it poorly.
+// Translation of the camera causes parallax which the rotation can't explain, so the video should mostly rotate, ideally around all axes.
+// The uncertainty is the standard deviation of the parameters from the covariance of the least squares, σ² (JᵀJ)⁻¹. The errors of the features
+// are not independent, so it's rather a lower bound, which tells apart the estimates which are not constrained by the motion at all.
+
+use argmin::core::{ Error, Executor, Jacobian, Operator, State };
+use nalgebra::{ DMatrix, DVector, Matrix3, Rotation3, Vector3 };
+use super::fisheye;
+use super::levenberg_marquardt::LevenbergMarquardt;
+use super::reprojection::BEHIND_CAMERA_ERROR;
+use crate::GyroflowCoreError;
+
+const PARAMS: usize = 3; // f, k1, k2
+const MIN_MOTIONS: usize = 100;
+const MAX_MOTIONS: usize = 20000;
+/// Smallest rotation between the frames in radians, without rotation the motion of the features doesn't depend on the lens
+const MIN_ROTATION: f64 = 0.002;
+/// Horizontal fields of view of the initial guess, in degrees
+const FOV_RANGE: (f64, f64) = (30.0, 170.0);
+const FOV_STEPS: usize = 57;
+/// Features with a bigger error than this multiple of the median are outliers, but the threshold is at least `MIN_OUTLIER_THRESHOLD` pixels
+const OUTLIER_THRESHOLD: f64 = 3.0;
+const MIN_OUTLIER_THRESHOLD: f64 = 1.0;
+const MAX_ITERATIONS: u64 = 50;
+const DERIVATIVE_STEP: f64 = 1e-6;
+
+#[derive(Clone, Debug)]
+pub struct FeatureMotion {
+    /// Feature in the first frame, in pixels
+    pub from: (f64, f64),
+    /// The same feature tracked in the second frame
+    pub to: (f64, f64),
+    /// Rotation of the camera rays from the first frame to the second one, from the gyro
+    pub rotation: Rotation3<f64>,
+}
+
+#[derive(Clone, Debug)]
+pub struct SelfCalibration {
+    /// RMS error of the inliers in pixels
+    pub rms: f64,
+    pub k: Matrix3<f64>,
+    /// OpenCV fisheye coefficients, k3 and k4 are always 0
+    pub d: Vec<f64>,
+    /// Standard deviation of the focal length in pixels
+    pub focal_length_std: f64,
+    /// Standard deviations of k1 and k2
+    pub distortion_coeffs_std: Vec<f64>,
+    /// Features used in the final fit
+    pub inliers: usize,
+    /// Features with enough rotation
+    pub motions: usize,
+}
+
+/// `size` is the size of the frames in which the features were tracked
+pub fn calibrate(size: (usize, usize), motions: &[FeatureMotion]) -> Result<SelfCalibration, GyroflowCoreError> {
+    let err = |msg: &str| GyroflowCoreError::CalibrationError(msg.into());
+    let center = (size.0 as f64 / 2.0, size.1 as f64 / 2.0);
+
+    let mut motions: Vec<&FeatureMotion> = motions.iter().filter(|x| x.rotation.angle() >= MIN_ROTATION).collect();
+    if motions.len() < MIN_MOTIONS {
+        return Err(err("Not enough camera rotation in the analyzed parts of the video"));
+    }
+    if motions.len() > MAX_MOTIONS {
+        let step = motions.len() as f64 / MAX_MOTIONS as f64;
+        motions = (0..MAX_MOTIONS).map(|i| motions[(i as f64 * step) as usize]).collect();
+    }
+
+    // 1. Initial focal length. Without distortion the fisheye model is equidistant
+    let (_, focal) = (0..FOV_STEPS).map(|i| {
+        let fov = FOV_RANGE.0 + (FOV_RANGE.1 - FOV_RANGE.0) * i as f64 / (FOV_STEPS - 1) as f64;
+        let f = center.0 / (fov / 2.0).to_radians();
+        (median(feature_errors(&motions, center, &[f, 0.0, 0.0])), f)
+    }).fold((f64::MAX, 0.0), |a, b| if b.0 < a.0 { b } else { a });
+
+    // 2. Refinement
+    let param = Motions { motions: &motions, center }.optimize(vec![focal, 0.0, 0.0])?;
+
+    // 3. Outlier rejection
+    let errors = feature_errors(&motions, center, &param);
+    let threshold = (median(errors.clone()) * OUTLIER_THRESHOLD).max(MIN_OUTLIER_THRESHOLD);
+    let inliers: Vec<&FeatureMotion> = motions.iter().zip(errors.iter()).filter(|(_, e)| **e <= threshold).map(|(x, _)| *x).collect();
+    if inliers.len() < MIN_MOTIONS {
+        return Err(err("The motion of the features doesn't match the gyro, make sure the video is synchronized"));
+    }
+    let problem = Motions { motions: &inliers, center };
+    let param = problem.optimize(param)?;
+    if param[0].is_nan() || param[0] <= 0.0 {
+        return Err(err("Calibration didn't converge"));
+    }
+
+    // Uncertainty
+    let p = DVector::from_vec(param.clone());
+    let residuals = problem.residuals(&param);
+    let jacobian = (&problem).jacobian(&p).map_err(|e| err(&e.to_string()))?;
+    let variance = residuals.norm_squared() / (residuals.len() - PARAMS).max(1) as f64;
+    let covariance = jacobian.tr_mul(&jacobian).try_inverse()
+        .ok_or_else(|| err("The lens can't be determined from this motion, the camera has to rotate around more axes"))? * variance;
+    let std: Vec<f64> = (0..PARAMS).map(|i| covariance[(i, i)].max(0.0).sqrt()).collect();
+
+    Ok(SelfCalibration {
+        rms: (residuals.norm_squared() / inliers.len() as f64).sqrt(),
+        k: Matrix3::new(param[0], 0.0, center.0,
+                        0.0, param[0], center.1,
+                        0.0, 0.0, 1.0),
+        d: vec![param[1], param[2], 0.0, 0.0],
+        focal_length_std: std[0],
+        distortion_coeffs_std: std[1..].to_vec(),
+        inliers: inliers.len(),
+        motions: motions.len(),
+    })
+}
+
+/// Ray of a pixel, the inverse of `fisheye::distort` with k3 = k4 = 0.
+/// `None` if the pixel is outside of the monotonic part of the distortion or at 90° and more from the axis
+fn unproject(intrinsics: &[f64], p: (f64, f64)) -> Option<Vector3<f64>> {
+    let (x, y) = ((p.0 - intrinsics[2]) / intrinsics[0], (p.1 - intrinsics[3]) / intrinsics[1]);
+    let theta_d = (x * x + y * y).sqrt();
+    if theta_d < 1e-8 { return Some(Vector3::new(x, y, 1.0)); }
+
+    let (k1, k2) = (intrinsics[4], intrinsics[5]);
+    let mut theta = theta_d;
+    for _ in 0..10 {
+        let theta2 = theta * theta;
+        let derivative = 1.0 + theta2 * (3.0 * k1 + 5.0 * k2 * theta2);
+        if derivative <= 0.0 { return None; }
+        theta -= (theta * (1.0 + theta2 * (k1 + theta2 * k2)) - theta_d) / derivative;
+    }
+    if !(0.0..std::f64::consts::FRAC_PI_2).contains(&theta) { return None; }
+
+    let scale = theta.tan() / theta_d;
+    Some(Vector3::new(x * scale, y * scale, 1.0))
+}
+
+// Difference between the predicted and the tracked point
+fn residual(motion: &FeatureMotion, center: (f64, f64), param: &[f64]) -> (f64, f64) {
+    let intrinsics = [param[0], param[0], center.0, center.1, param[1], param[2], 0.0, 0.0];
+    unproject(&intrinsics, motion.from)
+        .map(|ray| motion.rotation * ray)
+        .filter(|ray| ray.z > 1e-9)
+        .map(|ray| fisheye::distort(&intrinsics, &ray))
+        .filter(|(u, v)| u.is_finite() && v.is_finite())
+        .map(|(u, v)| (u - motion.to.0, v - motion.to.1))
+        .unwrap_or((BEHIND_CAMERA_ERROR, BEHIND_CAMERA_ERROR))
+}
+
+fn feature_errors(motions: &[&FeatureMotion], center: (f64, f64), param: &[f64]) -> Vec<f64> {
+    motions.iter().map(|x| {
+        let (u, v) = residual(x, center, param);
+        (u * u + v * v).sqrt()
+    }).collect()
+}
+
+fn median(mut v: Vec<f64>) -> f64 {
+    if v.is_empty() { return f64::MAX; }
+    let mid = v.len() / 2;
+    *v.select_nth_unstable_by(mid, |a, b| a.total_cmp(b)).1
+}
+
+// Parameters: focal length, k1, k2. Two residuals for every feature
+struct Motions<'a> {
+    motions: &'a [&'a FeatureMotion],
+    center: (f64, f64),
+}
+
+impl<'a> Motions<'a> {
+    fn residuals(&self, param: &[f64]) -> DVector<f64> {
+        let mut ret = DVector::zeros(self.motions.len() * 2);
+        for (i, motion) in self.motions.iter().enumerate() {
+            let (u, v) = residual(motion, self.center, param);
+            ret[i * 2]     = u;
+            ret[i * 2 + 1] = v;
+        }
+        ret
+    }
+
+    fn optimize(&self, param: Vec<f64>) -> Result<Vec<f64>, GyroflowCoreError> {
+        let result = Executor::new(self, LevenbergMarquardt::new())
+            .configure(|state| state.param(DVector::from_vec(param)).max_iters(MAX_ITERATIONS))
+            .run()
+            .map_err(|e| GyroflowCoreError::CalibrationError(e.to_string()))?;
+
+        let state = result.state();
+        match state.get_best_param() {
+            Some(best) if state.get_best_cost().is_finite() => Ok(best.as_slice().to_vec()),
+            _ => Err(GyroflowCoreError::CalibrationError("Calibration didn't converge".into()))
+        }
+    }
+}
+
+impl<'a> Operator for &Motions<'a> {
+    type Param = DVector<f64>;
+    type Output = DVector<f64>;
+
+    fn apply(&self, param: &Self::Param) -> Result<Self::Output, Error> {
+        Ok(self.residuals(param.as_slice()))
+    }
+}
+
+impl<'a> Jacobian for &Motions<'a> {
+    type Param = DVector<f64>;
+    type Jacobian = DMatrix<f64>;
+
+    // Central differences
+    fn jacobian(&self, param: &Self::Param) -> Result<Self::Jacobian, Error> {
+        let mut ret = DMatrix::zeros(self.motions.len() * 2, param.len());
+        let mut p = param.as_slice().to_vec();
+        for column in 0..param.len() {
+            let org = p[column];
+            let h = DERIVATIVE_STEP * org.abs().max(1.0);
+            p[column] = org + h;
+            let plus = self.residuals(&p);
+            p[column] = org - h;
+            let minus = self.residuals(&p);
+            p[column] = org;
+            ret.set_column(column, &((plus - minus) / (2.0 * h)));
+        }
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Features tracked in a video of a rotating camera, with noise and some wrong matches
+    #[test]
+    fn recovers_lens_from_rotation() {
+        let (size, f, d) = ((1920, 1080), 900.0, [0.05, -0.02]);
+        let intrinsics = [f, f, 960.0, 540.0, d[0], d[1], 0.0, 0.0];
+
+        let mut seed = 12345u64;
+        let mut random = move || {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (seed >> 11) as f64 / (1u64 << 53) as f64
+        };
+
+        let mut motions = Vec::new();
+        for frame in 0..100 {
+            let t = frame as f64 * 0.1;
+            let rotation = Rotation3::new(Vector3::new(0.02 * (1.3 * t).sin(), 0.03 * (0.7 * t).cos(), 0.01 * (2.1 * t).sin()));
+            for i in 0..50 {
+                let from = (random() * 1920.0, random() * 1080.0);
+                let Some(ray) = unproject(&intrinsics, from) else { continue; };
+                let ray = rotation * ray;
+                if ray.z <= 0.0 { continue; }
+                let mut to = fisheye::distort(&intrinsics, &ray);
+                if i % 10 == 0 {
+                    to = (random() * 1920.0, random() * 1080.0);
+                } else {
+                    to = (to.0 + (random() - 0.5) * 0.6, to.1 + (random() - 0.5) * 0.6);
+                }
+                motions.push(FeatureMotion { from, to, rotation });
+            }
+        }
+
+        let result = calibrate(size, &motions).unwrap();
+        assert!((result.k[(0, 0)] - f).abs() < 5.0, "f: {}", result.k[(0, 0)]);
+        assert!((result.d[0] - d[0]).abs() < 0.01 && (result.d[1] - d[1]).abs() < 0.01, "d: {:?}", result.d);
+        assert!(result.focal_length_std > 0.0 && result.focal_length_std < 5.0, "std: {}", result.focal_length_std);
+        assert!(result.inliers * 10 >= result.motions * 8, "inliers: {} of {}", result.inliers, result.motions);
+        assert!(result.rms < 0.5, "rms: {}", result.rms);
+    }
+}
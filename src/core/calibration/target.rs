@@ -0,0 +1,91 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Calibration targets. `columns` and `rows` of the calibrator are the inner corners for the chessboard-based targets and the circles for the circle grid.
+//   - Chessboard: the whole board has to be visible. Uses `findChessboardCornersSB` with the `use-opencv` feature
+//   - Partial chessboard: the corners are detected the same way as on the chessboard, but any part of the board is accepted, as long as it has
+//     enough corners. The visible part gets its own board coordinates, which is fine for the calibration of a single camera because every view
+//     has its own board pose anyway. ChArUco boards can be used too, but their markers aren't decoded, so views of different parts of the board aren't tied together.
+//   - Asymmetric circle grid: same layout as OpenCV's `CALIB_CB_ASYMMETRIC_GRID`
+
+use serde::{ Serialize, Deserialize };
+use super::{ chessboard, circles };
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CalibrationTarget {
+    #[default]
+    Chessboard,
+    PartialChessboard,
+    AsymmetricCircles,
+}
+
+#[derive(Clone, Default, Debug)]
+pub struct TargetDetection {
+    pub points: Vec<(f32, f32)>,
+    /// Position of every point on the board, in the same order as `points`
+    pub object_points: Vec<(f64, f64)>,
+    pub sharpness: f64,
+}
+
+impl CalibrationTarget {
+    pub fn from_name(name: &str) -> Self {
+        match name {
+            "partial_chessboard" | "charuco" => Self::PartialChessboard,
+            "asymmetric_circles" => Self::AsymmetricCircles,
+            _ => Self::Chessboard
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Chessboard => "chessboard",
+            Self::PartialChessboard => "partial_chessboard",
+            Self::AsymmetricCircles => "asymmetric_circles",
+        }
+    }
+
+    /// Object points of the whole target, row by row
+    pub fn object_points(&self, columns: usize, rows: usize) -> Vec<(f64, f64)> {
+        (0..rows).flat_map(|y| (0..columns).map(move |x| match self {
+            Self::Chessboard | Self::PartialChessboard => (x as f64, y as f64),
+            Self::AsymmetricCircles => ((2 * x + y % 2) as f64, y as f64),
+        })).collect()
+    }
+
+    /// Minimum number of points in a single view
+    pub fn min_points(&self, columns: usize, rows: usize) -> usize {
+        match self {
+            Self::PartialChessboard => (columns * rows / 5).max(12),
+            _ => columns * rows
+        }
+    }
+
+    /// Pure-Rust detection of the target
+    pub fn detect(&self, img: &chessboard::GrayImage, columns: usize, rows: usize) -> Option<TargetDetection> {
+        match self {
+            Self::Chessboard => {
+                let points = chessboard::find_chessboard_corners(img, columns, rows)?;
+                let sharpness = chessboard::estimate_sharpness(img, columns, rows, &points);
+                Some(TargetDetection { points, object_points: self.object_points(columns, rows), sharpness })
+            },
+            Self::PartialChessboard => {
+                let cells = chessboard::find_partial_chessboard(img, columns, rows, self.min_points(columns, rows))?;
+                let sharpness = chessboard::estimate_grid_sharpness(img, &cells);
+                Some(TargetDetection {
+                    points: cells.iter().map(|x| x.0).collect(),
+                    object_points: cells.iter().map(|(_, (i, j))| (*i as f64, *j as f64)).collect(),
+                    sharpness
+                })
+            },
+            Self::AsymmetricCircles => {
+                let circles = circles::find_circles_grid(img, columns, rows)?;
+                let sharpness = circles::estimate_sharpness(img, &circles);
+                Some(TargetDetection {
+                    points: circles.iter().map(|x| x.0).collect(),
+                    object_points: circles.iter().map(|x| x.1).collect(),
+                    sharpness
+                })
+            }
+        }
+    }
+}
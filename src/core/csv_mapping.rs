@@ -0,0 +1,300 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Import of IMU data from arbitrary CSV files, using a user-supplied column mapping. Example:
+// {
+//     "delimiter": ",",                                        // optional, detected from the header line if missing
+//     "skip_rows": 0,                                          // lines to skip before the header
+//     "has_header": true,
+//     "timestamp": { "column": "time", "unit": "s" },          // s, ms, us or ns
+//     "gyro":  { "columns": ["gx", "gy", "gz"], "unit": "rad" }, // deg or rad (per second)
+//     "accel": { "columns": [4, 5, 6], "unit": "g" },          // optional, g or m/s2
+//     "magn":  { "columns": ["mx", "my", "mz"] },              // optional
+//     "imu_orientation": "XYZ"                                 // optional, same as in the UI
+// }
+// Columns can be given by name (requires a header) or by zero-based index. `scale` multiplies the values after unit conversion.
+
+use crate::gyro_source::{ FileMetadata, TimeIMU };
+use crate::GyroflowCoreError;
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(untagged)]
+pub enum CsvColumn {
+    Index(usize),
+    Name(String),
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct CsvTimestamp {
+    pub column: CsvColumn,
+    #[serde(default = "default_timestamp_unit")]
+    pub unit: String,
+    #[serde(default = "default_scale")]
+    pub scale: f64,
+    /// Added after conversion to milliseconds
+    #[serde(default)]
+    pub offset_ms: f64,
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct CsvVector {
+    pub columns: [CsvColumn; 3],
+    #[serde(default)]
+    pub unit: Option<String>,
+    #[serde(default = "default_scale")]
+    pub scale: f64,
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct CsvMapping {
+    #[serde(default)]
+    pub delimiter: Option<String>,
+    #[serde(default)]
+    pub skip_rows: usize,
+    #[serde(default = "default_true")]
+    pub has_header: bool,
+    pub timestamp: CsvTimestamp,
+    pub gyro: CsvVector,
+    #[serde(default)]
+    pub accel: Option<CsvVector>,
+    #[serde(default)]
+    pub magn: Option<CsvVector>,
+    #[serde(default)]
+    pub imu_orientation: Option<String>,
+}
+
+fn default_timestamp_unit() -> String { "ms".into() }
+fn default_scale() -> f64 { 1.0 }
+fn default_true() -> bool { true }
+
+impl CsvMapping {
+    pub fn from_json(json: &str) -> Result<Self, GyroflowCoreError> {
+        let mapping: Self = serde_json::from_str(json)?;
+        mapping.validate()?;
+        Ok(mapping)
+    }
+
+    fn validate(&self) -> Result<(), GyroflowCoreError> {
+        let err = |msg: String| Err(GyroflowCoreError::CsvMappingError(msg));
+        if timestamp_to_ms(&self.timestamp.unit).is_none() {
+            return err(format!("Unknown timestamp unit: {}", self.timestamp.unit));
+        }
+        if gyro_to_deg(self.gyro.unit.as_deref()).is_none() {
+            return err(format!("Unknown gyro unit: {:?}", self.gyro.unit));
+        }
+        if let Some(accel) = &self.accel {
+            if accel_to_ms2(accel.unit.as_deref()).is_none() {
+                return err(format!("Unknown accelerometer unit: {:?}", accel.unit));
+            }
+        }
+        if let Some(io) = &self.imu_orientation {
+            if io.len() != 3 || !io.chars().all(|c| "XYZxyz".contains(c)) {
+                return err(format!("Invalid IMU orientation: {io}"));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn parse(&self, text: &str) -> Result<FileMetadata, GyroflowCoreError> {
+        self.validate()?;
+        let mut lines = text.lines().skip(self.skip_rows).filter(|x| !x.trim().is_empty()).peekable();
+
+        let delimiter = match &self.delimiter {
+            Some(d) if d == "\\t" => '\t',
+            Some(d) => d.chars().next().unwrap_or(','),
+            None => detect_delimiter(lines.peek().copied().unwrap_or_default())
+        };
+
+        let header: Vec<String> = if self.has_header {
+            lines.next().map(|x| split_line(x, delimiter)).unwrap_or_default()
+        } else {
+            Vec::new()
+        };
+        let column_index = |col: &CsvColumn| -> Result<usize, GyroflowCoreError> {
+            match col {
+                CsvColumn::Index(i) => Ok(*i),
+                CsvColumn::Name(name) => header.iter().position(|x| x.eq_ignore_ascii_case(name))
+                    .ok_or_else(|| GyroflowCoreError::CsvMappingError(format!("Column {name} not found in the header")))
+            }
+        };
+        let vector_indices = |v: &CsvVector| -> Result<[usize; 3], GyroflowCoreError> {
+            Ok([column_index(&v.columns[0])?, column_index(&v.columns[1])?, column_index(&v.columns[2])?])
+        };
+
+        let ts_col = column_index(&self.timestamp.column)?;
+        let ts_scale = timestamp_to_ms(&self.timestamp.unit).unwrap_or(1.0) * self.timestamp.scale;
+        let gyro_cols = vector_indices(&self.gyro)?;
+        let gyro_scale = gyro_to_deg(self.gyro.unit.as_deref()).unwrap_or(1.0) * self.gyro.scale;
+        let accel = match &self.accel {
+            Some(a) => Some((vector_indices(a)?, accel_to_ms2(a.unit.as_deref()).unwrap_or(1.0) * a.scale)),
+            None => None
+        };
+        let magn = match &self.magn {
+            Some(m) => Some((vector_indices(m)?, m.scale)),
+            None => None
+        };
+
+        let mut raw_imu = Vec::new();
+        let mut skipped = 0;
+        for line in lines {
+            let values: Vec<Option<f64>> = split_line(line, delimiter).iter().map(|x| x.parse::<f64>().ok()).collect();
+            let get = |i: usize| values.get(i).copied().flatten();
+            let get_vec = |cols: &[usize; 3], scale: f64| -> Option<[f64; 3]> {
+                Some([get(cols[0])? * scale, get(cols[1])? * scale, get(cols[2])? * scale])
+            };
+
+            match (get(ts_col), get_vec(&gyro_cols, gyro_scale)) {
+                (Some(ts), Some(gyro)) => {
+                    raw_imu.push(TimeIMU {
+                        timestamp_ms: ts * ts_scale + self.timestamp.offset_ms,
+                        gyro: Some(gyro),
+                        accl: accel.as_ref().and_then(|(cols, scale)| get_vec(cols, *scale)),
+                        magn: magn.as_ref().and_then(|(cols, scale)| get_vec(cols, *scale)),
+                    });
+                },
+                _ => skipped += 1
+            }
+        }
+        if skipped > 0 {
+            log::warn!("Skipped {skipped} CSV lines without a valid timestamp or gyro values");
+        }
+        if raw_imu.is_empty() {
+            return Err(GyroflowCoreError::CsvMappingError("No IMU samples found".into()));
+        }
+        raw_imu.sort_by(|a, b| a.timestamp_ms.total_cmp(&b.timestamp_ms));
+
+        Ok(FileMetadata {
+            imu_orientation: self.imu_orientation.clone(),
+            detected_source: Some("CSV".into()),
+            raw_imu,
+            ..Default::default()
+        })
+    }
+}
+
+fn timestamp_to_ms(unit: &str) -> Option<f64> {
+    match unit {
+        "s" => Some(1000.0),
+        "ms" => Some(1.0),
+        "us" | "µs" => Some(0.001),
+        "ns" => Some(0.000001),
+        _ => None
+    }
+}
+fn gyro_to_deg(unit: Option<&str>) -> Option<f64> {
+    match unit.unwrap_or("deg") {
+        "deg" | "deg/s" | "dps" => Some(1.0),
+        "rad" | "rad/s" => Some(180.0 / std::f64::consts::PI),
+        _ => None
+    }
+}
+fn accel_to_ms2(unit: Option<&str>) -> Option<f64> {
+    match unit.unwrap_or("m/s2") {
+        "m/s2" | "m/s^2" | "m/s²" => Some(1.0),
+        "g" => Some(9.80665),
+        _ => None
+    }
+}
+
+fn detect_delimiter(line: &str) -> char {
+    [' ', '\t', ';', ','].into_iter().filter(|d| line.contains(*d)).max_by_key(|d| line.matches(*d).count()).unwrap_or(',')
+}
+
+fn split_line(line: &str, delimiter: char) -> Vec<String> {
+    line.split(delimiter).map(|x| x.trim().trim_matches('"').to_owned()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_vec(a: Option<[f64; 3]>, b: [f64; 3]) {
+        let a = a.unwrap();
+        assert!(a.iter().zip(b.iter()).all(|(x, y)| (x - y).abs() < 1e-9), "{a:?} != {b:?}");
+    }
+
+    #[test]
+    fn header_names() {
+        // Names are matched case-insensitively, in any order, with quoted headers and a detected delimiter
+        let mapping = CsvMapping::from_json(r#"{
+            "timestamp": { "column": "Time" },
+            "gyro": { "columns": ["GX", "gy", "gz"] },
+            "accel": { "columns": ["ax", "ay", "az"] }
+        }"#).unwrap();
+        let data = mapping.parse("\"az\";\"ay\";\"ax\";\"gz\";\"gy\";\"gx\";\"time\"\n3;2;1;30;20;10;5\n6;5;4;60;50;40;15\n").unwrap();
+
+        assert_eq!(data.detected_source.as_deref(), Some("CSV"));
+        assert_eq!(data.raw_imu.len(), 2);
+        assert_eq!(data.raw_imu[0].timestamp_ms, 5.0);
+        assert_vec(data.raw_imu[0].gyro, [10.0, 20.0, 30.0]);
+        assert_vec(data.raw_imu[0].accl, [1.0, 2.0, 3.0]);
+        assert_eq!(data.raw_imu[1].timestamp_ms, 15.0);
+        assert!(data.raw_imu[0].magn.is_none());
+    }
+
+    #[test]
+    fn indices_without_header() {
+        let mapping = CsvMapping::from_json(r#"{
+            "delimiter": "\\t",
+            "skip_rows": 1,
+            "has_header": false,
+            "timestamp": { "column": 0 },
+            "gyro": { "columns": [1, 2, 3] },
+            "magn": { "columns": [4, 5, 6], "scale": 2.0 },
+            "imu_orientation": "yXz"
+        }"#).unwrap();
+        // Out of order and invalid lines are sorted and skipped
+        let data = mapping.parse("some description\n20\t1\t2\t3\t4\t5\t6\n10\t1\t2\t3\t4\t5\t6\nnot\tnumbers\n\n").unwrap();
+        assert_eq!(data.imu_orientation.as_deref(), Some("yXz"));
+        assert_eq!(data.raw_imu.iter().map(|x| x.timestamp_ms).collect::<Vec<_>>(), vec![10.0, 20.0]);
+        assert_vec(data.raw_imu[0].magn, [8.0, 10.0, 12.0]);
+    }
+
+    #[test]
+    fn unit_conversion() {
+        let mapping = CsvMapping::from_json(r#"{
+            "timestamp": { "column": "t", "unit": "us", "offset_ms": 100.0 },
+            "gyro": { "columns": ["x", "y", "z"], "unit": "rad", "scale": 0.5 },
+            "accel": { "columns": ["ax", "ay", "az"], "unit": "g" }
+        }"#).unwrap();
+        let pi = std::f64::consts::PI;
+        let data = mapping.parse(&format!("t,x,y,z,ax,ay,az\n1500,{pi},{},0,1,-0.5,0\n", -pi / 2.0)).unwrap();
+        assert!((data.raw_imu[0].timestamp_ms - 101.5).abs() < 1e-9);
+        assert_vec(data.raw_imu[0].gyro, [90.0, -45.0, 0.0]);
+        assert_vec(data.raw_imu[0].accl, [9.80665, -4.903325, 0.0]);
+
+        for (unit, scale) in [("s", 1000.0), ("ms", 1.0), ("ns", 0.000001)] {
+            let mapping = CsvMapping::from_json(&format!(r#"{{ "timestamp": {{ "column": 0, "unit": "{unit}", "scale": 2.0 }}, "gyro": {{ "columns": [1, 2, 3] }}, "has_header": false }}"#)).unwrap();
+            let data = mapping.parse("3,0,0,0").unwrap();
+            assert!((data.raw_imu[0].timestamp_ms - 6.0 * scale).abs() < 1e-12, "{unit}");
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let is_mapping_error = |r: Result<FileMetadata, GyroflowCoreError>, msg: &str| {
+            match r {
+                Err(GyroflowCoreError::CsvMappingError(e)) => assert!(e.contains(msg), "{e}"),
+                other => panic!("Expected a mapping error, got {:?}", other.map(|x| x.raw_imu.len()))
+            }
+        };
+        let mapping = CsvMapping::from_json(r#"{ "timestamp": { "column": "time" }, "gyro": { "columns": ["gx", "gy", "gz"] } }"#).unwrap();
+        is_mapping_error(mapping.parse("time,gx,gy\n1,2,3\n"), "Column gz not found");
+        is_mapping_error(mapping.parse("time,gx,gy,gz\nfoo,bar,baz,qux\n"), "No IMU samples");
+        is_mapping_error(mapping.parse(""), "Column time not found");
+
+        for (json, msg) in [
+            (r#"{ "timestamp": { "column": 0, "unit": "min" }, "gyro": { "columns": [1, 2, 3] } }"#, "Unknown timestamp unit"),
+            (r#"{ "timestamp": { "column": 0 }, "gyro": { "columns": [1, 2, 3], "unit": "rpm" } }"#, "Unknown gyro unit"),
+            (r#"{ "timestamp": { "column": 0 }, "gyro": { "columns": [1, 2, 3] }, "accel": { "columns": [4, 5, 6], "unit": "ft/s2" } }"#, "Unknown accelerometer unit"),
+            (r#"{ "timestamp": { "column": 0 }, "gyro": { "columns": [1, 2, 3] }, "imu_orientation": "XYW" }"#, "Invalid IMU orientation"),
+        ] {
+            match CsvMapping::from_json(json) {
+                Err(GyroflowCoreError::CsvMappingError(e)) => assert!(e.contains(msg), "{e}"),
+                other => panic!("Expected a mapping error for {json}, got {other:?}")
+            }
+        }
+        // Only 2 gyro columns
+        assert!(matches!(CsvMapping::from_json(r#"{ "timestamp": { "column": 0 }, "gyro": { "columns": [1, 2] } }"#), Err(GyroflowCoreError::JSONError(_))));
+    }
+}
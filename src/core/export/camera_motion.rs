@@ -0,0 +1,297 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Per-frame camera motion of the stabilized plate, for match-moving in compositing and 3D applications.
+// Rotation is the smoothed camera orientation (the virtual camera of the stabilized output), FOV and focal length
+// are in output pixels and take the dynamic zoom into account.
+
+use std::fmt::Write;
+use std::str::FromStr;
+use rayon::iter::{ ParallelIterator, IntoParallelIterator };
+
+use crate::{ StabilizationManager, GyroflowCoreError };
+use crate::gyro_source::Quat64;
+use crate::keyframes::KeyframeType;
+use crate::stabilization::{ ComputeParams, FrameTransform };
+
+const RAD2DEG: f64 = 180.0 / std::f64::consts::PI;
+const DEG2RAD: f64 = std::f64::consts::PI / 180.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum CameraMotionFormat {
+    NukeChan,
+    BlenderJson,
+    Csv,
+    AfterEffects
+}
+impl CameraMotionFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::NukeChan     => "chan",
+            Self::BlenderJson  => "json",
+            Self::Csv          => "csv",
+            Self::AfterEffects => "txt",
+        }
+    }
+}
+impl FromStr for CameraMotionFormat {
+    type Err = serde_json::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> { serde_json::from_str(&format!("\"{}\"", s)) }
+}
+
+#[derive(Default, Clone, Debug, serde::Serialize)]
+pub struct CameraMotionFrame {
+    pub frame: usize,
+    pub timestamp_ms: f64,
+    pub quaternion: [f64; 4], // w, x, y, z. Camera looks down -Z, Y is up
+    pub focal_length_px: (f64, f64), // in output pixels
+    pub horizontal_fov: f64, // degrees
+    pub vertical_fov: f64, // degrees
+    pub fov_scale: f64,
+    pub focal_length_mm: Option<f64>,
+    pub lens_center: (f64, f64), // in input pixels
+    pub lens_correction_amount: f64,
+    pub distortion_coeffs: Vec<f64>,
+}
+
+#[derive(Default, Clone, Debug, serde::Serialize)]
+pub struct CameraMotion {
+    pub fps: f64,
+    pub width: usize,
+    pub height: usize,
+    pub distortion_model: String,
+    pub digital_lens: Option<String>,
+    pub frames: Vec<CameraMotionFrame>,
+}
+
+impl CameraMotion {
+    pub fn from_manager(mgr: &StabilizationManager) -> Result<Self, GyroflowCoreError> {
+        let mut params = ComputeParams::from_manager(mgr);
+        // Only the main matrix is needed, skip computing the rolling shutter rows
+        params.frame_readout_time = 0.0;
+        // Full resolution instead of the preview size
+        params.width         = params.video_width;
+        params.height        = params.video_height;
+        params.output_width  = params.video_output_width;
+        params.output_height = params.video_output_height;
+
+        if params.gyro.read().smoothed_quaternions.is_empty() || params.scaled_fps <= 0.0 {
+            return Err(GyroflowCoreError::InvalidData);
+        }
+
+        let frames = (0..params.frame_count).into_par_iter().map(|frame| {
+            let timestamp_ms = crate::timestamp_at_frame(frame as i32, params.scaled_fps);
+            let transform = FrameTransform::at_timestamp(&params, timestamp_ms, frame);
+            let kp = &transform.kernel_params;
+
+            let additional_rotation_x = params.keyframes.value_at_video_timestamp(&KeyframeType::AdditionalRotationX, timestamp_ms).unwrap_or(params.additional_rotation.0) * DEG2RAD;
+            let additional_rotation_y = params.keyframes.value_at_video_timestamp(&KeyframeType::AdditionalRotationY, timestamp_ms).unwrap_or(params.additional_rotation.1) * DEG2RAD;
+            let additional_rotation_z = params.keyframes.value_at_video_timestamp(&KeyframeType::AdditionalRotationZ, timestamp_ms).unwrap_or(params.additional_rotation.2) * DEG2RAD;
+            let additional_rotation   = Quat64::from_euler_angles(additional_rotation_y, additional_rotation_x, additional_rotation_z);
+
+            // `smoothed_quat_at_timestamp` is the correction (smoothed⁻¹ * org), so this gives back the smoothed camera orientation
+            let quat = {
+                let gyro = params.gyro.read();
+                gyro.org_quat_at_timestamp(timestamp_ms) * (additional_rotation * gyro.smoothed_quat_at_timestamp(timestamp_ms)).inverse()
+            };
+
+            // Focal length of the output image, `kp.fov` includes the zoom and the processing -> output size ratio
+            let fov = (kp.fov as f64).max(0.0001);
+            let focal_length_px = (kp.f[0] as f64 / fov, kp.f[1] as f64 / fov);
+            let ratio = FrameTransform::get_ratio(&params);
+
+            CameraMotionFrame {
+                frame,
+                timestamp_ms,
+                quaternion: [quat.w, quat.i, quat.j, quat.k],
+                focal_length_px,
+                horizontal_fov: 2.0 * (params.output_width  as f64 / 2.0 / focal_length_px.0.max(0.0001)).atan() * RAD2DEG,
+                vertical_fov:   2.0 * (params.output_height as f64 / 2.0 / focal_length_px.1.max(0.0001)).atan() * RAD2DEG,
+                fov_scale: transform.fov,
+                focal_length_mm: transform.focal_length.or(params.lens.focal_length),
+                lens_center: (kp.c[0] as f64 / ratio, kp.c[1] as f64 / ratio),
+                lens_correction_amount: kp.lens_correction_amount as f64,
+                distortion_coeffs: kp.k.iter().map(|x| *x as f64).collect(),
+            }
+        }).collect::<Vec<_>>();
+
+        Ok(Self {
+            fps: params.scaled_fps,
+            width: params.output_width,
+            height: params.output_height,
+            distortion_model: params.distortion_model.id().to_owned(),
+            digital_lens: params.digital_lens.as_ref().map(|x| x.id().to_owned()),
+            frames
+        })
+    }
+
+    pub fn export(&self, format: CameraMotionFormat) -> Result<String, GyroflowCoreError> {
+        Ok(match format {
+            CameraMotionFormat::NukeChan     => self.to_nuke_chan(),
+            CameraMotionFormat::BlenderJson  => self.to_blender_json()?,
+            CameraMotionFormat::Csv          => self.to_csv(),
+            CameraMotionFormat::AfterEffects => self.to_after_effects(),
+        })
+    }
+
+    /// Nuke camera .chan: `frame tx ty tz rx ry rz vfov`, ZXY rotation order (Nuke's default), 1-based frames
+    pub fn to_nuke_chan(&self) -> String {
+        let mut out = String::new();
+        for f in &self.frames {
+            let (rx, ry, rz) = euler_zxy(&quat(f));
+            let _ = writeln!(out, "{}\t0.0\t0.0\t0.0\t{:.6}\t{:.6}\t{:.6}\t{:.6}", f.frame + 1, rx * RAD2DEG, ry * RAD2DEG, rz * RAD2DEG, f.vertical_fov);
+        }
+        out
+    }
+
+    /// JSON meant to be applied to a Blender camera by a script: XYZ euler in radians, focal length for a 36 mm sensor width, 1-based frames
+    pub fn to_blender_json(&self) -> Result<String, GyroflowCoreError> {
+        const SENSOR_WIDTH: f64 = 36.0;
+        let frames = self.frames.iter().map(|f| {
+            let q = quat(f);
+            let (rx, ry, rz) = q.euler_angles();
+            serde_json::json!({
+                "frame": f.frame + 1,
+                "timestamp_ms": f.timestamp_ms,
+                "rotation_euler": [rx, ry, rz],
+                "rotation_quaternion": f.quaternion,
+                "lens": f.focal_length_px.0 / self.width.max(1) as f64 * SENSOR_WIDTH,
+                "horizontal_fov": f.horizontal_fov * DEG2RAD,
+                "focal_length_mm": f.focal_length_mm,
+                "lens_correction_amount": f.lens_correction_amount,
+                "distortion_coeffs": f.distortion_coeffs,
+            })
+        }).collect::<Vec<_>>();
+
+        Ok(serde_json::to_string_pretty(&serde_json::json!({
+            "fps": self.fps,
+            "resolution": [self.width, self.height],
+            "rotation_mode": "XYZ",
+            "sensor_width": SENSOR_WIDTH,
+            "sensor_fit": "HORIZONTAL",
+            "distortion_model": self.distortion_model,
+            "digital_lens": self.digital_lens,
+            "frames": frames
+        }))?)
+    }
+
+    /// Plain CSV with everything we have, rotation as a quaternion and XYZ euler angles in degrees, 0-based frames
+    pub fn to_csv(&self) -> String {
+        let num_coeffs = self.frames.first().map(|x| x.distortion_coeffs.len()).unwrap_or_default();
+        let mut out = String::from("frame,timestamp_ms,qw,qx,qy,qz,rx,ry,rz,focal_length_px_x,focal_length_px_y,horizontal_fov,vertical_fov,fov_scale,focal_length_mm,lens_center_x,lens_center_y,lens_correction_amount");
+        for i in 0..num_coeffs { let _ = write!(out, ",k{i}"); }
+        out.push('\n');
+
+        for f in &self.frames {
+            let (rx, ry, rz) = quat(f).euler_angles();
+            let _ = write!(out, "{},{:.3},{:.8},{:.8},{:.8},{:.8},{:.6},{:.6},{:.6},{:.4},{:.4},{:.6},{:.6},{:.6},{},{:.4},{:.4},{:.4}",
+                f.frame, f.timestamp_ms,
+                f.quaternion[0], f.quaternion[1], f.quaternion[2], f.quaternion[3],
+                rx * RAD2DEG, ry * RAD2DEG, rz * RAD2DEG,
+                f.focal_length_px.0, f.focal_length_px.1,
+                f.horizontal_fov, f.vertical_fov, f.fov_scale,
+                f.focal_length_mm.map(|x| format!("{x:.4}")).unwrap_or_default(),
+                f.lens_center.0, f.lens_center.1,
+                f.lens_correction_amount
+            );
+            for k in &f.distortion_coeffs { let _ = write!(out, ",{k:.8}"); }
+            out.push('\n');
+        }
+        out
+    }
+
+    /// After Effects keyframe data, can be pasted on a camera layer. 0-based frames
+    pub fn to_after_effects(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "Adobe After Effects 8.0 Keyframe Data\n");
+        let _ = writeln!(out, "\tUnits Per Second\t{:.3}", self.fps);
+        let _ = writeln!(out, "\tSource Width\t{}", self.width);
+        let _ = writeln!(out, "\tSource Height\t{}", self.height);
+        let _ = writeln!(out, "\tSource Pixel Aspect Ratio\t1");
+        let _ = writeln!(out, "\tComp Pixel Aspect Ratio\t1\n");
+
+        // After Effects has Y pointing down and the camera looking down +Z
+        let rotations = self.frames.iter().map(|f| {
+            let q = quat(f);
+            let q = Quat64::new_normalize(nalgebra::Quaternion::new(q.w, q.i, -q.j, -q.k));
+            let (rx, ry, rz) = q.euler_angles();
+            (rx * RAD2DEG, ry * RAD2DEG, rz * RAD2DEG)
+        }).collect::<Vec<_>>();
+
+        for (i, name) in ["X Rotation", "Y Rotation", "Z Rotation"].iter().enumerate() {
+            let _ = writeln!(out, "Transform\t{name}");
+            let _ = writeln!(out, "\tFrame\tdegrees\t");
+            for (f, r) in self.frames.iter().zip(rotations.iter()) {
+                let v = match i { 0 => r.0, 1 => r.1, _ => r.2 };
+                let _ = writeln!(out, "\t{}\t{:.6}\t", f.frame, v);
+            }
+            out.push('\n');
+        }
+
+        let _ = writeln!(out, "Camera Options\tZoom");
+        let _ = writeln!(out, "\tFrame\tpixels\t");
+        for f in &self.frames {
+            let _ = writeln!(out, "\t{}\t{:.4}\t", f.frame, f.focal_length_px.0);
+        }
+        out.push('\n');
+
+        let _ = writeln!(out, "End of Keyframe Data");
+        out
+    }
+}
+
+fn quat(f: &CameraMotionFrame) -> Quat64 {
+    Quat64::new_normalize(nalgebra::Quaternion::new(f.quaternion[0], f.quaternion[1], f.quaternion[2], f.quaternion[3]))
+}
+
+// Decomposes `R = Ry * Rx * Rz` (Z applied first), returns (x, y, z) in radians
+fn euler_zxy(q: &Quat64) -> (f64, f64, f64) {
+    let m = q.to_rotation_matrix();
+    let m = m.matrix();
+    let x = (-m[(1, 2)]).clamp(-1.0, 1.0).asin();
+    if m[(1, 2)].abs() < 0.999999 {
+        (x, m[(0, 2)].atan2(m[(2, 2)]), m[(1, 0)].atan2(m[(1, 1)]))
+    } else {
+        // Gimbal lock, put everything in Y
+        (x, (-m[(2, 0)]).atan2(m[(0, 0)]), 0.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::{ Unit, Vector3 };
+
+    // Nuke's ZXY order, Z is applied first
+    fn rotation_zxy(x: f64, y: f64, z: f64) -> Quat64 {
+        let axis = |v: Vector3<f64>, angle: f64| Quat64::from_axis_angle(&Unit::new_normalize(v), angle * DEG2RAD);
+        axis(Vector3::y(), y) * axis(Vector3::x(), x) * axis(Vector3::z(), z)
+    }
+
+    #[test]
+    fn nuke_chan() {
+        let rotations = [(0.0, 0.0, 0.0), (10.0, -20.0, 30.0), (-45.0, 120.0, -170.0), (89.0, 5.0, 0.5), (90.0, 30.0, 0.0)];
+        let motion = CameraMotion {
+            fps: 25.0,
+            width: 1920,
+            height: 1080,
+            frames: rotations.iter().enumerate().map(|(i, (x, y, z))| {
+                let q = rotation_zxy(*x, *y, *z);
+                CameraMotionFrame { frame: i, quaternion: [q.w, q.i, q.j, q.k], vertical_fov: 40.0 + i as f64, ..Default::default() }
+            }).collect(),
+            ..Default::default()
+        };
+
+        let lines: Vec<Vec<f64>> = motion.to_nuke_chan().lines().map(|l| l.split('\t').map(|x| x.parse().unwrap()).collect()).collect();
+        assert_eq!(lines.len(), rotations.len());
+        for (i, ((x, y, z), line)) in rotations.iter().zip(lines.iter()).enumerate() {
+            assert_eq!(line.len(), 8);
+            assert_eq!(line[0], i as f64 + 1.0);
+            assert_eq!(&line[1..4], &[0.0, 0.0, 0.0]);
+            assert!((line[4] - x).abs() < 1e-4 && (line[5] - y).abs() < 1e-4 && (line[6] - z).abs() < 1e-4, "{:?} != {:?}", &line[4..7], (x, y, z));
+            assert!((line[7] - (40.0 + i as f64)).abs() < 1e-6);
+            // The same rotation when read back
+            assert!(rotation_zxy(line[4], line[5], line[6]).angle_to(&rotation_zxy(*x, *y, *z)) < 1e-6);
+        }
+    }
+}
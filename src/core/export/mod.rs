@@ -0,0 +1,7 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Exporters of the stabilization data to formats readable by other applications
+
+pub mod camera_motion;
+pub mod stmap;
@@ -0,0 +1,284 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// ST-maps (UV maps) of the lens model, so the distortion can be applied in Nuke/Fusion without Gyroflow.
+// Each pixel holds the normalized coordinates to sample from: R = s (x / width), G = t (1 - y / height, origin at the bottom left),
+// A = 1 if the mapping is valid, 0 otherwise.
+
+use std::io::Cursor;
+use std::str::FromStr;
+
+use crate::GyroflowCoreError;
+use crate::lens_profile::LensProfile;
+use crate::stabilization::{ KernelParams, apply_lens_correction_amount, map_output_pixel, stmap_from };
+use crate::stabilization::distortion_models::DistortionModel;
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum StmapFormat {
+    Exr,
+    Tiff
+}
+impl StmapFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Exr  => "exr",
+            Self::Tiff => "tif",
+        }
+    }
+}
+impl FromStr for StmapFormat {
+    type Err = serde_json::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> { serde_json::from_str(&format!("\"{}\"", s)) }
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct StmapParams {
+    pub input_size: Option<(usize, usize)>, // defaults to the calibration dimension of the profile
+    pub output_size: Option<(usize, usize)>, // defaults to the input size
+    pub fov: f64,
+    pub lens_correction_amount: f64,
+}
+impl Default for StmapParams {
+    fn default() -> Self {
+        Self {
+            input_size: None,
+            output_size: None,
+            fov: 1.0,
+            lens_correction_amount: 1.0,
+        }
+    }
+}
+
+pub struct Stmap {
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<f32>, // RGBA
+}
+
+impl Stmap {
+    pub fn encode(&self, format: StmapFormat) -> Result<Vec<u8>, GyroflowCoreError> {
+        if self.data.len() != self.width * self.height * 4 {
+            return Err(GyroflowCoreError::BufferTooSmall);
+        }
+        let mut buf = Vec::new();
+        match format {
+            StmapFormat::Exr => {
+                let img = image::Rgba32FImage::from_raw(self.width as u32, self.height as u32, self.data.clone()).ok_or(GyroflowCoreError::BufferTooSmall)?;
+                image::DynamicImage::ImageRgba32F(img).write_to(&mut Cursor::new(&mut buf), image::ImageFormat::OpenExr)?;
+            }
+            StmapFormat::Tiff => {
+                // The TIFF encoder of `image` doesn't support floats
+                let tiff_error = |e: tiff::TiffError| image::ImageError::Encoding(image::error::EncodingError::new(image::ImageFormat::Tiff.into(), e));
+                let mut encoder = tiff::encoder::TiffEncoder::new(Cursor::new(&mut buf)).map_err(tiff_error)?;
+                encoder.write_image::<tiff::encoder::colortype::RGBA32Float>(self.width as u32, self.height as u32, &self.data).map_err(tiff_error)?;
+            }
+        }
+        Ok(buf)
+    }
+    pub fn save(&self, url: &str, format: StmapFormat) -> Result<(), GyroflowCoreError> {
+        crate::filesystem::write(url, &self.encode(format)?)?;
+        Ok(())
+    }
+}
+
+/// Maps points between the input (distorted) image and the output (undistorted) image,
+/// following the same math as the undistortion kernel without any rotation.
+pub struct LensMapper {
+    distortion_model: DistortionModel,
+    digital_lens: Option<DistortionModel>,
+    kernel_params: KernelParams,
+    matrices: [[f32; 12]; 1],
+    input_size: (usize, usize),
+    output_size: (usize, usize),
+    out_f: (f32, f32),
+    out_c: (f32, f32),
+}
+
+impl LensMapper {
+    pub fn new(profile: &LensProfile, params: &StmapParams) -> Self {
+        let calib_size = (profile.calib_dimension.w, profile.calib_dimension.h);
+        let input_size = params.input_size.filter(|x| x.0 > 0 && x.1 > 0).unwrap_or(calib_size);
+        let input_size = (input_size.0.max(1), input_size.1.max(1));
+        let output_size = params.output_size.filter(|x| x.0 > 0 && x.1 > 0).unwrap_or(input_size);
+
+        let mut camera_matrix = profile.get_camera_matrix(input_size, input_size);
+        if calib_size.0 > 0 && calib_size.1 > 0 {
+            let horizontal_stretch = if profile.input_horizontal_stretch > 0.01 { profile.input_horizontal_stretch } else { 1.0 };
+            let vertical_stretch   = if profile.input_vertical_stretch   > 0.01 { profile.input_vertical_stretch   } else { 1.0 };
+            let ratiox = (input_size.0 as f64 / calib_size.0 as f64) * horizontal_stretch;
+            let ratioy = (input_size.1 as f64 / calib_size.1 as f64) * vertical_stretch;
+            camera_matrix[(0, 0)] *= ratiox;
+            camera_matrix[(1, 1)] *= ratioy;
+            camera_matrix[(0, 2)] *= ratiox;
+            camera_matrix[(1, 2)] *= ratioy;
+        }
+
+        let fov = params.fov.max(0.001) * profile.optimal_fov.unwrap_or(1.0);
+        let output_ratio = output_size.0 as f64 / input_size.0 as f64;
+
+        let mut digital_lens_params = [0f32; 4];
+        if let Some(p) = &profile.digital_lens_params {
+            for (i, v) in p.iter().take(4).enumerate() {
+                digital_lens_params[i] = *v as f32;
+            }
+        }
+
+        let digital_lens = profile.digital_lens.as_ref().map(|x| DistortionModel::from_name(x));
+        let out_f = ((camera_matrix[(0, 0)] * output_ratio / fov) as f32, (camera_matrix[(1, 1)] * output_ratio / fov) as f32);
+        let out_c = (output_size.0 as f32 / 2.0, output_size.1 as f32 / 2.0);
+
+        let kernel_params = KernelParams {
+            width:         input_size.0 as i32,
+            height:        input_size.1 as i32,
+            output_width:  output_size.0 as i32,
+            output_height: output_size.1 as i32,
+            f:             [camera_matrix[(0, 0)] as f32, camera_matrix[(1, 1)] as f32],
+            c:             [camera_matrix[(0, 2)] as f32, camera_matrix[(1, 2)] as f32],
+            k:             profile.get_distortion_coeffs().iter().map(|x| *x as f32).collect::<Vec<f32>>().try_into().unwrap(),
+            fov:           (fov / output_ratio) as f32, // The kernel uses `f / fov` as the output focal length
+            matrix_count:  1,
+            flags:         if digital_lens.is_some() { 2 } else { 0 }, // Has digital lens
+            r_limit:       profile.fisheye_params.radial_distortion_limit.unwrap_or_default() as f32,
+            lens_correction_amount:   params.lens_correction_amount.max(0.0).min(1.0) as f32,
+            input_horizontal_stretch: profile.input_horizontal_stretch as f32,
+            input_vertical_stretch:   profile.input_vertical_stretch as f32,
+            digital_lens_params,
+            light_refraction_coefficient: 1.0,
+            ..Default::default()
+        };
+
+        Self {
+            distortion_model: DistortionModel::from_name(profile.distortion_model.as_deref().unwrap_or("opencv_fisheye")),
+            digital_lens,
+            input_size,
+            output_size,
+            // No rotation, only the output camera matrix inverse
+            matrices: [[
+                1.0 / out_f.0, 0.0, -out_c.0 / out_f.0,
+                0.0, 1.0 / out_f.1, -out_c.1 / out_f.1,
+                0.0, 0.0, 1.0,
+                0.0, 0.0, 0.0
+            ]],
+            out_f,
+            out_c,
+            kernel_params,
+        }
+    }
+
+    pub fn input_size(&self) -> (usize, usize) { self.input_size }
+    pub fn output_size(&self) -> (usize, usize) { self.output_size }
+
+    /// Position in the input image for a pixel of the output image
+    pub fn output_to_input(&self, out_pos: (f32, f32)) -> Option<(f32, f32)> {
+        map_output_pixel(out_pos, &self.kernel_params, &self.matrices, &self.distortion_model, self.digital_lens.as_ref())
+    }
+
+    /// Position in the output image for a pixel of the input image
+    pub fn input_to_output(&self, in_pos: (f32, f32)) -> Option<(f32, f32)> {
+        let params = &self.kernel_params;
+        let mut uv = in_pos;
+        if params.input_horizontal_stretch > 0.001 { uv.0 *= params.input_horizontal_stretch; }
+        if params.input_vertical_stretch   > 0.001 { uv.1 *= params.input_vertical_stretch; }
+
+        if let Some(digital) = &self.digital_lens {
+            if let Some(pt) = digital.undistort_point(uv, params) {
+                uv = pt;
+            }
+        }
+
+        let pw = ((uv.0 - params.c[0]) / params.f[0], (uv.1 - params.c[1]) / params.f[1]);
+        let pt = self.distortion_model.undistort_point(pw, params)?;
+        let target = ((pt.0 * self.out_f.0) + self.out_c.0, (pt.1 * self.out_f.1) + self.out_c.1);
+
+        if params.lens_correction_amount >= 1.0 {
+            return Some(target);
+        }
+
+        // `apply_lens_correction_amount` doesn't have a closed form inverse, solve it iteratively
+        let mut out_pos = target;
+        for _ in 0..20 {
+            let current = apply_lens_correction_amount(out_pos, params, &self.distortion_model, self.digital_lens.as_ref());
+            let diff = (target.0 - current.0, target.1 - current.1);
+            out_pos = (out_pos.0 + diff.0, out_pos.1 + diff.1);
+            if diff.0.abs() < 0.001 && diff.1.abs() < 0.001 {
+                break;
+            }
+        }
+        Some(out_pos)
+    }
+
+    /// ST-map with the size of the output image, pointing to the input image. Removes the lens distortion.
+    pub fn undistort_map(&self) -> Stmap {
+        let (w, h) = self.output_size;
+        let norm = (self.input_size.0 as f32, self.input_size.1 as f32);
+        Stmap { width: w, height: h, data: stmap_from(w, h, norm, |pos| self.output_to_input(pos)) }
+    }
+
+    /// ST-map with the size of the input image, pointing to the output image. Adds the lens distortion back.
+    pub fn redistort_map(&self) -> Stmap {
+        let (w, h) = self.input_size;
+        let norm = (self.output_size.0 as f32, self.output_size.1 as f32);
+        Stmap { width: w, height: h, data: stmap_from(w, h, norm, |pos| self.input_to_output(pos)) }
+    }
+}
+
+/// Writes `{base}_undistort.{ext}` and `{base}_redistort.{ext}` next to each other.
+pub fn export_lens_stmaps(profile: &LensProfile, params: &StmapParams, folder_url: &str, base_name: &str, format: StmapFormat) -> Result<(String, String), GyroflowCoreError> {
+    let mapper = LensMapper::new(profile, params);
+
+    let undistort_url = crate::filesystem::get_file_url(folder_url, &format!("{base_name}_undistort.{}", format.extension()), true);
+    let redistort_url = crate::filesystem::get_file_url(folder_url, &format!("{base_name}_redistort.{}", format.extension()), true);
+
+    mapper.undistort_map().save(&undistort_url, format)?;
+    mapper.redistort_map().save(&redistort_url, format)?;
+
+    Ok((undistort_url, redistort_url))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode() {
+        let (width, height) = (7, 5);
+        let data: Vec<f32> = (0..width * height * 4).map(|i| i as f32 / 16.0 - 1.0).collect();
+        let map = Stmap { width, height, data: data.clone() };
+
+        for format in [StmapFormat::Exr, StmapFormat::Tiff] {
+            let encoded = map.encode(format).unwrap();
+            let decoded = match format {
+                StmapFormat::Exr => image::load_from_memory_with_format(&encoded, image::ImageFormat::OpenExr).unwrap().into_rgba32f().into_raw(),
+                StmapFormat::Tiff => {
+                    let mut decoder = tiff::decoder::Decoder::new(Cursor::new(&encoded)).unwrap();
+                    assert_eq!(decoder.colortype().unwrap(), tiff::ColorType::RGBA(32));
+                    match decoder.read_image().unwrap() {
+                        tiff::decoder::DecodingResult::F32(x) => x,
+                        _ => panic!("TIFF is not 32-bit float")
+                    }
+                }
+            };
+            assert_eq!(decoded, data, "{format:?}");
+        }
+
+        assert!(Stmap { width, height, data: vec![0.0; 4] }.encode(StmapFormat::Tiff).is_err());
+    }
+
+    #[test]
+    fn lens_mapper_round_trip() {
+        let mut profile = LensProfile::default();
+        profile.calib_dimension = crate::lens_profile::Dimensions { w: 1920, h: 1080 };
+        profile.fisheye_params.camera_matrix = vec![[1000.0, 0.0, 960.0], [0.0, 1000.0, 540.0], [0.0, 0.0, 1.0]];
+        profile.fisheye_params.distortion_coeffs = vec![0.05, -0.02, 0.01, -0.003];
+
+        for lens_correction_amount in [1.0, 0.5] {
+            let mapper = LensMapper::new(&profile, &StmapParams { output_size: Some((960, 540)), fov: 1.3, lens_correction_amount, ..Default::default() });
+            for pt in [(960.0, 540.0), (100.0, 80.0), (1700.0, 900.0), (400.0, 1000.0)] {
+                let out = mapper.input_to_output(pt).unwrap();
+                let back = mapper.output_to_input(out).unwrap();
+                assert!((back.0 - pt.0).abs() < 0.05 && (back.1 - pt.1).abs() < 0.05, "{lens_correction_amount}: {pt:?} -> {out:?} -> {back:?}");
+            }
+        }
+    }
+}
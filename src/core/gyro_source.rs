@@ -3,7 +3,7 @@
 
 use nalgebra::*;
 use std::iter::zip;
-use std::collections::BTreeMap;
+use std::collections::{ BTreeMap, BTreeSet };
 use std::collections::btree_map::Entry;
 use std::sync::{ Arc, atomic::AtomicBool };
 use telemetry_parser::{ Input, util };
@@ -14,8 +14,10 @@ use crate::keyframes::KeyframeManager;
 use crate::filesystem;
 
 use super::imu_integration::*;
-use super::smoothing::SmoothingAlgorithm;
+use super::smoothing::{ SmoothingAlgorithm, look_ahead::LookAhead };
 use crate::StabilizationParams;
+use crate::synchronization::SyncQuality;
+use crate::synchronization::offset_model::{ self, OffsetModel };
 
 pub type Quat64 = UnitQuaternion<f64>;
 pub type TimeIMU = telemetry_parser::util::IMUData;
@@ -30,6 +32,7 @@ pub struct LensParams {
     pub capture_area_origin: Option<(f32, f32)>, // pixels
     pub capture_area_size: Option<(f32, f32)>, // pixels
     pub pixel_focal_length: Option<f32>, // pixels
+    pub focus_distance: Option<f32>, // meters
     pub distortion_coefficients: Vec<f64>,
 }
 
@@ -81,7 +84,10 @@ impl FileMetadata {
 
 #[derive(Default, Clone, serde::Serialize, serde::Deserialize)]
 pub struct FileLoadOptions {
-    pub sample_index: Option<usize>
+    pub sample_index: Option<usize>,
+    /// Read the file as CSV using this mapping instead of detecting the format
+    #[serde(default)]
+    pub csv_mapping: Option<crate::csv_mapping::CsvMapping>,
 }
 
 #[derive(Default, Clone, serde::Serialize, serde::Deserialize)]
@@ -120,9 +126,13 @@ pub struct GyroSource {
 
     pub file_metadata: FileMetadata,
 
+    pub offset_model: OffsetModel,
     offsets: BTreeMap<i64, f64>, // <microseconds timestamp, offset in milliseconds>
-    offsets_linear: BTreeMap<i64, f64>, // <microseconds timestamp, offset in milliseconds> - linear fit
+    offsets_linear: BTreeMap<i64, f64>, // <microseconds timestamp, offset in milliseconds> - value of the offset model
+    offsets_fitted: BTreeMap<i64, f64>, // <microseconds timestamp, offset in milliseconds> - samples of the offset model
+    offsets_rejected: BTreeSet<i64>, // sync points which don't agree with the offset model
     offsets_adjusted: BTreeMap<i64, f64>, // <timestamp + offset, offset>
+    offsets_quality: BTreeMap<i64, SyncQuality>, // <microseconds timestamp, quality of the autosync result>
 
     pub file_url: String
 }
@@ -157,6 +167,10 @@ impl GyroSource {
         self.duration_ms = stabilization_params.get_scaled_duration_ms();
     }
     pub fn parse_telemetry_file<F: Fn(f64)>(url: &str, options: &FileLoadOptions, size: (usize, usize), fps: f64, progress_cb: F, cancel_flag: Arc<AtomicBool>) -> Result<FileMetadata, crate::GyroflowCoreError> {
+        if let Some(mapping) = &options.csv_mapping {
+            return mapping.parse(&filesystem::read_to_string(url)?);
+        }
+
         let base = filesystem::get_engine_base();
         let mut file = filesystem::open_file(&base, url, false, false)?;
         let filesize = file.size;
@@ -234,6 +248,9 @@ impl GyroSource {
                             lens_positions.insert(timestamp_us, *v as f64);
                             lens_info.focal_length = Some(*v);
                         }
+                        if let Some(v) = map.get_t(TagId::FocusDistance) as Option<&f32> {
+                            lens_info.focus_distance = Some(*v);
+                        }
                     }
                     if lens_info.focal_length.is_none() {
                         if let Some(md) = tag_map.get(&GroupId::Custom("LensDistortion".into())) {
@@ -246,7 +263,8 @@ impl GyroSource {
                             }
                         }
                     }
-                    if lens_info.pixel_pitch.is_some() && lens_info.capture_area_size.is_some() && (lens_info.pixel_focal_length.is_some() || lens_info.focal_length.is_some()) {
+                    // The focus distance selects the calibration of lens profiles interpolated by focus, even without the sensor data
+                    if (lens_info.pixel_pitch.is_some() && lens_info.capture_area_size.is_some() && (lens_info.pixel_focal_length.is_some() || lens_info.focal_length.is_some())) || lens_info.focus_distance.is_some() {
                         lens_params.insert(timestamp_us, lens_info.clone());
                     }
 
@@ -646,15 +664,21 @@ impl GyroSource {
         }
     }
 
-    pub fn recompute_smoothness(&self, alg: &dyn SmoothingAlgorithm, horizon_lock: super::smoothing::horizon::HorizonLock, stabilization_params: &StabilizationParams, keyframes: &KeyframeManager) -> (TimeQuat, TimeQuat, (f64, f64, f64)) {
+    pub fn recompute_smoothness(&self, alg: &dyn SmoothingAlgorithm, horizon_lock: super::smoothing::horizon::HorizonLock, look_ahead: Option<&LookAhead>, stabilization_params: &StabilizationParams, keyframes: &KeyframeManager) -> (TimeQuat, TimeQuat, (f64, f64, f64)) {
+        let smooth = |quats: &TimeQuat| -> TimeQuat {
+            match look_ahead {
+                Some(look_ahead) => look_ahead.smooth(alg, quats, self.duration_ms, stabilization_params, keyframes),
+                None => alg.smooth(quats, self.duration_ms, stabilization_params, keyframes)
+            }
+        };
         let mut smoothed_quaternions = self.quaternions.clone();
         if true {
             // Lock horizon, then smooth
             horizon_lock.lock(&mut smoothed_quaternions, &self.quaternions, &self.file_metadata.gravity_vectors, self.use_gravity_vectors, self.integration_method, keyframes, stabilization_params);
-            smoothed_quaternions = alg.smooth(&smoothed_quaternions, self.duration_ms, stabilization_params, keyframes);
+            smoothed_quaternions = smooth(&smoothed_quaternions);
         } else {
             // Smooth, then lock horizon
-            smoothed_quaternions = alg.smooth(&smoothed_quaternions, self.duration_ms, stabilization_params, keyframes);
+            smoothed_quaternions = smooth(&smoothed_quaternions);
             horizon_lock.lock(&mut smoothed_quaternions, &self.quaternions, &self.file_metadata.gravity_vectors, self.use_gravity_vectors, self.integration_method, keyframes, stabilization_params);
         }
 
@@ -674,126 +698,80 @@ impl GyroSource {
                 Entry::Occupied(o) => { *o.into_mut() = offset_ms; }
                 Entry::Vacant(v) => { v.insert(offset_ms); }
             }
+            // The quality is only known for offsets found by autosync
+            self.offsets_quality.remove(&timestamp_us);
             self.adjust_offsets();
         }
     }
     pub fn remove_offset(&mut self, timestamp_us: i64) {
         self.offsets.remove(&timestamp_us);
+        self.offsets_quality.remove(&timestamp_us);
         self.adjust_offsets();
     }
     pub fn clear_offsets(&mut self) {
         self.offsets.clear();
+        self.offsets_linear.clear();
+        self.offsets_fitted.clear();
+        self.offsets_rejected.clear();
         self.offsets_adjusted.clear();
+        self.offsets_quality.clear();
     }
     pub fn get_offsets(&self) -> &BTreeMap<i64, f64> {
         &self.offsets
     }
+    /// Offsets used for the lookup, either the sync points or the samples of the offset model
+    pub fn get_fitted_offsets(&self) -> &BTreeMap<i64, f64> {
+        &self.offsets_fitted
+    }
+    pub fn get_rejected_offsets(&self) -> &BTreeSet<i64> {
+        &self.offsets_rejected
+    }
+    pub fn set_offset_model(&mut self, model: OffsetModel) {
+        self.offset_model = model;
+        self.adjust_offsets();
+    }
     pub fn get_offsets_plus_linear(&self) -> BTreeMap<i64, (f64, f64)> {
         self.offsets.iter().map(|(k, v)| (*k, (*v, self.offsets_linear.get(k).copied().unwrap_or(*v)))).collect()
     }
     pub fn set_offsets(&mut self, offsets: BTreeMap<i64, f64>) {
         self.offsets = offsets;
+        self.offsets_quality.clear();
         self.adjust_offsets();
     }
+    pub fn set_offset_quality(&mut self, timestamp_us: i64, quality: SyncQuality) {
+        if self.offsets.contains_key(&timestamp_us) {
+            self.offsets_quality.insert(timestamp_us, quality);
+        }
+    }
+    pub fn get_offsets_quality(&self) -> &BTreeMap<i64, SyncQuality> {
+        &self.offsets_quality
+    }
+    pub fn set_offsets_quality(&mut self, quality: BTreeMap<i64, SyncQuality>) {
+        self.offsets_quality = quality.into_iter().filter(|(k, _)| self.offsets.contains_key(k)).collect();
+    }
+    /// Number of sync points found by autosync which shouldn't be trusted
+    pub fn doubtful_offsets(&self) -> usize {
+        self.offsets_quality.values().filter(|q| !q.trustworthy).count()
+    }
     pub fn remove_offsets_near(&mut self, ts: i64, range_ms: f64) {
         let range_us = (range_ms * 1000.0).round() as i64;
         self.offsets.retain(|k, _| !(ts-range_us..ts+range_us).contains(k));
+        self.offsets_quality.retain(|k, _| !(ts-range_us..ts+range_us).contains(k));
         self.adjust_offsets();
     }
 
-    fn line_fit(offsets: &BTreeMap<i64, f64>) -> Option<[f64; 3]> {
-        let a = OMatrix::<f64, nalgebra::Dyn, U2>::from_row_iterator(offsets.len(), offsets.iter().flat_map(|(k, _)| [*k as f64, 1.0]));
-        let b = OVector::<f64, nalgebra::Dyn>::from_iterator(offsets.len(), offsets.iter().map(|(_, v)| *v));
-
-        let svd = nalgebra::linalg::SVD::new(a.clone(), true, true);
-        let solution = svd.solve(&b, 1e-14).ok()?;
-        if solution.len() >= 2 {
-            let model: OVector<f64, nalgebra::Dyn> = a * &solution;
-            let l1: OVector<f64, nalgebra::Dyn> = model - b;
-            let residuals: f64 = l1.dot(&l1);
-
-            Some([solution[0], solution[1], residuals])
-        } else {
-            None
-        }
-    }
-
     pub fn adjust_offsets(&mut self) {
         if self.prevent_recompute { return; }
-        // Calculate line fit
-        if self.offsets.len() > 1 {
-            let len = self.offsets.len();
-            let keys: Vec<i64> = self.offsets.keys().copied().collect();
-
-            #[derive(Default)]
-            struct Params {
-                offsets: BTreeMap<i64, f64>,
-                rsquared: f64,
-                coeffs: [f64; 3]
-            }
-            let mut best = Params { rsquared: 1000.0, ..Default::default() };
-
-            let max_fitting_error = 5.0; // max 5 ms
-
-            for i in 0..len {
-                for j in 0..len {
-                    if i != j {
-                        let i_offset = self.offsets.get(&keys[i]).unwrap();
-                        let j_offset = self.offsets.get(&keys[j]).unwrap();
-                        let slope = (j_offset - i_offset) / (keys[j] - keys[i]) as f64;
-                        let intersect = i_offset - keys[i] as f64 * slope;
-
-                        let within_error: BTreeMap<i64, f64> = self.offsets.iter().filter_map(|(k, v)| {
-                            if ((*k as f64 * slope + intersect) - *v).abs() < max_fitting_error {
-                                Some((*k, *v))
-                            } else {
-                                None
-                            }
-                        }).collect();
-
-                        if within_error.len() >= best.offsets.len() && within_error != best.offsets {
-                            if let Some(solution) = Self::line_fit(&within_error) {
-                                let close_constant = solution[0].abs() < 0.1;
-                                if within_error.len() > 2 && close_constant {
-                                    if solution[2] < best.rsquared {
-                                        best = Params {
-                                            rsquared: solution[2],
-                                            offsets: within_error.clone(),
-                                            coeffs: solution.clone()
-                                        };
-                                    }
-                                } else if close_constant {
-                                    best = Params {
-                                        rsquared: best.rsquared,
-                                        offsets: within_error.clone(),
-                                        coeffs: solution.clone()
-                                    };
-                                }
-                            }
-                        }
-                    }
-                }
-            }
 
-            self.offsets_linear.clear();
-            if !best.offsets.is_empty() {
-                for (k, _) in &self.offsets {
-                    let fitted = *k as f64 * best.coeffs[0] + best.coeffs[1];
-                    self.offsets_linear.insert(*k, fitted);
-                }
-            } else {
-                if let Some(solution) = Self::line_fit(&self.offsets) {
-                    for (k, _) in &self.offsets {
-                        let fitted = *k as f64 * solution[0] + solution[1];
-                        self.offsets_linear.insert(*k, fitted);
-                    }
-                }
-            }
-        } else {
-            self.offsets_linear = self.offsets.clone();
+        let fit = offset_model::fit(&self.offsets, self.offset_model, (0, (self.duration_ms * 1000.0).round() as i64));
+        if !fit.rejected.is_empty() {
+            log::info!("Sync points rejected by the offset model: {:?}", fit.rejected);
         }
+        self.offsets_linear = fit.fitted;
+        self.offsets_fitted = fit.samples;
+        self.offsets_rejected = fit.rejected;
 
-        self.offsets_adjusted = self.offsets.iter().map(|(k, v)| (*k + (*v * 1000.0).round() as i64, *v)).collect::<BTreeMap<i64, f64>>();
+        self.offsets_adjusted = self.offsets_fitted.iter().map(|(k, v)| (*k + (*v * 1000.0).round() as i64, *v)).collect::<BTreeMap<i64, f64>>();
     }
 
     pub fn apply_transforms(&mut self) {
@@ -812,17 +790,6 @@ impl GyroSource {
         }
 
         if let Some(ref orientation) = self.imu_orientation {
-            pub fn orient(inp: &[f64; 3], io: &[u8]) -> [f64; 3] {
-                let map = |o: u8| -> f64 {
-                    match o as char {
-                        'X' => inp[0], 'x' => -inp[0],
-                        'Y' => inp[1], 'y' => -inp[1],
-                        'Z' => inp[2], 'z' => -inp[2],
-                        err => { panic!("Invalid orientation {}", err); }
-                    }
-                };
-                [map(io[0]), map(io[1]), map(io[2]) ]
-            }
             for x in &mut self.raw_imu {
                 // Change orientation
                 if let Some(g) = x.gyro.as_mut() { *g = orient(g, orientation.as_bytes()); }
@@ -932,7 +899,7 @@ impl GyroSource {
         }
     }
     pub fn offset_at_video_timestamp(&self, timestamp_ms: f64) -> f64 { Self::offset_at_timestamp(&self.offsets_adjusted, timestamp_ms) }
-    pub fn offset_at_gyro_timestamp (&self, timestamp_ms: f64) -> f64 { Self::offset_at_timestamp(&self.offsets, timestamp_ms) }
+    pub fn offset_at_gyro_timestamp (&self, timestamp_ms: f64) -> f64 { Self::offset_at_timestamp(&self.offsets_fitted, timestamp_ms) }
 
     /// Partial clone with data necessary only for computations
     pub fn clone_quaternions(&self) -> Self {
@@ -941,6 +908,7 @@ impl GyroSource {
             quaternions:          self.quaternions.clone(),
             smoothed_quaternions: self.smoothed_quaternions.clone(),
             offsets:              self.offsets.clone(),
+            offsets_fitted:       self.offsets_fitted.clone(),
             offsets_adjusted:     self.offsets_adjusted.clone(),
             file_metadata:        FileMetadata {
                 gravity_vectors:        self.file_metadata.gravity_vectors.clone(),
@@ -977,7 +945,7 @@ impl GyroSource {
         hasher.write_usize(self.file_metadata.lens_params.len());
         hasher.write_u32(if self.use_gravity_vectors { 1 } else { 0 });
         hasher.write_usize(self.integration_method);
-        for (ts, v) in &self.offsets {
+        for (ts, v) in &self.offsets_fitted {
             hasher.write_i64(*ts);
             hasher.write_u64(v.to_bits());
         }
@@ -1042,3 +1010,16 @@ impl GyroSource {
         (bias_vals[0], bias_vals[1], bias_vals[2])
     }
 }
+
+/// Maps an IMU reading to Gyroflow coordinates using the orientation string, eg. "YxZ": X = Y, Y = -X, Z = Z
+pub fn orient(inp: &[f64; 3], io: &[u8]) -> [f64; 3] {
+    let map = |o: u8| -> f64 {
+        match o as char {
+            'X' => inp[0], 'x' => -inp[0],
+            'Y' => inp[1], 'y' => -inp[1],
+            'Z' => inp[2], 'z' => -inp[2],
+            err => { panic!("Invalid orientation {}", err); }
+        }
+    };
+    [map(io[0]), map(io[1]), map(io[2]) ]
+}
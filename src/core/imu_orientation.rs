@@ -0,0 +1,319 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Detection of the `imu_orientation` string without video: the gravity direction measured by the accelerometer
+// while the camera is still should point up, and the rotation during a known motion (eg. a deliberate pan) should be
+// around the expected axis. Every orientation string is scored by how well the oriented measurements match.
+// The camera is expected to be level while it's still. Gravity alone leaves the rotation around the vertical axis open
+// and a pan is around the vertical axis too, so to get a single best candidate record a tilt or a roll as well.
+// Some cameras store mirrored axes, so mirrored orientations are candidates too, like in `guess_orient` of rs-sync.
+// A mirrored orientation only differs from a rotation in the sign of one axis, so to tell them apart all three axes
+// have to be measured, eg. a pan, a tilt and a roll. When they can't be told apart, the rotation is listed first.
+
+use crate::gyro_source::{ orient, TimeIMU };
+use crate::GyroflowCoreError;
+
+/// Length of the windows checked for no motion, in ms
+const STATIC_WINDOW_MS: f64 = 500.0;
+/// Maximum angular velocity in a static window, in deg/s
+const STATIC_MAX_GYRO: f64 = 3.0;
+/// Maximum relative deviation of the acceleration magnitude in a static window
+const STATIC_MAX_ACCEL_DEVIATION: f64 = 0.05;
+/// Minimum angular velocity of a motion segment, in deg/s
+const MOTION_MIN_GYRO: f64 = 15.0;
+/// Minimum rotation of a motion segment, in degrees
+const MOTION_MIN_ANGLE: f64 = 20.0;
+/// Temperature of the softmax which turns the scores into confidences
+const CONFIDENCE_TEMPERATURE: f64 = 0.05;
+
+/// Accelerometer reading of a level camera at rest, in Gyroflow's IMU coordinates
+const GRAVITY_AXIS: [f64; 3] = [1.0, 0.0, 0.0];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KnownMotion {
+    PanLeft,
+    PanRight,
+    TiltUp,
+    TiltDown,
+    /// Counter-clockwise, as seen from behind the camera
+    RollLeft,
+    /// Clockwise, as seen from behind the camera
+    RollRight,
+}
+
+impl KnownMotion {
+    /// Direction of the gyro readings during this motion, in Gyroflow's IMU coordinates
+    pub fn axis(&self) -> [f64; 3] {
+        match self {
+            Self::PanLeft   => [ 1.0,  0.0,  0.0],
+            Self::PanRight  => [-1.0,  0.0,  0.0],
+            Self::TiltDown  => [ 0.0,  1.0,  0.0],
+            Self::TiltUp    => [ 0.0, -1.0,  0.0],
+            Self::RollLeft  => [ 0.0,  0.0,  1.0],
+            Self::RollRight => [ 0.0,  0.0, -1.0],
+        }
+    }
+}
+
+impl std::str::FromStr for KnownMotion {
+    type Err = GyroflowCoreError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_value(serde_json::Value::String(s.trim().to_ascii_lowercase()))
+            .map_err(|_| GyroflowCoreError::ImuOrientationError(format!("Unknown motion {s}, expected one of: pan_left, pan_right, tilt_up, tilt_down, roll_left, roll_right")))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct KnownMotionSegment {
+    pub motion: KnownMotion,
+    /// Time range of the motion in ms. When not set, the motions are matched in order to the largest rotations in the data
+    #[serde(default)]
+    pub range_ms: Option<(f64, f64)>,
+}
+
+impl std::str::FromStr for KnownMotionSegment {
+    type Err = GyroflowCoreError;
+    /// `motion` or `motion:from-to` with the range in seconds, eg. "pan_left:2.5-4"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (motion, range) = s.split_once(':').map_or((s, None), |(m, r)| (m, Some(r)));
+        let range_ms = match range {
+            Some(range) => {
+                let parsed = range.split_once('-').and_then(|(from, to)| Some((from.trim().parse::<f64>().ok()?, to.trim().parse::<f64>().ok()?)));
+                match parsed {
+                    Some((from, to)) if to > from => Some((from * 1000.0, to * 1000.0)),
+                    _ => { return Err(GyroflowCoreError::ImuOrientationError(format!("Invalid time range {range}, expected eg. 2.5-4"))); }
+                }
+            }
+            None => None
+        };
+        Ok(Self { motion: motion.parse()?, range_ms })
+    }
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct OrientationCandidate {
+    pub orientation: String,
+    /// Mean cosine between the oriented measurements and the expected directions, from -1 to 1
+    pub score: f64,
+    /// Probability of this candidate among all of them
+    pub confidence: f64,
+}
+
+/// All 48 orientation strings: axis permutations with all sign combinations.
+/// The 24 rotations come first, followed by the 24 mirrored orientations
+pub fn all_orientations() -> Vec<String> {
+    const PERMUTATIONS: [[u8; 3]; 6] = [*b"XYZ", *b"YZX", *b"ZXY", *b"XZY", *b"YXZ", *b"ZYX"];
+    let mut all: Vec<String> = PERMUTATIONS.iter().flat_map(|p| (0..8u8).map(move |signs| {
+        p.iter().enumerate().map(|(i, c)| if signs & (1 << i) != 0 { c.to_ascii_lowercase() as char } else { *c as char }).collect()
+    })).collect();
+    all.sort_by_key(|x| is_mirrored(x));
+    all
+}
+
+/// Whether the orientation flips the handedness of the coordinates, ie. it's not a rotation
+pub fn is_mirrored(orientation: &str) -> bool {
+    let b = orientation.as_bytes();
+    if b.len() != 3 { return false; }
+    let axis = |c: u8| c.to_ascii_uppercase().wrapping_sub(b'X');
+    let odd_permutation = axis(b[1]) != (axis(b[0]) + 1) % 3;
+    let odd_signs = b.iter().filter(|c| c.is_ascii_lowercase()).count() % 2 == 1;
+    odd_permutation != odd_signs
+}
+
+/// Returns all orientation candidates sorted from the best one. `imu` is the data as read from the file, before any orientation is applied.
+pub fn detect(imu: &[TimeIMU], motions: &[KnownMotionSegment]) -> Result<Vec<OrientationCandidate>, GyroflowCoreError> {
+    if !imu.iter().any(|x| x.accl.is_some()) {
+        return Err(GyroflowCoreError::ImuOrientationError("No accelerometer data".into()));
+    }
+    let (gravity, bias) = static_measurement(imu).ok_or_else(|| GyroflowCoreError::ImuOrientationError("No static segment found, keep the camera still and level for at least one second".into()))?;
+
+    // (measured direction, expected direction)
+    let mut pairs = vec![(gravity, GRAVITY_AXIS)];
+    let mut candidates = motion_segments(imu, bias);
+    let unranged = motions.iter().filter(|x| x.range_ms.is_none()).count();
+    candidates.sort_by(|a, b| b.2.total_cmp(&a.2));
+    candidates.truncate(unranged);
+    candidates.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let mut candidates = candidates.into_iter();
+    for m in motions {
+        let rotation = match m.range_ms {
+            Some((from, to)) => integrate(imu.iter().filter(|x| x.timestamp_ms >= from && x.timestamp_ms <= to), bias),
+            None => candidates.next().map(|x| x.3).unwrap_or_default()
+        };
+        let angle = norm(&rotation);
+        if angle < MOTION_MIN_ANGLE {
+            return Err(GyroflowCoreError::ImuOrientationError(format!("Motion {:?} not found in the gyro data", m.motion)));
+        }
+        pairs.push((rotation.map(|v| v / angle), m.motion.axis()));
+    }
+
+    let mut result: Vec<OrientationCandidate> = all_orientations().into_iter().map(|orientation| {
+        let score = pairs.iter().map(|(measured, expected)| dot(&orient(measured, orientation.as_bytes()), expected)).sum::<f64>() / pairs.len() as f64;
+        OrientationCandidate { orientation, score, confidence: 0.0 }
+    }).collect();
+    // Equal scores up to rounding errors are sorted by the order of `all_orientations`, ie. rotations first
+    let rounded = |x: f64| (x * 1e9).round();
+    result.sort_by(|a, b| rounded(b.score).total_cmp(&rounded(a.score)));
+
+    let best = result[0].score;
+    let total: f64 = result.iter().map(|x| ((x.score - best) / CONFIDENCE_TEMPERATURE).exp()).sum();
+    for x in &mut result {
+        x.confidence = ((x.score - best) / CONFIDENCE_TEMPERATURE).exp() / total;
+    }
+    Ok(result)
+}
+
+/// Returns the normalized mean acceleration and the mean gyro reading of all windows without motion
+fn static_measurement(imu: &[TimeIMU]) -> Option<([f64; 3], [f64; 3])> {
+    let (mut accl_sum, mut gyro_sum, mut count) = ([0.0; 3], [0.0; 3], 0usize);
+    for window in windows(imu, STATIC_WINDOW_MS) {
+        let samples: Vec<([f64; 3], [f64; 3])> = window.iter().filter_map(|x| Some((x.gyro?, x.accl?))).collect();
+        if samples.len() < 3 || samples.iter().any(|(g, _)| norm(g) > STATIC_MAX_GYRO) { continue; }
+
+        let magnitudes: Vec<f64> = samples.iter().map(|(_, a)| norm(a)).collect();
+        let mean_magnitude = magnitudes.iter().sum::<f64>() / magnitudes.len() as f64;
+        if mean_magnitude <= 0.0 || magnitudes.iter().any(|m| (m - mean_magnitude).abs() > mean_magnitude * STATIC_MAX_ACCEL_DEVIATION) { continue; }
+
+        for (g, a) in &samples {
+            for i in 0..3 {
+                // Normalized, so it doesn't matter whether the data is in g or m/s²
+                accl_sum[i] += a[i] / mean_magnitude;
+                gyro_sum[i] += g[i];
+            }
+        }
+        count += samples.len();
+    }
+    let length = norm(&accl_sum);
+    if count == 0 || length <= 0.0 { return None; }
+    Some((accl_sum.map(|v| v / length), gyro_sum.map(|v| v / count as f64)))
+}
+
+/// Continuous parts with motion: (start ms, end ms, rotation angle in degrees, rotation vector)
+fn motion_segments(imu: &[TimeIMU], bias: [f64; 3]) -> Vec<(f64, f64, f64, [f64; 3])> {
+    let moving = |x: &TimeIMU| x.gyro.map(|g| norm(&sub(&g, &bias)) >= MOTION_MIN_GYRO).unwrap_or_default();
+    imu.split(|x| !moving(x))
+        .filter(|s| s.len() > 1)
+        .map(|s| {
+            let rotation = integrate(s.iter(), bias);
+            (s[0].timestamp_ms, s[s.len() - 1].timestamp_ms, norm(&rotation), rotation)
+        })
+        .filter(|s| s.2 >= MOTION_MIN_ANGLE)
+        .collect()
+}
+
+/// Sum of the gyro readings multiplied by the sample interval, in degrees
+fn integrate<'a>(samples: impl Iterator<Item = &'a TimeIMU>, bias: [f64; 3]) -> [f64; 3] {
+    let mut sum = [0.0; 3];
+    let mut prev_ts = None;
+    for x in samples {
+        if let (Some(g), Some(prev)) = (x.gyro, prev_ts) {
+            let dt = (x.timestamp_ms - prev) / 1000.0;
+            let g = sub(&g, &bias);
+            for i in 0..3 { sum[i] += g[i] * dt; }
+        }
+        prev_ts = Some(x.timestamp_ms);
+    }
+    sum
+}
+
+fn windows(imu: &[TimeIMU], length_ms: f64) -> Vec<&[TimeIMU]> {
+    let mut result = Vec::new();
+    let mut start = 0;
+    for i in 0..imu.len() {
+        if imu[i].timestamp_ms - imu[start].timestamp_ms >= length_ms {
+            result.push(&imu[start..i]);
+            start = i;
+        }
+    }
+    result
+}
+
+fn norm(v: &[f64; 3]) -> f64 { dot(v, v).sqrt() }
+fn dot(a: &[f64; 3], b: &[f64; 3]) -> f64 { a[0] * b[0] + a[1] * b[1] + a[2] * b[2] }
+fn sub(a: &[f64; 3], b: &[f64; 3]) -> [f64; 3] { [a[0] - b[0], a[1] - b[1], a[2] - b[2]] }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Still and level, pan left, still, tilt down, still, roll left, still. Sampled at 200 Hz and read by an IMU mounted as `orientation`
+    fn recording(orientation: &str) -> Vec<TimeIMU> {
+        // Inverse of `orient`: the file values which give the Gyroflow coordinates after applying the orientation
+        let to_file = |v: [f64; 3]| {
+            let mut out = [0.0; 3];
+            for (i, c) in orientation.bytes().enumerate() {
+                let axis = (c.to_ascii_uppercase() - b'X') as usize;
+                out[axis] = if c.is_ascii_uppercase() { v[i] } else { -v[i] };
+            }
+            out
+        };
+        let mut imu = Vec::new();
+        for i in 0..1600 {
+            let t = i as f64 * 5.0;
+            let noise = ((i * 7919) % 13) as f64 / 13.0 - 0.5;
+            let gyro = match t {
+                t if (2000.0..3000.0).contains(&t) => [90.0, 0.0, 0.0],
+                t if (5000.0..5500.0).contains(&t) => [0.0, 60.0, 0.0],
+                t if (7000.0..7500.0).contains(&t) => [0.0, 0.0, 90.0],
+                _ => [0.0; 3]
+            };
+            imu.push(TimeIMU {
+                timestamp_ms: t,
+                gyro: Some(to_file([gyro[0] + noise + 0.5, gyro[1] - noise, gyro[2] + 0.2])),
+                accl: Some(to_file([9.81 + noise * 0.05, noise * 0.05, 0.0])),
+                magn: None
+            });
+        }
+        imu
+    }
+
+    #[test]
+    fn detects_orientation() {
+        let motions = vec![
+            KnownMotionSegment { motion: KnownMotion::PanLeft, range_ms: None },
+            KnownMotionSegment { motion: KnownMotion::TiltDown, range_ms: None },
+            KnownMotionSegment { motion: KnownMotion::RollLeft, range_ms: None },
+        ];
+        for orientation in ["XYZ", "YxZ", "zXy", "ZYx", "yzX", "XYz", "YXZ", "zxy"] {
+            let result = detect(&recording(orientation), &motions).unwrap();
+            assert_eq!(result.len(), 48);
+            assert_eq!(result[0].orientation, orientation);
+            assert!(result[0].confidence > 0.9, "{orientation}: {:?}", result[0]);
+        }
+
+        // Without a roll, the mirrored orientation scores the same and the rotation is preferred
+        let pan_tilt = vec![
+            KnownMotionSegment { motion: KnownMotion::PanLeft, range_ms: None },
+            KnownMotionSegment { motion: KnownMotion::TiltDown, range_ms: Some((5000.0, 5500.0)) },
+        ];
+        let result = detect(&recording("YxZ"), &pan_tilt).unwrap();
+        assert_eq!(result[0].orientation, "YxZ");
+        assert_eq!(result[1].orientation, "Yxz");
+        assert!(result[0].score - result[1].score < 1e-6);
+
+        // Only a pan leaves the rotation around the vertical axis open
+        let result = detect(&recording("XYZ"), &motions[..1]).unwrap();
+        assert!(result[0].score - result[3].score < 1e-6);
+        assert!(result[0].confidence < 0.3);
+    }
+
+    #[test]
+    fn orientations() {
+        let all = all_orientations();
+        assert_eq!(all.len(), 48);
+        assert_eq!(all.iter().collect::<std::collections::HashSet<_>>().len(), 48);
+        assert!(all[..24].iter().all(|x| !is_mirrored(x)));
+        assert!(all[24..].iter().all(|x| is_mirrored(x)));
+        assert!(!is_mirrored("XYZ") && !is_mirrored("YxZ") && !is_mirrored("ZXY") && !is_mirrored("xyZ"));
+        assert!(is_mirrored("XYz") && is_mirrored("YXZ") && is_mirrored("xyz") && is_mirrored("XZY"));
+    }
+
+    #[test]
+    fn parse_segment() {
+        assert_eq!("pan_left".parse::<KnownMotionSegment>().unwrap(), KnownMotionSegment { motion: KnownMotion::PanLeft, range_ms: None });
+        assert_eq!("Tilt_Up:2.5-4".parse::<KnownMotionSegment>().unwrap(), KnownMotionSegment { motion: KnownMotion::TiltUp, range_ms: Some((2500.0, 4000.0)) });
+        assert!("spin".parse::<KnownMotionSegment>().is_err());
+        assert!("pan_left:4-2".parse::<KnownMotionSegment>().is_err());
+    }
+}
@@ -177,7 +177,7 @@ impl KeyframeManager {
     }
 
     pub fn update_gyro(&mut self, gyro: &GyroSource) {
-        self.gyro_offsets = gyro.get_offsets().clone();
+        self.gyro_offsets = gyro.get_fitted_offsets().clone();
     }
     pub fn clear(&mut self) {
         *self = Self::new();
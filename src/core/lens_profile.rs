@@ -1,15 +1,16 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 // Copyright © 2021-2022 Adrian <adrian.eddy at gmail>
 
-use std::collections::{ HashSet, BTreeMap };
+use std::collections::HashSet;
 use itertools::Itertools;
 
 use serde::{ Serialize, Deserialize };
 
 use crate::stabilization::distortion_models::DistortionModel;
 
-#[cfg(feature = "opencv")]
 use super::LensCalibrator;
+use crate::calibration::self_calibration::SelfCalibration;
+use crate::lens_profile_interpolation::{ self, LensInterpolation };
 
 #[derive(Deserialize, Serialize, Default, Clone, Debug)]
 pub struct Dimensions { pub w: usize, pub h: usize }
@@ -19,6 +20,18 @@ pub struct Dimensions { pub w: usize, pub h: usize }
 #[allow(non_snake_case)]
 pub struct CameraParams { pub RMS_error: f64, pub camera_matrix: Vec<[f64; 3]>, pub distortion_coeffs: Vec<f64>, pub radial_distortion_limit: Option<f64> }
 
+/// Uncertainty of a profile estimated from the motion in a normal video and the gyro, instead of a calibration target
+#[derive(Deserialize, Serialize, Default, Clone, Debug)]
+#[serde(default)]
+pub struct AutoEstimation {
+    /// Standard deviation of the focal length, in pixels of `calib_dimension`
+    pub focal_length_std: f64,
+    /// Standard deviations of the estimated distortion coefficients
+    pub distortion_coeffs_std: Vec<f64>,
+    /// Number of tracked features used for the estimation
+    pub features: usize,
+}
+
 #[derive(Deserialize, Serialize, Default, Clone, Debug)]
 #[serde(default)]
 pub struct LensProfile {
@@ -56,6 +69,8 @@ pub struct LensProfile {
 
     pub calibrator_version: String,
     pub date: String,
+    pub calibration_target: Option<String>,
+    pub auto_estimated: Option<AutoEstimation>,
 
     pub compatible_settings: Vec<serde_json::Value>,
 
@@ -68,6 +83,8 @@ pub struct LensProfile {
     pub interpolations: Option<serde_json::Value>,
 
     pub focal_length: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub focus_distance: Option<f64>,
     pub crop_factor: Option<f64>,
     pub global_shutter: bool,
 
@@ -77,7 +94,7 @@ pub struct LensProfile {
     pub is_copy: bool,
     pub rating: Option<f64>,
     pub checksum: Option<String>,
-    parsed_interpolations: BTreeMap<i64, LensProfile>,
+    parsed_interpolations: LensInterpolation,
 }
 
 impl LensProfile {
@@ -102,7 +119,13 @@ impl LensProfile {
     }
 
     pub fn load_from_file(&mut self, url: &str) -> std::result::Result<(), crate::GyroflowCoreError> {
-        self.load_from_data(&crate::filesystem::read_to_string(url)?)
+        let data = crate::filesystem::read_to_string(url)?;
+        if crate::lens_profile_formats::is_supported(url, &data) {
+            // Lensfun, Adobe or Hugin file, use the first lens from it
+            *self = crate::lens_profile_formats::import(&data, url)?.swap_remove(0);
+            return Ok(());
+        }
+        self.load_from_data(&data)
     }
 
     pub fn load_from_json_value(&mut self, v: &serde_json::Value) -> Option<()> {
@@ -110,7 +133,6 @@ impl LensProfile {
         Some(())
     }
 
-    #[cfg(feature = "opencv")]
     pub fn set_from_calibrator(&mut self, cal: &LensCalibrator) {
         if self.input_horizontal_stretch <= 0.01 { self.input_horizontal_stretch = 1.0; }
         if self.input_vertical_stretch   <= 0.01 { self.input_vertical_stretch   = 1.0; }
@@ -122,14 +144,58 @@ impl LensProfile {
         self.optimal_fov = None;
 
         self.asymmetrical = cal.asymmetrical;
+        self.calibration_target = Some(cal.target.name().to_string());
+        self.distortion_model = Some(cal.distortion_model_id().to_string());
 
         self.fisheye_params = CameraParams {
             RMS_error: cal.rms,
             camera_matrix: cal.k.row_iter().map(|x| [x[0], x[1], x[2]]).collect(),
-            distortion_coeffs: cal.d.as_slice().to_vec(),
+            distortion_coeffs: cal.d.clone(),
             radial_distortion_limit: if cal.r_limit > 0.0 { Some(cal.r_limit) } else { None }
         };
 
+        if let Some(imu) = &cal.imu_calibration {
+            self.frame_readout_time = Some(imu.frame_readout_time);
+            let mut sync_settings = self.sync_settings.take().filter(|x| x.is_object()).unwrap_or_else(|| serde_json::json!({}));
+            sync_settings["initial_offset"]  = serde_json::json!(imu.offset / 1000.0); // ms to s
+            sync_settings["imu_orientation"] = serde_json::json!(imu.imu_orientation);
+            sync_settings["imu_rotation"]    = serde_json::json!(imu.imu_rotation);
+            self.sync_settings = Some(sync_settings);
+        }
+
+        self.init();
+    }
+
+    /// `analyzed_size` is the size of the frames in which the features were tracked
+    pub fn set_from_self_calibration(&mut self, cal: &SelfCalibration, analyzed_size: (usize, usize), video_size: (usize, usize)) {
+        if self.input_horizontal_stretch <= 0.01 { self.input_horizontal_stretch = 1.0; }
+        if self.input_vertical_stretch   <= 0.01 { self.input_vertical_stretch   = 1.0; }
+
+        self.calib_dimension = Dimensions { w: video_size.0, h: video_size.1 };
+        self.orig_dimension  = Dimensions { w: video_size.0, h: video_size.1 };
+        self.num_images = 0;
+        self.optimal_fov = None;
+        self.calibration_target = None;
+        self.distortion_model = Some("opencv_fisheye".into());
+
+        // The fisheye coefficients don't depend on the resolution
+        let scale = video_size.0 as f64 / analyzed_size.0.max(1) as f64;
+        let k = cal.k * scale;
+        self.fisheye_params = CameraParams {
+            RMS_error: cal.rms * scale,
+            camera_matrix: vec![[k[(0, 0)], 0.0, k[(0, 2)]], [0.0, k[(1, 1)], k[(1, 2)]], [0.0, 0.0, 1.0]],
+            distortion_coeffs: cal.d.clone(),
+            radial_distortion_limit: None
+        };
+        self.auto_estimated = Some(AutoEstimation {
+            focal_length_std: cal.focal_length_std * scale,
+            distortion_coeffs_std: cal.distortion_coeffs_std.clone(),
+            features: cal.inliers,
+        });
+        if self.note.is_empty() {
+            self.note = format!("Estimated from the video, focal length ±{:.1}%", cal.focal_length_std / cal.k[(0, 0)] * 100.0);
+        }
+
         self.init();
     }
 
@@ -209,6 +275,12 @@ impl LensProfile {
     }
 
     pub fn save_to_file(&mut self, url: &str) -> std::result::Result<String, crate::GyroflowCoreError> {
+        if crate::lens_profile_formats::is_exportable(url) {
+            let (data, error) = crate::lens_profile_formats::export(self, url, None)?;
+            log::info!("Lens profile exported to {url}, fit error: {:.3} px RMS, {:.3} px max", error.rms, error.max);
+            crate::filesystem::write(url, data.as_bytes())?;
+            return Ok(data);
+        }
         let json = self.get_json()?;
 
         crate::filesystem::write(url, json.as_bytes())?;
@@ -216,6 +288,12 @@ impl LensProfile {
         Ok(json)
     }
 
+    /// Checks the profile for invalid values. The profiles referenced by `interpolations` are checked only with `db`
+    pub fn validate(&self, db: Option<&crate::lens_profile_database::LensProfileDatabase>) -> Vec<crate::lens_profile_validation::ValidationIssue> {
+        let is_known = db.map(|db| move |id: &str| db.contains_id(id));
+        crate::lens_profile_validation::validate(self, is_known.as_ref().map(|x| x as &dyn Fn(&str) -> bool))
+    }
+
     pub fn swapped(&self) -> LensProfile {
         let mut ret = self.clone();
         std::mem::swap(&mut ret.orig_dimension.w, &mut ret.orig_dimension.h);
@@ -248,7 +326,7 @@ impl LensProfile {
         }
 
         // Swap interpolations
-        for (_, x) in ret.parsed_interpolations.iter_mut() {
+        for x in ret.parsed_interpolations.profiles_mut() {
             *x = x.swapped();
         }
 
@@ -462,59 +540,12 @@ impl LensProfile {
     }
 
     pub fn get_interpolated_lens_at(&self, val: f64) -> LensProfile {
-        let mut cpy = self.clone();
-
-        if !self.parsed_interpolations.is_empty() {
-            let key = (val * 1000000.0).round() as i64;
-
-            if let Some(v) = self.parsed_interpolations.get(&key) { return v.clone(); }
-
-            if let Some(&first) = self.parsed_interpolations.keys().next() {
-                if let Some(&last) = self.parsed_interpolations.keys().next_back() {
-                    let lookup = (key).min(last-1).max(first+1);
-                    if let Some(p1) = self.parsed_interpolations.range(..=lookup).next_back() {
-                        if *p1.0 == lookup {
-                            return p1.1.clone();
-                        }
-                        if let Some(p2) = self.parsed_interpolations.range(lookup..).next() {
-                            let time_delta = (p2.0 - p1.0) as f64;
-                            let fract = (key - p1.0) as f64 / time_delta;
-
-                            let l1 = p1.1;
-                            let l2 = p2.1;
-                            // println!("interpolated at {:.4}, fract: {:.4}", val, fract);
-
-                            cpy.fisheye_params.camera_matrix[0][0] = l1.fisheye_params.camera_matrix[0][0] * (1.0 - fract) + (l2.fisheye_params.camera_matrix[0][0] * fract);
-                            cpy.fisheye_params.camera_matrix[1][1] = l1.fisheye_params.camera_matrix[1][1] * (1.0 - fract) + (l2.fisheye_params.camera_matrix[1][1] * fract);
-                            cpy.fisheye_params.camera_matrix[0][2] = l1.fisheye_params.camera_matrix[0][2] * (1.0 - fract) + (l2.fisheye_params.camera_matrix[0][2] * fract);
-                            cpy.fisheye_params.camera_matrix[1][2] = l1.fisheye_params.camera_matrix[1][2] * (1.0 - fract) + (l2.fisheye_params.camera_matrix[1][2] * fract);
-
-                            if cpy.fisheye_params.distortion_coeffs.len() == l1.fisheye_params.distortion_coeffs.len() && l1.fisheye_params.distortion_coeffs.len() == l2.fisheye_params.distortion_coeffs.len() {
-                                for i in 0..l1.fisheye_params.distortion_coeffs.len() {
-                                    cpy.fisheye_params.distortion_coeffs[i] = l1.fisheye_params.distortion_coeffs[i] * (1.0 - fract) + (l2.fisheye_params.distortion_coeffs[i] * fract);
-                                }
-                            }
-                            cpy.crop = Some(l1.crop.unwrap_or(1.0) * (1.0 - fract) + (l2.crop.unwrap_or(1.0) * fract));
-
-                            match (l1.focal_length, l2.focal_length) {
-                                (Some(fl1), Some(fl2)) => { cpy.focal_length = Some(fl1 * (1.0 - fract) + (fl2 * fract))},
-                                _ => { }
-                            }
-
-                            cpy.calib_dimension.w = (l1.calib_dimension.w as f64 * (1.0 - fract) + (l2.calib_dimension.w as f64 * fract)).round() as usize;
-                            cpy.calib_dimension.h = (l1.calib_dimension.h as f64 * (1.0 - fract) + (l2.calib_dimension.h as f64 * fract)).round() as usize;
-
-                            cpy.input_horizontal_stretch = l1.input_horizontal_stretch * (1.0 - fract) + (l2.input_horizontal_stretch * fract);
-                            cpy.input_vertical_stretch   = l1.input_vertical_stretch   * (1.0 - fract) + (l2.input_vertical_stretch   * fract);
-
-                            // TODO: digital lens interpolation?
-                        }
-                    }
-                }
-            }
-        }
+        self.get_interpolated_lens(val, None)
+    }
 
-        cpy
+    /// Lens at the focal length in mm and the focus distance in meters, interpolated between the calibrations in `interpolations`
+    pub fn get_interpolated_lens(&self, focal_length: f64, focus_distance: Option<f64>) -> LensProfile {
+        self.parsed_interpolations.interpolate(focal_length, focus_distance).unwrap_or_else(|| self.clone())
     }
 
     pub fn resolve_interpolations(&mut self, db: &crate::lens_profile_database::LensProfileDatabase) {
@@ -528,11 +559,10 @@ impl LensProfile {
         }
 
         if let Some(serde_json::Value::Object(map)) = &self.interpolations {
-            let mut interpolations = BTreeMap::new();
+            let mut interpolations = LensInterpolation::default();
             for (k, v) in map {
                 if let serde_json::Value::Object(v) = v {
-                    if let Ok(key) = k.parse::<f64>() {
-                        let key = (key * 1000000.0).round() as i64;
+                    if let Some((focal_length, focus_distance)) = lens_profile_interpolation::parse_key(k) {
                         let mut new_profile = self.clone();
                         if let Some(id) = v.get("identifier").and_then(|x| x.as_str()) {
                             if let Some(profile) = db.get_by_id(id) {
@@ -544,8 +574,8 @@ impl LensProfile {
                             for (i, r) in row.iter().enumerate() {
                                 if let Some(col) = r.as_array() {
                                     for (j, c) in col.iter().enumerate() {
-                                        if let Some(v) = c.as_f64() {
-                                            new_profile.fisheye_params.camera_matrix[i][j] = v;
+                                        if let (Some(v), Some(dst)) = (c.as_f64(), new_profile.fisheye_params.camera_matrix.get_mut(i).and_then(|x| x.get_mut(j))) {
+                                            *dst = v;
                                         }
                                     }
                                 }
@@ -553,15 +583,19 @@ impl LensProfile {
                         }
                         if let Some(row) = v.get("distortion_coeffs").and_then(|x| x.as_array()) {
                             for (i, v) in row.iter().enumerate() {
-                                if let Some(v) = v.as_f64() {
-                                    new_profile.fisheye_params.distortion_coeffs[i] = v;
+                                if let (Some(v), Some(dst)) = (v.as_f64(), new_profile.fisheye_params.distortion_coeffs.get_mut(i)) {
+                                    *dst = v;
                                 }
                             }
                         }
                         if let Some(fl) = v.get("focal_length").and_then(|x| x.as_f64()) {
                             new_profile.focal_length = Some(fl);
                         }
-                        interpolations.insert(key, new_profile);
+                        if let Some(params) = v.get("digital_lens_params").and_then(|x| serde_json::from_value::<Vec<f64>>(x.clone()).ok()) {
+                            new_profile.digital_lens_params = Some(params);
+                        }
+                        new_profile.focus_distance = focus_distance.or_else(|| v.get("focus_distance").and_then(|x| x.as_f64()));
+                        interpolations.insert(focal_length, new_profile.focus_distance, new_profile);
                     }
                 }
             }
@@ -160,6 +160,22 @@ impl LensProfileDatabase {
                         load(DataSource::String(data), &f_name);
                     }
                 }
+                if crate::lens_profile_formats::has_supported_extension(&f_name) {
+                    if let Ok(data) = std::fs::read_to_string(&f_name) {
+                        if crate::lens_profile_formats::is_supported(&f_name, &data) {
+                            match crate::lens_profile_formats::import(&data, &f_name) {
+                                Ok(profiles) => {
+                                    for profile in profiles {
+                                        if let Ok(v) = profile.get_json_value() {
+                                            load(DataSource::SerdeValue(v), &f_name);
+                                        }
+                                    }
+                                }
+                                Err(e) => log::warn!("Failed to import lens profiles from {}: {:?}", f_name, e)
+                            }
+                        }
+                    }
+                }
                 if f_name.ends_with(".cbor.gz") {
                     if let Ok(data) = std::fs::read(&f_name) {
                         let mut e = flate2::read::GzDecoder::new(std::io::Cursor::new(data));
@@ -0,0 +1,316 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Adobe Lens Profile (.lcp), XMP with a list of camera profiles:
+// <photoshop:CameraProfiles><rdf:Seq><rdf:li rdf:parseType="Resource">
+//     <stCamera:Make>Canon</stCamera:Make>
+//     <stCamera:Model>Canon EOS 5D Mark II</stCamera:Model>
+//     <stCamera:Lens>EF24-105mm f/4L IS USM</stCamera:Lens>
+//     <stCamera:ImageWidth>5616</stCamera:ImageWidth>
+//     <stCamera:ImageLength>3744</stCamera:ImageLength>
+//     <stCamera:FocalLength>24</stCamera:FocalLength>
+//     <stCamera:FocusDistance>10000</stCamera:FocusDistance>
+//     <stCamera:PerspectiveModel>
+//         <rdf:Description stCamera:FocalLengthX="0.73" stCamera:FocalLengthY="0.73" stCamera:ImageXCenter="0.5" stCamera:ImageYCenter="0.333"
+//                          stCamera:RadialDistortParam1="-0.11" stCamera:RadialDistortParam2="0.05" stCamera:RadialDistortParam3="0"/>
+//     </stCamera:PerspectiveModel>
+// </rdf:li></rdf:Seq></photoshop:CameraProfiles>
+// Every property can also be an attribute instead of an element. The focal lengths and the principal point are relative to the longer image side.
+// The perspective model is OpenCV's standard model (radial k1, k2, k3 and tangential p1, p2) and the fisheye model is OpenCV's fisheye model with k1 and k2.
+// From multiple focus distances at one focal length, the farthest one is used.
+// Exported profiles are not raw profiles, they describe the processed video frames.
+
+use std::collections::BTreeMap;
+use roxmltree::{ Document, Node };
+use crate::LensProfile;
+use crate::GyroflowCoreError;
+use super::{ build_profile, calibrated_profiles, escape_xml, reference_size, refit, Calibration, FitError, ProfileInfo };
+
+/// Distortion models which can be exported: the perspective model and the fisheye model
+pub const EXPORT_MODELS: &[&str] = &["opencv_standard", "opencv_fisheye"];
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Key {
+    make: String,
+    model: String,
+    lens: String,
+    size: (usize, usize),
+    raw: bool,
+}
+
+pub fn import(data: &str) -> Result<Vec<LensProfile>, GyroflowCoreError> {
+    let doc = Document::parse(data).map_err(|e| GyroflowCoreError::LensProfileFormatError(format!("Invalid XML: {e}")))?;
+
+    // (focal length, focus distance, calibration) for each camera and lens
+    let mut groups = BTreeMap::<Key, Vec<(f64, f64, Calibration)>>::new();
+    let mut crop_factors = BTreeMap::<Key, f64>::new();
+    for entry in doc.descendants().filter(|x| x.is_element() && (child(x, "PerspectiveModel").is_some() || child(x, "FisheyeModel").is_some())) {
+        let size = match (number(&entry, "ImageWidth"), number(&entry, "ImageLength")) {
+            (Some(w), Some(h)) if w > 0.0 && h > 0.0 => (w as usize, h as usize),
+            _ => reference_size(1.5)
+        };
+        let max_dim = size.0.max(size.1) as f64;
+        let focal_length = number(&entry, "FocalLength").unwrap_or_default();
+
+        // Fisheye lenses can have both models, the fisheye one describes the lens
+        let calibration = if let Some(model) = child(&entry, "FisheyeModel") {
+            let model = description(model);
+            calibration(&model, size, max_dim, "opencv_fisheye", vec![
+                number(&model, "FisheyeParam1").unwrap_or_default(),
+                number(&model, "FisheyeParam2").unwrap_or_default(),
+                0.0,
+                0.0
+            ])
+        } else {
+            let model = description(child(&entry, "PerspectiveModel").unwrap());
+            calibration(&model, size, max_dim, "opencv_standard", vec![
+                number(&model, "RadialDistortParam1").unwrap_or_default(),
+                number(&model, "RadialDistortParam2").unwrap_or_default(),
+                number(&model, "TangentialDistortParam1").unwrap_or_default(),
+                number(&model, "TangentialDistortParam2").unwrap_or_default(),
+                number(&model, "RadialDistortParam3").unwrap_or_default()
+            ])
+        };
+        let Some(mut calibration) = calibration else { continue; };
+        calibration.focal_length = (focal_length > 0.0).then_some(focal_length);
+
+        let key = Key {
+            make:  property(&entry, "Make").unwrap_or_default(),
+            model: property(&entry, "Model").unwrap_or_default(),
+            lens:  property(&entry, "LensPrettyName").or_else(|| property(&entry, "Lens")).unwrap_or_default(),
+            size,
+            raw: property(&entry, "CameraRawProfile").map(|x| x.eq_ignore_ascii_case("true")).unwrap_or_default(),
+        };
+        if let Some(crop) = number(&entry, "SensorFormatFactor") {
+            crop_factors.insert(key.clone(), crop);
+        }
+        groups.entry(key).or_default().push((focal_length, number(&entry, "FocusDistance").unwrap_or(f64::MAX), calibration));
+    }
+
+    let mut profiles = Vec::new();
+    for (key, mut entries) in groups.iter().map(|(k, v)| (k, v.clone())) {
+        // Videos are processed images, so the raw profiles are only used when there are no others
+        if key.raw && groups.keys().any(|x| !x.raw && x.make == key.make && x.model == key.model && x.lens == key.lens) {
+            continue;
+        }
+        // Farthest focus distance first, it's then kept by `build_profile`
+        entries.sort_by(|a, b| a.0.total_cmp(&b.0).then(b.1.total_cmp(&a.1)));
+        let info = ProfileInfo {
+            brand: &key.make,
+            model: &key.model,
+            lens: &key.lens,
+            setting: "",
+            size: key.size,
+            crop_factor: crop_factors.get(key).copied(),
+            calibrated_by: "Adobe LCP",
+        };
+        if let Some(profile) = build_profile(&info, entries.into_iter().map(|x| x.2).collect()) {
+            profiles.push(profile);
+        }
+    }
+    Ok(profiles)
+}
+
+/// Adobe Lens Profile with a camera profile for each focal length of the profile
+pub fn export(profile: &LensProfile, model: &str) -> Result<(String, FitError), GyroflowCoreError> {
+    if !EXPORT_MODELS.contains(&model) {
+        return Err(GyroflowCoreError::LensProfileFormatError(format!("Distortion model {model} can't be exported to LCP")));
+    }
+    let (w, h) = (profile.calib_dimension.w, profile.calib_dimension.h);
+    let max_dim = w.max(h) as f64;
+    if max_dim <= 0.0 {
+        return Err(GyroflowCoreError::LensProfileFormatError(format!("The profile doesn't have a calibration size: {}", profile.get_name())));
+    }
+    let lens = if profile.lens_model.is_empty() { &profile.camera_model } else { &profile.lens_model };
+    let author = if profile.calibrated_by.is_empty() { "Gyroflow" } else { &profile.calibrated_by };
+
+    let mut entries = Vec::new();
+    let mut errors = Vec::new();
+    for p in calibrated_profiles(profile) {
+        let (c, error) = refit(&p, model, false)?;
+        let mut properties = vec![
+            ("Author", escape_xml(author)),
+            ("Make", escape_xml(&profile.camera_brand)),
+            ("Model", escape_xml(&profile.camera_model)),
+            ("UniqueCameraModel", escape_xml(&format!("{} {}", profile.camera_brand, profile.camera_model))),
+            ("CameraRawProfile", "False".to_owned()),
+            ("Lens", escape_xml(lens)),
+            ("LensPrettyName", escape_xml(lens)),
+            ("ProfileName", escape_xml(&profile.get_name())),
+            ("ImageWidth", w.to_string()),
+            ("ImageLength", h.to_string()),
+        ];
+        if let Some(crop_factor) = profile.crop_factor {
+            properties.push(("SensorFormatFactor", format!("{crop_factor:.3}")));
+        }
+        if let Some(focal_length) = c.focal_length {
+            properties.push(("FocalLength", format!("{focal_length:.3}")));
+        }
+        let mut entry = String::from("     <rdf:li rdf:parseType=\"Resource\">\n");
+        for (name, value) in properties {
+            entry.push_str(&format!("      <stCamera:{name}>{value}</stCamera:{name}>\n"));
+        }
+        let (element, params) = match c.model {
+            "opencv_fisheye" => ("FisheyeModel", vec![("FisheyeParam1", c.coeffs[0]), ("FisheyeParam2", c.coeffs[1])]),
+            _ => ("PerspectiveModel", vec![
+                ("RadialDistortParam1", c.coeffs[0]),
+                ("RadialDistortParam2", c.coeffs[1]),
+                ("RadialDistortParam3", c.coeffs[4]),
+            ])
+        };
+        entry.push_str(&format!("      <stCamera:{element} rdf:parseType=\"Resource\">\n"));
+        entry.push_str("       <stCamera:Version>2</stCamera:Version>\n");
+        for (name, value) in [
+            ("FocalLengthX", c.focal_px.0 / max_dim),
+            ("FocalLengthY", c.focal_px.1 / max_dim),
+            ("ImageXCenter", c.center.0 / max_dim),
+            ("ImageYCenter", c.center.1 / max_dim)
+        ].into_iter().chain(params) {
+            entry.push_str(&format!("       <stCamera:{name}>{value:.6}</stCamera:{name}>\n"));
+        }
+        entry.push_str(&format!("      </stCamera:{element}>\n"));
+        entry.push_str("     </rdf:li>");
+        entries.push(entry);
+        errors.push(error);
+    }
+    let error = FitError::combine(&errors);
+
+    let xml = format!(r#"<?xml version="1.0" encoding="UTF-8"?>
+<!-- Exported from Gyroflow, fit error: {rms:.3} px RMS, {max:.3} px max at {w}x{h} -->
+<x:xmpmeta xmlns:x="adobe:ns:meta/" x:xmptk="Gyroflow">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about="" xmlns:photoshop="http://ns.adobe.com/photoshop/1.0/" xmlns:stCamera="http://ns.adobe.com/photoshop/1.0/camera-profile">
+   <photoshop:CameraProfiles>
+    <rdf:Seq>
+{entries}
+    </rdf:Seq>
+   </photoshop:CameraProfiles>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>
+"#, rms = error.rms, max = error.max, entries = entries.join("\n"));
+    Ok((xml, error))
+}
+
+fn calibration(model: &Node, size: (usize, usize), max_dim: f64, model_id: &'static str, coeffs: Vec<f64>) -> Option<Calibration> {
+    let fx = number(model, "FocalLengthX")?;
+    let fy = number(model, "FocalLengthY").unwrap_or(fx);
+    let cx = number(model, "ImageXCenter").map(|x| x * max_dim).unwrap_or(size.0 as f64 / 2.0);
+    let cy = number(model, "ImageYCenter").map(|x| x * max_dim).unwrap_or(size.1 as f64 / 2.0);
+    Some(Calibration {
+        focal_length: None,
+        focal_px: (fx * max_dim, fy * max_dim),
+        center: (cx, cy),
+        model: model_id,
+        coeffs
+    })
+}
+
+fn child<'a, 'input>(node: &Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children().find(|x| x.is_element() && x.tag_name().name() == name)
+}
+
+/// The model's properties are either in a nested rdf:Description or directly in the element
+fn description<'a, 'input>(node: Node<'a, 'input>) -> Node<'a, 'input> {
+    child(&node, "Description").unwrap_or(node)
+}
+
+fn property(node: &Node, name: &str) -> Option<String> {
+    if let Some(attr) = node.attributes().find(|x| x.name() == name) {
+        return Some(attr.value().trim().to_owned());
+    }
+    child(node, name).and_then(|x| x.text()).map(|x| x.trim().to_owned())
+}
+
+fn number(node: &Node, name: &str) -> Option<f64> {
+    property(node, name)?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn camera_profiles() {
+        let lcp = r#"<?xml version="1.0" encoding="UTF-8"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about="" xmlns:photoshop="http://ns.adobe.com/photoshop/1.0/" xmlns:stCamera="http://ns.adobe.com/photoshop/1.0/camera-profile">
+   <photoshop:CameraProfiles>
+    <rdf:Seq>
+     <rdf:li rdf:parseType="Resource">
+      <stCamera:Make>Canon</stCamera:Make>
+      <stCamera:Model>Canon EOS 5D Mark II</stCamera:Model>
+      <stCamera:Lens>EF24-105mm f/4L IS USM</stCamera:Lens>
+      <stCamera:CameraRawProfile>False</stCamera:CameraRawProfile>
+      <stCamera:ImageWidth>5616</stCamera:ImageWidth>
+      <stCamera:ImageLength>3744</stCamera:ImageLength>
+      <stCamera:FocalLength>24</stCamera:FocalLength>
+      <stCamera:FocusDistance>0.5</stCamera:FocusDistance>
+      <stCamera:PerspectiveModel>
+       <rdf:Description stCamera:FocalLengthX="0.70" stCamera:FocalLengthY="0.70" stCamera:ImageXCenter="0.5" stCamera:ImageYCenter="0.3333"
+                        stCamera:RadialDistortParam1="-0.2" stCamera:RadialDistortParam2="0.1"/>
+      </stCamera:PerspectiveModel>
+     </rdf:li>
+     <rdf:li>
+      <rdf:Description stCamera:Make="Canon" stCamera:Model="Canon EOS 5D Mark II" stCamera:Lens="EF24-105mm f/4L IS USM" stCamera:CameraRawProfile="False"
+                       stCamera:ImageWidth="5616" stCamera:ImageLength="3744" stCamera:FocalLength="24" stCamera:FocusDistance="10">
+       <stCamera:PerspectiveModel rdf:parseType="Resource">
+        <stCamera:FocalLengthX>0.71</stCamera:FocalLengthX>
+        <stCamera:FocalLengthY>0.71</stCamera:FocalLengthY>
+        <stCamera:RadialDistortParam1>-0.1</stCamera:RadialDistortParam1>
+        <stCamera:TangentialDistortParam1>0.001</stCamera:TangentialDistortParam1>
+       </stCamera:PerspectiveModel>
+      </rdf:Description>
+     </rdf:li>
+     <rdf:li stCamera:Make="Canon" stCamera:Model="Canon EOS 5D Mark II" stCamera:Lens="EF24-105mm f/4L IS USM" stCamera:CameraRawProfile="False"
+             stCamera:ImageWidth="5616" stCamera:ImageLength="3744" stCamera:FocalLength="105">
+      <stCamera:PerspectiveModel><rdf:Description stCamera:FocalLengthX="3.1" stCamera:RadialDistortParam1="0.02"/></stCamera:PerspectiveModel>
+     </rdf:li>
+     <rdf:li stCamera:Make="Canon" stCamera:Model="Canon EOS 5D Mark II" stCamera:Lens="EF24-105mm f/4L IS USM" stCamera:CameraRawProfile="True"
+             stCamera:ImageWidth="5634" stCamera:ImageLength="3753" stCamera:FocalLength="24">
+      <stCamera:PerspectiveModel><rdf:Description stCamera:FocalLengthX="0.7" stCamera:RadialDistortParam1="-0.1"/></stCamera:PerspectiveModel>
+     </rdf:li>
+    </rdf:Seq>
+   </photoshop:CameraProfiles>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>"#;
+        let profiles = import(lcp).unwrap();
+        assert_eq!(profiles.len(), 1);
+        let p = &profiles[0];
+        assert_eq!(p.distortion_model.as_deref(), Some("opencv_standard"));
+        assert_eq!((p.calib_dimension.w, p.calib_dimension.h), (5616, 3744));
+        // The farther focus distance at 24mm
+        assert_eq!(p.fisheye_params.distortion_coeffs, vec![-0.1, 0.0, 0.001, 0.0, 0.0]);
+        assert!((p.fisheye_params.camera_matrix[0][0] - 0.71 * 5616.0).abs() < 1e-6);
+        assert!((p.fisheye_params.camera_matrix[1][2] - 1872.0).abs() < 1e-6);
+        assert_eq!(p.interpolations.as_ref().and_then(|x| x.as_object()).map(|x| x.len()), Some(2));
+    }
+
+    #[test]
+    fn export_round_trip() {
+        let profile = import(r#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+            <rdf:Description xmlns:stCamera="http://ns.adobe.com/photoshop/1.0/camera-profile">
+            <rdf:li stCamera:Make="Sony" stCamera:Model="ILCE-7M3" stCamera:Lens="FE 16-35mm" stCamera:ImageWidth="6000" stCamera:ImageLength="4000" stCamera:FocalLength="16">
+             <stCamera:PerspectiveModel stCamera:FocalLengthX="0.45" stCamera:ImageXCenter="0.501" stCamera:ImageYCenter="0.332" stCamera:RadialDistortParam1="-0.1" stCamera:RadialDistortParam2="0.02"/>
+            </rdf:li>
+            <rdf:li stCamera:Make="Sony" stCamera:Model="ILCE-7M3" stCamera:Lens="FE 16-35mm" stCamera:ImageWidth="6000" stCamera:ImageLength="4000" stCamera:FocalLength="35">
+             <stCamera:PerspectiveModel stCamera:FocalLengthX="0.98" stCamera:RadialDistortParam1="0.01"/>
+            </rdf:li>
+            </rdf:Description></rdf:RDF></x:xmpmeta>"#).unwrap().remove(0);
+
+        let (xml, error) = export(&profile, "opencv_standard").unwrap();
+        assert!(error.max < 0.01, "{error:?}");
+        let p = &import(&xml).unwrap()[0];
+        assert_eq!(p.lens_model, "FE 16-35mm");
+        assert_eq!(p.interpolations.as_ref().and_then(|x| x.as_object()).map(|x| x.len()), Some(2));
+        assert!((p.fisheye_params.camera_matrix[1][2] - 0.332 * 6000.0).abs() < 0.01);
+        assert!((p.fisheye_params.distortion_coeffs[0] + 0.1).abs() < 1e-4, "{:?}", p.fisheye_params.distortion_coeffs);
+
+        // A rectilinear lens in the fisheye model
+        let (xml, error) = export(&profile, "opencv_fisheye").unwrap();
+        assert!(error.rms < 10.0, "{error:?}");
+        assert!(import(&xml).unwrap()[0].distortion_model.as_deref() == Some("opencv_fisheye"));
+    }
+}
@@ -0,0 +1,328 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Lensfun database: https://lensfun.github.io/manual/latest/elem_lens.html
+// <lensdatabase>
+//     <lens>
+//         <maker>Canon</maker>
+//         <model>Canon EF-S 18-55mm f/3.5-5.6 IS</model>
+//         <mount>Canon EF-S</mount>
+//         <cropfactor>1.6</cropfactor>
+//         <aspect-ratio>3:2</aspect-ratio>
+//         <calibration>
+//             <distortion model="ptlens" focal="18" a="0.0131" b="-0.0439" c="0.0036"/>
+//             <distortion model="poly3" focal="55" k1="0.0046"/>
+//         </calibration>
+//     </lens>
+// </lensdatabase>
+// Only rectilinear lenses are supported, the coefficients of other projections are relative to their own mapping.
+// Calibrations are relative to the sensor they were made with, so a lens calibrated with multiple crop factors
+// or aspect ratios becomes one profile for each of them.
+// Exported lenses always have `real-focal`, because the fitted focal length rarely matches the one derived from the nominal focal length.
+// Lensfun has no principal point, so the export is fitted at the center of the image.
+
+use roxmltree::{ Document, Node };
+use crate::LensProfile;
+use crate::GyroflowCoreError;
+use super::{ build_profile, calibrated_profiles, escape_xml, gyroflow_to_hugin, hugin_to_gyroflow, hugin_unit_mm, reference_size, refit, Calibration, FitError, ProfileInfo };
+
+/// Distortion models which can be exported, the first one is the default
+pub const EXPORT_MODELS: &[&str] = &["ptlens", "poly5"];
+
+pub fn import(data: &str) -> Result<Vec<LensProfile>, GyroflowCoreError> {
+    let doc = Document::parse(data).map_err(|e| GyroflowCoreError::LensProfileFormatError(format!("Invalid XML: {e}")))?;
+    let root = doc.root_element();
+    if root.tag_name().name() != "lensdatabase" {
+        return Err(GyroflowCoreError::LensProfileFormatError("Not a Lensfun database".into()));
+    }
+
+    let mut profiles = Vec::new();
+    for lens in root.children().filter(|x| x.has_tag_name("lens")) {
+        let maker = text(&lens, "maker").unwrap_or_default();
+        let model = text(&lens, "model").unwrap_or_default();
+        let projection = text(&lens, "type").unwrap_or("rectilinear");
+        if projection != "rectilinear" {
+            log::debug!("Skipping {maker} {model}, {projection} lenses are not supported");
+            continue;
+        }
+        let crop_factor = text(&lens, "cropfactor").and_then(|x| x.parse::<f64>().ok()).unwrap_or(1.0);
+        let aspect_ratio = text(&lens, "aspect-ratio").and_then(parse_aspect_ratio).unwrap_or(1.5);
+
+        // ((crop factor, aspect ratio), calibrations)
+        let mut groups: Vec<((f64, f64), Vec<Calibration>)> = Vec::new();
+        for calibration in lens.children().filter(|x| x.has_tag_name("calibration")) {
+            // Database version 2 can have the sensor per calibration
+            let crop_factor  = calibration.attribute("cropfactor").and_then(|x| x.parse::<f64>().ok()).unwrap_or(crop_factor);
+            let aspect_ratio = calibration.attribute("aspect-ratio").and_then(parse_aspect_ratio).unwrap_or(aspect_ratio);
+            let unit_mm = hugin_unit_mm(crop_factor, aspect_ratio);
+            let size = reference_size(aspect_ratio);
+            let unit_px = size.0.min(size.1) as f64 / 2.0;
+            let sensor = (crop_factor, aspect_ratio);
+            let group = match groups.iter().position(|(s, _)| *s == sensor) {
+                Some(i) => i,
+                None => { groups.push((sensor, Vec::new())); groups.len() - 1 }
+            };
+
+            for distortion in calibration.children().filter(|x| x.has_tag_name("distortion")) {
+                let Some(focal) = attr(&distortion, "focal") else { continue; };
+                let real_focal = attr(&distortion, "real-focal");
+                let (model, mut coeffs) = match distortion.attribute("model") {
+                    Some("ptlens") => ("ptlens", vec![attr(&distortion, "a").unwrap_or(0.0), attr(&distortion, "b").unwrap_or(0.0), attr(&distortion, "c").unwrap_or(0.0)]),
+                    Some("poly3")  => ("poly3",  vec![attr(&distortion, "k1").unwrap_or(0.0)]),
+                    Some("poly5")  => ("poly5",  vec![attr(&distortion, "k1").unwrap_or(0.0), attr(&distortion, "k2").unwrap_or(0.0)]),
+                    other => {
+                        log::debug!("Skipping {maker} {model} at {focal}mm, distortion model {other:?} is not supported");
+                        continue;
+                    }
+                };
+                let focal_units = hugin_to_gyroflow(model, &mut coeffs, focal / unit_mm, real_focal.map(|x| x / unit_mm));
+                let focal_px = focal_units * unit_px;
+                groups[group].1.push(Calibration {
+                    focal_length: Some(focal),
+                    focal_px: (focal_px, focal_px),
+                    center: (size.0 as f64 / 2.0, size.1 as f64 / 2.0),
+                    model,
+                    coeffs
+                });
+            }
+        }
+
+        let multiple_sensors = groups.len() > 1;
+        for ((crop_factor, aspect_ratio), calibrations) in groups {
+            // Tell the profiles of the same lens apart by the sensor
+            let sensor = if multiple_sensors { format!("crop factor {crop_factor}") } else { String::new() };
+            let info = ProfileInfo {
+                brand: maker,
+                model,
+                lens: &sensor,
+                setting: text(&lens, "mount").unwrap_or_default(),
+                size: reference_size(aspect_ratio),
+                crop_factor: Some(crop_factor),
+                calibrated_by: "Lensfun",
+            };
+            if let Some(profile) = build_profile(&info, calibrations) {
+                profiles.push(profile);
+            }
+        }
+    }
+    Ok(profiles)
+}
+
+/// Whether the root element of the XML document is a Lensfun database
+pub fn is_database(data: &str) -> bool {
+    super::root_element_name(data) == Some("lensdatabase")
+}
+
+/// Lensfun database with the lens of the profile and a camera with the same mount. The crop factor of the profile is required
+pub fn export(profile: &LensProfile, model: &str) -> Result<(String, FitError), GyroflowCoreError> {
+    let err = |msg: String| GyroflowCoreError::LensProfileFormatError(msg);
+    if !EXPORT_MODELS.contains(&model) {
+        return Err(err(format!("Distortion model {model} can't be exported to Lensfun")));
+    }
+    let crop_factor = profile.crop_factor.filter(|x| *x > 0.0).ok_or_else(|| err(format!("The crop factor is required for Lensfun: {}", profile.get_name())))?;
+    let (w, h) = (profile.calib_dimension.w as f64, profile.calib_dimension.h as f64);
+    if w <= 0.0 || h <= 0.0 {
+        return Err(err(format!("The profile doesn't have a calibration size: {}", profile.get_name())));
+    }
+    let aspect_ratio = w.max(h) / w.min(h);
+    let unit_mm = hugin_unit_mm(crop_factor, aspect_ratio);
+    let unit_px = w.min(h) / 2.0;
+
+    let mut distortions = Vec::new();
+    let mut errors = Vec::new();
+    for p in calibrated_profiles(profile) {
+        let (calibration, error) = refit(&p, model, true)?;
+        let mut k = calibration.coeffs.clone();
+        let real_focal = calibration.focal_px.0 / unit_px;
+        let d = gyroflow_to_hugin(model, &mut k, real_focal);
+        let real_focal_mm = real_focal * unit_mm;
+        let focal = calibration.focal_length.unwrap_or(real_focal_mm / d);
+        let coeffs = match model {
+            "ptlens" => format!(r#"a="{:.6}" b="{:.6}" c="{:.6}""#, k[0], k[1], k[2]),
+            _        => format!(r#"k1="{:.6}" k2="{:.6}""#, k[0], k[1]),
+        };
+        distortions.push(format!(r#"            <distortion model="{model}" focal="{focal:.3}" real-focal="{real_focal_mm:.4}" {coeffs}/>"#));
+        errors.push(error);
+    }
+    let error = FitError::combine(&errors);
+
+    let brand = if profile.camera_brand.is_empty() { "Generic" } else { &profile.camera_brand };
+    let lens = [&profile.camera_model, &profile.lens_model, &profile.camera_setting].into_iter().filter(|x| !x.is_empty()).cloned().collect::<Vec<_>>().join(" ");
+    // Fixed lens cameras have a mount named after the camera
+    let mount = format!("{brand}{}", profile.camera_model).replace(' ', "");
+    let xml = format!(r#"<?xml version="1.0" encoding="UTF-8"?>
+<!-- Exported from Gyroflow profile {name}, fit error: {rms:.3} px RMS, {max:.3} px max at {w}x{h} -->
+<lensdatabase version="2">
+    <camera>
+        <maker>{brand}</maker>
+        <model>{model_name}</model>
+        <mount>{mount}</mount>
+        <cropfactor>{crop_factor:.3}</cropfactor>
+    </camera>
+    <lens>
+        <maker>{brand}</maker>
+        <model>{lens}</model>
+        <mount>{mount}</mount>
+        <cropfactor>{crop_factor:.3}</cropfactor>
+        <aspect-ratio>{aspect_ratio:.4}</aspect-ratio>
+        <calibration>
+{distortions}
+        </calibration>
+    </lens>
+</lensdatabase>
+"#,
+        name = profile.get_name().replace("--", "-"),
+        rms = error.rms,
+        max = error.max,
+        brand = escape_xml(brand),
+        model_name = escape_xml(&profile.camera_model),
+        mount = escape_xml(&mount),
+        lens = escape_xml(&lens),
+        distortions = distortions.join("\n"),
+    );
+    Ok((xml, error))
+}
+
+/// Text of the first child element with this name, without the translations
+fn text<'a>(node: &Node<'a, '_>, name: &str) -> Option<&'a str> {
+    node.children().find(|x| x.has_tag_name(name) && x.attribute("lang").is_none()).and_then(|x| x.text()).map(str::trim)
+}
+
+fn attr(node: &Node, name: &str) -> Option<f64> {
+    node.attribute(name)?.trim().parse().ok()
+}
+
+/// "3:2" or "1.5"
+fn parse_aspect_ratio(s: &str) -> Option<f64> {
+    let ratio = match s.split_once(':') {
+        Some((w, h)) => w.trim().parse::<f64>().ok()? / h.trim().parse::<f64>().ok()?,
+        None => s.trim().parse().ok()?
+    };
+    (ratio.is_finite() && ratio > 0.0).then_some(ratio.max(1.0 / ratio))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zoom_lens() {
+        let xml = r#"<lensdatabase version="2">
+            <camera><maker>Canon</maker><model>Canon EOS 550D</model><mount>Canon EF-S</mount><cropfactor>1.613</cropfactor></camera>
+            <lens>
+                <maker>Canon</maker>
+                <model>Canon EF-S 18-55mm f/3.5-5.6 IS</model>
+                <model lang="en">EF-S 18-55mm</model>
+                <mount>Canon EF-S</mount>
+                <cropfactor>1.611</cropfactor>
+                <calibration>
+                    <distortion model="ptlens" focal="18" a="0.0131" b="-0.0439" c="0.0036"/>
+                    <distortion model="ptlens" focal="35" a="0.0024" b="-0.0045" c="-0.0013"/>
+                    <distortion model="poly3" focal="55" k1="0.0046"/>
+                    <tca model="poly3" focal="18" vr="1.0001" vb="1.0002"/>
+                </calibration>
+            </lens>
+            <lens>
+                <maker>Samyang</maker>
+                <model>Samyang 8mm f/3.5 Fish-Eye</model>
+                <type>fisheye-stereographic</type>
+                <calibration><distortion model="ptlens" focal="8" a="0.01" b="0.02" c="0.03"/></calibration>
+            </lens>
+        </lensdatabase>"#;
+        let profiles = import(xml).unwrap();
+        assert_eq!(profiles.len(), 1);
+        let p = &profiles[0];
+        assert_eq!(p.camera_model, "Canon EF-S 18-55mm f/3.5-5.6 IS");
+        assert_eq!(p.distortion_model.as_deref(), Some("ptlens"));
+        assert_eq!((p.calib_dimension.w, p.calib_dimension.h), (6000, 4000));
+        assert_eq!(p.focal_length, Some(18.0));
+        assert_eq!(p.interpolations.as_ref().and_then(|x| x.as_object()).map(|x| x.len()), Some(2));
+
+        // 18mm on APS-C is about 64° of horizontal field of view
+        let fov = 2.0 * (3000.0 / p.fisheye_params.camera_matrix[0][0]).atan().to_degrees();
+        assert!((fov - 64.0).abs() < 2.0, "fov: {fov}");
+
+        assert!(import("<lensdatabase/>").unwrap().is_empty());
+        assert!(import("<xmp/>").is_err());
+    }
+
+    #[test]
+    fn multiple_sensors() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+        <!DOCTYPE lensdatabase SYSTEM "lensfun-database.dtd">
+        <!-- Lenses calibrated on full frame and APS-C bodies -->
+        <lensdatabase version="2">
+            <lens>
+                <maker>Sigma</maker>
+                <model>Sigma 24-70mm f/2.8</model>
+                <mount>Canon EF</mount>
+                <cropfactor>1.0</cropfactor>
+                <calibration>
+                    <distortion model="ptlens" focal="24" a="0.01" b="-0.03" c="0.01"/>
+                </calibration>
+                <calibration cropfactor="1.6">
+                    <distortion model="ptlens" focal="24" a="0.002" b="-0.008" c="0.003"/>
+                </calibration>
+                <calibration>
+                    <distortion model="ptlens" focal="70" a="0.0" b="0.005" c="0.0"/>
+                </calibration>
+            </lens>
+        </lensdatabase>"#;
+        assert!(crate::lens_profile_formats::is_supported("lenses.XML", xml));
+        assert!(!crate::lens_profile_formats::is_supported("lenses.xml", r#"<?xml version="1.0"?><project><lens/></project>"#));
+
+        let profiles = import(xml).unwrap();
+        assert_eq!(profiles.len(), 2);
+        let (full_frame, aps_c) = (&profiles[0], &profiles[1]);
+        assert_eq!(full_frame.crop_factor, Some(1.0));
+        assert_eq!(aps_c.crop_factor, Some(1.6));
+        assert_eq!(full_frame.interpolations.as_ref().and_then(|x| x.as_object()).map(|x| x.len()), Some(2));
+        assert!(aps_c.interpolations.is_none());
+        assert_ne!(full_frame.identifier, aps_c.identifier);
+        assert_ne!(full_frame.get_name(), aps_c.get_name());
+
+        // The same focal length has a narrower field of view on the smaller sensor
+        let fov = |p: &LensProfile| 2.0 * (3000.0 / p.fisheye_params.camera_matrix[0][0]).atan().to_degrees();
+        assert!((fov(full_frame) - 74.0).abs() < 3.0, "fov: {}", fov(full_frame));
+        assert!((fov(aps_c) - 50.0).abs() < 3.0, "fov: {}", fov(aps_c));
+    }
+
+    #[test]
+    fn export_round_trip() {
+        let mut profile = LensProfile::default();
+        profile.camera_brand = "GoPro".into();
+        profile.camera_model = "HERO & Co".into();
+        profile.calib_dimension = crate::lens_profile::Dimensions { w: 4000, h: 3000 };
+        profile.input_horizontal_stretch = 1.0;
+        profile.input_vertical_stretch = 1.0;
+        profile.fisheye_params.camera_matrix = vec![[3000.0, 0.0, 2000.0], [0.0, 3000.0, 1500.0], [0.0, 0.0, 1.0]];
+        profile.fisheye_params.distortion_coeffs = vec![0.02, -0.01, 0.002, 0.0];
+        profile.focal_length = Some(3.0);
+        assert!(export(&profile, "ptlens").is_err(), "crop factor is required");
+
+        profile.crop_factor = Some(5.6);
+        for model in EXPORT_MODELS {
+            let (xml, error) = export(&profile, model).unwrap();
+            assert!(error.rms < 2.0 && error.max < 6.0, "{model}: {error:?}");
+
+            let imported = import(&xml).unwrap();
+            assert_eq!(imported.len(), 1);
+            let p = &imported[0];
+            assert_eq!(p.camera_model, "HERO & Co");
+            assert_eq!(p.distortion_model.as_deref(), Some(*model));
+            assert_eq!((p.calib_dimension.w, p.calib_dimension.h), (6000, 4500));
+
+            // The same rays in both profiles, the imported one is 1.5x bigger
+            let fisheye = crate::calibration::distortion::Projection::new("opencv_fisheye", &profile.fisheye_params.distortion_coeffs).unwrap();
+            let fitted = crate::calibration::distortion::Projection::new(model, &p.fisheye_params.distortion_coeffs).unwrap();
+            let m = &p.fisheye_params.camera_matrix;
+            let intrinsics = [&[m[0][0], m[1][1], m[0][2], m[1][2]][..], &p.fisheye_params.distortion_coeffs].concat();
+            for theta in [10.0f64, 20.0, 35.0] {
+                let ray = nalgebra::Vector3::new(theta.to_radians().sin(), 0.0, theta.to_radians().cos());
+                let a = fisheye.project(&[3000.0, 3000.0, 2000.0, 1500.0, 0.02, -0.01, 0.002, 0.0], &ray).unwrap();
+                let b = fitted.project(&intrinsics, &ray).unwrap();
+                assert!((a.0 - b.0 / 1.5).abs() < error.max + 0.1, "{model} at {theta}: {a:?} vs {b:?}");
+            }
+        }
+    }
+}
@@ -0,0 +1,410 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Lens profiles of other applications: Lensfun database (.xml), Adobe Lens Profile (.lcp) and Hugin/PTGui projects (.pto).
+// Their calibrations don't depend on the resolution, so the profiles are created for the image size of the calibration
+// (or `REFERENCE_WIDTH` when it's unknown) and Gyroflow scales them to the video like any other profile.
+// Calibrations at multiple focal lengths become `interpolations`, keyed by the focal length in mm like the lens telemetry.
+// Exporting refits the profile to a model of the target format: rays covering the whole image are projected with the profile's `distort_point`
+// and the target model is fitted to the resulting radii with linear least squares, then the error is measured by projecting the rays again with it.
+
+pub mod lensfun;
+pub mod lcp;
+pub mod pto;
+
+use std::collections::BTreeMap;
+use nalgebra::{ DMatrix, DVector, Vector3 };
+use crate::calibration::distortion::Projection;
+use crate::lens_profile::{ CameraParams, Dimensions, LensProfile };
+use crate::stabilization::distortion_models::DistortionModel;
+use crate::GyroflowCoreError;
+
+/// Width of the profile when the format doesn't store the image size
+pub const REFERENCE_WIDTH: usize = 6000;
+
+/// Largest angle from the optical axis which is fitted with rectilinear models, in degrees
+const MAX_RECTILINEAR_ANGLE: f64 = 80.0;
+const MAX_ANGLE: f64 = 89.0;
+const RADIAL_SAMPLES: usize = 100;
+const AZIMUTH_SAMPLES: usize = 16;
+
+/// Whether the file can be imported judging only by its name, to avoid reading files which certainly aren't lens profiles
+pub fn has_supported_extension(path: &str) -> bool {
+    let path = path.to_ascii_lowercase();
+    path.ends_with(".xml") || path.ends_with(".lcp") || path.ends_with(".pto")
+}
+
+/// Whether the file can be imported. `.xml` is used by many other applications, so the root element has to be a Lensfun database
+pub fn is_supported(path: &str, data: &str) -> bool {
+    let lower = path.to_ascii_lowercase();
+    if lower.ends_with(".xml") {
+        lensfun::is_database(data)
+    } else if lower.ends_with(".lcp") {
+        root_element_name(data) == Some("xmpmeta")
+    } else {
+        lower.ends_with(".pto")
+    }
+}
+
+/// Local name of the root element of an XML document, skipping the declaration, processing instructions, comments and the doctype
+pub(crate) fn root_element_name(data: &str) -> Option<&str> {
+    let mut rest = data;
+    loop {
+        rest = &rest[rest.find('<')? + 1..];
+        if let Some(comment) = rest.strip_prefix("!--") {
+            rest = &comment[comment.find("-->")? + 3..];
+        } else if rest.starts_with('?') || rest.starts_with('!') {
+            rest = &rest[rest.find('>')? + 1..];
+        } else {
+            let name = &rest[..rest.find(|c: char| c.is_whitespace() || c == '>' || c == '/')?];
+            return name.rsplit(':').next();
+        }
+    }
+}
+
+/// Converts all lenses in the file, the format is detected from the extension of `path`
+pub fn import(data: &str, path: &str) -> Result<Vec<LensProfile>, GyroflowCoreError> {
+    let lower = path.to_ascii_lowercase();
+    let profiles = if lower.ends_with(".xml") {
+        lensfun::import(data)?
+    } else if lower.ends_with(".lcp") {
+        lcp::import(data)?
+    } else if lower.ends_with(".pto") {
+        let name = std::path::Path::new(path).file_stem().map(|x| x.to_string_lossy().to_string()).unwrap_or_default();
+        pto::import(data, &name)?
+    } else {
+        return Err(GyroflowCoreError::LensProfileFormatError(format!("Unsupported lens profile format: {path}")));
+    };
+    if profiles.is_empty() {
+        return Err(GyroflowCoreError::LensProfileFormatError(format!("No supported lens calibration found in {path}")));
+    }
+    Ok(profiles)
+}
+
+pub fn is_exportable(path: &str) -> bool {
+    let path = path.to_ascii_lowercase();
+    path.ends_with(".xml") || path.ends_with(".lcp")
+}
+
+/// Converts the profile to the format detected from the extension of `path`. `model` is one of the format's `EXPORT_MODELS`, by default the closest one to the profile's model.
+/// Returns the file contents and the error of the refitted model
+pub fn export(profile: &LensProfile, path: &str, model: Option<&str>) -> Result<(String, FitError), GyroflowCoreError> {
+    let lower = path.to_ascii_lowercase();
+    if lower.ends_with(".xml") {
+        lensfun::export(profile, model.unwrap_or(lensfun::EXPORT_MODELS[0]))
+    } else if lower.ends_with(".lcp") {
+        let is_fisheye = matches!(profile.distortion_model.as_deref(), None | Some("opencv_fisheye" | "insta360" | "sony"));
+        lcp::export(profile, model.unwrap_or(lcp::EXPORT_MODELS[is_fisheye as usize]))
+    } else {
+        Err(GyroflowCoreError::LensProfileFormatError(format!("Unsupported lens profile format: {path}")))
+    }
+}
+
+/// Difference between the refitted model and the original profile, in pixels of the profile's size
+#[derive(Clone, Copy, Debug, Default, serde::Serialize)]
+pub struct FitError {
+    pub rms: f64,
+    pub max: f64,
+}
+
+impl FitError {
+    /// Combined error of refits at multiple focal lengths
+    fn combine(errors: &[FitError]) -> FitError {
+        FitError {
+            rms: (errors.iter().map(|x| x.rms.powi(2)).sum::<f64>() / errors.len().max(1) as f64).sqrt(),
+            max: errors.iter().map(|x| x.max).fold(0.0, f64::max),
+        }
+    }
+}
+
+/// Calibration at a single focal length, in pixels of the profile's size
+#[derive(Clone, Debug)]
+pub(crate) struct Calibration {
+    pub focal_length: Option<f64>,
+    pub focal_px: (f64, f64),
+    pub center: (f64, f64),
+    pub model: &'static str,
+    pub coeffs: Vec<f64>,
+}
+
+pub(crate) struct ProfileInfo<'a> {
+    pub brand: &'a str,
+    pub model: &'a str,
+    pub lens: &'a str,
+    pub setting: &'a str,
+    pub size: (usize, usize),
+    pub crop_factor: Option<f64>,
+    pub calibrated_by: &'a str,
+}
+
+/// Hugin and Lensfun describe the distortion in units of half of the shorter image side.
+/// `focal` is the nominal focal length in these units and `real_focal` the real one if known.
+/// Converts the coefficients to Gyroflow's normalized coordinates and returns the real focal length.
+pub(crate) fn hugin_to_gyroflow(model: &str, k: &mut [f64], focal: f64, real_focal: Option<f64>) -> f64 {
+    let d = match model {
+        "ptlens" => 1.0 - k[0] - k[1] - k[2],
+        "poly3"  => 1.0 - k[0],
+        _ => 1.0
+    };
+    let real_focal = real_focal.unwrap_or(focal * d);
+    DistortionModel::from_name(model).rescale_coeffs(k, real_focal);
+    real_focal
+}
+
+/// Half of the shorter image side in mm, for a sensor with `crop_factor` and `aspect_ratio` (>= 1)
+pub(crate) fn hugin_unit_mm(crop_factor: f64, aspect_ratio: f64) -> f64 {
+    36.0f64.hypot(24.0) / crop_factor / aspect_ratio.hypot(1.0) / 2.0
+}
+
+/// One profile from calibrations at different focal lengths. Only the calibrations with the most common model are used, because they can't be interpolated otherwise
+pub(crate) fn build_profile(info: &ProfileInfo, mut calibrations: Vec<Calibration>) -> Option<LensProfile> {
+    let mut counts = BTreeMap::<&str, usize>::new();
+    for c in &calibrations { *counts.entry(c.model).or_default() += 1; }
+    let model = counts.into_iter().max_by_key(|(_, count)| *count)?.0;
+    calibrations.retain(|c| c.model == model);
+    calibrations.sort_by(|a, b| a.focal_length.unwrap_or_default().total_cmp(&b.focal_length.unwrap_or_default()));
+    calibrations.dedup_by(|a, b| a.focal_length == b.focal_length);
+
+    let camera_matrix = |c: &Calibration| vec![
+        [c.focal_px.0, 0.0,          c.center.0],
+        [0.0,          c.focal_px.1, c.center.1],
+        [0.0,          0.0,          1.0]
+    ];
+    let first = calibrations.first()?;
+
+    let mut profile = LensProfile::default();
+    profile.camera_brand   = info.brand.to_owned();
+    profile.camera_model   = info.model.to_owned();
+    profile.lens_model     = info.lens.to_owned();
+    profile.camera_setting = info.setting.to_owned();
+    profile.calibrated_by  = info.calibrated_by.to_owned();
+    profile.calib_dimension = Dimensions { w: info.size.0, h: info.size.1 };
+    profile.orig_dimension  = Dimensions { w: info.size.0, h: info.size.1 };
+    profile.input_horizontal_stretch = 1.0;
+    profile.input_vertical_stretch   = 1.0;
+    profile.fisheye_params = CameraParams {
+        camera_matrix: camera_matrix(first),
+        distortion_coeffs: first.coeffs.clone(),
+        ..Default::default()
+    };
+    profile.distortion_model = Some(model.to_owned());
+    profile.focal_length = first.focal_length;
+    profile.crop_factor = info.crop_factor;
+    if calibrations.len() > 1 {
+        let interpolations: serde_json::Map<String, serde_json::Value> = calibrations.iter().filter_map(|c| {
+            Some((c.focal_length?.to_string(), serde_json::json!({
+                "camera_matrix": camera_matrix(c),
+                "distortion_coeffs": c.coeffs,
+                "focal_length": c.focal_length,
+            })))
+        }).collect();
+        profile.interpolations = Some(serde_json::Value::Object(interpolations));
+    }
+    let id = format!("{}|{}|{}|{}|{}x{}", info.brand, info.model, info.lens, info.setting, info.size.0, info.size.1);
+    profile.identifier = format!("{}_{:08x}", info.calibrated_by.to_ascii_lowercase().replace(' ', "_"), crc32fast::hash(id.as_bytes()));
+    profile.init();
+    Some(profile)
+}
+
+/// Inverse of `hugin_to_gyroflow` for the real focal length `real_focal` in Hugin's units, returns the factor `d` of the nominal focal length
+pub(crate) fn gyroflow_to_hugin(model: &str, k: &mut [f64], real_focal: f64) -> f64 {
+    // Hugin's coefficients are the normalized ones scaled by powers of `d`, and `d` depends on them: `d = 1 - sum(c * d^power)`
+    let terms: Vec<(f64, i32)> = match model {
+        "ptlens" => vec![(k[0] / real_focal.powi(3), 4), (k[1] / real_focal.powi(2), 3), (k[2] / real_focal, 2)],
+        "poly3"  => vec![(k[0] / real_focal.powi(2), 3)],
+        "poly5"  => {
+            k[0] /= real_focal.powi(2);
+            k[1] /= real_focal.powi(4);
+            return 1.0;
+        },
+        _ => return 1.0
+    };
+    let mut d = 1.0f64;
+    for _ in 0..50 {
+        let f  = d - 1.0 + terms.iter().map(|(c, p)| c * d.powi(*p)).sum::<f64>();
+        let df = 1.0 + terms.iter().map(|(c, p)| c * *p as f64 * d.powi(p - 1)).sum::<f64>();
+        if df.abs() < 1e-12 { break; }
+        d -= f / df;
+    }
+    for (k, (c, p)) in k.iter_mut().zip(&terms) {
+        *k = c * d.powi(*p);
+    }
+    d
+}
+
+/// The profile at each focal length of its `interpolations`, or the profile itself
+pub(crate) fn calibrated_profiles(profile: &LensProfile) -> Vec<LensProfile> {
+    let mut ret = Vec::new();
+    if let Some(serde_json::Value::Object(map)) = &profile.interpolations {
+        for (key, v) in map {
+            let Some((focal_length, focus_distance)) = crate::lens_profile_interpolation::parse_key(key) else { continue; };
+            // Interpolations which refer to other profiles in the database are not resolved here
+            let Some(camera_matrix) = v.get("camera_matrix").and_then(|x| serde_json::from_value::<Vec<[f64; 3]>>(x.clone()).ok()) else {
+                log::warn!("Skipping the calibration at {key}, it doesn't have a camera matrix");
+                continue;
+            };
+            let mut p = profile.clone();
+            p.interpolations = None;
+            p.focal_length = v.get("focal_length").and_then(|x| x.as_f64()).or(Some(focal_length));
+            p.focus_distance = focus_distance.or_else(|| v.get("focus_distance").and_then(|x| x.as_f64()));
+            p.fisheye_params.camera_matrix = camera_matrix;
+            if let Some(coeffs) = v.get("distortion_coeffs").and_then(|x| serde_json::from_value::<Vec<f64>>(x.clone()).ok()) {
+                p.fisheye_params.distortion_coeffs = coeffs;
+            }
+            ret.push(p);
+        }
+        ret.sort_by(|a, b| a.focal_length.unwrap_or_default().total_cmp(&b.focal_length.unwrap_or_default()));
+    }
+    if ret.is_empty() {
+        ret.push(profile.clone());
+    }
+    ret
+}
+
+/// Fits the `target` model to the profile. With `centered`, the format has no principal point and no pixel aspect ratio,
+/// so the calibration is at the center of the image with the same focal length in both axes.
+pub(crate) fn refit(profile: &LensProfile, target: &str, centered: bool) -> Result<(Calibration, FitError), GyroflowCoreError> {
+    let err = |msg: String| GyroflowCoreError::LensProfileFormatError(msg);
+    if profile.digital_lens.is_some() {
+        return Err(err(format!("Profiles with a digital lens can't be exported: {}", profile.get_name())));
+    }
+    if (profile.input_horizontal_stretch - 1.0).abs() > 1e-6 || (profile.input_vertical_stretch - 1.0).abs() > 1e-6 {
+        return Err(err(format!("Profiles with stretched input can't be exported: {}", profile.get_name())));
+    }
+    let m = &profile.fisheye_params.camera_matrix;
+    if m.len() != 3 || m[0][0] <= 0.0 || m[1][1] <= 0.0 {
+        return Err(err(format!("The profile doesn't have a valid camera matrix: {}", profile.get_name())));
+    }
+    let source_model = profile.distortion_model.as_deref().unwrap_or("opencv_fisheye");
+    let coeffs = &profile.fisheye_params.distortion_coeffs;
+    let source = Projection::new(source_model, coeffs).ok_or_else(|| err(format!("Distortion model {source_model} can't be exported")))?;
+    let source_intrinsics = [&[m[0][0], m[1][1], m[0][2], m[1][2]][..], &coeffs[..source.num_intrinsics() - 4]].concat();
+
+    let (target, powers, is_angle): (&'static str, &[i32], bool) = match target {
+        "ptlens"          => ("ptlens",          &[1, 2, 3, 4], false),
+        "poly3"           => ("poly3",           &[1, 3],       false),
+        "poly5"           => ("poly5",           &[1, 3, 5],    false),
+        "opencv_standard" => ("opencv_standard", &[1, 3, 5, 7], false),
+        "opencv_fisheye"  => ("opencv_fisheye",  &[1, 3, 5],    true),
+        _ => return Err(err(format!("Distortion model {target} can't be fitted")))
+    };
+
+    let (w, h) = (profile.calib_dimension.w as f64, profile.calib_dimension.h as f64);
+    let (fx, fy, cx, cy) = if centered {
+        let f = (m[0][0] + m[1][1]) / 2.0;
+        (f, f, w / 2.0, h / 2.0)
+    } else {
+        (m[0][0], m[1][1], m[0][2], m[1][2])
+    };
+    let normalized_radius = |(x, y): (f64, f64)| ((x - cx) / fx).hypot((y - cy) / fy);
+    let corner = [(0.0, 0.0), (w, 0.0), (0.0, h), (w, h)].into_iter().map(|(x, y)| ((x - m[0][2]) / m[0][0]).hypot((y - m[1][2]) / m[1][1])).fold(0.0, f64::max);
+
+    // The largest angle which is still in the image, as long as the projection is monotonic
+    let ray = |theta: f64, phi: f64| Vector3::new(theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos());
+    let limit = if is_angle { MAX_ANGLE } else { MAX_RECTILINEAR_ANGLE }.to_radians();
+    let step = 0.25f64.to_radians();
+    let (mut max_theta, mut prev_radius) = (0.0, 0.0);
+    let mut theta = step;
+    while theta <= limit {
+        let Some(r) = source.project(&source_intrinsics, &ray(theta, 0.0)).map(|(x, _)| (x - m[0][2]) / m[0][0]) else { break; };
+        if r <= prev_radius { break; }
+        max_theta = theta;
+        prev_radius = r;
+        if r >= corner { break; }
+        theta += step;
+    }
+    if max_theta <= 0.0 {
+        return Err(err(format!("Invalid lens projection: {}", profile.get_name())));
+    }
+
+    let samples: Vec<(Vector3<f64>, (f64, f64))> = (1..=RADIAL_SAMPLES).flat_map(|i| (0..AZIMUTH_SAMPLES).map(move |j| {
+        ray(max_theta * i as f64 / RADIAL_SAMPLES as f64, std::f64::consts::TAU * j as f64 / AZIMUTH_SAMPLES as f64)
+    })).filter_map(|p| Some((p, source.project(&source_intrinsics, &p)?))).collect();
+
+    // The distorted radius is linear in `focal scale * coefficient`, with the basis of powers of the angle or of the undistorted radius
+    let argument = |p: &Vector3<f64>| if is_angle { p.xy().norm().atan2(p.z) } else { p.xy().norm() / p.z };
+    let a = DMatrix::from_fn(samples.len(), powers.len(), |i, j| argument(&samples[i].0).powi(powers[j]));
+    let b = DVector::from_iterator(samples.len(), samples.iter().map(|x| normalized_radius(x.1)));
+    let x = a.svd(true, true).solve(&b, 1e-12).map_err(|e| err(format!("Fitting failed: {e}")))?;
+    let scale = x[0];
+    if !scale.is_finite() || scale <= 0.0 {
+        return Err(err(format!("Fitting failed: {}", profile.get_name())));
+    }
+    let k: Vec<f64> = x.iter().skip(1).map(|v| v / scale).collect();
+    let coeffs = match target {
+        "ptlens"          => vec![k[2], k[1], k[0]],
+        "opencv_standard" => vec![k[0], k[1], 0.0, 0.0, k[2]],
+        "opencv_fisheye"  => vec![k[0], k[1], 0.0, 0.0],
+        _ => k
+    };
+
+    let projection = Projection::new(target, &coeffs).ok_or_else(|| err(format!("Distortion model {target} can't be fitted")))?;
+    let intrinsics = [&[fx * scale, fy * scale, cx, cy][..], &coeffs[..projection.num_intrinsics() - 4]].concat();
+    let errors: Vec<f64> = samples.iter().map(|(p, (u, v))| {
+        projection.project(&intrinsics, p).map(|(x, y)| (x - u).hypot(y - v)).unwrap_or(f64::MAX)
+    }).collect();
+    let error = FitError {
+        rms: (errors.iter().map(|x| x * x).sum::<f64>() / errors.len() as f64).sqrt(),
+        max: errors.iter().copied().fold(0.0, f64::max),
+    };
+
+    Ok((Calibration {
+        focal_length: profile.focal_length,
+        focal_px: (fx * scale, fy * scale),
+        center: (cx, cy),
+        model: target,
+        coeffs
+    }, error))
+}
+
+/// Escapes text for XML elements and attributes
+pub(crate) fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// Size with the given aspect ratio and `REFERENCE_WIDTH`
+pub(crate) fn reference_size(aspect_ratio: f64) -> (usize, usize) {
+    let height = (REFERENCE_WIDTH as f64 / aspect_ratio.max(0.1) / 2.0).round() as usize * 2;
+    (REFERENCE_WIDTH, height)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Both descriptions have to give the same distorted radius in pixels
+    #[test]
+    fn hugin_coefficients() {
+        let (width, height) = (6000.0, 4000.0);
+        let unit_px = height / 2.0;
+        let focal_px = 4500.0;
+        for (model, coeffs) in [("ptlens", vec![0.01, -0.03, 0.02]), ("poly3", vec![-0.02]), ("poly5", vec![-0.02, 0.005])] {
+            let mut k = coeffs.clone();
+            let real_focal = hugin_to_gyroflow(model, &mut k, focal_px / unit_px, None) * unit_px;
+            for r in [100.0, 1000.0, 2500.0, width / 2.0] {
+                let ru = r / unit_px;
+                let rd_hugin = match model {
+                    "ptlens" => ru * (coeffs[0] * ru.powi(3) + coeffs[1] * ru.powi(2) + coeffs[2] * ru + 1.0 - coeffs[0] - coeffs[1] - coeffs[2]),
+                    "poly3"  => ru * (coeffs[0] * ru.powi(2) + 1.0 - coeffs[0]),
+                    _        => ru * (1.0 + coeffs[0] * ru.powi(2) + coeffs[1] * ru.powi(4)),
+                } * unit_px;
+
+                // Same ray in Gyroflow's normalized coordinates
+                let x = r / focal_px;
+                let rd_gyroflow = real_focal * x * match model {
+                    "ptlens" => k[0] * x.powi(3) + k[1] * x.powi(2) + k[2] * x + 1.0,
+                    "poly3"  => k[0] * x.powi(2) + 1.0,
+                    _        => 1.0 + k[0] * x.powi(2) + k[1] * x.powi(4),
+                };
+                assert!((rd_hugin - rd_gyroflow).abs() < 1e-6, "{model} at {r}: {rd_hugin} vs {rd_gyroflow}");
+            }
+
+            // And back
+            let d = gyroflow_to_hugin(model, &mut k, real_focal / unit_px);
+            assert!((real_focal / d - focal_px).abs() < 1e-6, "{model}: {}", real_focal / d);
+            for (a, b) in k.iter().zip(&coeffs) {
+                assert!((a - b).abs() < 1e-9, "{model}: {k:?} vs {coeffs:?}");
+            }
+        }
+    }
+}
@@ -0,0 +1,151 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Hugin and PTGui projects (PanoTools script), the lenses are taken from the image lines:
+// #-hugin  cropFactor=1.6
+// i w6000 h4000 f0 v65.5 Ra0 Rb0 Rc0 Rd0 Re0 Eev0 Er1 Eb1 r0 p0 y0 TrX0 TrY0 TrZ0 j0 a0.0131 b-0.0439 c0.0036 d12.5 e-3 g0 t0 n"IMG_0001.JPG"
+// o w6000 h4000 f0 v65.5 a0.0131 b-0.0439 c0.0036 d0 e0 n"IMG_0002.JPG"
+// `w` and `h` are the image size, `f` the projection (only 0 - rectilinear is supported), `v` the horizontal field of view in degrees,
+// `a`, `b`, `c` the PTLens coefficients and `d`, `e` the shift of the lens center in pixels. `a=0` means the same value as the first image.
+// Images with the same lens parameters share one profile.
+
+use std::collections::HashMap;
+use crate::LensProfile;
+use crate::GyroflowCoreError;
+use super::{ build_profile, hugin_to_gyroflow, hugin_unit_mm, Calibration, ProfileInfo };
+
+pub fn import(data: &str, name: &str) -> Result<Vec<LensProfile>, GyroflowCoreError> {
+    let mut images: Vec<HashMap<String, String>> = Vec::new();
+    let mut crop_factors: Vec<Option<f64>> = Vec::new();
+    let mut crop_factor = None;
+    let mut is_ptgui = false;
+    for line in data.lines().map(str::trim) {
+        if let Some(comment) = line.strip_prefix("#-hugin") {
+            crop_factor = comment.split_whitespace().find_map(|x| x.strip_prefix("cropFactor=")).and_then(|x| x.parse::<f64>().ok());
+            continue;
+        }
+        let Some(params) = line.strip_prefix("i ").or_else(|| line.strip_prefix("o ")) else { continue; };
+        is_ptgui |= line.starts_with("o ");
+        images.push(parse_params(params));
+        crop_factors.push(crop_factor.take());
+    }
+    if images.is_empty() {
+        return Err(GyroflowCoreError::LensProfileFormatError("No images in the project".into()));
+    }
+
+    // Resolves `=N` references to other images
+    let value = |image: usize, key: &str| -> Option<f64> {
+        let mut v = images.get(image)?.get(key)?;
+        for _ in 0..images.len() {
+            match v.strip_prefix('=') {
+                Some(index) => v = images.get(index.parse::<usize>().ok()?)?.get(key)?,
+                None => break
+            }
+        }
+        v.parse().ok()
+    };
+
+    let mut profiles = Vec::new();
+    let mut seen = Vec::new();
+    for (i, crop_factor) in crop_factors.iter().copied().enumerate() {
+        let (Some(w), Some(h), Some(hfov)) = (value(i, "w"), value(i, "h"), value(i, "v")) else { continue; };
+        if w <= 0.0 || h <= 0.0 || hfov <= 0.0 || hfov >= 180.0 { continue; }
+        let projection = value(i, "f").unwrap_or_default() as i32;
+        if projection != 0 {
+            log::debug!("Skipping image {i}, projection {projection} is not supported");
+            continue;
+        }
+        let mut coeffs = vec![value(i, "a").unwrap_or_default(), value(i, "b").unwrap_or_default(), value(i, "c").unwrap_or_default()];
+        let shift = (value(i, "d").unwrap_or_default(), value(i, "e").unwrap_or_default());
+        let lens = (w as usize, h as usize, hfov.to_bits(), coeffs.iter().map(|x| x.to_bits()).collect::<Vec<_>>(), shift.0.to_bits(), shift.1.to_bits());
+        if seen.contains(&lens) { continue; }
+        seen.push(lens);
+
+        let unit_px = w.min(h) / 2.0;
+        let focal_px = (w / 2.0) / (hfov.to_radians() / 2.0).tan();
+        let real_focal_px = hugin_to_gyroflow("ptlens", &mut coeffs, focal_px / unit_px, None) * unit_px;
+
+        // Focal length in mm is only known with the sensor size
+        let focal_length = crop_factor.map(|crop| focal_px / unit_px * hugin_unit_mm(crop, w.max(h) / w.min(h)));
+
+        let setting = if seen.len() > 1 { format!("Lens {}", seen.len()) } else { String::new() };
+        let info = ProfileInfo {
+            brand: if is_ptgui { "PTGui" } else { "Hugin" },
+            model: name,
+            lens: "",
+            setting: &setting,
+            size: (w as usize, h as usize),
+            crop_factor,
+            calibrated_by: if is_ptgui { "PTGui" } else { "Hugin" },
+        };
+        let calibration = Calibration {
+            focal_length,
+            focal_px: (real_focal_px, real_focal_px),
+            center: (w / 2.0 + shift.0, h / 2.0 + shift.1),
+            model: "ptlens",
+            coeffs
+        };
+        if let Some(profile) = build_profile(&info, vec![calibration]) {
+            profiles.push(profile);
+        }
+    }
+    Ok(profiles)
+}
+
+/// Splits `w6000 h4000 n"file name.jpg"` into keys and values. Keys are the leading letters of each parameter
+fn parse_params(line: &str) -> HashMap<String, String> {
+    let mut ret = HashMap::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() { }
+        let mut key = String::new();
+        while let Some(c) = chars.next_if(|c| c.is_ascii_alphabetic()) { key.push(c); }
+        if key.is_empty() {
+            // Unexpected character or the end of line
+            if chars.next().is_none() { break; }
+            continue;
+        }
+        let mut value = String::new();
+        if chars.next_if_eq(&'"').is_some() {
+            for c in chars.by_ref() {
+                if c == '"' { break; }
+                value.push(c);
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) { value.push(c); }
+        }
+        ret.insert(key, value);
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn project() {
+        let pto = "# hugin project file\n\
+            p f2 w3000 h1500 v360 E0 R0 n\"TIFF_m c:LZW\"\n\
+            #-hugin  cropFactor=1.6\n\
+            i w6000 h4000 f0 v65.5 Ra0 Eev0 r0 p0 y0 TrX0 j0 a0.01 b-0.04 c0.003 d10 e-5 g0 t0 n\"IMG 0001.JPG\"\n\
+            #-hugin  cropFactor=1.6\n\
+            i w6000 h4000 f0 v=0 Ra0 Eev0 r0 p0 y20 TrX0 j0 a=0 b=0 c=0 d=0 e=0 g0 t0 n\"IMG 0002.JPG\"\n\
+            i w4000 h6000 f3 v100 a0 b0 c0 d0 e0 n\"fisheye.jpg\"\n";
+        let profiles = import(pto, "panorama").unwrap();
+        assert_eq!(profiles.len(), 1);
+        let p = &profiles[0];
+        assert_eq!(p.camera_brand, "Hugin");
+        assert_eq!(p.camera_model, "panorama");
+        assert_eq!(p.distortion_model.as_deref(), Some("ptlens"));
+        assert_eq!(p.fisheye_params.camera_matrix[0][2], 3010.0);
+        assert_eq!(p.fisheye_params.camera_matrix[1][2], 1995.0);
+        // 65.5° on APS-C is about 17.5mm
+        assert!((p.focal_length.unwrap() - 17.5).abs() < 0.1, "{:?}", p.focal_length);
+
+        let params = parse_params("w10 n\"a b\" v=0 Eev-1.5");
+        assert_eq!(params["n"], "a b");
+        assert_eq!(params["v"], "=0");
+        assert_eq!(params["Eev"], "-1.5");
+    }
+}
@@ -0,0 +1,292 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Interpolation of lens profiles calibrated at multiple lens positions, ie. zoom lenses and lenses with focus breathing.
+// `interpolations` of the profile are keyed by the focal length in mm, optionally with the focus distance in meters: "24" or "24@1.5".
+// The calibrations at the same focus distance form a row, which is interpolated at the focal length with a monotone cubic spline,
+// then the rows are interpolated the same way at the focus distance. The focus is interpolated in diopters (1 / distance),
+// because the focus breathing is close to linear in them. Outside of the calibrated range the nearest calibration is used.
+
+use serde::{ Serialize, Deserialize };
+use crate::LensProfile;
+
+/// Calibrations at one focus distance, sorted by the focal length
+type Row = Vec<(f64, LensProfile)>;
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct LensInterpolation {
+    /// Calibrations grouped by the focus distance (`None` when unknown)
+    rows: Vec<(Option<f64>, Row)>,
+}
+
+/// Focal length and focus distance of an `interpolations` key
+pub fn parse_key(key: &str) -> Option<(f64, Option<f64>)> {
+    match key.split_once('@') {
+        Some((focal, focus)) => Some((focal.trim().parse().ok()?, Some(focus.trim().parse().ok()?))),
+        None => Some((key.trim().parse().ok()?, None))
+    }
+}
+
+/// Which of the values can be interpolated, the others are taken from the nearest calibration
+#[derive(Clone, Copy)]
+struct Layout {
+    focal_length: bool,
+    coeffs: Option<usize>,
+    digital_lens_params: Option<usize>,
+}
+
+impl LensInterpolation {
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    pub fn insert(&mut self, focal_length: f64, focus_distance: Option<f64>, profile: LensProfile) {
+        let row = match self.rows.iter_mut().position(|(focus, _)| *focus == focus_distance) {
+            Some(i) => &mut self.rows[i].1,
+            None => {
+                self.rows.push((focus_distance, Vec::new()));
+                // Nearest focus first, `None` at the end
+                self.rows.sort_by(|a, b| a.0.unwrap_or(f64::MAX).total_cmp(&b.0.unwrap_or(f64::MAX)));
+                &mut self.rows.iter_mut().find(|(focus, _)| *focus == focus_distance).unwrap().1
+            }
+        };
+        match row.binary_search_by(|(focal, _)| focal.total_cmp(&focal_length)) {
+            Ok(i) => row[i].1 = profile,
+            Err(i) => row.insert(i, (focal_length, profile))
+        }
+    }
+
+    pub fn profiles_mut(&mut self) -> impl Iterator<Item = &mut LensProfile> {
+        self.rows.iter_mut().flat_map(|(_, row)| row.iter_mut().map(|(_, profile)| profile))
+    }
+
+    /// Profile at the lens position. Without the focus distance, the calibrations without it are used, or the ones at the farthest focus
+    pub fn interpolate(&self, focal_length: f64, focus_distance: Option<f64>) -> Option<LensProfile> {
+        let with_focus: Vec<_> = self.rows.iter().filter_map(|(focus, row)| Some((focus.filter(|x| *x > 0.0)?, row))).collect();
+        let rows: Vec<(f64, &Row)> = match focus_distance.filter(|x| *x > 0.0) {
+            Some(_) if !with_focus.is_empty() => with_focus,
+            _ => {
+                let row = self.rows.iter().find(|(focus, _)| focus.is_none()).or_else(|| self.rows.iter().rfind(|(_, row)| !row.is_empty()))?;
+                vec![(1.0, &row.1)]
+            }
+        };
+
+        let nodes = || rows.iter().flat_map(|(_, row)| row.iter().map(|(_, p)| p));
+        let first = nodes().next()?;
+        let layout = Layout {
+            focal_length: nodes().all(|p| p.focal_length.is_some()),
+            coeffs: Some(first.fisheye_params.distortion_coeffs.len()).filter(|len| nodes().all(|p| p.fisheye_params.distortion_coeffs.len() == *len)),
+            digital_lens_params: first.digital_lens_params.as_ref().map(|x| x.len()).filter(|len| nodes().all(|p| p.digital_lens_params.as_ref().map(|x| x.len()) == Some(*len))),
+        };
+        if nodes().any(|p| p.fisheye_params.camera_matrix.len() != 3) {
+            return None;
+        }
+
+        // Each row at the focal length, then across the rows in diopters
+        let diopters = |focus: f64| 1.0 / focus;
+        let mut xs = Vec::with_capacity(rows.len());
+        let mut values = Vec::with_capacity(rows.len());
+        for (focus, row) in &rows {
+            if row.is_empty() { continue; }
+            let focals: Vec<f64> = row.iter().map(|(focal, _)| *focal).collect();
+            let packed: Vec<Vec<f64>> = row.iter().map(|(_, p)| pack(p, layout)).collect();
+            xs.push(diopters(*focus));
+            values.push(interpolate_vectors(&focals, &packed, focal_length));
+        }
+        // Rows sorted by increasing diopters, ie. from the farthest focus
+        let mut order: Vec<usize> = (0..xs.len()).collect();
+        order.sort_by(|a, b| xs[*a].total_cmp(&xs[*b]));
+        let xs: Vec<f64> = order.iter().map(|i| xs[*i]).collect();
+        let values: Vec<Vec<f64>> = order.iter().map(|i| values[*i].clone()).collect();
+        let at = focus_distance.filter(|x| *x > 0.0).map(diopters).unwrap_or(xs[0]);
+        let v = interpolate_vectors(&xs, &values, at);
+
+        // The nearest calibration provides everything else
+        let distance = |focal: f64, focus: f64| ((focal - focal_length) / focal_length.abs().max(1.0)).abs() + (diopters(focus) - at).abs();
+        let nearest = rows.iter().flat_map(|(focus, row)| row.iter().map(move |(focal, p)| (distance(*focal, *focus), p)))
+                          .min_by(|a, b| a.0.total_cmp(&b.0))?.1;
+        let mut profile = unpack(nearest, &v, layout);
+        profile.focus_distance = focus_distance.or(nearest.focus_distance);
+        Some(profile)
+    }
+}
+
+fn pack(p: &LensProfile, layout: Layout) -> Vec<f64> {
+    let m = &p.fisheye_params.camera_matrix;
+    let mut v = vec![
+        m[0][0], m[1][1], m[0][2], m[1][2],
+        p.crop.unwrap_or(1.0),
+        p.calib_dimension.w as f64, p.calib_dimension.h as f64,
+        p.input_horizontal_stretch, p.input_vertical_stretch,
+    ];
+    if layout.focal_length {
+        v.push(p.focal_length.unwrap_or_default());
+    }
+    if layout.coeffs.is_some() {
+        v.extend_from_slice(&p.fisheye_params.distortion_coeffs);
+    }
+    if layout.digital_lens_params.is_some() {
+        v.extend(p.digital_lens_params.iter().flatten());
+    }
+    v
+}
+
+fn unpack(base: &LensProfile, v: &[f64], layout: Layout) -> LensProfile {
+    let mut p = base.clone();
+    let m = &mut p.fisheye_params.camera_matrix;
+    m[0][0] = v[0];
+    m[1][1] = v[1];
+    m[0][2] = v[2];
+    m[1][2] = v[3];
+    if base.crop.is_some() || (v[4] - 1.0).abs() > 1e-9 {
+        p.crop = Some(v[4]);
+    }
+    p.calib_dimension.w = v[5].round() as usize;
+    p.calib_dimension.h = v[6].round() as usize;
+    p.input_horizontal_stretch = v[7];
+    p.input_vertical_stretch   = v[8];
+    let mut i = 9;
+    if layout.focal_length {
+        p.focal_length = Some(v[i]);
+        i += 1;
+    }
+    if let Some(len) = layout.coeffs {
+        p.fisheye_params.distortion_coeffs = v[i..i + len].to_vec();
+        i += len;
+    }
+    if let Some(len) = layout.digital_lens_params {
+        p.digital_lens_params = Some(v[i..i + len].to_vec());
+    }
+    p
+}
+
+fn interpolate_vectors(xs: &[f64], values: &[Vec<f64>], x: f64) -> Vec<f64> {
+    let len = values.first().map(|v| v.len()).unwrap_or_default();
+    (0..len).map(|i| {
+        let ys: Vec<f64> = values.iter().map(|v| v[i]).collect();
+        monotone_cubic(xs, &ys, x)
+    }).collect()
+}
+
+/// Monotone cubic Hermite spline (Fritsch-Carlson) of sorted `xs`, so the values don't overshoot between the calibrations. Clamped outside of the range
+fn monotone_cubic(xs: &[f64], ys: &[f64], x: f64) -> f64 {
+    let n = xs.len();
+    if n == 0 { return 0.0; }
+    if n == 1 || x <= xs[0] { return ys[0]; }
+    if x >= xs[n - 1] { return ys[n - 1]; }
+
+    let i = xs.partition_point(|v| *v <= x) - 1;
+    let h = |k: usize| xs[k + 1] - xs[k];
+    let slope = |k: usize| (ys[k + 1] - ys[k]) / h(k);
+    let tangent = |k: usize| -> f64 {
+        if k == 0 { return slope(0); }
+        if k == n - 1 { return slope(n - 2); }
+        let (d0, d1) = (slope(k - 1), slope(k));
+        if d0 * d1 <= 0.0 { return 0.0; }
+        let (w0, w1) = (2.0 * h(k) + h(k - 1), h(k) + 2.0 * h(k - 1));
+        (w0 + w1) / (w0 / d0 + w1 / d1)
+    };
+
+    let t = (x - xs[i]) / h(i);
+    let (t2, t3) = (t * t, t * t * t);
+    (2.0 * t3 - 3.0 * t2 + 1.0) * ys[i] + (t3 - 2.0 * t2 + t) * h(i) * tangent(i) + (-2.0 * t3 + 3.0 * t2) * ys[i + 1] + (t3 - t2) * h(i) * tangent(i + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn calibration(focal: f64, fx: f64, k1: f64, digital: f64) -> LensProfile {
+        let mut p = LensProfile::default();
+        p.calib_dimension.w = 4000;
+        p.calib_dimension.h = 3000;
+        p.input_horizontal_stretch = 1.0;
+        p.input_vertical_stretch = 1.0;
+        p.focal_length = Some(focal);
+        p.fisheye_params.camera_matrix = vec![[fx, 0.0, 2000.0], [0.0, fx, 1500.0], [0.0, 0.0, 1.0]];
+        p.fisheye_params.distortion_coeffs = vec![k1, 0.0, 0.0, 0.0];
+        p.digital_lens_params = Some(vec![digital, 1.0]);
+        p
+    }
+
+    #[test]
+    fn zoom_and_focus() {
+        assert_eq!(parse_key("24"), Some((24.0, None)));
+        assert_eq!(parse_key("24@1.5"), Some((24.0, Some(1.5))));
+        assert_eq!(parse_key("wide"), None);
+
+        let mut interpolation = LensInterpolation::default();
+        for (focal, fx) in [(10.0, 1000.0), (20.0, 2100.0), (40.0, 4000.0)] {
+            interpolation.insert(focal, Some(10.0), calibration(focal, fx, 0.01 * focal, focal / 10.0));
+            // Focus breathing: 5% longer at the close focus
+            interpolation.insert(focal, Some(0.5), calibration(focal, fx * 1.05, 0.01 * focal, focal / 10.0));
+        }
+
+        // At the calibrations
+        let p = interpolation.interpolate(20.0, Some(10.0)).unwrap();
+        assert!((p.fisheye_params.camera_matrix[0][0] - 2100.0).abs() < 1e-9);
+        assert_eq!(p.digital_lens_params, Some(vec![2.0, 1.0]));
+
+        // The spline is monotonic between the calibrations and is not linear
+        let mut prev = 0.0;
+        for focal in (10..=40).map(|x| x as f64) {
+            let fx = interpolation.interpolate(focal, Some(10.0)).unwrap().fisheye_params.camera_matrix[0][0];
+            assert!(fx > prev, "{focal}: {fx}");
+            prev = fx;
+        }
+        let p = interpolation.interpolate(15.0, Some(10.0)).unwrap();
+        assert!((p.fisheye_params.camera_matrix[0][0] - 1550.0).abs() > 1.0);
+        assert!((p.digital_lens_params.as_ref().unwrap()[0] - 1.5).abs() < 0.01);
+        assert!((p.focal_length.unwrap() - 15.0).abs() < 0.01);
+
+        // Halfway in diopters between 0.5m (2 D) and 10m (0.1 D) is 1.05 D
+        let p = interpolation.interpolate(20.0, Some(1.0 / 1.05)).unwrap();
+        assert!((p.fisheye_params.camera_matrix[0][0] - 2100.0 * 1.025).abs() < 1e-6);
+
+        // Clamped outside, and the farthest focus without the focus distance
+        assert!((interpolation.interpolate(100.0, Some(100.0)).unwrap().fisheye_params.camera_matrix[0][0] - 4000.0).abs() < 1e-9);
+        assert!((interpolation.interpolate(10.0, None).unwrap().fisheye_params.camera_matrix[0][0] - 1000.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn focus_distance_from_metadata() {
+        use crate::stabilization::{ ComputeParams, FrameTransform };
+
+        let stab = crate::StabilizationManager::default();
+        stab.init_from_video_data(1000.0, 25.0, 25, (4000, 3000));
+        stab.set_size(4000, 3000);
+        stab.set_output_size(4000, 3000);
+        let matrix = |fx: f64| serde_json::json!([[fx, 0.0, 2000.0], [0.0, fx, 1500.0], [0.0, 0.0, 1.0]]);
+        let profile = serde_json::json!({
+            "calibrator_version": "1.6.0",
+            "calib_dimension": { "w": 4000, "h": 3000 },
+            "fisheye_params": { "camera_matrix": matrix(2000.0), "distortion_coeffs": [0.01, 0.0, 0.0, 0.0] },
+            "focal_length": 20.0,
+            "interpolations": {
+                "20@10":  { "camera_matrix": matrix(2000.0) },
+                "20@0.5": { "camera_matrix": matrix(2100.0) },
+                "40@10":  { "camera_matrix": matrix(4000.0) },
+                "40@0.5": { "camera_matrix": matrix(4200.0) }
+            }
+        });
+        stab.load_lens_profile(&profile.to_string()).unwrap();
+
+        let fx_at_focus = |focus_distance: Option<f32>| {
+            {
+                let mut gyro = stab.gyro.write();
+                gyro.file_metadata.lens_positions = [(0, 20.0)].into();
+                gyro.file_metadata.lens_params = [(0, crate::gyro_source::LensParams { focal_length: Some(20.0), focus_distance, ..Default::default() })].into();
+            }
+            let params = ComputeParams::from_manager(&stab);
+            FrameTransform::get_lens_data_at_timestamp(&params, 0.0).0[(0, 0)]
+        };
+        assert!((fx_at_focus(Some(0.5)) - 2100.0).abs() < 1e-6);
+        assert!((fx_at_focus(Some(10.0)) - 2000.0).abs() < 1e-6);
+        // The farthest focus without the focus distance
+        assert!((fx_at_focus(None) - 2000.0).abs() < 1e-6);
+
+        // Only set when known
+        let json = stab.lens.read().get_json_value().unwrap();
+        assert!(json.get("focus_distance").is_none());
+    }
+}
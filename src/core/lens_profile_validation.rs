@@ -0,0 +1,306 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Checks of lens profiles, used to lint the profile database before accepting submissions.
+// Every issue has a stable `code`, so the tools can filter or allow them. Errors make the profile unusable or the stabilization wrong,
+// warnings are suspicious values which can still be correct.
+
+use std::collections::{ BTreeMap, HashSet };
+use std::path::Path;
+use nalgebra::Vector3;
+use serde::Serialize;
+use crate::LensProfile;
+use crate::calibration::distortion::Projection;
+use crate::stabilization::distortion_models::DistortionModel;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ValidationIssue {
+    pub severity: Severity,
+    pub code: &'static str,
+    pub message: String,
+}
+
+/// Issues of one profile in a linted directory. Files with multiple profiles have a report for each of them
+#[derive(Clone, Debug, Serialize)]
+pub struct LintReport {
+    pub path: String,
+    pub identifier: String,
+    pub issues: Vec<ValidationIssue>,
+}
+
+impl LintReport {
+    pub fn has_errors(&self) -> bool {
+        self.issues.iter().any(|x| x.severity == Severity::Error)
+    }
+}
+
+/// Numbers of the distortion coefficients which are valid for each physical model
+fn coefficient_counts(model: &str) -> Option<&'static [usize]> {
+    Some(match model {
+        "opencv_fisheye"  => &[4],
+        "opencv_standard" => &[4, 5, 8, 12],
+        "poly3"           => &[1],
+        "poly5"           => &[2],
+        "ptlens"          => &[3],
+        "insta360"        => &[6],
+        "sony"            => &[8],
+        _ => return None
+    })
+}
+
+/// Checks the profile. `is_known_identifier` resolves the profiles referenced by `interpolations`, they are not checked without it
+pub fn validate(profile: &LensProfile, is_known_identifier: Option<&dyn Fn(&str) -> bool>) -> Vec<ValidationIssue> {
+    let mut issues = Vec::new();
+    let mut add = |severity: Severity, code: &'static str, message: String| issues.push(ValidationIssue { severity, code, message });
+
+    if profile.calibrator_version.is_empty() {
+        add(Severity::Error, "calibrator_version", "Missing calibrator_version, the profile can't be loaded from a file".into());
+    }
+    if profile.identifier.is_empty() {
+        add(Severity::Warning, "identifier", "Missing identifier, the profile can't be referenced and is keyed by its file name".into());
+    }
+
+    let (w, h) = (profile.calib_dimension.w as f64, profile.calib_dimension.h as f64);
+    let has_size = w > 0.0 && h > 0.0;
+    if !has_size {
+        add(Severity::Error, "calib_dimension", format!("Invalid calib_dimension {}x{}", profile.calib_dimension.w, profile.calib_dimension.h));
+    }
+    let (ow, oh) = (profile.orig_dimension.w as f64, profile.orig_dimension.h as f64);
+    if ow <= 0.0 || oh <= 0.0 {
+        add(Severity::Warning, "orig_dimension", format!("Invalid orig_dimension {}x{}", profile.orig_dimension.w, profile.orig_dimension.h));
+    } else if has_size {
+        // The calibration can be downscaled or stretched, but not cropped
+        let stretch = profile.input_horizontal_stretch / profile.input_vertical_stretch;
+        let (calib_ratio, orig_ratio) = (w / h, ow / oh);
+        if (calib_ratio / orig_ratio - 1.0).abs() > 0.01 && (calib_ratio / (orig_ratio * stretch) - 1.0).abs() > 0.01 {
+            add(Severity::Warning, "orig_dimension", format!("Aspect ratio of orig_dimension {}x{} doesn't match calib_dimension {}x{}", profile.orig_dimension.w, profile.orig_dimension.h, profile.calib_dimension.w, profile.calib_dimension.h));
+        }
+    }
+    if !(profile.input_horizontal_stretch > 0.0 && profile.input_vertical_stretch > 0.0) {
+        add(Severity::Error, "input_stretch", format!("Invalid input stretch {}x{}", profile.input_horizontal_stretch, profile.input_vertical_stretch));
+    }
+    if profile.frame_readout_time.is_some_and(|x| !x.is_finite()) {
+        add(Severity::Error, "frame_readout_time", "frame_readout_time is not a number".into());
+    }
+
+    let m = &profile.fisheye_params.camera_matrix;
+    let has_matrix = m.len() == 3 && m.iter().flatten().all(|x| x.is_finite()) && m[0][0] > 0.0 && m[1][1] > 0.0;
+    if !has_matrix {
+        add(Severity::Error, "camera_matrix", format!("Invalid camera matrix {m:?}"));
+    } else if has_size && (!(0.0..=w).contains(&m[0][2]) || !(0.0..=h).contains(&m[1][2])) {
+        add(Severity::Error, "principal_point", format!("Principal point ({:.1}, {:.1}) is outside of the {}x{} image", m[0][2], m[1][2], w, h));
+    }
+    if let Some(estimation) = &profile.auto_estimated {
+        add(Severity::Warning, "auto_estimated", format!("The profile was estimated from a video without a calibration target, focal length ±{:.1} px", estimation.focal_length_std));
+    }
+
+    let model = profile.distortion_model.as_deref().unwrap_or("opencv_fisheye");
+    let coeffs = &profile.fisheye_params.distortion_coeffs;
+    let mut has_coeffs = false;
+    match coefficient_counts(model) {
+        None => add(Severity::Error, "distortion_model", format!("Unknown distortion model {model}")),
+        Some(_) if coeffs.iter().any(|x| !x.is_finite()) => add(Severity::Error, "distortion_coeffs", format!("Invalid distortion coefficients {coeffs:?}")),
+        Some(counts) => {
+            // Missing coefficients are zero when stabilizing, and with less than 4 they are read from the video metadata if it has them
+            let (min, max) = (counts[0], counts[counts.len() - 1]);
+            if coeffs.len() > max && coeffs[max..].iter().any(|x| *x != 0.0) {
+                add(Severity::Error, "coefficient_count", format!("{model} has {:?} distortion coefficients, but the profile has {} and the extra ones are not zero", counts, coeffs.len()));
+            } else if coeffs.len() < min && coeffs.len() >= 4 {
+                add(Severity::Warning, "coefficient_count", format!("{model} has {:?} distortion coefficients, but the profile has {}, the missing ones are zero", counts, coeffs.len()));
+                has_coeffs = true;
+            } else {
+                has_coeffs = coeffs.len() >= min;
+            }
+        }
+    }
+    if let Some(digital) = &profile.digital_lens {
+        if DistortionModel::from_name(digital).id() != digital || coefficient_counts(digital).is_some() {
+            add(Severity::Error, "digital_lens", format!("Unknown digital lens {digital}"));
+        }
+    }
+    match &profile.digital_lens_params {
+        Some(params) if profile.digital_lens.is_none() => add(Severity::Warning, "digital_lens_params", format!("digital_lens_params {params:?} without a digital lens")),
+        Some(params) if params.len() > 4 || params.iter().any(|x| !x.is_finite()) => add(Severity::Error, "digital_lens_params", format!("Invalid digital_lens_params {params:?}, up to 4 numbers are used")),
+        _ => { }
+    }
+
+    if has_size && has_matrix && has_coeffs {
+        if let Some(message) = check_monotonic(profile, model) {
+            add(Severity::Error, "non_monotonic", message);
+        }
+    }
+
+    match &profile.interpolations {
+        None => { }
+        Some(serde_json::Value::Object(map)) => {
+            for (key, v) in map {
+                if crate::lens_profile_interpolation::parse_key(key).is_none() {
+                    add(Severity::Error, "interpolations", format!("Interpolation key {key:?} is not a focal length or \"focal@focus\""));
+                }
+                let Some(v) = v.as_object() else {
+                    add(Severity::Error, "interpolations", format!("Interpolation at {key} is not an object"));
+                    continue;
+                };
+                match v.get("identifier").map(|x| x.as_str()) {
+                    Some(None) => add(Severity::Error, "interpolation_reference", format!("Interpolation at {key} has an invalid identifier")),
+                    Some(Some(id)) if is_known_identifier.is_some_and(|f| !f(id)) => {
+                        add(Severity::Error, "interpolation_reference", format!("Interpolation at {key} refers to an unknown profile {id}"));
+                    },
+                    None if v.get("camera_matrix").is_none() => {
+                        add(Severity::Warning, "interpolations", format!("Interpolation at {key} has neither an identifier nor a camera matrix, it's the same as the profile"));
+                    },
+                    _ => { }
+                }
+                if let Some(matrix) = v.get("camera_matrix") {
+                    if serde_json::from_value::<[[f64; 3]; 3]>(matrix.clone()).is_err() {
+                        add(Severity::Error, "interpolations", format!("Interpolation at {key} has an invalid camera matrix"));
+                    }
+                }
+                if let Some(d) = v.get("distortion_coeffs") {
+                    match serde_json::from_value::<Vec<f64>>(d.clone()) {
+                        Ok(d) if d.len() > coeffs.len() => {
+                            add(Severity::Error, "interpolations", format!("Interpolation at {key} has {} distortion coefficients, but the profile has {}", d.len(), coeffs.len()));
+                        },
+                        Ok(_) => { },
+                        Err(_) => add(Severity::Error, "interpolations", format!("Interpolation at {key} has invalid distortion coefficients"))
+                    }
+                }
+            }
+        },
+        Some(_) => add(Severity::Error, "interpolations", "interpolations is not an object".into())
+    }
+
+    issues.sort_by_key(|x| std::cmp::Reverse(x.severity));
+    issues
+}
+
+/// The distorted radius has to grow with the angle up to the corners of the image, otherwise multiple rays end up at the same pixel
+fn check_monotonic(profile: &LensProfile, model: &str) -> Option<String> {
+    let m = &profile.fisheye_params.camera_matrix;
+    let coeffs = profile.get_distortion_coeffs();
+    let projection = Projection::new(model, &coeffs)?;
+    let intrinsics = [&[m[0][0], m[1][1], m[0][2], m[1][2]][..], &coeffs[..projection.num_intrinsics() - 4]].concat();
+    let (w, h) = (profile.calib_dimension.w as f64, profile.calib_dimension.h as f64);
+    let corner = [(0.0, 0.0), (w, 0.0), (0.0, h), (w, h)].into_iter().map(|(x, y)| ((x - m[0][2]) / m[0][0]).hypot((y - m[1][2]) / m[1][1])).fold(0.0, f64::max);
+    let limit = profile.fisheye_params.radial_distortion_limit.filter(|x| *x > 0.0);
+
+    let mut prev = 0.0;
+    for i in 1..=356 {
+        let theta = (i as f64 * 0.25).to_radians();
+        if limit.is_some_and(|l| theta.tan() > l) { break; }
+        let (x, _) = projection.project(&intrinsics, &Vector3::new(theta.sin(), 0.0, theta.cos()))?;
+        let r = (x - m[0][2]) / m[0][0];
+        if r.is_nan() || r <= prev {
+            return Some(format!("The distortion is not monotonic at {:.2}° from the optical axis, {:.0}% of the distance to the image corner", theta.to_degrees(), prev / corner * 100.0));
+        }
+        if r >= corner { break; }
+        prev = r;
+    }
+    None
+}
+
+/// Validates all lens profiles in the directory, including duplicate identifiers and the references between the profiles
+pub fn lint_directory(path: &Path) -> Vec<LintReport> {
+    let mut reports = Vec::new();
+    let mut profiles = Vec::new();
+    for entry in walkdir::WalkDir::new(path).sort_by_file_name().into_iter().flatten() {
+        let f_name = entry.path().to_string_lossy().replace('\\', "/");
+        let parsed = if f_name.ends_with(".json") {
+            std::fs::read_to_string(entry.path()).map_err(|e| e.to_string())
+                .and_then(|x| LensProfile::from_json(&x).map_err(|e| e.to_string()))
+                .map(|x| x.get_all_matching_profiles())
+        } else if crate::lens_profile_formats::has_supported_extension(&f_name) {
+            match std::fs::read_to_string(entry.path()) {
+                Ok(x) if !crate::lens_profile_formats::is_supported(&f_name, &x) => continue,
+                Ok(x) => crate::lens_profile_formats::import(&x, &f_name).map_err(|e| e.to_string()),
+                Err(e) => Err(e.to_string())
+            }
+        } else {
+            continue;
+        };
+        match parsed {
+            Ok(parsed) => profiles.extend(parsed.into_iter().map(|x| (f_name.clone(), x))),
+            Err(e) => reports.push(LintReport {
+                path: f_name,
+                identifier: String::new(),
+                issues: vec![ValidationIssue { severity: Severity::Error, code: "invalid_file", message: e }]
+            })
+        }
+    }
+
+    let mut files_by_id = BTreeMap::<&str, Vec<&str>>::new();
+    for (f_name, profile) in &profiles {
+        if !profile.identifier.is_empty() {
+            files_by_id.entry(&profile.identifier).or_default().push(f_name);
+        }
+    }
+    let known: HashSet<&str> = files_by_id.keys().copied().collect();
+    let is_known = |id: &str| known.contains(id);
+
+    for (f_name, profile) in &profiles {
+        let mut issues = validate(profile, Some(&is_known));
+        if let Some(files) = files_by_id.get(profile.identifier.as_str()).filter(|x| x.len() > 1) {
+            let others: Vec<&str> = files.iter().copied().filter(|x| x != f_name).collect();
+            if others.is_empty() {
+                // Only the first one is loaded
+                issues.push(ValidationIssue { severity: Severity::Warning, code: "duplicate_identifier", message: format!("Identifier {} is used by multiple settings in this file", profile.identifier) });
+            } else {
+                issues.insert(0, ValidationIssue { severity: Severity::Error, code: "duplicate_identifier", message: format!("Identifier {} is also used by {}", profile.identifier, others.join(", ")) });
+            }
+        }
+        if !issues.is_empty() {
+            reports.push(LintReport { path: f_name.clone(), identifier: profile.identifier.clone(), issues });
+        }
+    }
+    reports.sort_by(|a, b| a.path.cmp(&b.path));
+    reports
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profile_issues() {
+        let mut profile = LensProfile::from_json(r#"{
+            "calibrator_version": "1.5.4", "identifier": "test",
+            "calib_dimension": { "w": 4000, "h": 3000 }, "orig_dimension": { "w": 1920, "h": 1080 },
+            "input_horizontal_stretch": 1.0, "input_vertical_stretch": 1.0,
+            "fisheye_params": { "camera_matrix": [[2000.0, 0.0, 2000.0], [0.0, 2000.0, 1500.0], [0.0, 0.0, 1.0]], "distortion_coeffs": [0.02, -0.01, 0.002, 0.0] },
+            "interpolations": { "3.5": { "identifier": "missing" }, "4": { "camera_matrix": [[2100.0, 0.0, 2000.0], [0.0, 2100.0, 1500.0], [0.0, 0.0, 1.0]], "distortion_coeffs": [0.0, 0.0, 0.0, 0.0, 0.0] } }
+        }"#).unwrap();
+        let codes = |p: &LensProfile| validate(p, Some(&|id: &str| id == "test")).into_iter().map(|x| x.code).collect::<Vec<_>>();
+        assert_eq!(codes(&profile), vec!["interpolation_reference", "interpolations", "orig_dimension"]);
+
+        profile.interpolations = None;
+        profile.orig_dimension = profile.calib_dimension.clone();
+        assert!(codes(&profile).is_empty());
+
+        profile.fisheye_params.camera_matrix[1][2] = 3100.0;
+        profile.distortion_model = Some("poly5".into());
+        assert_eq!(codes(&profile), vec!["principal_point", "coefficient_count"]);
+
+        // The radius folds back at about 45°, before the corner of the image
+        profile.fisheye_params.camera_matrix[1][2] = 1500.0;
+        profile.fisheye_params.distortion_coeffs = vec![-0.5, 0.0];
+        let issues = validate(&profile, None);
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].code, "non_monotonic");
+
+        // Without coefficients they are read from the video metadata, and extra zero coefficients are ignored
+        profile.distortion_model = None;
+        profile.fisheye_params.distortion_coeffs = vec![];
+        assert!(codes(&profile).is_empty());
+        profile.fisheye_params.distortion_coeffs = vec![0.02, -0.01, 0.002, 0.0, 0.0, 0.0];
+        assert!(codes(&profile).is_empty());
+        profile.fisheye_params.distortion_coeffs = vec![0.02, -0.01, 0.002, 0.0, 0.1];
+        assert_eq!(codes(&profile), vec!["coefficient_count"]);
+    }
+}
@@ -2,10 +2,14 @@
 // Copyright © 2021-2022 Adrian <adrian.eddy at gmail>
 
 pub mod gyro_source;
+pub mod csv_mapping;
 pub mod imu_integration;
+pub mod imu_orientation;
 pub mod lens_profile;
 pub mod lens_profile_database;
-#[cfg(feature = "opencv")]
+pub mod lens_profile_formats;
+pub mod lens_profile_interpolation;
+pub mod lens_profile_validation;
 pub mod calibration;
 pub mod synchronization;
 pub mod stabilization;
@@ -18,6 +22,8 @@ pub mod filtering;
 pub mod filesystem;
 
 pub mod gpu;
+pub mod export;
+pub mod streaming;
 
 pub mod util;
 pub mod stabilization_params;
@@ -41,7 +47,6 @@ use gpu::drawing::*;
 
 pub use telemetry_parser;
 
-#[cfg(feature = "opencv")]
 use calibration::LensCalibrator;
 
 #[global_allocator]
@@ -68,7 +73,6 @@ pub struct StabilizationManager {
     pub stabilization: Arc<RwLock<Stabilization>>,
 
     pub pose_estimator: Arc<synchronization::PoseEstimator>,
-    #[cfg(feature = "opencv")]
     pub lens_calibrator: Arc<RwLock<Option<LensCalibrator>>>,
 
     pub current_compute_id: Arc<AtomicU64>,
@@ -115,7 +119,6 @@ impl Default for StabilizationManager {
 
             input_file: Arc::new(RwLock::new(InputFile::default())),
 
-            #[cfg(feature = "opencv")]
             lens_calibrator: Arc::new(RwLock::new(None)),
 
             keyframes: Arc::new(RwLock::new(KeyframeManager::new())),
@@ -318,9 +321,38 @@ impl StabilizationManager {
         if !from_db {
             lens.resolve_interpolations(&db);
         }
+
+        drop(lens);
+        drop(db);
+        // Don't override the IMU orientation of the project or the video metadata, see `apply_lens_imu_transform`
+        if self.gyro.read().imu_orientation.is_none() {
+            self.apply_lens_imu_transform();
+        }
         result
     }
 
+    /// Applies the IMU orientation and rotation from the IMU calibration (`calibration::imu`) stored in the lens profile.
+    /// Returns false if the lens profile doesn't have them
+    pub fn apply_lens_imu_transform(&self) -> bool {
+        let (orientation, rotation) = match self.lens.read().sync_settings.as_ref() {
+            Some(x) => (
+                x.get("imu_orientation").and_then(|x| x.as_str()).map(|x| x.to_owned()),
+                x.get("imu_rotation").and_then(|x| serde_json::from_value::<[f64; 3]>(x.clone()).ok())
+            ),
+            None => return false
+        };
+        if orientation.is_none() && rotation.is_none() {
+            return false;
+        }
+        {
+            let mut gyro = self.gyro.write();
+            if orientation.is_some() { gyro.imu_orientation = orientation; }
+            if rotation.is_some() { gyro.imu_rotation_angles = rotation; }
+        }
+        self.recompute_gyro();
+        true
+    }
+
     fn init_size(&self) {
         let (w, h, ow, oh) = {
             let params = self.params.read();
@@ -367,19 +399,25 @@ impl StabilizationManager {
         false
     }
 
-    pub fn recompute_adaptive_zoom_static(compute_params: &ComputeParams, params: &RwLock<StabilizationParams>, keyframes: &KeyframeManager) -> (Vec<f64>, Vec<f64>, BTreeMap<i64, Vec<(f64, f64)>>) {
+    pub fn recompute_adaptive_zoom_static(compute_params: &ComputeParams, params: &RwLock<StabilizationParams>, keyframes: &KeyframeManager, locked_fov: Option<f64>) -> (Vec<f64>, Vec<f64>, BTreeMap<i64, Vec<(f64, f64)>>) {
         let (frames, fps, method) = {
             let params = params.read();
             (params.frame_count, params.get_scaled_fps(), params.adaptive_zoom_method)
         };
         let timestamps = (0..frames).map(|i| i as f64 * 1000.0 / fps).collect::<Vec<f64>>();
 
-        zooming::calculate_fovs(compute_params, &timestamps, &keyframes, method.into())
+        let (mut fovs, minimal_fovs, debug_points) = zooming::calculate_fovs(compute_params, &timestamps, &keyframes, method.into());
+        if let Some(fov) = locked_fov {
+            // The smoothing algorithm already keeps this crop inside the frame
+            fovs.iter_mut().for_each(|v| *v = fov);
+        }
+        (fovs, minimal_fovs, debug_points)
     }
     pub fn recompute_adaptive_zoom(&self) {
         let params = stabilization::ComputeParams::from_manager(self);
         let lens_fov_adjustment = params.lens.optimal_fov.unwrap_or(1.0);
-        let (fovs, minimal_fovs, debug_points) = Self::recompute_adaptive_zoom_static(&params, &self.params, &self.keyframes.read());
+        let locked_fov = self.smoothing.read().current().locked_fov();
+        let (fovs, minimal_fovs, debug_points) = Self::recompute_adaptive_zoom_static(&params, &self.params, &self.keyframes.read(), locked_fov);
 
         let mut stab_params = self.params.write();
         stab_params.set_fovs(fovs, lens_fov_adjustment);
@@ -388,12 +426,17 @@ impl StabilizationManager {
     }
 
     pub fn recompute_smoothness(&self) {
+        if self.smoothing.read().current().needs_compute_params() {
+            let compute_params = stabilization::ComputeParams::from_manager(self);
+            self.smoothing.write().current_mut().set_compute_params(&compute_params);
+        }
+
         let params = self.params.read();
         let keyframes = self.keyframes.read().clone();
         let smoothing = self.smoothing.read();
         let horizon_lock = smoothing.horizon_lock.clone();
 
-        let (quats, org_quats, max_angles) = self.gyro.read().recompute_smoothness(smoothing.current().as_ref(), horizon_lock, &params, &keyframes);
+        let (quats, org_quats, max_angles) = self.gyro.read().recompute_smoothness(smoothing.current().as_ref(), horizon_lock, smoothing.look_ahead.as_ref(), &params, &keyframes);
         let mut gyro = self.gyro.write();
         gyro.max_angles = max_angles;
         gyro.org_smoothed_quaternions = org_quats;
@@ -419,6 +462,9 @@ impl StabilizationManager {
         //self.recompute_smoothness();
         //self.recompute_adaptive_zoom();
         let mut params = stabilization::ComputeParams::from_manager(self);
+        if self.smoothing.read().current().needs_compute_params() {
+            self.smoothing.write().current_mut().set_compute_params(&params);
+        }
 
         let smoothing = self.smoothing.clone();
         let stabilization_params = self.params.clone();
@@ -443,11 +489,11 @@ impl StabilizationManager {
 
             let mut smoothing_changed = false;
             if smoothing.read().get_state_checksum(gyro_checksum) != smoothing_checksum.load(SeqCst) {
-                let (mut smoothing, horizon_lock) = {
+                let (mut smoothing, horizon_lock, look_ahead) = {
                     let lock = smoothing.read();
-                    (lock.current().clone(), lock.horizon_lock.clone())
+                    (lock.current().clone(), lock.horizon_lock.clone(), lock.look_ahead)
                 };
-                let (quats, org_quats, max_angles) = gyro.read().recompute_smoothness(smoothing.as_mut(), horizon_lock, &stabilization_params.read(), &keyframes);
+                let (quats, org_quats, max_angles) = gyro.read().recompute_smoothness(smoothing.as_mut(), horizon_lock, look_ahead.as_ref(), &stabilization_params.read(), &keyframes);
 
                 if current_compute_id.load(SeqCst) != compute_id { return cb((compute_id, true)); }
                 if gyro_checksum != gyro.read().get_checksum() { return cb((compute_id, true)); }
@@ -465,7 +511,8 @@ impl StabilizationManager {
             if current_compute_id.load(SeqCst) != compute_id { return cb((compute_id, true)); }
 
             if smoothing_changed || zooming::get_checksum(&params) != zooming_checksum.load(SeqCst) {
-                let (fovs, minimal_fovs, debug_points) = Self::recompute_adaptive_zoom_static(&params, &stabilization_params, &keyframes);
+                let locked_fov = smoothing.read().current().locked_fov();
+                let (fovs, minimal_fovs, debug_points) = Self::recompute_adaptive_zoom_static(&params, &stabilization_params, &keyframes, locked_fov);
                 params.fovs = fovs;
                 params.minimal_fovs = minimal_fovs;
 
@@ -538,7 +585,8 @@ impl StabilizationManager {
             let frame = frame_at_timestamp(timestamp_us as f64 / 1000.0, p.get_scaled_fps()) as usize; // used only to draw features and OF
 
             if p.show_optical_flow {
-                let num_frames = if p.of_method == 2 { 1 } else { 3 };
+                // Dense methods have a line at every grid point, so draw only one frame
+                let num_frames = if p.of_method == 2 || p.of_method == 4 { 1 } else { 3 };
                 if let Some(pxs) = self.get_opticalflow_pixels(timestamp_us, num_frames, size) {
                     for (x, y, a) in pxs {
                         let a = Alpha::from(a as u8);
@@ -553,13 +601,16 @@ impl StabilizationManager {
                     }
                 }
             }
-            #[cfg(feature = "opencv")]
             if p.is_calibrator {
                 let lock = self.lens_calibrator.read();
                 if let Some(ref cal) = *lock {
                     let points = cal.all_matches.read();
                     if let Some(entry) = points.get(&(frame as i32)) {
-                        calibration::drawing::draw_chessboard_corners(cal.width, cal.height, p.size.0, p.size.1, drawing, (cal.columns, cal.rows), &entry.points, true, y_inverted);
+                        if entry.target == calibration::CalibrationTarget::Chessboard {
+                            calibration::drawing::draw_chessboard_corners(cal.width, cal.height, p.size.0, p.size.1, drawing, (cal.columns, cal.rows), &entry.points, true, y_inverted);
+                        } else {
+                            calibration::drawing::draw_detected(cal.width, cal.height, p.size.0, p.size.1, drawing, &entry.points, &entry.object_points, y_inverted);
+                        }
                     }
                 }
             }
@@ -696,7 +747,6 @@ impl StabilizationManager {
 
     pub fn set_digital_lens_name(&self, v: String) {
         self.lens.write().digital_lens =  if !v.is_empty() { Some(v.clone()) } else { None };
-        #[cfg(feature = "opencv")]
         if let Some(ref mut calib) = *self.lens_calibrator.write() {
             calib.digital_lens = if !v.is_empty() { Some(v) } else { None };
         }
@@ -708,15 +758,23 @@ impl StabilizationManager {
             lens.digital_lens_params = Some(vec![0f64; 4]);
         }
         lens.digital_lens_params.as_mut().unwrap()[index] = value;
-        #[cfg(feature = "opencv")]
         if let Some(ref mut calib) = *self.lens_calibrator.write() {
             calib.digital_lens_params = lens.digital_lens_params.clone();
         }
         self.invalidate_zooming();
     }
+    pub fn set_calibration_target(&self, v: String) {
+        if let Some(ref mut calib) = *self.lens_calibrator.write() {
+            calib.set_target(calibration::CalibrationTarget::from_name(&v));
+        }
+    }
+    pub fn set_calibration_grid_size(&self, columns: usize, rows: usize) {
+        if let Some(ref mut calib) = *self.lens_calibrator.write() {
+            calib.set_grid_size(columns, rows);
+        }
+    }
     pub fn set_lens_is_asymmetrical(&self, v: bool) {
         self.lens.write().asymmetrical = v;
-        #[cfg(feature = "opencv")]
         if let Some(ref mut calib) = *self.lens_calibrator.write() {
             calib.asymmetrical = v;
         }
@@ -741,6 +799,11 @@ impl StabilizationManager {
     pub fn offset_at_video_timestamp(&self, timestamp_us: i64) -> f64 {
         self.gyro.read().offset_at_video_timestamp(timestamp_us as f64 / 1000.0)
     }
+    pub fn set_offset_model(&self, model: i32) {
+        self.gyro.write().set_offset_model(model.into());
+        self.keyframes.write().update_gyro(&self.gyro.read());
+        self.invalidate_zooming();
+    }
 
     pub fn set_imu_lpf(&self, lpf: f64) {
         self.gyro.write().imu_lpf = lpf;
@@ -754,6 +817,10 @@ impl StabilizationManager {
     pub fn set_imu_orientation(&self, orientation: String) {
         self.gyro.write().imu_orientation = Some(orientation);
     }
+    /// Ranked `imu_orientation` candidates from the accelerometer while the camera is still and the given motions
+    pub fn detect_imu_orientation(&self, motions: &[imu_orientation::KnownMotionSegment]) -> std::result::Result<Vec<imu_orientation::OrientationCandidate>, GyroflowCoreError> {
+        imu_orientation::detect(&self.gyro.read().file_metadata.raw_imu, motions)
+    }
     pub fn set_imu_bias(&self, bx: f64, by: f64, bz: f64) {
         self.gyro.write().gyro_bias = Some([bx, by, bz]);
     }
@@ -783,7 +850,6 @@ impl StabilizationManager {
                 "k3" => lens.fisheye_params.distortion_coeffs[2] = value,
                 "k4" => lens.fisheye_params.distortion_coeffs[3] = value,
                 "r_limit" => {
-                    #[cfg(feature = "opencv")]
                     if let Some(ref mut calib) = *self.lens_calibrator.write() {
                         calib.r_limit = value;
                     }
@@ -802,10 +868,29 @@ impl StabilizationManager {
 
         smooth.current().get_parameters_json()
     }
+    pub fn set_smoothing_method_id(&self, id: &str) -> Option<serde_json::Value> {
+        let mut smooth = self.smoothing.write();
+        if !smooth.set_current_id(id) { return None; }
+
+        self.invalidate_smoothing();
+
+        Some(smooth.current().get_parameters_json())
+    }
+    /// Loads a smoothing algorithm from a dynamic library, see `smoothing::plugin`. Returns the algorithm id
+    pub fn load_smoothing_plugin(&self, path: &str) -> Result<String, GyroflowCoreError> {
+        let id = smoothing::plugin::load(path)?;
+        self.smoothing.write().add_registered();
+        Ok(id)
+    }
     pub fn set_smoothing_param(&self, name: &str, val: f64) {
         self.smoothing.write().current_mut().as_mut().set_parameter(name, val);
         self.invalidate_smoothing();
     }
+    /// Enables bounded-latency smoothing, see `smoothing::look_ahead`. `None` smooths the whole recording at once
+    pub fn set_smoothing_look_ahead(&self, look_ahead: Option<smoothing::look_ahead::LookAhead>) {
+        self.smoothing.write().look_ahead = look_ahead;
+        self.invalidate_smoothing();
+    }
     pub fn set_horizon_lock(&self, lock_percent: f64, roll: f64) {
         self.smoothing.write().horizon_lock.set_horizon(lock_percent, roll);
         self.invalidate_smoothing();
@@ -827,6 +912,9 @@ impl StabilizationManager {
     pub fn get_smoothing_algs(&self) -> Vec<String> {
         self.smoothing.read().get_names()
     }
+    pub fn get_smoothing_alg_ids(&self) -> Vec<String> {
+        self.smoothing.read().get_ids()
+    }
 
     pub fn get_cloned(&self) -> StabilizationManager {
         StabilizationManager {
@@ -868,7 +956,9 @@ impl StabilizationManager {
         *self.input_file.write() = InputFile::default();
         *self.camera_id.write() = None;
 
+        let offset_model = self.gyro.read().offset_model;
         *self.gyro.write() = GyroSource::new();
+        self.gyro.write().offset_model = offset_model;
         self.keyframes.write().clear();
 
         self.pose_estimator.clear();
@@ -914,11 +1004,52 @@ impl StabilizationManager {
 
         Ok(())
     }
+    pub fn export_camera_motion(&self, url: &str, format: export::camera_motion::CameraMotionFormat) -> Result<(), GyroflowCoreError> {
+        let motion = export::camera_motion::CameraMotion::from_manager(self)?;
+        filesystem::write(url, motion.export(format)?.as_bytes())?;
+        Ok(())
+    }
+    pub fn export_lens_stmaps(&self, folder_url: &str, base_name: &str, format: export::stmap::StmapFormat) -> Result<(String, String), GyroflowCoreError> {
+        let params = {
+            let params = self.params.read();
+            export::stmap::StmapParams {
+                input_size: Some(params.video_size),
+                output_size: Some(params.video_output_size),
+                fov: params.fov,
+                lens_correction_amount: params.lens_correction_amount,
+            }
+        };
+        export::stmap::export_lens_stmaps(&self.lens.read(), &params, folder_url, base_name, format)
+    }
+    /// Writes `{base_name}_{frame:05}.{ext}` ST-maps with the full stabilizing warp of every frame, at the video and output resolution
+    pub fn export_stmap_sequence<F: Fn(f64)>(&self, folder_url: &str, base_name: &str, format: export::stmap::StmapFormat, progress_cb: F, cancel_flag: Arc<AtomicBool>) -> Result<usize, GyroflowCoreError> {
+        let mut compute_params = ComputeParams::from_manager(self);
+        compute_params.width         = compute_params.video_width;
+        compute_params.height        = compute_params.video_height;
+        compute_params.output_width  = compute_params.video_output_width;
+        compute_params.output_height = compute_params.video_output_height;
+        if compute_params.scaled_fps <= 0.0 || compute_params.frame_count == 0 {
+            return Err(GyroflowCoreError::InvalidData);
+        }
+
+        let frame_count = compute_params.frame_count;
+        for frame in 0..frame_count {
+            if cancel_flag.load(SeqCst) { return Ok(frame); }
+            let timestamp_ms = timestamp_at_frame(frame as i32, compute_params.scaled_fps);
+            let transform = stabilization::FrameTransform::at_timestamp(&compute_params, timestamp_ms, frame);
+
+            let url = filesystem::get_file_url(folder_url, &format!("{base_name}_{frame:05}.{}", format.extension()), true);
+            transform.to_stmap(&compute_params).save(&url, format)?;
+
+            progress_cb((frame + 1) as f64 / frame_count as f64);
+        }
+        Ok(frame_count)
+    }
     pub fn export_gyroflow_data(&self, typ: GyroflowProjectType, additional_data: &str, _project_url: Option<&str>) -> Result<String, GyroflowCoreError> {
         let gyro = self.gyro.read();
         let params = self.params.read();
 
-        let (smoothing_name, smoothing_params, horizon_amount, horizon_roll) = {
+        let (smoothing_id, smoothing_name, smoothing_params, horizon_amount, horizon_roll, look_ahead) = {
             let smoothing_lock = self.smoothing.read();
             let smoothing = smoothing_lock.current();
 
@@ -938,7 +1069,7 @@ impl StabilizationManager {
                 horizon_amount = 0.0;
             }
 
-            (smoothing.get_name(), parameters, horizon_amount, smoothing_lock.horizon_lock.horizonroll)
+            (smoothing.get_id(), smoothing.get_name(), parameters, horizon_amount, smoothing_lock.horizon_lock.horizonroll, smoothing_lock.look_ahead)
         };
 
         let input_file = self.input_file.read().clone();
@@ -976,7 +1107,9 @@ impl StabilizationManager {
             "stabilization": {
                 "fov":                    params.fov,
                 "method":                 smoothing_name,
+                "method_id":              smoothing_id,
                 "smoothing_params":       smoothing_params,
+                "smoothing_look_ahead":   look_ahead,
                 "frame_readout_time":     params.frame_readout_time,
                 "adaptive_zoom_window":   params.adaptive_zoom_window,
                 "adaptive_zoom_center_offset": params.adaptive_zoom_center_offset,
@@ -1001,11 +1134,14 @@ impl StabilizationManager {
                 "imu_orientation":    gyro.imu_orientation,
                 "gyro_bias":          gyro.gyro_bias,
                 "integration_method": gyro.integration_method,
+                "offset_model":       gyro.offset_model,
                 "sample_index":       gyro.file_load_options.sample_index,
+                "csv_mapping":        gyro.file_load_options.csv_mapping,
                 "detected_source":    gyro.file_metadata.detected_source,
             },
 
             "offsets": gyro.get_offsets(), // timestamp, offset value
+            "offsets_quality": gyro.get_offsets_quality(), // timestamp, autosync quality
             "keyframes": self.keyframes.read().serialize(),
 
             // "trim_ranges": params.trim_ranges,
@@ -1168,6 +1304,14 @@ impl StabilizationManager {
                 }
                 use crate::gyro_source::TimeIMU;
 
+                let mut load_options = gyro_source::FileLoadOptions::default();
+                if let Some(mapping) = obj.get("csv_mapping").filter(|x| !x.is_null()) {
+                    match serde_json::from_value(mapping.clone()) {
+                        Ok(mapping) => load_options.csv_mapping = Some(mapping),
+                        Err(e) => ::log::warn!("Invalid CSV mapping: {e:?}")
+                    }
+                }
+
                 let is_compressed = obj.get("raw_imu").map(|x| x.is_string()).unwrap_or_default();
                 let is_main_video = org_gyro_url == org_video_url;
 
@@ -1243,12 +1387,12 @@ impl StabilizationManager {
                         let mut gyro = self.gyro.write();
                         gyro.load_from_telemetry(md);
                     } else if filesystem::exists(&gyro_url) && blocking {
-                        if let Err(e) = self.load_gyro_data(&gyro_url, is_main_video, &Default::default(), progress_cb, cancel_flag) {
+                        if let Err(e) = self.load_gyro_data(&gyro_url, is_main_video, &load_options, progress_cb, cancel_flag) {
                             ::log::warn!("Failed to load gyro data from {:?}: {:?}", gyro_url, e);
                         }
                     }
                 } else if filesystem::exists(&gyro_url) && blocking {
-                    if let Err(e) = self.load_gyro_data(&gyro_url, is_main_video, &Default::default(), progress_cb, cancel_flag) {
+                    if let Err(e) = self.load_gyro_data(&gyro_url, is_main_video, &load_options, progress_cb, cancel_flag) {
                         ::log::warn!("Failed to load gyro data from {:?}: {:?}", gyro_url, e);
                     }
                 }
@@ -1264,6 +1408,7 @@ impl StabilizationManager {
                 if let Some(v) = obj.get("rotation")     { gyro.imu_rotation_angles = serde_json::from_value(v.clone()).ok(); }
                 if let Some(v) = obj.get("acc_rotation") { gyro.acc_rotation_angles = serde_json::from_value(v.clone()).ok(); }
                 if let Some(v) = obj.get("gyro_bias")    { gyro.gyro_bias           = serde_json::from_value(v.clone()).ok(); }
+                if let Some(v) = obj.get("offset_model") { gyro.offset_model        = serde_json::from_value(v.clone()).unwrap_or_default(); }
 
                 obj.remove("raw_imu");
                 obj.remove("quaternions");
@@ -1314,14 +1459,23 @@ impl StabilizationManager {
                     params.adaptive_zoom_method = zooming_method as i32;
                 }
 
-                if let Some(method) = obj.get("method").and_then(|x| x.as_str()) {
-                    let method_idx = self.get_smoothing_algs()
-                        .iter().enumerate()
-                        .find(|(_, m)| method == m.as_str())
-                        .map(|(idx, _)| idx)
-                        .unwrap_or(1);
-
-                    self.smoothing.write().set_current(method_idx);
+                let method_id = obj.get("method_id").and_then(|x| x.as_str());
+                if !method_id.map(|id| self.smoothing.write().set_current_id(id)).unwrap_or_default() {
+                    if let Some(id) = method_id {
+                        log::warn!("Unknown smoothing algorithm: {id}");
+                    }
+                    // Older projects only have the display name or the index
+                    if let Some(method) = obj.get("method").and_then(|x| x.as_str()) {
+                        let method_idx = self.get_smoothing_algs()
+                            .iter().enumerate()
+                            .find(|(_, m)| method == m.as_str())
+                            .map(|(idx, _)| idx)
+                            .unwrap_or(1);
+
+                        self.smoothing.write().set_current(method_idx);
+                    } else if let Some(method_idx) = obj.get("method").and_then(|x| x.as_u64()) {
+                        self.smoothing.write().set_current(method_idx as usize);
+                    }
                 }
 
                 let mut smoothing = self.smoothing.write();
@@ -1336,6 +1490,9 @@ impl StabilizationManager {
                         Some(())
                     })();
                 }
+                if let Some(look_ahead) = obj.get("smoothing_look_ahead") {
+                    smoothing.look_ahead = serde_json::from_value(look_ahead.clone()).ok().flatten();
+                }
                 if let Some(horizon_amount) = obj.get("horizon_lock_amount").and_then(|x| x.as_f64()) {
                     if let Some(horizon_roll) = obj.get("horizon_lock_roll").and_then(|x| x.as_f64()) {
                         smoothing.horizon_lock.set_horizon(horizon_amount, horizon_roll);
@@ -1369,9 +1526,13 @@ impl StabilizationManager {
             if let Some(serde_json::Value::Object(offsets)) = obj.get("offsets") {
                 let mut gyro = self.gyro.write();
                 gyro.set_offsets(offsets.iter().filter_map(|(k, v)| Some((k.parse().ok()?, v.as_f64()?))).collect());
+                if let Some(serde_json::Value::Object(quality)) = obj.get("offsets_quality") {
+                    gyro.set_offsets_quality(quality.iter().filter_map(|(k, v)| Some((k.parse().ok()?, serde_json::from_value(v.clone()).ok()?))).collect());
+                }
                 self.keyframes.write().update_gyro(&gyro);
             }
             obj.remove("offsets");
+            obj.remove("offsets_quality");
 
             if let Some(keyframes) = obj.get("keyframes") {
                 self.keyframes.write().deserialize(keyframes);
@@ -1597,6 +1758,31 @@ impl StabilizationManager {
     }
 }
 
+#[cfg(test)]
+impl StabilizationManager {
+    // Manager for the tests, with a video of `size` and the gyro samples from `raw_imu`
+    pub(crate) fn with_test_gyro(duration_ms: f64, fps: f64, size: (usize, usize), raw_imu: Vec<gyro_source::TimeIMU>) -> Self {
+        let stab = Self::default();
+        stab.init_from_video_data(duration_ms, fps, (duration_ms * fps / 1000.0).round() as usize, size);
+        stab.set_size(size.0, size.1);
+        stab.set_output_size(size.0, size.1);
+        {
+            let mut gyro = stab.gyro.write();
+            gyro.init_from_params(&stab.params.read());
+            gyro.load_from_telemetry(gyro_source::FileMetadata { raw_imu, ..Default::default() });
+        }
+        stab
+    }
+
+    // Stabilizes every frame to the orientation at `timestamp_ms`, the smoothed quaternions are stored as corrections like in `GyroSource::recompute_smoothness`
+    pub(crate) fn lock_smoothed_orientation(&self, timestamp_ms: f64) {
+        self.recompute_smoothness();
+        let mut gyro = self.gyro.write();
+        let reference = gyro.org_quat_at_timestamp(timestamp_ms);
+        gyro.smoothed_quaternions = gyro.quaternions.iter().map(|(ts, q)| (*ts, reference.inverse() * q)).collect();
+    }
+}
+
 pub fn timestamp_at_frame(frame: i32, fps: f64) -> f64 { frame as f64 * 1000.0 / fps }
 pub fn frame_at_timestamp(timestamp_ms: f64, fps: f64) -> i32 { (timestamp_ms * (fps / 1000.0)).round() as i32 }
 
@@ -1660,6 +1846,24 @@ pub enum GyroflowCoreError {
     #[error("IO error {0:?}")]
     IOError(#[from] std::io::Error),
 
+    #[error("CSV mapping error: {0}")]
+    CsvMappingError(String),
+
+    #[error("Calibration error: {0}")]
+    CalibrationError(String),
+
+    #[error("IMU orientation error: {0}")]
+    ImuOrientationError(String),
+
+    #[error("Lens profile format error: {0}")]
+    LensProfileFormatError(String),
+
+    #[error("Image error {0:?}")]
+    ImageError(#[from] image::ImageError),
+
+    #[error("Plugin error: {0}")]
+    PluginError(String),
+
     #[error("Unknown error")]
     Unknown
 }
@@ -48,6 +48,7 @@ impl Default for DefaultAlgo {
 }
 
 impl SmoothingAlgorithm for DefaultAlgo {
+    fn get_id(&self) -> String { "default".to_owned() }
     fn get_name(&self) -> String { "Default".to_owned() }
 
     fn set_parameter(&mut self, name: &str, val: f64) {
@@ -14,6 +14,7 @@ pub struct Fixed {
 }
 
 impl SmoothingAlgorithm for Fixed {
+    fn get_id(&self) -> String { "fixed".to_owned() }
     fn get_name(&self) -> String { "Fixed camera".to_owned() }
 
     fn set_parameter(&mut self, name: &str, val: f64) {
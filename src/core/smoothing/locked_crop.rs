@@ -0,0 +1,517 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+use super::*;
+
+use crate::gyro_source::{ TimeQuat, Quat64 };
+use crate::keyframes::*;
+use crate::stabilization::{ ComputeParams, FrameTransform };
+use crate::zooming::{ self, fov_iterative::FovIterative };
+use rayon::iter::{ ParallelIterator, IntoParallelIterator };
+
+/*
+Smoothing with a locked crop, using L1 optimal camera paths like in
+"Auto-Directed Video Stabilization with Robust L1 Optimal Camera Paths" (Grundmann et al.):
+    - the camera path is sampled at frame rate and each frame's orientation is the raw orientation rotated by an offset `y`
+      (a rotation vector in the camera frame). The correction applied to the frame is then `exp(-y)`
+    - the path is unrolled to a continuous vector path by summing the raw frame to frame rotations,
+      so the path differences are linear in the offsets for the small rotations within the crop
+    - the objective is the weighted L1 norm of the 1st, 2nd and 3rd differences of the path, so the result is made of
+      constant, linear and parabolic segments (a still camera, pans and smooth transitions between them)
+    - the constraint is that the output at `crop_fov` stays inside the source frame. For every frame `FovIterative`
+      finds how far the correction can go along each camera axis, which gives a box of allowed offsets
+    - the three axes are independent LPs, which are solved with ADMM (the x-update is a banded linear system)
+    - the box doesn't include the combined rotations along more than one axis, so the frames which don't fit
+      after the solve get a smaller box and the path is solved again
+  The result keeps the crop inside the frame as long as the raw orientation fits.
+  Zooming uses `crop_fov` for every frame instead of fitting the fov (see `locked_fov`).
+*/
+
+// Weights of the 1st, 2nd and 3rd differences, from the paper
+const WEIGHTS: [f64; 3] = [10.0, 1.0, 100.0];
+const DIFFERENCES: [&[f64]; 3] = [&[-1.0, 1.0], &[1.0, -2.0, 1.0], &[-1.0, 3.0, -3.0, 1.0]];
+
+const MAX_CORRECTION: f64 = 30.0 * std::f64::consts::PI / 180.0;
+const BISECTION_STEPS: usize = 10;
+const REFINEMENTS: usize = 10;
+const SHRINK: f64 = 0.7;
+
+const ADMM_ITERATIONS: usize = 5000;
+const ADMM_RELAXATION: f64 = 1.6;
+const ADMM_EPS_ABS: f64 = 1e-5;
+const ADMM_EPS_REL: f64 = 1e-4;
+
+#[derive(Clone)]
+struct FrameLens {
+    camera_matrix: Matrix3<f64>,
+    distortion_coeffs: [f64; 12],
+    new_k: Matrix3<f64>,
+}
+
+#[derive(Clone)]
+struct FrameGeometry {
+    compute_params: ComputeParams,
+    frames: Vec<FrameLens>, // Lens data for every video frame
+    fps: f64,
+    checksum: u64,
+}
+
+#[derive(Clone)]
+pub struct LockedCrop {
+    pub crop_fov: f64,
+    geometry: Option<FrameGeometry>,
+}
+
+impl Default for LockedCrop {
+    fn default() -> Self { Self {
+        crop_fov: 0.85,
+        geometry: None,
+    } }
+}
+
+impl SmoothingAlgorithm for LockedCrop {
+    fn get_id(&self) -> String { "locked_crop".to_owned() }
+    fn get_name(&self) -> String { "Locked crop (L1 optimal path)".to_owned() }
+
+    fn set_parameter(&mut self, name: &str, val: f64) {
+        match name {
+            "crop_fov" => self.crop_fov = val,
+            _ => log::error!("Invalid parameter name: {}", name)
+        }
+    }
+    fn get_parameter(&self, name: &str) -> f64 {
+        match name {
+            "crop_fov" => self.crop_fov,
+            _ => 0.0
+        }
+    }
+
+    fn get_parameters_json(&self) -> serde_json::Value {
+        serde_json::json!([
+            {
+                "name": "crop_fov",
+                "description": "Crop FOV",
+                "type": "SliderWithField",
+                "from": 0.3,
+                "to": 1.0,
+                "value": self.crop_fov,
+                "default": 0.85,
+                "precision": 2,
+                "unit": ""
+            }
+        ])
+    }
+    fn get_status_json(&self) -> serde_json::Value {
+        if self.geometry.is_none() {
+            serde_json::json!([{
+                "type": "Label",
+                "text": "Lens profile is required to keep the crop inside the frame."
+            }])
+        } else {
+            serde_json::json!([])
+        }
+    }
+
+    fn get_checksum(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        hasher.write_u64(self.crop_fov.to_bits());
+        hasher.write_u64(self.geometry.as_ref().map(|x| x.checksum).unwrap_or_default());
+        hasher.finish()
+    }
+
+    fn needs_compute_params(&self) -> bool { true }
+
+    fn set_compute_params(&mut self, params: &ComputeParams) {
+        let checksum = geometry_checksum(params);
+        if self.geometry.as_ref().map(|x| x.checksum) == Some(checksum) {
+            return;
+        }
+        let compute_params = zooming::compute_params_for_fov(params);
+        let (camera_matrix, _, _, _, _, _) = FrameTransform::get_lens_data_at_timestamp(&compute_params, 0.0);
+        if compute_params.video_width == 0 || compute_params.video_height == 0 || camera_matrix[(0, 0)] <= 0.0 {
+            self.geometry = None;
+            return;
+        }
+
+        // The lens can change during the clip (zoom lenses, focus breathing), so read it for every frame.
+        // This can't be done in `smooth`, because it's called with the gyro data locked
+        let fps = if compute_params.scaled_fps > 0.0 { compute_params.scaled_fps } else { 30.0 };
+        let frames = (0..compute_params.frame_count.max(1)).map(|i| {
+            let (camera_matrix, distortion_coeffs, _, _, _, _) = FrameTransform::get_lens_data_at_timestamp(&compute_params, i as f64 * 1000.0 / fps);
+            let new_k = FrameTransform::get_new_k(&compute_params, &camera_matrix, 1.0);
+            FrameLens { camera_matrix, distortion_coeffs, new_k }
+        }).collect();
+
+        self.geometry = Some(FrameGeometry { compute_params, frames, fps, checksum });
+    }
+
+    fn locked_fov(&self) -> Option<f64> {
+        self.geometry.as_ref().map(|_| self.crop_fov)
+    }
+
+    fn smooth(&self, quats: &TimeQuat, duration: f64, stabilization_params: &StabilizationParams, _keyframes: &KeyframeManager) -> TimeQuat {
+        if quats.is_empty() || duration <= 0.0 { return quats.clone(); }
+        // Without the lens there's no constraint and the optimal path would be a still camera
+        let Some(geometry) = self.geometry.as_ref() else { return quats.clone(); };
+
+        // Plan the path at frame rate, the fov test is too expensive to run for every gyro sample
+        let fps = stabilization_params.get_scaled_fps();
+        let fps = if fps > 0.0 { fps } else { 30.0 };
+        let step = 1_000_000.0 / fps;
+        let first_ts = *quats.keys().next().unwrap();
+        let last_ts = *quats.keys().next_back().unwrap();
+        let count = ((last_ts - first_ts) as f64 / step).ceil() as usize + 1;
+        let timestamps: Vec<i64> = (0..count).map(|i| (first_ts + (i as f64 * step).round() as i64).min(last_ts)).collect();
+        let raw: Vec<Quat64> = timestamps.iter().map(|&ts| quat_at(quats, ts)).collect();
+
+        let estimator = FovIterative::new(&geometry.compute_params);
+        let fits = |i: usize, correction: &Quat64| -> bool {
+            let timestamp_ms = timestamps[i] as f64 / 1000.0;
+            let frame = &geometry.frames[((timestamp_ms * geometry.fps / 1000.0).round().max(0.0) as usize).min(geometry.frames.len() - 1)];
+            estimator.fov_for_rotation(correction, timestamp_ms, frame.camera_matrix, &frame.distortion_coeffs, frame.new_k) >= self.crop_fov
+        };
+
+        let mut unrolled = vec![Vector3::<f64>::zeros(); count];
+        for i in 1..count {
+            unrolled[i] = unrolled[i - 1] + (raw[i - 1].inverse() * raw[i]).scaled_axis();
+        }
+
+        // Allowed offsets along each camera axis, (negative, positive). The raw orientation always fits, unless the crop is bigger than the undistorted frame
+        let mut bounds: Vec<[(f64, f64); 3]> = (0..count).into_par_iter().map(|i| {
+            let extent = |direction: Vector3<f64>| -> f64 {
+                let fits_at = |angle: f64| fits(i, &Quat64::from_scaled_axis(-direction * angle));
+                if fits_at(MAX_CORRECTION) { return MAX_CORRECTION; }
+                let (mut lo, mut hi) = (0.0, MAX_CORRECTION);
+                for _ in 0..BISECTION_STEPS {
+                    let mid = (lo + hi) / 2.0;
+                    if fits_at(mid) { lo = mid; } else { hi = mid; }
+                }
+                lo
+            };
+            [0, 1, 2].map(|axis| {
+                let direction = Vector3::ith(axis, 1.0);
+                (-extent(-direction), extent(direction))
+            })
+        }).collect();
+
+        let mut offsets = vec![Vector3::<f64>::zeros(); count];
+        for _ in 0..REFINEMENTS {
+            let solved: Vec<Vec<f64>> = (0..3usize).into_par_iter().map(|axis| {
+                let path: Vec<f64> = unrolled.iter().map(|x| x[axis]).collect();
+                let lower: Vec<f64> = path.iter().zip(&bounds).map(|(x, b)| x + b[axis].0).collect();
+                let upper: Vec<f64> = path.iter().zip(&bounds).map(|(x, b)| x + b[axis].1).collect();
+                l1_optimal_path(&path, &lower, &upper)
+            }).collect();
+            for (i, offset) in offsets.iter_mut().enumerate() {
+                *offset = Vector3::new(solved[0][i], solved[1][i], solved[2][i]) - unrolled[i];
+            }
+
+            let outside: Vec<usize> = (0..count).into_par_iter().filter(|&i| !fits(i, &Quat64::from_scaled_axis(-offsets[i]))).collect();
+            if outside.is_empty() { break; }
+            for i in outside {
+                for b in bounds[i].iter_mut() {
+                    b.0 *= SHRINK;
+                    b.1 *= SHRINK;
+                }
+            }
+        }
+
+        // Frames which still don't fit after the last refinement are pulled back towards the raw orientation
+        let path: Vec<Quat64> = (0..count).into_par_iter().map(|i| {
+            let q = raw[i] * Quat64::from_scaled_axis(offsets[i]);
+            if fits(i, &(q.inverse() * raw[i])) { return q; }
+            let (mut lo, mut hi) = (0.0, 1.0);
+            for _ in 0..BISECTION_STEPS {
+                let mid = (lo + hi) / 2.0;
+                if fits(i, &(raw[i].slerp(&q, mid).inverse() * raw[i])) { lo = mid; } else { hi = mid; }
+            }
+            raw[i].slerp(&q, lo)
+        }).collect();
+
+        quats.keys().map(|&ts| {
+            let pos = ((ts - first_ts) as f64 / step).max(0.0);
+            let idx = (pos.floor() as usize).min(count - 1);
+            let next = (idx + 1).min(count - 1);
+            (ts, path[idx].slerp(&path[next], (pos - idx as f64).min(1.0)))
+        }).collect()
+    }
+}
+
+// Everything `set_compute_params` reads, so the per-frame lens data is only rebuilt when it can change
+fn geometry_checksum(params: &ComputeParams) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write_u64(zooming::get_checksum(params));
+    for row in &params.lens.fisheye_params.camera_matrix {
+        for x in row { hasher.write_u64(x.to_bits()); }
+    }
+    hasher.write_usize(params.lens.calib_dimension.w);
+    hasher.write_usize(params.lens.calib_dimension.h);
+    hasher.write_u64(params.lens.input_horizontal_stretch.to_bits());
+    hasher.write_u64(params.lens.input_vertical_stretch.to_bits());
+    hasher.write_usize(params.frame_count);
+    hasher.write_u64(params.lens_correction_amount.to_bits());
+    hasher.write_u64(params.adaptive_zoom_center_offset.0.to_bits());
+    hasher.write_u64(params.adaptive_zoom_center_offset.1.to_bits());
+    {
+        let gyro = params.gyro.read();
+        hasher.write_usize(gyro.file_metadata.lens_positions.len());
+        hasher.write_usize(gyro.file_metadata.lens_params.len());
+    }
+    hasher.finish()
+}
+
+// Minimizes the `WEIGHTS`-weighted L1 norms of the `DIFFERENCES` of `path` subject to `lower <= path <= upper`, with ADMM.
+// `path` is only used as the starting point and to normalize the scale, it has to be within the bounds
+fn l1_optimal_path(path: &[f64], lower: &[f64], upper: &[f64]) -> Vec<f64> {
+    let n = path.len();
+    let scale = path.windows(2).map(|x| (x[1] - x[0]).abs()).sum::<f64>() / n.max(2) as f64;
+    if n <= DIFFERENCES[2].len() || scale < 1e-12 {
+        return path.to_vec();
+    }
+    let lower: Vec<f64> = lower.iter().map(|x| x / scale).collect();
+    let upper: Vec<f64> = upper.iter().map(|x| x / scale).collect();
+
+    let system = BandedSystem::new(n);
+
+    let mut p: Vec<f64> = path.iter().map(|x| x / scale).collect();
+    let mut z: Vec<Vec<f64>> = DIFFERENCES.iter().map(|coeffs| difference(&p, coeffs)).collect();
+    let mut q = p.clone();
+    let mut v: Vec<Vec<f64>> = z.iter().map(|x| vec![0.0; x.len()]).collect();
+    let mut vq = vec![0.0; n];
+    let mut rho = 1.0;
+    let constraints = n + z.iter().map(|x| x.len()).sum::<usize>();
+
+    for iteration in 0..ADMM_ITERATIONS {
+        let mut rhs: Vec<f64> = q.iter().zip(&vq).map(|(a, b)| a - b).collect();
+        for (coeffs, (z, v)) in DIFFERENCES.iter().zip(z.iter().zip(&v)) {
+            add_difference_transposed(&mut rhs, &z.iter().zip(v).map(|(a, b)| a - b).collect::<Vec<_>>(), coeffs);
+        }
+        p = system.solve(rhs);
+
+        let (mut primal, mut dual, mut ap_norm, mut z_norm, mut v_norm) = (0.0, 0.0, 0.0, 0.0, 0.0);
+        // With over-relaxation, which converges much faster on these problems
+        let relaxed = |ap: f64, z: f64| ADMM_RELAXATION * ap + (1.0 - ADMM_RELAXATION) * z;
+        let mut update = |ap: f64, ap_relaxed: f64, z: &mut f64, v: &mut f64, new_z: f64| {
+            primal += (ap - new_z).powi(2);
+            dual += (new_z - *z).powi(2);
+            ap_norm += ap * ap;
+            z_norm += new_z * new_z;
+            *v += ap_relaxed - new_z;
+            *z = new_z;
+            v_norm += *v * *v;
+        };
+        for (k, coeffs) in DIFFERENCES.iter().enumerate() {
+            let dp = difference(&p, coeffs);
+            for ((ap, z), v) in dp.into_iter().zip(z[k].iter_mut()).zip(v[k].iter_mut()) {
+                let ap_relaxed = relaxed(ap, *z);
+                let new_z = soft_threshold(ap_relaxed + *v, WEIGHTS[k] / rho);
+                update(ap, ap_relaxed, z, v, new_z);
+            }
+        }
+        for (i, (q, vq)) in q.iter_mut().zip(vq.iter_mut()).enumerate() {
+            let p_relaxed = relaxed(p[i], *q);
+            let new_q = (p_relaxed + *vq).max(lower[i]).min(upper[i]);
+            update(p[i], p_relaxed, q, vq, new_q);
+        }
+
+        let (primal, dual) = (primal.sqrt(), rho * dual.sqrt());
+        if primal <= ADMM_EPS_ABS * (constraints as f64).sqrt() + ADMM_EPS_REL * ap_norm.max(z_norm).sqrt()
+        && dual <= ADMM_EPS_ABS * (n as f64).sqrt() + ADMM_EPS_REL * rho * v_norm.sqrt() {
+            break;
+        }
+        // Residual balancing, the system doesn't depend on rho so it doesn't have to be factorized again
+        if iteration % 10 == 9 {
+            let factor = if primal > 10.0 * dual { 2.0 } else if dual > 10.0 * primal { 0.5 } else { 1.0 };
+            if factor != 1.0 {
+                rho *= factor;
+                v.iter_mut().flatten().chain(vq.iter_mut()).for_each(|x| *x /= factor);
+            }
+        }
+    }
+
+    // `q` is always within the bounds
+    q.into_iter().map(|x| x * scale).collect()
+}
+
+fn soft_threshold(x: f64, threshold: f64) -> f64 {
+    x.signum() * (x.abs() - threshold).max(0.0)
+}
+fn difference(x: &[f64], coeffs: &[f64]) -> Vec<f64> {
+    x.windows(coeffs.len()).map(|w| w.iter().zip(coeffs).map(|(a, b)| a * b).sum::<f64>()).collect()
+}
+fn add_difference_transposed(out: &mut [f64], y: &[f64], coeffs: &[f64]) {
+    for (r, y) in y.iter().enumerate() {
+        for (j, c) in coeffs.iter().enumerate() {
+            out[r + j] += c * y;
+        }
+    }
+}
+
+// LDLᵀ factorization of `I + Σ DᵀD` for the `DIFFERENCES`, which is symmetric positive definite with 3 subdiagonals
+struct BandedSystem {
+    l: Vec<[f64; 3]>, // l[i][d - 1] = L[i][i - d]
+    d: Vec<f64>,
+}
+impl BandedSystem {
+    fn new(n: usize) -> Self {
+        // band[i][d] = M[i][i + d]
+        let mut band = vec![[0.0; 4]; n];
+        for x in band.iter_mut() { x[0] = 1.0; }
+        for coeffs in DIFFERENCES {
+            for r in 0..=(n - coeffs.len()) {
+                for (a, ca) in coeffs.iter().enumerate() {
+                    for (b, cb) in coeffs.iter().enumerate().skip(a) {
+                        band[r + a][b - a] += ca * cb;
+                    }
+                }
+            }
+        }
+
+        let mut l = vec![[0.0; 3]; n];
+        let mut d = vec![0.0; n];
+        for i in 0..n {
+            for j in i.saturating_sub(3)..i {
+                let mut v = band[j][i - j];
+                for k in i.saturating_sub(3)..j {
+                    v -= l[i][i - k - 1] * l[j][j - k - 1] * d[k];
+                }
+                l[i][i - j - 1] = v / d[j];
+            }
+            let mut v = band[i][0];
+            for k in i.saturating_sub(3)..i {
+                v -= l[i][i - k - 1].powi(2) * d[k];
+            }
+            d[i] = v;
+        }
+        Self { l, d }
+    }
+
+    fn solve(&self, mut x: Vec<f64>) -> Vec<f64> {
+        let n = x.len();
+        for i in 0..n {
+            for k in i.saturating_sub(3)..i {
+                x[i] -= self.l[i][i - k - 1] * x[k];
+            }
+        }
+        for (x, d) in x.iter_mut().zip(&self.d) {
+            *x /= d;
+        }
+        for i in (0..n).rev() {
+            for k in (i + 1)..(i + 4).min(n) {
+                x[i] -= self.l[k][k - i - 1] * x[k];
+            }
+        }
+        x
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StabilizationManager;
+    use nalgebra::{ Unit, Vector3 };
+
+    fn compute_params() -> ComputeParams {
+        let stab = StabilizationManager::default();
+        stab.init_from_video_data(3000.0, 30.0, 90, (1920, 1080));
+        stab.set_size(1920, 1080);
+        stab.set_output_size(1920, 1080);
+        {
+            let mut lens = stab.lens.write();
+            lens.calib_dimension = crate::lens_profile::Dimensions { w: 1920, h: 1080 };
+            lens.fisheye_params.camera_matrix = vec![[1000.0, 0.0, 960.0], [0.0, 1000.0, 540.0], [0.0, 0.0, 1.0]];
+            lens.fisheye_params.distortion_coeffs = vec![0.0; 4];
+        }
+        ComputeParams::from_manager(&stab)
+    }
+
+    #[test]
+    fn crop_stays_inside_frame() {
+        let params = compute_params();
+        let mut stabilization_params = StabilizationParams::default();
+        stabilization_params.fps = 30.0;
+
+        // Shaky pan with ±10° of yaw, a still path would need a crop of about 0.8 at f = 1000px
+        let quats: TimeQuat = (0..3000).map(|ms| {
+            let t = ms as f64 / 1000.0;
+            let yaw = (10.0 * (t * 5.0).sin() + 3.0 * (t * 23.0).sin()).to_radians();
+            (ms * 1000, Quat64::from_axis_angle(&Unit::new_normalize(Vector3::y()), yaw))
+        }).collect();
+
+        let mut algo = LockedCrop::default();
+        algo.set_compute_params(&params);
+        assert_eq!(algo.locked_fov(), Some(0.85));
+        let smoothed = algo.smooth(&quats, 3000.0, &stabilization_params, &KeyframeManager::new());
+        assert_eq!(smoothed.len(), quats.len());
+
+        let geometry = algo.geometry.as_ref().unwrap();
+        let estimator = FovIterative::new(&geometry.compute_params);
+        let frame = &geometry.frames[0];
+        let (mut raw_motion, mut smoothed_motion) = (0.0, 0.0);
+        for i in 0..90 {
+            let ts = (i as f64 * 1_000_000.0 / 30.0).round() as i64;
+            let raw = quat_at(&quats, ts);
+            let path = quat_at(&smoothed, ts);
+            let fov = estimator.fov_for_rotation(&(path.inverse() * raw), ts as f64 / 1000.0, frame.camera_matrix, &frame.distortion_coeffs, frame.new_k);
+            assert!(fov >= algo.crop_fov - 0.01, "frame {i}: fov {fov}");
+
+            if i > 0 {
+                let prev_ts = ((i - 1) as f64 * 1_000_000.0 / 30.0).round() as i64;
+                raw_motion += quat_at(&quats, prev_ts).angle_to(&raw);
+                smoothed_motion += quat_at(&smoothed, prev_ts).angle_to(&path);
+            }
+        }
+        assert!(smoothed_motion < raw_motion * 0.7, "{smoothed_motion} >= {raw_motion}");
+    }
+
+    fn objective(path: &[f64]) -> f64 {
+        DIFFERENCES.iter().zip(WEIGHTS).map(|(coeffs, w)| w * difference(path, coeffs).iter().map(|x| x.abs()).sum::<f64>()).sum()
+    }
+
+    #[test]
+    fn l1_path_ramp() {
+        // A pan within a ±0.2 box, the optimum is the straight line from (0, 0.2) to (199, 1.79) with objective 10 * 1.59
+        let path: Vec<f64> = (0..200).map(|i| i as f64 * 0.01).collect();
+        let lower: Vec<f64> = path.iter().map(|x| x - 0.2).collect();
+        let upper: Vec<f64> = path.iter().map(|x| x + 0.2).collect();
+        let result = l1_optimal_path(&path, &lower, &upper);
+
+        for ((x, lo), hi) in result.iter().zip(&lower).zip(&upper) {
+            assert!(x >= lo && x <= hi, "{x} outside of [{lo}, {hi}]");
+        }
+        assert!(objective(&result) < 15.9 * 1.02, "objective {} (raw path {})", objective(&result), objective(&path));
+    }
+
+    #[test]
+    fn l1_path_still_camera() {
+        // Shake which fits in the box, so the camera doesn't have to move at all
+        let path: Vec<f64> = (0..300).map(|i| 0.02 * (i as f64 * 0.7).sin() + 0.01 * (i as f64 * 2.3).cos()).collect();
+        let lower: Vec<f64> = path.iter().map(|x| x - 0.05).collect();
+        let upper: Vec<f64> = path.iter().map(|x| x + 0.05).collect();
+        let result = l1_optimal_path(&path, &lower, &upper);
+
+        let (min, max) = result.iter().fold((f64::MAX, f64::MIN), |(a, b), x| (a.min(*x), b.max(*x)));
+        assert!(max - min < 1e-3, "path moves by {}", max - min);
+    }
+
+    #[test]
+    fn full_frame_crop_keeps_raw_path() {
+        let params = compute_params();
+        let mut stabilization_params = StabilizationParams::default();
+        stabilization_params.fps = 30.0;
+
+        let quats: TimeQuat = (0..1000).map(|ms| {
+            let yaw = (5.0 * (ms as f64 / 100.0).sin()).to_radians();
+            (ms * 1000, Quat64::from_axis_angle(&Unit::new_normalize(Vector3::y()), yaw))
+        }).collect();
+
+        let mut algo = LockedCrop { crop_fov: 1.0, ..Default::default() };
+        algo.set_compute_params(&params);
+        let smoothed = algo.smooth(&quats, 1000.0, &stabilization_params, &KeyframeManager::new());
+        // Any correction would show a border, so the camera has to follow the raw motion
+        for i in 0..30 {
+            let ts = (i as f64 * 1_000_000.0 / 30.0).round() as i64;
+            assert!(quat_at(&smoothed, ts).angle_to(&quat_at(&quats, ts)) < 0.5f64.to_radians(), "frame {i}");
+        }
+    }
+}
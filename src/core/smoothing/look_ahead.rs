@@ -0,0 +1,208 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Bounded-latency smoothing, for live feeds and incremental processing of long recordings.
+// Any `SmoothingAlgorithm` is evaluated in a sliding window, so the smoothed orientation at time `t` only depends on the samples
+// within `[t - history_ms, t + look_ahead_ms]`. The window is evaluated on a fixed grid of `step_ms` (aligned to timestamp 0)
+// and the samples in between are interpolated, so the output at `t` is final once samples up to `t + latency_ms()` are known.
+// Every grid point depends only on the samples in its own window (and the trim ranges), so `smooth` over the whole recording
+// and `smooth_at` called incrementally give the same results. They are close to offline smoothing of the whole recording
+// when the window is much longer than the smoothing time constant, but not identical: the algorithm doesn't see past the window.
+// Every sample is smoothed in `(history_ms + look_ahead_ms) / step_ms` windows, so the step is limited to keep that at `MAX_WINDOWS_PER_SAMPLE`.
+
+use super::*;
+use crate::gyro_source::{ TimeQuat, Quat64 };
+
+const MAX_WINDOWS_PER_SAMPLE: f64 = 100.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct LookAhead {
+    /// How far into the future the algorithm can see. 0 means fully causal
+    pub look_ahead_ms: f64,
+    /// How much of the past is taken into account
+    pub history_ms: f64,
+    /// Interval between the evaluated windows. Can't be shorter than `(history_ms + look_ahead_ms) / MAX_WINDOWS_PER_SAMPLE`
+    pub step_ms: f64,
+}
+
+impl Default for LookAhead {
+    fn default() -> Self {
+        Self {
+            look_ahead_ms: 500.0,
+            history_ms: 3000.0,
+            step_ms: 40.0,
+        }
+    }
+}
+
+impl LookAhead {
+    /// Maximum delay between receiving a sample and having the final smoothed orientation for it
+    pub fn latency_ms(&self) -> f64 {
+        self.look_ahead_ms.max(0.0) + self.step_us() as f64 / 1000.0
+    }
+
+    fn step_us(&self) -> i64 {
+        let min_step_ms = (self.history_ms.max(0.0) + self.look_ahead_ms.max(0.0)) / MAX_WINDOWS_PER_SAMPLE;
+        ((self.step_ms.max(min_step_ms) * 1000.0).round() as i64).max(1)
+    }
+
+    /// Duration of `len` samples between `first_ts_us` and `last_ts_us`, so the algorithms see the same sample rate as in offline smoothing
+    pub fn window_duration_ms(first_ts_us: i64, last_ts_us: i64, len: usize) -> f64 {
+        if len < 2 || last_ts_us <= first_ts_us { return 0.0; }
+        (last_ts_us - first_ts_us) as f64 / 1000.0 * (len as f64 / (len - 1) as f64)
+    }
+
+    /// Smoothed orientation at `timestamp_us`, computed only from the samples within its window.
+    /// `duration` is the duration of the whole recording in ms, the trim ranges are relative to it.
+    pub fn smooth_window(&self, alg: &dyn SmoothingAlgorithm, quats: &TimeQuat, duration: f64, params: &StabilizationParams, keyframes: &KeyframeManager, timestamp_us: i64) -> Option<Quat64> {
+        let from = timestamp_us - (self.history_ms.max(0.0) * 1000.0).round() as i64;
+        let to = timestamp_us + (self.look_ahead_ms.max(0.0) * 1000.0).round() as i64;
+        let window: TimeQuat = quats.range(from..=to).map(|(&ts, &q)| (ts, q)).collect();
+        let (first_ts, last_ts) = (*window.keys().next()?, *window.keys().next_back()?);
+        if window.len() < 2 {
+            return window.values().next().copied();
+        }
+
+        let window_duration = Self::window_duration_ms(first_ts, last_ts, window.len());
+        let smoothed = if params.trim_ranges.is_empty() || duration <= 0.0 {
+            alg.smooth(&window, window_duration, params, keyframes)
+        } else {
+            // The algorithms map the trim ranges using the duration they get, keep them at the same timestamps as in the whole recording
+            let mut params = params.clone();
+            let scale = duration / window_duration;
+            params.trim_ranges.iter_mut().for_each(|x| *x = (x.0 * scale, x.1 * scale));
+            alg.smooth(&window, window_duration, &params, keyframes)
+        };
+        Some(quat_at(&smoothed, timestamp_us))
+    }
+
+    /// Smoothed orientation at `timestamp_us`, interpolated between the nearest grid points.
+    /// Returns `None` until `quats` contains the samples up to `timestamp_us + latency_ms()` (or the end of the data if `is_final`).
+    pub fn smooth_at(&self, alg: &dyn SmoothingAlgorithm, quats: &TimeQuat, duration: f64, params: &StabilizationParams, keyframes: &KeyframeManager, timestamp_us: i64, is_final: bool) -> Option<Quat64> {
+        let step = self.step_us();
+        let last_ts = *quats.keys().next_back()?;
+        let prev = timestamp_us.div_euclid(step) * step;
+        let next = if prev == timestamp_us { prev } else { prev + step };
+        if !is_final && last_ts < next + (self.look_ahead_ms.max(0.0) * 1000.0).round() as i64 {
+            return None;
+        }
+
+        let q1 = self.smooth_window(alg, quats, duration, params, keyframes, prev);
+        if next == prev { return q1; }
+        let q2 = self.smooth_window(alg, quats, duration, params, keyframes, next);
+        Self::interpolate(q1, q2, (timestamp_us - prev) as f64 / step as f64)
+    }
+
+    /// Smooths the whole `quats`, giving the same result as calling `smooth_at` for every sample as the data arrives
+    pub fn smooth(&self, alg: &dyn SmoothingAlgorithm, quats: &TimeQuat, duration: f64, params: &StabilizationParams, keyframes: &KeyframeManager) -> TimeQuat {
+        if quats.is_empty() || duration <= 0.0 { return quats.clone(); }
+
+        let step = self.step_us();
+        let first_ts = *quats.keys().next().unwrap();
+        let last_ts = *quats.keys().next_back().unwrap();
+        let first_grid = first_ts.div_euclid(step);
+        let last_grid = (last_ts + step - 1).div_euclid(step);
+
+        let grid: Vec<Option<Quat64>> = (first_grid..=last_grid).map(|i| {
+            self.smooth_window(alg, quats, duration, params, keyframes, i * step)
+        }).collect();
+
+        quats.iter().map(|(&ts, &org)| {
+            let idx = (ts.div_euclid(step) - first_grid) as usize;
+            let fract = ts.rem_euclid(step) as f64 / step as f64;
+            let q1 = grid.get(idx).copied().flatten();
+            let q2 = if fract > 0.0 { grid.get(idx + 1).copied().flatten() } else { q1 };
+            (ts, Self::interpolate(q1, q2, fract).unwrap_or(org))
+        }).collect()
+    }
+
+    fn interpolate(q1: Option<Quat64>, q2: Option<Quat64>, fract: f64) -> Option<Quat64> {
+        match (q1, q2) {
+            (Some(q1), Some(q2)) => Some(if fract > 0.0 { q1.slerp(&q2, fract) } else { q1 }),
+            (Some(q), None) | (None, Some(q)) => Some(q),
+            (None, None) => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::{ Unit, Vector3 };
+
+    // 1 kHz samples of a shaky pan
+    fn shaky_quats(duration_ms: i64) -> TimeQuat {
+        (0..duration_ms).map(|ms| {
+            let t = ms as f64 / 1000.0;
+            let yaw = 0.3 * t + 0.05 * (t * 17.0).sin();
+            let pitch = 0.03 * (t * 29.0).sin() + 0.02 * (t * 3.0).cos();
+            (ms * 1000, Quat64::from_axis_angle(&Unit::new_normalize(Vector3::y()), yaw) * Quat64::from_axis_angle(&Unit::new_normalize(Vector3::x()), pitch))
+        }).collect()
+    }
+
+    fn max_angle(a: &TimeQuat, b: &TimeQuat, range: std::ops::Range<i64>) -> f64 {
+        a.range(range).map(|(ts, q)| q.angle_to(&b[ts])).fold(0.0, f64::max)
+    }
+
+    #[test]
+    fn matches_offline_smoothing() {
+        let quats = shaky_quats(10000);
+        let params = StabilizationParams::default();
+        let keyframes = KeyframeManager::new();
+        let alg = plain::Plain { time_constant: 0.2, ..Default::default() };
+
+        let offline = alg.smooth(&quats, 10000.0, &params, &keyframes);
+        let look_ahead = LookAhead { look_ahead_ms: 1500.0, history_ms: 3000.0, step_ms: 45.0 };
+        let live = look_ahead.smooth(&alg, &quats, 10000.0, &params, &keyframes);
+
+        // The windows are 7.5 time constants long in each direction, away from the ends of the recording the results are the same
+        let diff = max_angle(&offline, &live, 3000_000..8500_000);
+        assert!(diff < 0.05f64.to_radians(), "{}°", diff.to_degrees());
+
+        // Trim ranges are relative to the whole recording, not to the window
+        let mut params = params.clone();
+        params.trim_ranges = vec![(0.4, 0.6)];
+        let alg = plain::Plain { time_constant: 0.2, trim_range_only: true };
+        let offline = alg.smooth(&quats, 10000.0, &params, &keyframes);
+        let live = look_ahead.smooth(&alg, &quats, 10000.0, &params, &keyframes);
+        let diff = max_angle(&offline, &live, 3000_000..8500_000);
+        assert!(diff < 0.05f64.to_radians(), "{}°", diff.to_degrees());
+    }
+
+    #[test]
+    fn incremental_is_deterministic() {
+        let quats = shaky_quats(3000);
+        let params = StabilizationParams::default();
+        let keyframes = KeyframeManager::new();
+        let alg = plain::Plain::default();
+        let look_ahead = LookAhead { look_ahead_ms: 300.0, history_ms: 1000.0, step_ms: 20.0 };
+
+        let full = look_ahead.smooth(&alg, &quats, 3000.0, &params, &keyframes);
+
+        // Samples arrive in chunks of 100 ms, every sample is smoothed as soon as it's final
+        let mut received = TimeQuat::new();
+        let mut next = quats.keys().copied().peekable();
+        for (i, (&ts, &q)) in quats.iter().enumerate() {
+            received.insert(ts, q);
+            let is_final = i == quats.len() - 1;
+            if i % 100 != 99 && !is_final { continue; }
+            while let Some(&sample_ts) = next.peek() {
+                match look_ahead.smooth_at(&alg, &received, 3000.0, &params, &keyframes, sample_ts, is_final) {
+                    Some(q) => {
+                        assert!(q.angle_to(&full[&sample_ts]) < 1e-9, "{sample_ts}");
+                        next.next();
+                    },
+                    None => break
+                }
+            }
+        }
+        assert!(next.peek().is_none());
+    }
+
+    #[test]
+    fn step_is_bounded() {
+        let look_ahead = LookAhead { look_ahead_ms: 500.0, history_ms: 3000.0, step_ms: 1.0 };
+        assert_eq!(look_ahead.step_us(), 35_000);
+        assert!((LookAhead::window_duration_ms(0, 999_000, 1000) - 1000.0).abs() < 1e-9);
+    }
+}
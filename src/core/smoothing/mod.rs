@@ -6,12 +6,17 @@ pub mod none;
 pub mod plain;
 pub mod fixed;
 pub mod default_algo;
+pub mod locked_crop;
+pub mod look_ahead;
+pub mod plugin;
 
 pub use nalgebra::*;
-use super::gyro_source::TimeQuat;
+use super::gyro_source::{ TimeQuat, Quat64 };
 pub use std::collections::HashMap;
 use dyn_clone::{ clone_trait_object, DynClone };
 use std::borrow::Cow;
+use std::sync::Arc;
+use parking_lot::RwLock;
 
 use std::hash::Hasher;
 use std::collections::hash_map::DefaultHasher;
@@ -19,6 +24,8 @@ use crate::keyframes::*;
 use crate::stabilization_params::StabilizationParams;
 
 pub trait SmoothingAlgorithm: DynClone {
+    /// Stable identifier, saved in the project files
+    fn get_id(&self) -> String;
     fn get_name(&self) -> String;
 
     fn get_parameters_json(&self) -> serde_json::Value;
@@ -28,30 +35,63 @@ pub trait SmoothingAlgorithm: DynClone {
 
     fn get_checksum(&self) -> u64;
 
+    /// Whether `set_compute_params` should be called before `smooth`. Building the parameters is not free, so it's opt-in
+    fn needs_compute_params(&self) -> bool { false }
+    /// Called before `smooth` with the current video and lens parameters, for algorithms which need to know the frame geometry
+    fn set_compute_params(&mut self, _params: &crate::stabilization::ComputeParams) { }
+
+    /// FOV which zooming should use for every frame instead of fitting it, for algorithms which already keep a fixed crop inside the frame
+    fn locked_fov(&self) -> Option<f64> { None }
+
     fn smooth(&self, quats: &TimeQuat, duration: f64, _stabilization_params: &StabilizationParams, keyframes: &KeyframeManager) -> TimeQuat;
 }
 clone_trait_object!(SmoothingAlgorithm);
 
+pub type AlgorithmFactory = Arc<dyn Fn() -> Box<dyn SmoothingAlgorithm> + Send + Sync>;
+
+lazy_static::lazy_static! {
+    static ref REGISTRY: RwLock<Vec<(String, AlgorithmFactory)>> = RwLock::new(vec![
+        ("none".into(),    Arc::new(|| Box::new(self::none::None::default())                 as Box<dyn SmoothingAlgorithm>)),
+        ("default".into(), Arc::new(|| Box::new(self::default_algo::DefaultAlgo::default()) as Box<dyn SmoothingAlgorithm>)),
+        ("plain".into(),   Arc::new(|| Box::new(self::plain::Plain::default())               as Box<dyn SmoothingAlgorithm>)),
+        ("fixed".into(),   Arc::new(|| Box::new(self::fixed::Fixed::default())               as Box<dyn SmoothingAlgorithm>)),
+        ("locked_crop".into(), Arc::new(|| Box::new(self::locked_crop::LockedCrop::default())  as Box<dyn SmoothingAlgorithm>)),
+    ]);
+}
+
+/// Registers a smoothing algorithm under a stable `id`. The factory must create algorithms returning the same `id` from `get_id`.
+/// `Smoothing` instances created before this call need `Smoothing::add_registered` to see it.
+/// Returns false if the `id` is already registered.
+pub fn register_algorithm(id: &str, factory: AlgorithmFactory) -> bool {
+    let mut registry = REGISTRY.write();
+    if registry.iter().any(|(x, _)| x == id) {
+        log::warn!("Smoothing algorithm {id} is already registered");
+        return false;
+    }
+    registry.push((id.to_owned(), factory));
+    true
+}
+pub fn registered_algorithms() -> Vec<String> {
+    REGISTRY.read().iter().map(|(id, _)| id.clone()).collect()
+}
+
 struct Algs(Vec<Box<dyn SmoothingAlgorithm>>);
 impl Default for Algs {
     fn default() -> Self {
-        Self(vec![
-            Box::new(self::none::None::default()),
-            Box::new(self::default_algo::DefaultAlgo::default()),
-            Box::new(self::plain::Plain::default()),
-            Box::new(self::fixed::Fixed::default())
-        ])
+        Self(REGISTRY.read().iter().map(|(_, factory)| factory()).collect())
     }
-
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct Smoothing {
     #[serde(skip)]
     algs: Algs,
-    current_id: usize,
+    current_id: String,
 
-    pub horizon_lock: horizon::HorizonLock
+    pub horizon_lock: horizon::HorizonLock,
+    /// Bounded-latency mode, `None` smooths the whole recording at once
+    #[serde(default)]
+    pub look_ahead: Option<look_ahead::LookAhead>,
 }
 unsafe impl Send for Smoothing { }
 unsafe impl Sync for Smoothing { }
@@ -61,9 +101,10 @@ impl Default for Smoothing {
         Self {
             algs: Algs::default(),
 
-            current_id: 1,
+            current_id: "default".into(),
 
             horizon_lock: horizon::HorizonLock::default(),
+            look_ahead: None,
         }
     }
 }
@@ -71,8 +112,9 @@ impl Default for Smoothing {
 impl Clone for Smoothing {
     fn clone(&self) -> Self {
         let mut ret = Self::default();
-        ret.current_id = self.current_id;
+        ret.current_id = self.current_id.clone();
         ret.horizon_lock = self.horizon_lock.clone();
+        ret.look_ahead = self.look_ahead;
 
         let parameters = self.current().get_parameters_json();
         if let serde_json::Value::Array(ref arr) = parameters {
@@ -93,29 +135,66 @@ impl Clone for Smoothing {
 }
 
 impl Smoothing {
-    pub fn set_current(&mut self, id: usize) {
-        self.current_id = id.min(self.algs.0.len() - 1);
+    pub fn set_current(&mut self, index: usize) {
+        if let Some(alg) = self.algs.0.get(index.min(self.algs.0.len() - 1)) {
+            self.current_id = alg.get_id();
+        }
+    }
+    /// Returns false if there's no algorithm with this id
+    pub fn set_current_id(&mut self, id: &str) -> bool {
+        if self.algs.0.iter().any(|x| x.get_id() == id) {
+            self.current_id = id.to_owned();
+            true
+        } else {
+            false
+        }
+    }
+    pub fn current_id(&self) -> &str {
+        &self.current_id
+    }
+    fn current_index(&self) -> usize {
+        self.algs.0.iter().position(|x| x.get_id() == self.current_id)
+            .or_else(|| self.algs.0.iter().position(|x| x.get_id() == "default"))
+            .unwrap_or_default()
     }
 
     pub fn current(&self) -> &Box<dyn SmoothingAlgorithm> {
-        &self.algs.0[self.current_id]
+        &self.algs.0[self.current_index()]
     }
     pub fn current_mut(&mut self) -> &mut Box<dyn SmoothingAlgorithm> {
-        &mut self.algs.0[self.current_id]
+        let index = self.current_index();
+        &mut self.algs.0[index]
+    }
+
+    /// Adds the algorithms registered after this instance was created
+    pub fn add_registered(&mut self) {
+        for (id, factory) in REGISTRY.read().iter() {
+            if !self.algs.0.iter().any(|x| &x.get_id() == id) {
+                self.algs.0.push(factory());
+            }
+        }
     }
 
     pub fn get_state_checksum(&self, gyro_checksum: u64) -> u64 {
         let mut hasher = DefaultHasher::new();
         hasher.write_u64(gyro_checksum);
-        hasher.write_usize(self.current_id);
-        hasher.write_u64(self.algs.0[self.current_id].get_checksum());
+        hasher.write(self.current_id.as_bytes());
+        hasher.write_u64(self.current().get_checksum());
         hasher.write_u64(self.horizon_lock.get_checksum());
+        if let Some(look_ahead) = &self.look_ahead {
+            hasher.write_u64(look_ahead.look_ahead_ms.to_bits());
+            hasher.write_u64(look_ahead.history_ms.to_bits());
+            hasher.write_u64(look_ahead.step_ms.to_bits());
+        }
         hasher.finish()
     }
 
     pub fn get_names(&self) -> Vec<String> {
         self.algs.0.iter().map(|x| x.get_name()).collect()
     }
+    pub fn get_ids(&self) -> Vec<String> {
+        self.algs.0.iter().map(|x| x.get_id()).collect()
+    }
 
     pub fn get_trimmed_quats<'a>(quats: &'a TimeQuat, duration: f64, trim_range_only: bool, trim_ranges: &[(f64, f64)]) -> Cow<'a, TimeQuat> {
         if trim_range_only && !trim_ranges.is_empty() {
@@ -181,3 +260,101 @@ impl Smoothing {
         (max_pitch * RAD2DEG, max_yaw * RAD2DEG, max_roll * RAD2DEG)
     }
 }
+
+// Quaternion at `ts` (us), interpolated between the nearest samples
+fn quat_at(quats: &TimeQuat, ts: i64) -> Quat64 {
+    match (quats.range(..=ts).next_back(), quats.range(ts..).next()) {
+        (Some(a), Some(b)) if b.0 != a.0 => a.1.slerp(b.1, (ts - a.0) as f64 / (b.0 - a.0) as f64),
+        (Some(a), _) => *a.1,
+        (_, Some(b)) => *b.1,
+        _ => Quat64::identity()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+
+    #[derive(Clone)]
+    struct TestAlgorithm(&'static str);
+    impl SmoothingAlgorithm for TestAlgorithm {
+        fn get_id(&self) -> String { self.0.to_owned() }
+        fn get_name(&self) -> String { format!("Test {}", self.0) }
+        fn get_parameters_json(&self) -> serde_json::Value { serde_json::json!([]) }
+        fn get_status_json(&self) -> serde_json::Value { serde_json::json!([]) }
+        fn set_parameter(&mut self, _name: &str, _val: f64) { }
+        fn get_parameter(&self, _name: &str) -> f64 { 0.0 }
+        fn get_checksum(&self) -> u64 { 0 }
+        fn smooth(&self, quats: &TimeQuat, _: f64, _: &StabilizationParams, _: &KeyframeManager) -> TimeQuat { quats.clone() }
+    }
+
+    // The registry is global, so every test uses its own ids
+    fn register_test_algorithm(id: &'static str) -> bool {
+        register_algorithm(id, Arc::new(move || Box::new(TestAlgorithm(id)) as Box<dyn SmoothingAlgorithm>))
+    }
+
+    fn import_project(stab: &crate::StabilizationManager, project: &serde_json::Value) {
+        stab.import_gyroflow_data(project.to_string().as_bytes(), false, None, |_| (), Arc::new(AtomicBool::new(false)), &mut false).unwrap();
+    }
+
+    #[test]
+    fn duplicate_id() {
+        assert!(!register_test_algorithm("default"));
+        assert!(register_test_algorithm("test_duplicate"));
+        assert!(!register_test_algorithm("test_duplicate"));
+        assert_eq!(registered_algorithms().iter().filter(|x| *x == "test_duplicate").count(), 1);
+    }
+
+    #[test]
+    fn add_registered() {
+        let mut smoothing = Smoothing::default();
+        assert!(register_test_algorithm("test_add_registered"));
+        assert!(!smoothing.set_current_id("test_add_registered"));
+
+        smoothing.add_registered();
+        smoothing.add_registered();
+        assert_eq!(smoothing.get_ids().iter().filter(|x| *x == "test_add_registered").count(), 1);
+        assert!(smoothing.set_current_id("test_add_registered"));
+        assert_eq!(smoothing.current().get_id(), "test_add_registered");
+    }
+
+    #[test]
+    fn set_method_id() {
+        let stab = crate::StabilizationManager::default();
+        assert!(stab.set_smoothing_method_id("plain").is_some());
+        assert_eq!(stab.smoothing.read().current_id(), "plain");
+        assert_eq!(stab.smoothing.read().current().get_name(), "Plain 3D");
+
+        assert!(stab.set_smoothing_method_id("test_not_registered").is_none());
+        assert_eq!(stab.smoothing.read().current_id(), "plain");
+    }
+
+    #[test]
+    fn project_method_id() {
+        let stab = crate::StabilizationManager::default();
+        stab.set_smoothing_method_id("fixed").unwrap();
+        let project: serde_json::Value = serde_json::from_str(&stab.export_gyroflow_data(crate::GyroflowProjectType::Simple, "{}", None).unwrap()).unwrap();
+        assert_eq!(project["stabilization"]["method_id"], "fixed");
+
+        let imported = crate::StabilizationManager::default();
+        import_project(&imported, &project);
+        assert_eq!(imported.smoothing.read().current_id(), "fixed");
+    }
+
+    #[test]
+    fn project_legacy_method() {
+        let stab = crate::StabilizationManager::default();
+        import_project(&stab, &serde_json::json!({ "stabilization": { "method": "Plain 3D" } }));
+        assert_eq!(stab.smoothing.read().current_id(), "plain");
+
+        let ids = stab.smoothing.read().get_ids();
+        let fixed = ids.iter().position(|x| x == "fixed").unwrap();
+        import_project(&stab, &serde_json::json!({ "stabilization": { "method": fixed } }));
+        assert_eq!(stab.smoothing.read().current_id(), "fixed");
+
+        // Project saved with a plugin which isn't loaded
+        import_project(&stab, &serde_json::json!({ "stabilization": { "method_id": "test_not_loaded", "method": "Plain 3D" } }));
+        assert_eq!(stab.smoothing.read().current_id(), "plain");
+    }
+}
@@ -8,6 +8,7 @@ use crate::gyro_source::TimeQuat;
 pub struct None;
 
 impl SmoothingAlgorithm for None {
+    fn get_id(&self) -> String { "none".to_owned() }
     fn get_name(&self) -> String { "No smoothing".to_owned() }
 
     fn get_parameters_json(&self) -> serde_json::Value { serde_json::json!([]) }
@@ -0,0 +1,154 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Smoothing algorithms loaded from a dynamic library.
+// The library has to export `gyroflow_smoothing_plugin` returning a pointer to a static `PluginApi`.
+// Parameters are described the same way as in `SmoothingAlgorithm::get_parameters_json` and are passed to `smooth` as a JSON object `{ "name": value }`.
+
+use super::*;
+use crate::gyro_source::{ TimeQuat, Quat64 };
+use crate::GyroflowCoreError;
+use std::ffi::{ CStr, CString, c_char };
+
+pub const PLUGIN_ABI_VERSION: u32 = 1;
+pub const PLUGIN_ENTRY_POINT: &[u8] = b"gyroflow_smoothing_plugin\0";
+
+pub type SmoothFn = unsafe extern "C" fn(
+    timestamps_us: *const i64,
+    quats: *const f64, // `len` quaternions as [w, x, y, z]
+    len: usize,
+    duration_ms: f64,
+    parameters_json: *const c_char,
+    out_quats: *mut f64 // same layout as `quats`, allocated by the caller
+) -> i32; // 0 on success
+
+#[repr(C)]
+pub struct PluginApi {
+    pub abi_version: u32,
+    pub id: *const c_char,
+    pub name: *const c_char,
+    pub parameters_json: *const c_char,
+    pub smooth: SmoothFn,
+}
+
+#[derive(Clone)]
+pub struct PluginAlgorithm {
+    _library: Arc<libloading::Library>,
+    smooth_fn: SmoothFn,
+    id: String,
+    name: String,
+    parameters: serde_json::Value,
+}
+
+impl PluginAlgorithm {
+    fn parameter_values(&self) -> serde_json::Map<String, serde_json::Value> {
+        let mut ret = serde_json::Map::new();
+        if let serde_json::Value::Array(ref arr) = self.parameters {
+            for v in arr {
+                if let Some(name) = v.get("name").and_then(|x| x.as_str()) {
+                    ret.insert(name.to_owned(), v.get("value").or(v.get("default")).cloned().unwrap_or(serde_json::Value::Null));
+                }
+            }
+        }
+        ret
+    }
+}
+
+impl SmoothingAlgorithm for PluginAlgorithm {
+    fn get_id(&self) -> String { self.id.clone() }
+    fn get_name(&self) -> String { self.name.clone() }
+
+    fn set_parameter(&mut self, name: &str, val: f64) {
+        if let serde_json::Value::Array(ref mut arr) = self.parameters {
+            if let Some(param) = arr.iter_mut().find(|x| x.get("name").and_then(|x| x.as_str()) == Some(name)) {
+                param["value"] = serde_json::json!(val);
+                return;
+            }
+        }
+        log::error!("Invalid parameter name: {}", name);
+    }
+    fn get_parameter(&self, name: &str) -> f64 {
+        self.parameter_values().get(name).and_then(|x| x.as_f64().or_else(|| x.as_bool().map(|b| if b { 1.0 } else { 0.0 }))).unwrap_or_default()
+    }
+
+    fn get_parameters_json(&self) -> serde_json::Value { self.parameters.clone() }
+    fn get_status_json(&self) -> serde_json::Value { serde_json::json!([]) }
+
+    fn get_checksum(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        hasher.write(self.id.as_bytes());
+        for (k, v) in self.parameter_values() {
+            hasher.write(k.as_bytes());
+            hasher.write_u64(v.as_f64().unwrap_or_default().to_bits());
+        }
+        hasher.finish()
+    }
+
+    fn smooth(&self, quats: &TimeQuat, duration: f64, _: &StabilizationParams, _: &KeyframeManager) -> TimeQuat {
+        if quats.is_empty() { return quats.clone(); }
+
+        let timestamps: Vec<i64> = quats.keys().copied().collect();
+        let input: Vec<f64> = quats.values().flat_map(|q| [q.w, q.i, q.j, q.k]).collect();
+        let mut output = vec![0.0f64; input.len()];
+        let params = CString::new(serde_json::Value::Object(self.parameter_values()).to_string()).unwrap_or_default();
+
+        let result = unsafe { (self.smooth_fn)(timestamps.as_ptr(), input.as_ptr(), timestamps.len(), duration, params.as_ptr(), output.as_mut_ptr()) };
+        if result != 0 {
+            log::error!("Smoothing plugin {} failed with code {result}", self.id);
+            return quats.clone();
+        }
+
+        timestamps.into_iter().zip(output.chunks_exact(4)).map(|(ts, q)| {
+            (ts, Quat64::from_quaternion(Quaternion::new(q[0], q[1], q[2], q[3]).normalize()))
+        }).collect()
+    }
+}
+
+/// Loads the plugin library and registers the algorithm. Returns the algorithm id.
+pub fn load(path: &str) -> Result<String, GyroflowCoreError> {
+    unsafe {
+        let library = libloading::Library::new(path).map_err(|e| GyroflowCoreError::PluginError(e.to_string()))?;
+        let api = {
+            let entry: libloading::Symbol<unsafe extern "C" fn() -> *const PluginApi> = library.get(PLUGIN_ENTRY_POINT).map_err(|e| GyroflowCoreError::PluginError(e.to_string()))?;
+            entry()
+        };
+        if api.is_null() {
+            return Err(GyroflowCoreError::PluginError(format!("{path}: entry point returned null")));
+        }
+        let api = &*api;
+        if api.abi_version != PLUGIN_ABI_VERSION {
+            return Err(GyroflowCoreError::PluginError(format!("{path}: unsupported ABI version {} (expected {PLUGIN_ABI_VERSION})", api.abi_version)));
+        }
+        let read_str = |ptr: *const c_char| -> String {
+            if ptr.is_null() { String::new() } else { CStr::from_ptr(ptr).to_string_lossy().into_owned() }
+        };
+        let id = read_str(api.id);
+        if id.is_empty() {
+            return Err(GyroflowCoreError::PluginError(format!("{path}: empty algorithm id")));
+        }
+        let mut name = read_str(api.name);
+        if name.is_empty() { name = id.clone(); }
+
+        let mut parameters: serde_json::Value = serde_json::from_str(&read_str(api.parameters_json)).unwrap_or_else(|_| serde_json::json!([]));
+        if let serde_json::Value::Array(ref mut arr) = parameters {
+            for v in arr.iter_mut() {
+                if v.get("value").is_none() {
+                    v["value"] = v.get("default").cloned().unwrap_or(serde_json::json!(0.0));
+                }
+            }
+        }
+
+        let alg = PluginAlgorithm {
+            _library: Arc::new(library),
+            smooth_fn: api.smooth,
+            id: id.clone(),
+            name,
+            parameters,
+        };
+        if !register_algorithm(&id, Arc::new(move || Box::new(alg.clone()) as Box<dyn SmoothingAlgorithm>)) {
+            return Err(GyroflowCoreError::PluginError(format!("{path}: algorithm {id} is already registered")));
+        }
+        log::info!("Loaded smoothing plugin {id} from {path}");
+        Ok(id)
+    }
+}
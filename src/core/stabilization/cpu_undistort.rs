@@ -164,44 +164,6 @@ impl Stabilization {
             return (x - in_min) * (out_max - out_min) / (in_max - in_min) + out_min;
         }
 
-        fn rotate_and_distort(pos: (f32, f32), idx: usize, params: &KernelParams, matrices: &[[f32; 12]], distortion_model: &DistortionModel, digital_lens: Option<&DistortionModel>, r_limit: f32) -> Option<(f32, f32)> {
-            let matrices = matrices[idx];
-            let _x = (pos.0 * matrices[0]) + (pos.1 * matrices[1]) + matrices[2] + params.translation3d[0];
-            let _y = (pos.0 * matrices[3]) + (pos.1 * matrices[4]) + matrices[5] + params.translation3d[1];
-            let mut _w = (pos.0 * matrices[6]) + (pos.1 * matrices[7]) + matrices[8] + params.translation3d[2];
-            if _w > 0.0 {
-                if params.r_limit > 0.0 && ((_x / _w).powi(2) + (_y / _w).powi(2)).sqrt() > r_limit {
-                    return None;
-                }
-
-                if params.light_refraction_coefficient != 1.0 && params.light_refraction_coefficient > 0.0 {
-                    if _w != 0.0 {
-                        let r = (_x.powi(2) + _y.powi(2)).sqrt() / _w;
-                        let sin_theta_d = (r / (1.0 + r * r).sqrt()) * params.light_refraction_coefficient;
-                        let r_d = sin_theta_d / (1.0 - sin_theta_d * sin_theta_d).sqrt();
-                        if r_d != 0.0 {
-                            _w *= r / r_d;
-                        }
-                    }
-                }
-
-                let mut uv = distortion_model.distort_point(_x, _y, _w, &params);
-                uv = ((uv.0 * params.f[0]) + params.c[0], (uv.1 * params.f[1]) + params.c[1]);
-
-                if (params.flags & 2) == 2 { // Has digital lens
-                    if let Some(digital) = digital_lens {
-                        uv = digital.distort_point(uv.0, uv.1, 1.0, params);
-                    }
-                }
-
-                if params.input_horizontal_stretch > 0.001 { uv.0 /= params.input_horizontal_stretch; }
-                if params.input_vertical_stretch   > 0.001 { uv.1 /= params.input_vertical_stretch; }
-
-                return Some(uv);
-            }
-            return None;
-        }
-
         fn rotate_point(pos: (f32, f32), angle: f32, origin: (f32, f32)) -> (f32, f32) {
              return (angle.cos() * (pos.0 - origin.0) - angle.sin() * (pos.1 - origin.1) + origin.0,
                      angle.sin() * (pos.0 - origin.0) + angle.cos() * (pos.1 - origin.1) + origin.1);
@@ -269,15 +231,9 @@ impl Stabilization {
 
         if let BufferSource::Cpu { buffer: input } = &mut buffers.input.data {
             if let BufferSource::Cpu { buffer: output } = &mut buffers.output.data {
-                let r_limit = params.r_limit * params.r_limit; // Square it so we don't have to do sqrt on the point length
-
                 let bg = Vector4::<f32>::new(params.background[0], params.background[1], params.background[2], params.background[3]) * params.max_pixel_value;
                 let bg_t: T = PixelType::from_float(bg);
 
-                let factor = (1.0 - params.lens_correction_amount).max(0.001); // FIXME: this is close but wrong
-                let out_c = (params.output_width as f32 / 2.0, params.output_height as f32 / 2.0);
-                let out_f = ((params.f[0] / params.fov / factor), (params.f[1] / params.fov / factor));
-
                 // let drawing_enabled = !drawing.is_empty() && (params.flags & 8) == 8;
                 let fill_bg = (params.flags & 4) == 4;
                 let fix_range = (params.flags & 1) == 1;
@@ -290,7 +246,7 @@ impl Stabilization {
                 output.par_chunks_mut(buffers.output.size.2).enumerate().for_each(|(y, row_bytes)| { // Parallel iterator over buffer rows
                     row_bytes.chunks_mut(params.bytes_per_pixel as usize).enumerate().for_each(|(x, pix_chunk)| { // iterator over row pixels
 
-                        let mut out_pos = (
+                        let out_pos = (
                             map_coord(x as f32, params.output_rect[0] as f32, (params.output_rect[0] + params.output_rect[2]) as f32, 0.0, params.output_width  as f32),
                             map_coord(y as f32, params.output_rect[1] as f32, (params.output_rect[1] + params.output_rect[3]) as f32, 0.0, params.output_height as f32)
                         );
@@ -301,9 +257,6 @@ impl Stabilization {
                             // let p = out_pos;
                             let mut pixel = bg;
 
-                            out_pos.0 += params.translation2d[0];
-                            out_pos.1 += params.translation2d[1];
-
                             let pix_out = bytemuck::from_bytes_mut(pix_chunk); // treat this byte chunk as `T`
 
                             if fill_bg {
@@ -311,61 +264,7 @@ impl Stabilization {
                                 return;
                             }
 
-                            ///////////////////////////////////////////////////////////////////
-                            // Add lens distortion back
-                            if params.lens_correction_amount < 1.0 {
-                                let mut new_out_pos = out_pos;
-
-                                if (params.flags & 2) == 2 { // Has digial lens
-                                    if let Some(digital) = digital_lens {
-                                        if let Some(pt) = digital.undistort_point(new_out_pos, params) {
-                                            new_out_pos = pt;
-                                        }
-                                    }
-                                }
-
-                                new_out_pos = ((new_out_pos.0 - out_c.0) / out_f.0, (new_out_pos.1 - out_c.1) / out_f.1);
-                                new_out_pos = distortion_model.undistort_point(new_out_pos, &params).unwrap_or_default();
-                                if params.light_refraction_coefficient != 1.0 && params.light_refraction_coefficient > 0.0 {
-                                    let r = (new_out_pos.0.powi(2) + new_out_pos.1.powi(2)).sqrt();
-                                    if r != 0.0 {
-                                        let sin_theta_d = (r / (1.0 + r * r).sqrt()) / params.light_refraction_coefficient;
-                                        let r_d = sin_theta_d / (1.0 - sin_theta_d * sin_theta_d).sqrt();
-                                        let factor = r_d / r;
-                                        new_out_pos.0 *= factor;
-                                        new_out_pos.1 *= factor;
-                                    }
-                                }
-                                new_out_pos = ((new_out_pos.0 * out_f.0) + out_c.0, (new_out_pos.1 * out_f.1) + out_c.1);
-
-                                out_pos = (
-                                    new_out_pos.0 * (1.0 - params.lens_correction_amount) + (out_pos.0 * params.lens_correction_amount),
-                                    new_out_pos.1 * (1.0 - params.lens_correction_amount) + (out_pos.1 * params.lens_correction_amount),
-                                );
-                            }
-                            ///////////////////////////////////////////////////////////////////
-
-                            ///////////////////////////////////////////////////////////////////
-                            // Calculate source `y` for rolling shutter
-                            let mut sy = if (params.flags & 16) == 16 { // Horizontal RS
-                                (out_pos.0.round() as i32).min(params.width).max(0) as usize
-                            } else {
-                                (out_pos.1.round() as i32).min(params.height).max(0) as usize
-                            };
-                            if params.matrix_count > 1 {
-                                let idx = params.matrix_count as usize / 2;
-                                if let Some(pt) = rotate_and_distort(out_pos, idx, params, matrices, distortion_model, digital_lens, r_limit) {
-                                    if (params.flags & 16) == 16 { // Horizontal RS
-                                        sy = (pt.0.round() as i32).min(params.width).max(0) as usize;
-                                    } else {
-                                        sy = (pt.1.round() as i32).min(params.height).max(0) as usize;
-                                    }
-                                }
-                            }
-                            ///////////////////////////////////////////////////////////////////
-
-                            let idx = sy.min(params.matrix_count as usize - 1);
-                            if let Some(mut uv) = rotate_and_distort(out_pos, idx, params, matrices, distortion_model, digital_lens, r_limit) {
+                            if let Some(mut uv) = map_output_pixel(out_pos, params, matrices, distortion_model, digital_lens) {
                                 let width_f = params.width as f32;
                                 let height_f = params.height as f32;
                                 match params.background_mode {
@@ -451,6 +350,142 @@ pub fn undistort_points_for_optical_flow(distorted: &[(f32, f32)], timestamp_us:
 
     undistort_points(distorted, scaled_k, &distortion_coeffs, Matrix3::identity(), None, None, params, 1.0, timestamp_us as f64 / 1000.0)
 }
+fn rotate_and_distort(pos: (f32, f32), idx: usize, params: &KernelParams, matrices: &[[f32; 12]], distortion_model: &DistortionModel, digital_lens: Option<&DistortionModel>) -> Option<(f32, f32)> {
+    let matrices = matrices[idx];
+    let _x = (pos.0 * matrices[0]) + (pos.1 * matrices[1]) + matrices[2] + params.translation3d[0];
+    let _y = (pos.0 * matrices[3]) + (pos.1 * matrices[4]) + matrices[5] + params.translation3d[1];
+    let mut _w = (pos.0 * matrices[6]) + (pos.1 * matrices[7]) + matrices[8] + params.translation3d[2];
+    if _w > 0.0 {
+        if params.r_limit > 0.0 && ((_x / _w).powi(2) + (_y / _w).powi(2)).sqrt() > params.r_limit {
+            return None;
+        }
+
+        if params.light_refraction_coefficient != 1.0 && params.light_refraction_coefficient > 0.0 {
+            if _w != 0.0 {
+                let r = (_x.powi(2) + _y.powi(2)).sqrt() / _w;
+                let sin_theta_d = (r / (1.0 + r * r).sqrt()) * params.light_refraction_coefficient;
+                let r_d = sin_theta_d / (1.0 - sin_theta_d * sin_theta_d).sqrt();
+                if r_d != 0.0 {
+                    _w *= r / r_d;
+                }
+            }
+        }
+
+        let mut uv = distortion_model.distort_point(_x, _y, _w, &params);
+        uv = ((uv.0 * params.f[0]) + params.c[0], (uv.1 * params.f[1]) + params.c[1]);
+
+        if (params.flags & 2) == 2 { // Has digital lens
+            if let Some(digital) = digital_lens {
+                uv = digital.distort_point(uv.0, uv.1, 1.0, params);
+            }
+        }
+
+        if params.input_horizontal_stretch > 0.001 { uv.0 /= params.input_horizontal_stretch; }
+        if params.input_vertical_stretch   > 0.001 { uv.1 /= params.input_vertical_stretch; }
+
+        return Some(uv);
+    }
+    return None;
+}
+
+/// Adds the lens distortion back when `lens_correction_amount` < 1
+pub fn apply_lens_correction_amount(out_pos: (f32, f32), params: &KernelParams, distortion_model: &DistortionModel, digital_lens: Option<&DistortionModel>) -> (f32, f32) {
+    if params.lens_correction_amount >= 1.0 {
+        return out_pos;
+    }
+    let factor = (1.0 - params.lens_correction_amount).max(0.001); // FIXME: this is close but wrong
+    let out_c = (params.output_width as f32 / 2.0, params.output_height as f32 / 2.0);
+    let out_f = ((params.f[0] / params.fov / factor), (params.f[1] / params.fov / factor));
+
+    let mut new_out_pos = out_pos;
+
+    if (params.flags & 2) == 2 { // Has digial lens
+        if let Some(digital) = digital_lens {
+            if let Some(pt) = digital.undistort_point(new_out_pos, params) {
+                new_out_pos = pt;
+            }
+        }
+    }
+
+    new_out_pos = ((new_out_pos.0 - out_c.0) / out_f.0, (new_out_pos.1 - out_c.1) / out_f.1);
+    new_out_pos = distortion_model.undistort_point(new_out_pos, params).unwrap_or_default();
+    if params.light_refraction_coefficient != 1.0 && params.light_refraction_coefficient > 0.0 {
+        let r = (new_out_pos.0.powi(2) + new_out_pos.1.powi(2)).sqrt();
+        if r != 0.0 {
+            let sin_theta_d = (r / (1.0 + r * r).sqrt()) / params.light_refraction_coefficient;
+            let r_d = sin_theta_d / (1.0 - sin_theta_d * sin_theta_d).sqrt();
+            let factor = r_d / r;
+            new_out_pos.0 *= factor;
+            new_out_pos.1 *= factor;
+        }
+    }
+    new_out_pos = ((new_out_pos.0 * out_f.0) + out_c.0, (new_out_pos.1 * out_f.1) + out_c.1);
+
+    (
+        new_out_pos.0 * (1.0 - params.lens_correction_amount) + (out_pos.0 * params.lens_correction_amount),
+        new_out_pos.1 * (1.0 - params.lens_correction_amount) + (out_pos.1 * params.lens_correction_amount),
+    )
+}
+
+/// Position in the input image for a pixel of the output image. This is the per-pixel math of `undistort_image_cpu` without sampling,
+/// `out_pos` is already mapped from `output_rect`.
+pub fn map_output_pixel(mut out_pos: (f32, f32), params: &KernelParams, matrices: &[[f32; 12]], distortion_model: &DistortionModel, digital_lens: Option<&DistortionModel>) -> Option<(f32, f32)> {
+    out_pos.0 += params.translation2d[0];
+    out_pos.1 += params.translation2d[1];
+
+    out_pos = apply_lens_correction_amount(out_pos, params, distortion_model, digital_lens);
+
+    ///////////////////////////////////////////////////////////////////
+    // Calculate source `y` for rolling shutter
+    let mut sy = if (params.flags & 16) == 16 { // Horizontal RS
+        (out_pos.0.round() as i32).min(params.width).max(0) as usize
+    } else {
+        (out_pos.1.round() as i32).min(params.height).max(0) as usize
+    };
+    if params.matrix_count > 1 {
+        let idx = params.matrix_count as usize / 2;
+        if let Some(pt) = rotate_and_distort(out_pos, idx, params, matrices, distortion_model, digital_lens) {
+            if (params.flags & 16) == 16 { // Horizontal RS
+                sy = (pt.0.round() as i32).min(params.width).max(0) as usize;
+            } else {
+                sy = (pt.1.round() as i32).min(params.height).max(0) as usize;
+            }
+        }
+    }
+    ///////////////////////////////////////////////////////////////////
+
+    let idx = sy.min(params.matrix_count.max(1) as usize - 1);
+    rotate_and_distort(out_pos, idx, params, matrices, distortion_model, digital_lens)
+}
+
+/// RGBA f32 ST-map data of `width`x`height` pixels, pointing to an image of `target_size`: s = x / width, t = 1 - y / height,
+/// alpha = 1 if the position is inside of the target image. Positions are at pixel centers like in the kernel, ST-maps are at pixel corners.
+pub fn stmap_from<F: Fn((f32, f32)) -> Option<(f32, f32)> + Sync>(width: usize, height: usize, target_size: (f32, f32), cb: F) -> Vec<f32> {
+    let mut data = vec![0.0f32; width * height * 4];
+    data.par_chunks_mut(width.max(1) * 4).enumerate().for_each(|(y, row)| {
+        row.chunks_mut(4).enumerate().for_each(|(x, px)| {
+            if let Some(pt) = cb((x as f32, y as f32)) {
+                px[0] = (pt.0 + 0.5) / target_size.0;
+                px[1] = 1.0 - (pt.1 + 0.5) / target_size.1;
+                px[3] = if (0.0..=target_size.0).contains(&pt.0) && (0.0..=target_size.1).contains(&pt.1) { 1.0 } else { 0.0 };
+            } else {
+                px[0] = -1.0;
+                px[1] = -1.0;
+            }
+        });
+    });
+    data
+}
+
+/// ST-map of the output image for the given frame matrices, see `stmap_from`
+pub fn build_stmap(params: &KernelParams, distortion_model: &DistortionModel, digital_lens: Option<&DistortionModel>, matrices: &[[f32; 12]]) -> Vec<f32> {
+    let width = params.output_width.max(1) as usize;
+    let height = params.output_height.max(1) as usize;
+    let input_size = (params.width.max(1) as f32, params.height.max(1) as f32);
+
+    stmap_from(width, height, input_size, |pos| map_output_pixel(pos, params, matrices, distortion_model, digital_lens))
+}
+
 // Ported from OpenCV: https://github.com/opencv/opencv/blob/4.x/modules/calib3d/src/fisheye.cpp#L321
 pub fn undistort_points(distorted: &[(f32, f32)], camera_matrix: Matrix3<f64>, distortion_coeffs: &[f64; 12], rotation: Matrix3<f64>, p: Option<Matrix3<f64>>, rot_per_point: Option<Vec<Matrix3<f64>>>, params: &ComputeParams, lens_correction_amount: f64, timestamp_ms: f64) -> Vec<(f32, f32)> {
     let f = (camera_matrix[(0, 0)] as f32, camera_matrix[(1, 1)] as f32);
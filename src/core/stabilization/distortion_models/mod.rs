@@ -75,3 +75,16 @@ impl_models! {
     GoProHyperview => gopro_hyperview::GoProHyperview,
     DigitalStretch => digital_stretch::DigitalStretch,
 }
+
+impl DistortionModel {
+    /// Converts Hugin (and Lensfun) coefficients, which are relative to half of the shorter image side, to the normalized coordinates.
+    /// `hugin_scaling` is the real focal length in units of half of the shorter image side
+    pub fn rescale_coeffs(&self, k: &mut [f64], hugin_scaling: f64) {
+        match &self.inner {
+            DistortionModels::Poly3(_)  => poly3::Poly3::rescale_coeffs(k, hugin_scaling),
+            DistortionModels::Poly5(_)  => poly5::Poly5::rescale_coeffs(k, hugin_scaling),
+            DistortionModels::PtLens(_) => ptlens::PtLens::rescale_coeffs(k, hugin_scaling),
+            _ => { }
+        }
+    }
+}
@@ -25,7 +25,7 @@ impl FrameTransform {
         }
         frame_readout_time
     }
-    fn get_new_k(params: &ComputeParams, camera_matrix: &Matrix3<f64>, fov: f64) -> Matrix3<f64> {
+    pub fn get_new_k(params: &ComputeParams, camera_matrix: &Matrix3<f64>, fov: f64) -> Matrix3<f64> {
         let horizontal_ratio = if params.lens.input_horizontal_stretch > 0.01 { params.lens.input_horizontal_stretch } else { 1.0 };
 
         let img_dim_ratio = Self::get_ratio(params) / horizontal_ratio;
@@ -59,8 +59,10 @@ impl FrameTransform {
         let gyro = params.gyro.read();
         if !gyro.file_metadata.lens_positions.is_empty() {
             use crate::util::MapClosest;
-            if let Some(val) = gyro.file_metadata.lens_positions.get_closest(&((timestamp_ms * 1000.0).round() as i64), 100000) { // closest within 100ms
-                interpolated_lens = Some(params.lens.get_interpolated_lens_at(*val));
+            let timestamp_us = (timestamp_ms * 1000.0).round() as i64;
+            if let Some(val) = gyro.file_metadata.lens_positions.get_closest(&timestamp_us, 100000) { // closest within 100ms
+                let focus_distance = gyro.file_metadata.lens_params.get_closest(&timestamp_us, 100000).and_then(|x| x.focus_distance).map(|x| x as f64);
+                interpolated_lens = Some(params.lens.get_interpolated_lens(*val, focus_distance));
             }
         }
         let lens = interpolated_lens.as_ref().unwrap_or(&params.lens);
@@ -260,6 +262,24 @@ impl FrameTransform {
         }
     }
 
+    /// ST-map of the full stabilizing warp of this frame (rotation, rolling shutter, lens distortion and FOV).
+    /// Has the output size and points to the input image.
+    pub fn to_stmap(&self, params: &ComputeParams) -> crate::export::stmap::Stmap {
+        let mut kernel_params = self.kernel_params;
+        kernel_params.width         = params.width as i32;
+        kernel_params.height        = params.height as i32;
+        kernel_params.output_width  = params.output_width as i32;
+        kernel_params.output_height = params.output_height as i32;
+        if params.digital_lens.is_some() { kernel_params.flags |= super::KernelParamsFlags::HAS_DIGITAL_LENS.bits(); }
+        if params.horizontal_rs          { kernel_params.flags |= super::KernelParamsFlags::HORIZONTAL_RS.bits(); }
+
+        crate::export::stmap::Stmap {
+            width: params.output_width,
+            height: params.output_height,
+            data: super::cpu_undistort::build_stmap(&kernel_params, &params.distortion_model, params.digital_lens.as_ref(), &self.matrices)
+        }
+    }
+
     pub fn at_timestamp_for_points(params: &ComputeParams, points: &[(f32, f32)], timestamp_ms: f64, use_fovs: bool) -> (Matrix3<f64>, [f64; 12], Matrix3<f64>, Vec<Matrix3<f64>>) { // camera_matrix, dist_coeffs, p, rotations_per_point
         // ----------- Keyframes -----------
         let video_rotation = params.keyframes.value_at_video_timestamp(&KeyframeType::VideoRotation, timestamp_ms).unwrap_or(params.video_rotation);
@@ -243,6 +243,19 @@ impl Stabilization {
         transform
     }
 
+    pub fn get_stmap_at(&self, timestamp_us: i64) -> crate::export::stmap::Stmap {
+        let timestamp_ms = (timestamp_us as f64) / 1000.0;
+        let frame = crate::frame_at_timestamp(timestamp_ms, self.compute_params.scaled_fps) as usize; // Only for FOVs
+
+        let mut params = self.compute_params.clone();
+        params.width  = self.size.0.max(1);
+        params.height = self.size.1.max(1);
+        params.output_width  = self.output_size.0.max(1);
+        params.output_height = self.output_size.1.max(1);
+
+        FrameTransform::at_timestamp(&params, timestamp_ms, frame).to_stmap(&params)
+    }
+
     pub fn ensure_stab_data_at_timestamp<T: PixelType>(&mut self, timestamp_us: i64, buffers: &mut Buffers, is_pixel_normalized: bool) {
         let mut insert = true;
         if let Some(itm) = self.stab_data.get(&timestamp_us) {
@@ -0,0 +1,146 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// IMU samples received as text lines over UDP or from any reader (eg. stdin):
+//     timestamp_ms,gx,gy,gz[,ax,ay,az]
+// Gyro in deg/s, accelerometer in m/s², same as `TimeIMU`. Empty lines and lines starting with `#` are ignored.
+// One UDP datagram can contain multiple lines. `format_line` can be used by a test harness to replay recorded data.
+
+use std::io::{ BufRead, BufReader, Stdin };
+use std::net::UdpSocket;
+use std::time::Duration;
+use crate::gyro_source::TimeIMU;
+
+pub trait ImuSource: Send {
+    /// Returns the samples received since the last call, an empty list if the read timed out, or `None` at the end of the stream
+    fn read_samples(&mut self) -> std::io::Result<Option<Vec<TimeIMU>>>;
+}
+
+pub fn parse_line(line: &str) -> Option<TimeIMU> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') { return None; }
+
+    let values = line.split([',', ';', ' ', '\t'])
+        .filter(|x| !x.is_empty())
+        .map(|x| x.parse::<f64>().ok())
+        .collect::<Option<Vec<f64>>>()?;
+
+    match values.len() {
+        4 | 7 => Some(TimeIMU {
+            timestamp_ms: values[0],
+            gyro: Some([values[1], values[2], values[3]]),
+            accl: if values.len() == 7 { Some([values[4], values[5], values[6]]) } else { None },
+            magn: None
+        }),
+        _ => None
+    }
+}
+
+pub fn format_line(sample: &TimeIMU) -> String {
+    let g = sample.gyro.unwrap_or_default();
+    match sample.accl {
+        Some(a) => format!("{:.3},{},{},{},{},{},{}", sample.timestamp_ms, g[0], g[1], g[2], a[0], a[1], a[2]),
+        None    => format!("{:.3},{},{},{}", sample.timestamp_ms, g[0], g[1], g[2])
+    }
+}
+
+fn parse_lines(text: &str) -> Vec<TimeIMU> {
+    text.lines().filter_map(|line| {
+        let parsed = parse_line(line);
+        if parsed.is_none() && !line.trim().is_empty() && !line.trim().starts_with('#') {
+            log::warn!("Invalid IMU line: {line}");
+        }
+        parsed
+    }).collect()
+}
+
+pub struct UdpImuSource {
+    socket: UdpSocket,
+    buffer: Vec<u8>,
+}
+impl UdpImuSource {
+    /// `address` is the local address to listen on, eg. `0.0.0.0:7777`. With `timeout`, `read_samples` returns an empty list if nothing arrived in time
+    pub fn bind(address: &str, timeout: Option<Duration>) -> std::io::Result<Self> {
+        let socket = UdpSocket::bind(address)?;
+        socket.set_read_timeout(timeout)?;
+        Ok(Self { socket, buffer: vec![0u8; 65536] })
+    }
+}
+impl ImuSource for UdpImuSource {
+    fn read_samples(&mut self) -> std::io::Result<Option<Vec<TimeIMU>>> {
+        match self.socket.recv(&mut self.buffer) {
+            Ok(len) => Ok(Some(parse_lines(&String::from_utf8_lossy(&self.buffer[..len])))),
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut => Ok(Some(Vec::new())),
+            Err(e) => Err(e)
+        }
+    }
+}
+
+pub struct LineImuSource<R: BufRead + Send> {
+    reader: R,
+    line: String,
+}
+impl<R: BufRead + Send> LineImuSource<R> {
+    pub fn new(reader: R) -> Self {
+        Self { reader, line: String::new() }
+    }
+}
+impl LineImuSource<BufReader<Stdin>> {
+    pub fn stdin() -> Self {
+        Self::new(BufReader::new(std::io::stdin()))
+    }
+}
+impl<R: BufRead + Send> ImuSource for LineImuSource<R> {
+    fn read_samples(&mut self) -> std::io::Result<Option<Vec<TimeIMU>>> {
+        self.line.clear();
+        if self.reader.read_line(&mut self.line)? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(parse_lines(&self.line)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_valid_lines() {
+        let s = parse_line("12.5,1,-2,3.25").unwrap();
+        assert_eq!(s.timestamp_ms, 12.5);
+        assert_eq!(s.gyro, Some([1.0, -2.0, 3.25]));
+        assert_eq!(s.accl, None);
+
+        let s = parse_line("  100;0.1 0.2\t0.3, 0,0,9.81 ").unwrap();
+        assert_eq!(s.timestamp_ms, 100.0);
+        assert_eq!(s.gyro, Some([0.1, 0.2, 0.3]));
+        assert_eq!(s.accl, Some([0.0, 0.0, 9.81]));
+    }
+
+    #[test]
+    fn parse_invalid_lines() {
+        assert!(parse_line("").is_none());
+        assert!(parse_line("   ").is_none());
+        assert!(parse_line("# timestamp_ms,gx,gy,gz").is_none());
+        assert!(parse_line("1,2,3").is_none());
+        assert!(parse_line("1,2,3,4,5").is_none());
+        assert!(parse_line("1,2,3,4,5,6,7,8").is_none());
+        assert!(parse_line("1,2,x,4").is_none());
+
+        let parsed = parse_lines("# header\n1,2,3,4\n\ninvalid\n2,3,4,5,6,7,8\n");
+        assert_eq!(parsed.iter().map(|x| x.timestamp_ms).collect::<Vec<_>>(), vec![1.0, 2.0]);
+    }
+
+    #[test]
+    fn format_round_trip() {
+        for sample in [
+            TimeIMU { timestamp_ms: 1234.5, gyro: Some([0.125, -40.0, 1e-3]), accl: None, magn: None },
+            TimeIMU { timestamp_ms: 0.0, gyro: Some([1.0, 2.0, 3.0]), accl: Some([-0.5, 9.81, 0.25]), magn: None },
+        ] {
+            let parsed = parse_line(&format_line(&sample)).unwrap();
+            assert_eq!(parsed.timestamp_ms, sample.timestamp_ms);
+            assert_eq!(parsed.gyro, sample.gyro);
+            assert_eq!(parsed.accl, sample.accl);
+        }
+    }
+}
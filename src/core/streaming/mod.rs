@@ -0,0 +1,260 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Live stabilization of frames and IMU samples arriving incrementally, for example from a drone video link.
+// Only a sliding window of the IMU data is kept. For every frame, the window `[t - history_ms, t + look_ahead_ms]` is integrated
+// and smoothed with the current smoothing algorithm, so a frame is stabilized as soon as the IMU data `look_ahead_ms` past it has arrived.
+// Every window is integrated again from its first sample, so the orientations differ from offline integration by a rotation that is
+// constant within the window. With gyro-only integration and algorithms that don't depend on the absolute orientation (eg. Plain 3D),
+// the correction of a frame is then the same as offline smoothing restricted to the window. Integrators using the accelerometer
+// and the horizon lock don't have this property, so their results can differ from offline smoothing more.
+// `read_imu` connects an `imu_source::ImuSource`, see also `--live-imu` in the CLI.
+
+pub mod imu_source;
+
+use std::collections::VecDeque;
+use std::sync::atomic::Ordering::SeqCst;
+use crate::gpu::{ Buffers, BufferDescription, BufferSource };
+use crate::gyro_source::TimeIMU;
+use crate::smoothing::look_ahead::LookAhead;
+use imu_source::ImuSource;
+use crate::stabilization::{ PixelType, ProcessedInfo };
+use crate::{ StabilizationManager, GyroflowCoreError };
+
+pub struct LiveFrame {
+    pub timestamp_us: i64,
+    pub size: (usize, usize, usize), // width, height, stride
+    pub data: Vec<u8>,
+    pub info: ProcessedInfo,
+}
+
+struct PendingFrame {
+    timestamp_us: i64,
+    size: (usize, usize, usize),
+    data: Vec<u8>,
+}
+
+pub struct LiveStabilizer {
+    /// Configured by the caller the same way as for a file: video size, lens profile, IMU orientation, smoothing algorithm, FOV etc.
+    pub stab: StabilizationManager,
+    look_ahead: LookAhead,
+    imu: VecDeque<TimeIMU>,
+    frames: VecDeque<PendingFrame>,
+}
+
+impl LiveStabilizer {
+    /// Frame and IMU timestamps must use the same clock.
+    /// The sliding window replaces the look-ahead mode of the smoothing, so it's disabled in `stab`.
+    pub fn new(stab: StabilizationManager, look_ahead: LookAhead) -> Self {
+        {
+            let mut params = stab.params.write();
+            // Adaptive zoom needs the whole video
+            params.adaptive_zoom_window = 0.0;
+            params.frame_count = 0;
+            params.fovs.clear();
+        }
+        stab.smoothing.write().look_ahead = None;
+        stab.recompute_undistortion();
+
+        Self {
+            stab,
+            look_ahead,
+            imu: VecDeque::new(),
+            frames: VecDeque::new(),
+        }
+    }
+
+    /// Delay between receiving a frame and being able to stabilize it
+    pub fn latency_ms(&self) -> f64 {
+        self.look_ahead.look_ahead_ms.max(0.0)
+    }
+
+    /// Samples have to be pushed in order, older ones are ignored
+    pub fn push_imu(&mut self, sample: TimeIMU) {
+        if let Some(last) = self.imu.back() {
+            if sample.timestamp_ms <= last.timestamp_ms {
+                log::warn!("Ignoring out of order IMU sample at {} ms", sample.timestamp_ms);
+                return;
+            }
+        }
+        self.imu.push_back(sample);
+        self.drop_old_imu();
+    }
+    /// Pushes the samples from `source` until a read returns nothing (eg. timed out). Returns false at the end of the stream
+    pub fn read_imu(&mut self, source: &mut dyn ImuSource) -> std::io::Result<bool> {
+        loop {
+            match source.read_samples()? {
+                Some(samples) if samples.is_empty() => return Ok(true),
+                Some(samples) => samples.into_iter().for_each(|x| self.push_imu(x)),
+                None => return Ok(false)
+            }
+        }
+    }
+    pub fn last_imu_timestamp_ms(&self) -> Option<f64> {
+        self.imu.back().map(|x| x.timestamp_ms)
+    }
+    pub fn push_frame(&mut self, timestamp_us: i64, size: (usize, usize, usize), data: Vec<u8>) {
+        self.frames.push_back(PendingFrame { timestamp_us, size, data });
+    }
+    pub fn pending_frames(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Stabilizes all frames which have enough IMU data after them, in the order they were pushed.
+    /// With `flush`, all pending frames are processed using whatever IMU data is available (end of the stream).
+    pub fn process<T: PixelType>(&mut self, flush: bool) -> Vec<Result<LiveFrame, GyroflowCoreError>> {
+        let last_imu_ms = self.imu.back().map(|x| x.timestamp_ms).unwrap_or(f64::MIN);
+        let mut ret = Vec::new();
+        while let Some(frame) = self.frames.front() {
+            let ts_ms = frame.timestamp_us as f64 / 1000.0;
+            if !flush && last_imu_ms < ts_ms + self.latency_ms() {
+                break;
+            }
+            let frame = self.frames.pop_front().unwrap();
+            ret.push(self.stabilize_frame::<T>(frame));
+        }
+        self.drop_old_imu();
+        ret
+    }
+
+    // Integrates and smooths the IMU window of the frame at `timestamp_us`
+    fn update_window(&mut self, timestamp_us: i64) -> Result<(), GyroflowCoreError> {
+        let ts_ms = timestamp_us as f64 / 1000.0;
+        let window: Vec<TimeIMU> = self.imu.iter()
+            .filter(|x| x.timestamp_ms >= ts_ms - self.look_ahead.history_ms.max(0.0) && x.timestamp_ms <= ts_ms + self.look_ahead.look_ahead_ms.max(0.0))
+            .cloned()
+            .collect();
+        if window.len() < 2 {
+            return Err(GyroflowCoreError::NoStabilizationData(timestamp_us));
+        }
+
+        {
+            let mut gyro = self.stab.gyro.write();
+            let first_ts = window.first().map(|x| (x.timestamp_ms * 1000.0).round() as i64).unwrap_or_default();
+            let last_ts  = window.last() .map(|x| (x.timestamp_ms * 1000.0).round() as i64).unwrap_or_default();
+            gyro.duration_ms = LookAhead::window_duration_ms(first_ts, last_ts, window.len());
+            gyro.file_metadata.raw_imu = window;
+            gyro.apply_transforms();
+        }
+        self.stab.recompute_smoothness();
+        self.stab.smoothing_invalidated.store(false, SeqCst);
+        Ok(())
+    }
+
+    fn stabilize_frame<T: PixelType>(&mut self, mut frame: PendingFrame) -> Result<LiveFrame, GyroflowCoreError> {
+        self.update_window(frame.timestamp_us)?;
+
+        let (ow, oh) = self.stab.params.read().output_size;
+        let output_stride = ow * T::COUNT * T::SCALAR_BYTES;
+        let mut output = vec![0u8; output_stride * oh];
+
+        let info = self.stab.process_pixels::<T>(frame.timestamp_us, &mut Buffers {
+            input: BufferDescription {
+                size: frame.size,
+                data: BufferSource::Cpu { buffer: &mut frame.data },
+                ..Default::default()
+            },
+            output: BufferDescription {
+                size: (ow, oh, output_stride),
+                data: BufferSource::Cpu { buffer: &mut output },
+                ..Default::default()
+            },
+        });
+        // Every frame has a different timestamp, don't keep the transforms around
+        self.stab.stabilization.write().clear_stab_data();
+
+        Ok(LiveFrame {
+            timestamp_us: frame.timestamp_us,
+            size: (ow, oh, output_stride),
+            data: output,
+            info: info?,
+        })
+    }
+
+    fn drop_old_imu(&mut self) {
+        // Keep the history of the oldest pending frame, or of the newest sample if there are no frames
+        let oldest_ms = self.frames.front().map(|x| x.timestamp_us as f64 / 1000.0)
+            .or_else(|| self.imu.back().map(|x| x.timestamp_ms - self.look_ahead.look_ahead_ms.max(0.0)))
+            .unwrap_or_default();
+        let keep_from = oldest_ms - self.look_ahead.history_ms.max(0.0);
+        while self.imu.front().map(|x| x.timestamp_ms < keep_from).unwrap_or_default() {
+            self.imu.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gyro_source::Quat64;
+
+    // 10 s of a shaky pan at 1 kHz, in deg/s
+    fn imu_samples() -> Vec<TimeIMU> {
+        (0..10000).map(|i| {
+            let t = i as f64 / 1000.0;
+            TimeIMU {
+                timestamp_ms: i as f64,
+                gyro: Some([20.0 * (t * 13.0).sin(), 15.0 + 30.0 * (t * 7.0).cos(), 10.0 * (t * 23.0).sin()]),
+                accl: None,
+                magn: None
+            }
+        }).collect()
+    }
+
+    fn manager() -> StabilizationManager {
+        let stab = StabilizationManager::default();
+        stab.init_from_video_data(10000.0, 30.0, 300, (1920, 1080));
+        stab.set_size(1920, 1080);
+        stab.set_output_size(1920, 1080);
+        stab.set_smoothing_method_id("plain");
+        stab.set_smoothing_param("time_constant", 0.2);
+        {
+            let mut gyro = stab.gyro.write();
+            gyro.init_from_params(&stab.params.read());
+            gyro.integration_method = 3; // Gyro only
+        }
+        stab
+    }
+
+    #[test]
+    fn matches_offline_smoothing() {
+        let samples = imu_samples();
+
+        let offline = manager();
+        {
+            let mut gyro = offline.gyro.write();
+            gyro.duration_ms = 10000.0;
+            gyro.file_metadata.raw_imu = samples.clone();
+            gyro.apply_transforms();
+        }
+        offline.recompute_smoothness();
+
+        let mut live = LiveStabilizer::new(manager(), LookAhead { look_ahead_ms: 1500.0, history_ms: 3000.0, step_ms: 40.0 });
+        let mut received = samples.into_iter().peekable();
+
+        // Far enough from the ends of the recording, the window sees everything that affects the smoothing
+        for frame in 105..240 {
+            let timestamp_us = (frame as f64 * 1_000_000.0 / 30.0).round() as i64;
+            let ts_ms = timestamp_us as f64 / 1000.0;
+            while let Some(sample) = received.next_if(|x| x.timestamp_ms <= ts_ms + 1500.0) {
+                live.push_imu(sample);
+            }
+            live.update_window(timestamp_us).unwrap();
+
+            let live_correction: Quat64 = live.stab.gyro.read().smoothed_quat_at_timestamp(ts_ms);
+            let offline_correction: Quat64 = offline.gyro.read().smoothed_quat_at_timestamp(ts_ms);
+            let diff = live_correction.angle_to(&offline_correction).to_degrees();
+            assert!(diff < 0.02, "frame {frame}: {diff}°");
+        }
+    }
+
+    #[test]
+    fn reads_imu_source() {
+        let text = imu_samples()[..500].iter().map(imu_source::format_line).collect::<Vec<_>>().join("\n");
+        let mut source = imu_source::LineImuSource::new(std::io::Cursor::new(text));
+        let mut live = LiveStabilizer::new(manager(), LookAhead { look_ahead_ms: 1500.0, history_ms: 3000.0, step_ms: 40.0 });
+        assert!(!live.read_imu(&mut source).unwrap());
+        assert_eq!(live.imu.len(), 500);
+        assert_eq!(live.last_imu_timestamp_ms(), Some(499.0));
+    }
+}
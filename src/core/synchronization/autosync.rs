@@ -8,16 +8,18 @@ use itertools::Either;
 use parking_lot::RwLock;
 
 use crate::StabilizationManager;
+use crate::LensProfile;
 use crate::stabilization::ComputeParams;
 use super::PoseEstimator;
 use super::SyncParams;
+use super::SyncQuality;
 
 pub struct AutosyncProcess {
     frame_count: usize,
     scaled_fps: f64,
     org_fps: f64,
     fps_scale: Option<f64>,
-    mode: String, // synchronize, guess_imu_orientation, estimate_rolling_shutter
+    mode: String, // synchronize, guess_imu_orientation, estimate_rolling_shutter, estimate_lens
     ranges_us: Vec<(i64, i64)>,
     scaled_ranges_us: Vec<(i64, i64)>,
     estimator: Arc<PoseEstimator>,
@@ -26,7 +28,8 @@ pub struct AutosyncProcess {
     compute_params: Arc<RwLock<ComputeParams>>,
     cancel_flag: Arc<AtomicBool>,
     progress_cb: Option<Arc<Box<dyn Fn(f64, usize, usize) + Send + Sync + 'static>>>,
-    finished_cb: Option<Arc<Box<dyn Fn(Either<Vec<(f64, f64, f64)>, Option<(String, f64)>>) + Send + Sync + 'static>>>,
+    finished_cb: Option<Arc<Box<dyn Fn(Either<Vec<(f64, f64, f64, SyncQuality)>, Option<(String, f64)>>) + Send + Sync + 'static>>>,
+    error_cb: Option<Arc<Box<dyn Fn((String, String)) + Send + Sync + 'static>>>,
 
     sync_params: SyncParams,
 
@@ -116,6 +119,7 @@ impl AutosyncProcess {
             total_detected_frames: Arc::new(AtomicUsize::new(0)),
             compute_params: Arc::new(RwLock::new(comp_params)),
             finished_cb: None,
+            error_cb: None,
             progress_cb: None,
             cancel_flag,
             thread_pool
@@ -238,15 +242,48 @@ impl AutosyncProcess {
         if let Some(cb) = &self.finished_cb {
             if self.mode == "estimate_rolling_shutter" {
                 use super::find_offset::visual_features::find_offsets;
-                cb(Either::Left(find_offsets(&self.estimator, &scaled_ranges_us, &self.sync_params, &self.compute_params.read(), true, progress_cb2, self.cancel_flag.clone())));
+                let offsets = find_offsets(&self.estimator, &scaled_ranges_us, &self.sync_params, &self.compute_params.read(), true, progress_cb2, self.cancel_flag.clone());
+                cb(Either::Left(offsets.into_iter().map(|(ts, offset, cost)| (ts, offset, cost, SyncQuality::default())).collect()));
             } else if self.mode == "guess_imu_orientation" {
                 use super::find_offset::rs_sync::FindOffsetsRssync;
                 let guessed = FindOffsetsRssync::new(&scaled_ranges_us, self.estimator.sync_results.clone(), &self.sync_params, &self.compute_params.read(), progress_cb2, self.cancel_flag.clone()).guess_orient();
                 if !self.cancel_flag.load(SeqCst) {
                     cb(Either::Right(guessed));
                 }
+            } else if self.mode == "estimate_lens" {
+                // Returns the lens profile json and the RMS error
+                let params = self.compute_params.read();
+                let (motions, size) = self.estimator.feature_motions(&scaled_ranges_us, &params);
+                match crate::calibration::self_calibration::calibrate((size.0 as usize, size.1 as usize), &motions) {
+                    Ok(cal) => {
+                        let mut profile = LensProfile::default();
+                        profile.set_from_self_calibration(&cal, (size.0 as usize, size.1 as usize), (params.video_width, params.video_height));
+                        if params.frame_readout_time != 0.0 {
+                            profile.frame_readout_time = Some(params.frame_readout_time);
+                        }
+                        log::info!("Lens estimated from {} of {} features, focal length: {:.1} ± {:.1} px, k1: {:.4} ± {:.4}, k2: {:.4} ± {:.4}, RMS: {:.3} px",
+                            cal.inliers, cal.motions, cal.k[(0, 0)], cal.focal_length_std, cal.d[0], cal.distortion_coeffs_std[0], cal.d[1], cal.distortion_coeffs_std[1], cal.rms);
+                        match profile.get_json() {
+                            Ok(json) => cb(Either::Right(Some((json, cal.rms)))),
+                            Err(e) => self.error("An error occured: %1", e.to_string())
+                        }
+                    },
+                    Err(e) => {
+                        log::error!("Failed to estimate the lens: {e}");
+                        self.error("Failed to estimate the lens: %1", e.to_string());
+                    }
+                }
             } else {
                 let offsets = self.estimator.find_offsets(&scaled_ranges_us, &self.sync_params, &self.compute_params.read(), progress_cb2, self.cancel_flag.clone());
+                let with_quality = |offsets: Vec<(f64, f64, f64)>| -> Vec<(f64, f64, f64, SyncQuality)> {
+                    let params = self.compute_params.read();
+                    offsets.into_iter().map(|(ts, offset, cost)| {
+                        let ts_us = (ts * 1000.0).round() as i64;
+                        let range = scaled_ranges_us.iter().copied().min_by_key(|(from, to)| ((from + to) / 2 - ts_us).abs()).unwrap_or_default();
+                        let quality = SyncQuality::compute(&self.estimator, range, offset, cost, &params);
+                        (ts, offset, cost, quality)
+                    }).collect()
+                };
                 if check_negative {
                     for_negative.store(true, SeqCst);
                     // Try also negative rough offset
@@ -254,18 +291,18 @@ impl AutosyncProcess {
                     sync_params.initial_offset = -sync_params.initial_offset;
                     let offsets2 = self.estimator.find_offsets(&scaled_ranges_us, &sync_params, &self.compute_params.read(), progress_cb2, self.cancel_flag.clone());
                     if offsets2.len() > offsets.len() {
-                        cb(Either::Left(offsets2));
+                        cb(Either::Left(with_quality(offsets2)));
                     } else if offsets2.len() == offsets.len() {
                         let sum1: f64 = offsets.iter().map(|(_, _, cost)| *cost).sum();
                         let sum2: f64 = offsets2.iter().map(|(_, _, cost)| *cost).sum();
                         if sum1 < sum2 {
-                            cb(Either::Left(offsets));
+                            cb(Either::Left(with_quality(offsets)));
                         } else {
-                            cb(Either::Left(offsets2));
+                            cb(Either::Left(with_quality(offsets2)));
                         }
                     }
                 } else {
-                    cb(Either::Left(offsets));
+                    cb(Either::Left(with_quality(offsets)));
                 }
             }
         }
@@ -278,7 +315,17 @@ impl AutosyncProcess {
     pub fn on_progress<F>(&mut self, cb: F) where F: Fn(f64, usize, usize) + Send + Sync + 'static {
         self.progress_cb = Some(Arc::new(Box::new(cb)));
     }
-    pub fn on_finished<F>(&mut self, cb: F) where F:  Fn(Either<Vec<(f64, f64, f64)>, Option<(String, f64)>>) + Send + Sync + 'static {
+    pub fn on_finished<F>(&mut self, cb: F) where F:  Fn(Either<Vec<(f64, f64, f64, SyncQuality)>, Option<(String, f64)>>) + Send + Sync + 'static {
         self.finished_cb = Some(Arc::new(Box::new(cb)));
     }
+    /// Receives the message with a `%1` placeholder and its argument when the process fails
+    pub fn on_error<F>(&mut self, cb: F) where F: Fn((String, String)) + Send + Sync + 'static {
+        self.error_cb = Some(Arc::new(Box::new(cb)));
+    }
+    fn error(&self, msg: &str, arg: String) {
+        match &self.error_cb {
+            Some(cb) => cb((msg.to_owned(), arg)),
+            None => log::error!("{}", msg.replace("%1", &arg))
+        }
+    }
 }
@@ -9,6 +9,9 @@ mod almeida;            pub use self::almeida::*;
 mod eight_point;        pub use self::eight_point::*;
 mod find_essential_mat; pub use self::find_essential_mat::*;
 mod find_homography;    pub use self::find_homography::*;
+mod ransac;
+mod ransac_essential_mat; pub use self::ransac_essential_mat::*;
+mod ransac_homography;    pub use self::ransac_homography::*;
 
 #[enum_delegate::register]
 pub trait EstimatePoseTrait {
@@ -23,14 +26,25 @@ pub enum EstimatePoseMethod {
     PoseAlmeida(PoseAlmeida),
     PoseEightPoint(PoseEightPoint),
     PoseFindHomography(PoseFindHomography),
+    PoseRansacEssentialMat(PoseRansacEssentialMat),
+    PoseRansacHomography(PoseRansacHomography),
 }
 impl From<u32> for EstimatePoseMethod {
     fn from(v: u32) -> Self {
         match v {
+            #[cfg(feature = "use-opencv")]
             0 => Self::PoseFindEssentialMat(Default::default()),
             1 => Self::PoseAlmeida(Default::default()),
             2 => Self::PoseEightPoint(Default::default()),
+            #[cfg(feature = "use-opencv")]
             3 => Self::PoseFindHomography(Default::default()),
+            // Without OpenCV, use the Rust implementations of the same models instead
+            #[cfg(not(feature = "use-opencv"))]
+            0 => Self::PoseRansacEssentialMat(Default::default()),
+            #[cfg(not(feature = "use-opencv"))]
+            3 => Self::PoseRansacHomography(Default::default()),
+            4 => Self::PoseRansacEssentialMat(Default::default()),
+            5 => Self::PoseRansacHomography(Default::default()),
             _ => { log::error!("Unknown pose method {v}", ); Self::PoseAlmeida(Default::default()) }
         }
     }
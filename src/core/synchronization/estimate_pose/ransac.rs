@@ -0,0 +1,113 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// RANSAC and the linear solvers shared by the pure-Rust pose estimators.
+// All points are undistorted to normalized camera coordinates, so the camera matrix is identity.
+
+use nalgebra::{ Matrix3, Rotation3, SMatrix, SVector, Vector3 };
+use rand_xoshiro::Xoshiro256PlusPlus;
+use rand_xoshiro::rand_core::{ RngCore, SeedableRng };
+use crate::stabilization::{ ComputeParams, FrameTransform };
+
+/// Inlier threshold in pixels of the processed frame
+pub const THRESHOLD_PX: f64 = 1.0;
+pub const CONFIDENCE: f64 = 0.999;
+/// Same as OpenCV's `recoverPose`, fewer inliers means the model isn't reliable
+pub const MIN_INLIERS: usize = 10;
+
+pub struct Ransac {
+    /// Maximum error of inliers, in the units of `error`
+    pub threshold: f64,
+    pub confidence: f64,
+    pub max_iterations: usize,
+}
+
+impl Ransac {
+    /// `fit` returns the model candidates for a set of match indices, `error` is the error of the match at an index.
+    /// The best model is refitted to all its inliers, returns the model with its inliers
+    pub fn run<M, F, E>(&self, num_matches: usize, sample_size: usize, fit: F, error: E) -> Option<(M, Vec<usize>)>
+    where F: Fn(&[usize]) -> Vec<M>, E: Fn(&M, usize) -> f64 {
+        if num_matches < sample_size { return None; }
+        let inliers_of = |model: &M| -> Vec<usize> { (0..num_matches).filter(|&i| error(model, i) < self.threshold).collect() };
+
+        // Seeded, so the sync results are repeatable
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+        let mut best: Option<(M, Vec<usize>)> = None;
+        let mut iterations = self.max_iterations;
+        let mut sample = Vec::with_capacity(sample_size);
+        let mut i = 0;
+        while i < iterations {
+            i += 1;
+            sample.clear();
+            while sample.len() < sample_size {
+                let index = (rng.next_u64() % num_matches as u64) as usize;
+                if !sample.contains(&index) { sample.push(index); }
+            }
+            for model in fit(&sample) {
+                let inliers = inliers_of(&model);
+                if inliers.len() > best.as_ref().map_or(sample_size - 1, |b| b.1.len()) {
+                    // Number of samples needed to pick an outlier-free one with the required confidence
+                    let outlier_sample = 1.0 - (inliers.len() as f64 / num_matches as f64).powi(sample_size as i32);
+                    if outlier_sample <= f64::EPSILON {
+                        iterations = i;
+                    } else {
+                        let needed = ((1.0 - self.confidence).ln() / outlier_sample.ln()).ceil();
+                        if needed.is_finite() { iterations = iterations.min(needed.max(0.0) as usize); }
+                    }
+                    best = Some((model, inliers));
+                }
+            }
+        }
+
+        let (mut model, mut inliers) = best?;
+        for _ in 0..3 {
+            let Some((refitted, refitted_inliers)) = fit(&inliers).into_iter()
+                .map(|m| { let inliers = inliers_of(&m); (m, inliers) })
+                .max_by_key(|(_, inliers)| inliers.len()) else { break; };
+            if refitted_inliers.len() < inliers.len() { break; }
+            let converged = refitted_inliers == inliers;
+            model = refitted;
+            inliers = refitted_inliers;
+            if converged { break; }
+        }
+        Some((model, inliers))
+    }
+}
+
+/// Inlier threshold in normalized coordinates, from the focal length of the lens at `timestamp_us` scaled to the processed frame
+pub fn normalized_threshold(params: &ComputeParams, size: (u32, u32), timestamp_us: i64) -> f64 {
+    let (camera_matrix, ..) = FrameTransform::get_lens_data_at_timestamp(params, timestamp_us as f64 / 1000.0);
+    let focal = camera_matrix[(0, 0)] * size.0 as f64 / params.video_width.max(1) as f64;
+    if focal > 0.0 { THRESHOLD_PX / focal } else { THRESHOLD_PX / size.0.max(1) as f64 }
+}
+
+pub fn to_vectors(points: &[(f32, f32)]) -> Vec<Vector3<f64>> {
+    points.iter().map(|p| Vector3::new(p.0 as f64, p.1 as f64, 1.0)).collect()
+}
+
+/// Translates the points to their centroid and scales them to the mean distance of √2 (Hartley normalization)
+pub fn normalization(points: &[Vector3<f64>], indices: &[usize]) -> Matrix3<f64> {
+    let n = indices.len() as f64;
+    let (cx, cy) = indices.iter().fold((0.0, 0.0), |a, &i| (a.0 + points[i].x / n, a.1 + points[i].y / n));
+    let mean_dist = indices.iter().map(|&i| ((points[i].x - cx).powi(2) + (points[i].y - cy).powi(2)).sqrt()).sum::<f64>() / n;
+    let s = if mean_dist > 1e-12 { std::f64::consts::SQRT_2 / mean_dist } else { 1.0 };
+    Matrix3::new(s, 0.0, -s * cx,
+                 0.0, s, -s * cy,
+                 0.0, 0.0, 1.0)
+}
+
+/// Unit vector minimizing |A·x|, from the accumulated AᵀA
+pub fn null_vector(ata: &SMatrix<f64, 9, 9>) -> Option<SVector<f64, 9>> {
+    let eigen = ata.symmetric_eigen();
+    let (min_idx, _) = eigen.eigenvalues.iter().enumerate().min_by(|a, b| a.1.total_cmp(b.1))?;
+    Some(eigen.eigenvectors.column(min_idx).into_owned())
+}
+
+pub fn closest_rotation(m: &Matrix3<f64>) -> Option<Rotation3<f64>> {
+    let svd = m.svd(true, true);
+    let (mut u, v_t) = (svd.u?, svd.v_t?);
+    if (u * v_t).determinant() < 0.0 {
+        u.set_column(2, &(-u.column(2)));
+    }
+    Some(Rotation3::from_matrix_unchecked(u * v_t))
+}
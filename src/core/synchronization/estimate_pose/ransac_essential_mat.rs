@@ -0,0 +1,169 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Essential matrix without OpenCV: RANSAC over the normalized eight-point algorithm with the Sampson error,
+// then the rotation and the translation direction are refined on the inliers with Gauss-Newton.
+// Of the two rotations of the essential matrix, the one closer to identity is used, the other one is rotated by 180° around the baseline.
+
+use super::super::OpticalFlowPair;
+use super::EstimatePoseTrait;
+use super::ransac::{ self, Ransac };
+
+use nalgebra::{ DMatrix, DVector, Matrix3, Rotation3, SMatrix, SVector, Vector3 };
+use crate::stabilization::*;
+
+const MAX_ITERATIONS: usize = 2000;
+const REFINE_ITERATIONS: usize = 10;
+
+#[derive(Default, Clone)]
+pub struct PoseRansacEssentialMat;
+
+impl EstimatePoseTrait for PoseRansacEssentialMat {
+    fn init(&mut self, _: &ComputeParams) { }
+
+    fn estimate_pose(&self, pairs: &OpticalFlowPair, size: (u32, u32), params: &ComputeParams, timestamp_us: i64, next_timestamp_us: i64) -> Option<Rotation3<f64>> {
+        let (pts1, pts2) = pairs.as_ref()?;
+
+        let pts1 = ransac::to_vectors(&undistort_points_for_optical_flow(pts1, timestamp_us, params, size));
+        let pts2 = ransac::to_vectors(&undistort_points_for_optical_flow(pts2, next_timestamp_us, params, size));
+        let threshold = ransac::normalized_threshold(params, size, timestamp_us);
+
+        let result = estimate(&pts1, &pts2, threshold);
+        if result.is_none() {
+            log::warn!("couldn't find model");
+        }
+        result
+    }
+}
+
+/// Rotation from `pts1` to `pts2` in normalized coordinates, `threshold` is the maximum Sampson distance of inliers
+pub fn estimate(pts1: &[Vector3<f64>], pts2: &[Vector3<f64>], threshold: f64) -> Option<Rotation3<f64>> {
+    let ransac = Ransac { threshold: threshold * threshold, confidence: ransac::CONFIDENCE, max_iterations: MAX_ITERATIONS };
+    let (e, inliers) = ransac.run(pts1.len().min(pts2.len()), 8,
+        |indices| eight_point(pts1, pts2, indices).into_iter().collect(),
+        |e, i| sampson_error(e, &pts1[i], &pts2[i]).powi(2)
+    )?;
+    if inliers.len() < ransac::MIN_INLIERS { return None; }
+
+    let (rotation, translation) = decompose(&e)?;
+    Some(refine(rotation, translation, pts1, pts2, &inliers))
+}
+
+/// Signed Sampson distance of the match to the epipolar constraint x2ᵀ·E·x1 = 0
+fn sampson_error(e: &Matrix3<f64>, x1: &Vector3<f64>, x2: &Vector3<f64>) -> f64 {
+    let ex1 = e * x1;
+    let etx2 = e.transpose() * x2;
+    let denominator = ex1.x * ex1.x + ex1.y * ex1.y + etx2.x * etx2.x + etx2.y * etx2.y;
+    if denominator < 1e-30 { return 0.0; }
+    x2.dot(&ex1) / denominator.sqrt()
+}
+
+fn eight_point(pts1: &[Vector3<f64>], pts2: &[Vector3<f64>], indices: &[usize]) -> Option<Matrix3<f64>> {
+    let (t1, t2) = (ransac::normalization(pts1, indices), ransac::normalization(pts2, indices));
+    let mut ata = SMatrix::<f64, 9, 9>::zeros();
+    for &i in indices {
+        let (a, b) = (t1 * pts1[i], t2 * pts2[i]);
+        let row = SVector::<f64, 9>::from_column_slice(&[b.x * a.x, b.x * a.y, b.x, b.y * a.x, b.y * a.y, b.y, a.x, a.y, 1.0]);
+        ata += row * row.transpose();
+    }
+    let f = ransac::null_vector(&ata)?;
+    let f = Matrix3::new(f[0], f[1], f[2],
+                         f[3], f[4], f[5],
+                         f[6], f[7], f[8]);
+    essential_projection(&(t2.transpose() * f * t1))
+}
+
+/// Closest matrix with the singular values (1, 1, 0)
+fn essential_projection(m: &Matrix3<f64>) -> Option<Matrix3<f64>> {
+    let svd = m.svd(true, true);
+    let (u, v_t) = (svd.u?, svd.v_t?);
+    let e = u * Matrix3::from_diagonal(&Vector3::new(1.0, 1.0, 0.0)) * v_t;
+    e.iter().all(|v| v.is_finite()).then_some(e)
+}
+
+fn decompose(e: &Matrix3<f64>) -> Option<(Rotation3<f64>, Vector3<f64>)> {
+    let svd = e.svd(true, true);
+    let (mut u, mut v_t) = (svd.u?, svd.v_t?);
+    if u.determinant() < 0.0 { u = -u; }
+    if v_t.determinant() < 0.0 { v_t = -v_t; }
+    let w = Matrix3::new(0.0, -1.0, 0.0,
+                         1.0,  0.0, 0.0,
+                         0.0,  0.0, 1.0);
+    let r1 = Rotation3::from_matrix_unchecked(u * w * v_t);
+    let r2 = Rotation3::from_matrix_unchecked(u * w.transpose() * v_t);
+    // Bigger trace is a smaller angle
+    let rotation = if r1.matrix().trace() >= r2.matrix().trace() { r1 } else { r2 };
+    Some((rotation, u.column(2).into_owned()))
+}
+
+fn residuals(rotation: &Rotation3<f64>, translation: &Vector3<f64>, pts1: &[Vector3<f64>], pts2: &[Vector3<f64>], inliers: &[usize]) -> DVector<f64> {
+    let e = translation.cross_matrix() * rotation.matrix();
+    DVector::from_iterator(inliers.len(), inliers.iter().map(|&i| sampson_error(&e, &pts1[i], &pts2[i])))
+}
+
+/// Gauss-Newton over a rotation update and two directions perpendicular to the translation, which has a unit length
+fn refine(mut rotation: Rotation3<f64>, mut translation: Vector3<f64>, pts1: &[Vector3<f64>], pts2: &[Vector3<f64>], inliers: &[usize]) -> Rotation3<f64> {
+    let update = |rotation: &Rotation3<f64>, translation: &Vector3<f64>, delta: &[f64]| -> (Rotation3<f64>, Vector3<f64>) {
+        let (b1, b2) = tangent_basis(translation);
+        (Rotation3::new(Vector3::new(delta[0], delta[1], delta[2])) * rotation, (translation + b1 * delta[3] + b2 * delta[4]).normalize())
+    };
+    let mut cost = residuals(&rotation, &translation, pts1, pts2, inliers).norm_squared();
+    for _ in 0..REFINE_ITERATIONS {
+        let r = residuals(&rotation, &translation, pts1, pts2, inliers);
+        let mut jacobian = DMatrix::zeros(inliers.len(), 5);
+        for column in 0..5 {
+            let h = 1e-7;
+            let mut delta = [0.0; 5];
+            delta[column] = h;
+            let (rp, tp) = update(&rotation, &translation, &delta);
+            delta[column] = -h;
+            let (rm, tm) = update(&rotation, &translation, &delta);
+            let derivative = (residuals(&rp, &tp, pts1, pts2, inliers) - residuals(&rm, &tm, pts1, pts2, inliers)) / (2.0 * h);
+            jacobian.set_column(column, &derivative);
+        }
+        let jt = jacobian.transpose();
+        let Some(step) = (&jt * &jacobian).lu().solve(&(-(jt * r))) else { break; };
+        let (new_rotation, new_translation) = update(&rotation, &translation, step.as_slice());
+        let new_cost = residuals(&new_rotation, &new_translation, pts1, pts2, inliers).norm_squared();
+        if new_cost.is_nan() || new_cost >= cost { break; }
+        let converged = cost - new_cost < cost * 1e-10;
+        (rotation, translation, cost) = (new_rotation, new_translation, new_cost);
+        if converged { break; }
+    }
+    rotation
+}
+
+fn tangent_basis(v: &Vector3<f64>) -> (Vector3<f64>, Vector3<f64>) {
+    let helper = if v.x.abs() < 0.9 { Vector3::x() } else { Vector3::y() };
+    let b1 = v.cross(&helper).normalize();
+    (b1, v.cross(&b1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Points in front of both cameras with a small translation, like between two consecutive frames
+    #[test]
+    fn recovers_rotation() {
+        let rotation = Rotation3::from_euler_angles(0.02, -0.035, 0.01);
+        let translation = Vector3::new(0.05, -0.02, 0.01);
+        let (mut pts1, mut pts2) = (Vec::new(), Vec::new());
+        let mut seed = 7u32;
+        let mut random = || { seed = seed.wrapping_mul(1103515245).wrapping_add(12345); (seed >> 16) as f64 / 65535.0 };
+        for i in 0..150 {
+            let p = Vector3::new(random() * 8.0 - 4.0, random() * 5.0 - 2.5, 4.0 + random() * 10.0);
+            let q = rotation * p + translation;
+            let mut x2 = q / q.z;
+            if i % 5 == 0 {
+                // Outlier
+                x2 += Vector3::new(random() * 0.2 - 0.1, random() * 0.2 - 0.1, 0.0);
+            }
+            pts1.push(p / p.z);
+            pts2.push(x2);
+        }
+        let result = estimate(&pts1, &pts2, 1e-3).unwrap();
+        let error = (result.matrix() - rotation.matrix()).norm();
+        assert!(error < 1e-4, "error: {error}");
+    }
+}
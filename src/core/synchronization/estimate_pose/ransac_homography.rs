@@ -0,0 +1,138 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Homography without OpenCV: RANSAC over the normalized four-point DLT with the transfer error, refitted on the inliers.
+// H = R + t·nᵀ/d is decomposed with the SVD method of Faugeras. Of the rotations of the valid decompositions,
+// the one closest to identity is used, which is the right one for consecutive frames.
+
+use super::super::OpticalFlowPair;
+use super::EstimatePoseTrait;
+use super::ransac::{ self, Ransac };
+
+use nalgebra::{ Matrix3, Rotation3, SMatrix, SVector, Vector3 };
+use crate::stabilization::*;
+
+const MAX_ITERATIONS: usize = 2000;
+
+#[derive(Default, Clone)]
+pub struct PoseRansacHomography;
+
+impl EstimatePoseTrait for PoseRansacHomography {
+    fn init(&mut self, _: &ComputeParams) { }
+
+    fn estimate_pose(&self, pairs: &OpticalFlowPair, size: (u32, u32), params: &ComputeParams, timestamp_us: i64, next_timestamp_us: i64) -> Option<Rotation3<f64>> {
+        let (pts1, pts2) = pairs.as_ref()?;
+
+        let pts1 = ransac::to_vectors(&undistort_points_for_optical_flow(pts1, timestamp_us, params, size));
+        let pts2 = ransac::to_vectors(&undistort_points_for_optical_flow(pts2, next_timestamp_us, params, size));
+        let threshold = ransac::normalized_threshold(params, size, timestamp_us);
+
+        let result = estimate(&pts1, &pts2, threshold);
+        if result.is_none() {
+            log::warn!("couldn't find model");
+        }
+        result
+    }
+}
+
+/// Rotation from `pts1` to `pts2` in normalized coordinates, `threshold` is the maximum transfer error of inliers
+pub fn estimate(pts1: &[Vector3<f64>], pts2: &[Vector3<f64>], threshold: f64) -> Option<Rotation3<f64>> {
+    let ransac = Ransac { threshold: threshold * threshold, confidence: ransac::CONFIDENCE, max_iterations: MAX_ITERATIONS };
+    let (h, inliers) = ransac.run(pts1.len().min(pts2.len()), 4,
+        |indices| homography(pts1, pts2, indices).into_iter().collect(),
+        |h, i| transfer_error(h, &pts1[i], &pts2[i])
+    )?;
+    if inliers.len() < ransac::MIN_INLIERS { return None; }
+
+    decompose(&h)
+}
+
+/// Squared distance of x2 from the transferred x1
+fn transfer_error(h: &Matrix3<f64>, x1: &Vector3<f64>, x2: &Vector3<f64>) -> f64 {
+    let p = h * x1;
+    if p.z.abs() < 1e-12 { return f64::MAX; }
+    (p.x / p.z - x2.x).powi(2) + (p.y / p.z - x2.y).powi(2)
+}
+
+fn homography(pts1: &[Vector3<f64>], pts2: &[Vector3<f64>], indices: &[usize]) -> Option<Matrix3<f64>> {
+    let (t1, t2) = (ransac::normalization(pts1, indices), ransac::normalization(pts2, indices));
+    let mut ata = SMatrix::<f64, 9, 9>::zeros();
+    for &i in indices {
+        let (s, d) = (t1 * pts1[i], t2 * pts2[i]);
+        let rows = [
+            SVector::<f64, 9>::from_column_slice(&[-s.x, -s.y, -1.0, 0.0, 0.0, 0.0, d.x * s.x, d.x * s.y, d.x]),
+            SVector::<f64, 9>::from_column_slice(&[0.0, 0.0, 0.0, -s.x, -s.y, -1.0, d.y * s.x, d.y * s.y, d.y]),
+        ];
+        for row in rows {
+            ata += row * row.transpose();
+        }
+    }
+    let h = ransac::null_vector(&ata)?;
+    let hn = Matrix3::new(h[0], h[1], h[2],
+                          h[3], h[4], h[5],
+                          h[6], h[7], h[8]);
+    let h = t2.try_inverse()? * hn * t1;
+    h.iter().all(|v| v.is_finite()).then_some(h)
+}
+
+fn decompose(h: &Matrix3<f64>) -> Option<Rotation3<f64>> {
+    let svd = h.svd(true, true);
+    let (u, v_t) = (svd.u?, svd.v_t?);
+    let mut d = svd.singular_values;
+    if d[1] < 1e-12 { return None; }
+    d /= d[1];
+    let (d1, d3) = (d[0], d[2]);
+    // Makes the rotation proper, and also takes care of the unknown sign of H
+    let s = (u.determinant() * v_t.determinant()).signum();
+
+    if d1 - d3 < 1e-9 {
+        // All singular values are equal, so it's a pure rotation
+        return ransac::closest_rotation(&(h * h.determinant().signum()));
+    }
+
+    let x1 = ((d1 * d1 - 1.0) / (d1 * d1 - d3 * d3)).max(0.0).sqrt();
+    let x3 = ((1.0 - d3 * d3) / (d1 * d1 - d3 * d3)).max(0.0).sqrt();
+    let cos = (1.0 + d1 * d3) / (d1 + d3);
+    [1.0, -1.0].into_iter().map(|sign| {
+        let sin = sign * (d1 - d3) * x1 * x3;
+        let r = Matrix3::new(cos, 0.0, -sin,
+                             0.0, 1.0,  0.0,
+                             sin, 0.0,  cos);
+        Rotation3::from_matrix_unchecked(u * r * v_t * s)
+    }).max_by(|a, b| a.matrix().trace().total_cmp(&b.matrix().trace()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Points on a plane seen from two poses, with a few outliers
+    #[test]
+    fn recovers_rotation() {
+        let rotation = Rotation3::from_euler_angles(-0.03, 0.025, 0.015);
+        let translation = Vector3::new(0.04, 0.03, -0.02);
+        let (mut pts1, mut pts2) = (Vec::new(), Vec::new());
+        let mut seed = 3u32;
+        let mut random = || { seed = seed.wrapping_mul(1103515245).wrapping_add(12345); (seed >> 16) as f64 / 65535.0 };
+        for i in 0..120 {
+            let (x, y) = (random() * 8.0 - 4.0, random() * 5.0 - 2.5);
+            // Plane 0.1·x + 0.2·y + z = 6
+            let p = Vector3::new(x, y, 6.0 - 0.1 * x - 0.2 * y);
+            let q = rotation * p + translation;
+            let mut x2 = q / q.z;
+            if i % 6 == 0 {
+                x2 += Vector3::new(random() * 0.2 - 0.1, random() * 0.2 - 0.1, 0.0);
+            }
+            pts1.push(p / p.z);
+            pts2.push(x2);
+        }
+        let result = estimate(&pts1, &pts2, 1e-3).unwrap();
+        let error = (result.matrix() - rotation.matrix()).norm();
+        assert!(error < 1e-4, "error: {error}");
+
+        // Pure rotation
+        let pts2: Vec<Vector3<f64>> = pts1.iter().map(|p| { let q = rotation * p; q / q.z }).collect();
+        let error = (estimate(&pts1, &pts2, 1e-3).unwrap().matrix() - rotation.matrix()).norm();
+        assert!(error < 1e-6, "error: {error}");
+    }
+}
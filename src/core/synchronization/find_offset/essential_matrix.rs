@@ -103,7 +103,7 @@ fn gyro_at_timestamp(ts: f64, gyro: &BTreeMap<usize, TimeIMU>) -> Option<&TimeIM
     gyro.range((ts * 1000.0) as usize..).next().map(|x| x.1)
 }
 
-fn calculate_cost(offs: f64, of: &[TimeIMU], gyro: &BTreeMap<usize, TimeIMU>) -> f64 {
+pub fn calculate_cost(offs: f64, of: &[TimeIMU], gyro: &BTreeMap<usize, TimeIMU>) -> f64 {
     let mut sum = 0.0;
     let mut matches_count = 0;
     for o in of {
@@ -13,6 +13,7 @@ use rayon::iter::{ ParallelIterator, IntoParallelRefIterator };
 
 use crate::gyro_source::{ Quat64, TimeQuat };
 use crate::stabilization::ComputeParams;
+use crate::calibration::self_calibration::FeatureMotion;
 
 mod optical_flow; pub use optical_flow::*;
 mod estimate_pose; pub use estimate_pose::*;
@@ -21,8 +22,11 @@ mod find_offset { pub mod rs_sync; pub mod essential_matrix; pub mod visual_feat
 use super::gyro_source::TimeIMU;
 
 pub mod optimsync;
+pub mod offset_model;
 mod autosync;
 pub use autosync::AutosyncProcess;
+mod quality;
+pub use quality::SyncQuality;
 use crate::util::MapClosest;
 
 pub type GrayImage = image::GrayImage;
@@ -246,6 +250,46 @@ impl PoseEstimator {
         (None, None)
     }
 
+    /// Tracked features between consecutive frames with the gyro rotation between them, for `calibration::self_calibration`.
+    /// The points are in the coordinates of the analyzed frames, their size is returned as well
+    pub fn feature_motions(&self, ranges: &[(i64, i64)], params: &ComputeParams) -> (Vec<FeatureMotion>, (u32, u32)) {
+        let gyro = params.gyro.read();
+        let keys: Vec<i64> = self.sync_results.read().keys().copied().collect();
+        // Same as in `FrameTransform`: the gyro rotation of the image is flipped around the X axis
+        let flip = Rotation3::from_axis_angle(&nalgebra::Vector3::x_axis(), std::f64::consts::PI);
+
+        let mut motions = Vec::new();
+        let mut size = (0, 0);
+        for ts in keys.iter().filter(|ts| ranges.iter().any(|(from, to)| (*from..*to).contains(*ts))) {
+            // Optical flow is cached to the next frame, or to the second next one for the visual features sync method
+            let Some((((ts1, pts1), (ts2, pts2)), frame_size)) = [1, 2].into_iter().find_map(|num_frames| {
+                match self.get_of_lines_for_timestamp(ts, 0, 1.0, num_frames, false) {
+                    (Some(lines), Some(frame_size)) => Some((lines, frame_size)),
+                    _ => None
+                }
+            }) else { continue; };
+            if pts1.len() != pts2.len() || frame_size.0 == 0 || frame_size.1 == 0 { continue; }
+            size = frame_size;
+
+            // Time when the row (or the column) of the point was read
+            let point_time = |ts: i64, p: &(f32, f32)| -> f64 {
+                let pos = if params.horizontal_rs { p.0 as f64 / frame_size.0 as f64 } else { p.1 as f64 / frame_size.1 as f64 };
+                ts as f64 / 1000.0 + params.frame_readout_time * (pos - 0.5)
+            };
+            for (p1, p2) in pts1.iter().zip(pts2.iter()) {
+                let q1 = gyro.org_quat_at_timestamp(point_time(ts1, p1));
+                let q2 = gyro.org_quat_at_timestamp(point_time(ts2, p2));
+                let rotation = (q1.inverse() * q2).to_rotation_matrix();
+                motions.push(FeatureMotion {
+                    from: (p1.0 as f64, p1.1 as f64),
+                    to: (p2.0 as f64, p2.1 as f64),
+                    rotation: flip * rotation.inverse() * flip
+                });
+            }
+        }
+        (motions, size)
+    }
+
     pub fn rgba_to_gray(width: u32, height: u32, stride: u32, slice: &[u8]) -> GrayImage {
         use image::Pixel;
         let mut img = image::GrayImage::new(width, height);
@@ -388,3 +432,77 @@ impl PoseEstimator {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::Vector3;
+
+    // A static scene has to end up at the same stabilized position in both frames, so the rotation of each feature motion
+    // has to move the ray of the feature in the first frame to the ray of the same feature in the second frame
+    #[test]
+    fn feature_motions_match_frame_transform() {
+        let (size, f, c, d) = ((1920, 1080), 900.0, (960.0, 540.0), [0.05, -0.02, 0.0, 0.0]);
+        let intrinsics = [f, f, c.0, c.1, d[0], d[1], d[2], d[3]];
+        let (ts1, ts2) = (1000.0, 1100.0);
+
+        // Uneven rotation around all axes, so the frame to frame motion differs across the frame
+        let raw_imu = (0..3000).step_by(2).map(|ms| {
+            let t = ms as f64 / 1000.0;
+            let g = Vector3::new(0.6 * (3.1 * t).cos(), -0.35 * (1.7 * t).sin(), 0.25 + 0.2 * (4.3 * t).sin());
+            TimeIMU { timestamp_ms: ms as f64, gyro: Some([g.x.to_degrees(), g.y.to_degrees(), g.z.to_degrees()]), accl: None, magn: None }
+        }).collect();
+        let stab = crate::StabilizationManager::with_test_gyro(3000.0, 30.0, size, raw_imu);
+        let profile = serde_json::json!({
+            "calibrator_version": "1.6.0",
+            "calib_dimension": { "w": size.0, "h": size.1 },
+            "fisheye_params": { "camera_matrix": [[f, 0.0, c.0], [0.0, f, c.1], [0.0, 0.0, 1.0]], "distortion_coeffs": d }
+        });
+        stab.load_lens_profile(&profile.to_string()).unwrap();
+        stab.lock_smoothed_orientation(ts1);
+        let params = ComputeParams::from_manager(&stab);
+        let stabilized = |p: (f32, f32), ts: f64| crate::stabilization::undistort_points_with_rolling_shutter(&[p], ts, &params, 1.0, false)[0];
+
+        // Features in the first frame, and the points in the second frame which are stabilized to the same position
+        let rays: Vec<Vector3<f64>> = [-0.5, 0.0, 0.5].into_iter().flat_map(|x| [-0.3, 0.0, 0.3].into_iter().map(move |y| Vector3::new(x, y, 1.0).normalize())).collect();
+        let pts1: Vec<(f32, f32)> = rays.iter().map(|ray| {
+            let p = crate::calibration::fisheye::distort(&intrinsics, ray);
+            (p.0 as f32, p.1 as f32)
+        }).collect();
+        let pts2: Vec<(f32, f32)> = pts1.iter().map(|p1| {
+            let target = stabilized(*p1, ts1);
+            let mut p2 = *p1;
+            for _ in 0..50 {
+                let s = stabilized(p2, ts2);
+                p2 = (p2.0 + target.0 - s.0, p2.1 + target.1 - s.1);
+            }
+            let s = stabilized(p2, ts2);
+            assert!((s.0 - target.0).hypot(s.1 - target.1) < 0.01, "{p1:?} didn't converge");
+            p2
+        }).collect();
+        assert!(pts1.iter().zip(&pts2).any(|(a, b)| (a.0 - b.0).hypot(a.1 - b.1) > 20.0), "not enough motion");
+
+        let (ts1_us, ts2_us) = ((ts1 * 1000.0) as i64, (ts2 * 1000.0) as i64);
+        let estimator = PoseEstimator::default();
+        estimator.sync_results.write().insert(ts1_us, FrameResult {
+            of_method: OpticalFlowMethod::detect_features(3, ts1_us, Arc::new(GrayImage::new(0, 0)), size.0 as u32, size.1 as u32),
+            frame_no: 0,
+            timestamp_us: ts1_us,
+            gyro_timestamp_us: ts1_us,
+            frame_size: (size.0 as u32, size.1 as u32),
+            rotation: None,
+            quat: None,
+            euler: None,
+            optical_flow: RefCell::new([(1, Some(((ts1_us, pts1), (ts2_us, pts2.clone()))))].into())
+        });
+
+        let (motions, motions_size) = estimator.feature_motions(&[(0, 3_000_000)], &params);
+        assert_eq!(motions_size, (size.0 as u32, size.1 as u32));
+        assert_eq!(motions.len(), rays.len());
+        for ((motion, ray), p2) in motions.iter().zip(&rays).zip(&pts2) {
+            let predicted = crate::calibration::fisheye::distort(&intrinsics, &(motion.rotation * ray));
+            let error = (predicted.0 - p2.0 as f64).hypot(predicted.1 - p2.1 as f64);
+            assert!(error < 0.5, "{:?} predicted at {predicted:?} instead of {p2:?}", motion.from);
+        }
+    }
+}
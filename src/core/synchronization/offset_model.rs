@@ -0,0 +1,346 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Models of the sync offset over the duration of the file. Sync points which don't agree with the model are rejected,
+// and the model is sampled, so it can be looked up by interpolating the samples the same way as the sync points.
+
+use std::collections::{ BTreeMap, BTreeSet };
+
+/// Sync points further than this from the model are rejected, in ms
+const MAX_FITTING_ERROR: f64 = 5.0;
+/// Number of sync points around each point used to check it for the spline model
+const SPLINE_WINDOW: usize = 5;
+/// Interval of the spline samples, in us
+const SPLINE_STEP_US: i64 = 1_000_000;
+/// Number of lines tried by `robust_line`, every pair of points is tried when there are fewer pairs
+const MAX_LINE_CANDIDATES: usize = 500;
+
+#[derive(Default, Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum OffsetModel {
+    /// Interpolate between all sync points
+    #[default]
+    Interpolate,
+    /// Single line, for a constant clock drift
+    Line,
+    /// Lines between jumps of the offset, eg. caused by dropped IMU samples
+    Piecewise,
+    /// Cubic spline, for a clock drift which changes over time
+    Spline,
+}
+impl From<i32> for OffsetModel {
+    fn from(v: i32) -> Self {
+        match v {
+            1 => Self::Line,
+            2 => Self::Piecewise,
+            3 => Self::Spline,
+            _ => Self::Interpolate
+        }
+    }
+}
+
+#[derive(Default, Clone, Debug)]
+pub struct OffsetFit {
+    /// Model sampled in the gyro timestamps (us), to be interpolated linearly
+    pub samples: BTreeMap<i64, f64>,
+    /// Value of the model at each sync point
+    pub fitted: BTreeMap<i64, f64>,
+    /// Sync points which don't agree with the model
+    pub rejected: BTreeSet<i64>,
+}
+
+/// `offsets` are the sync points, `range_us` is the range the model is extrapolated to
+pub fn fit(offsets: &BTreeMap<i64, f64>, model: OffsetModel, range_us: (i64, i64)) -> OffsetFit {
+    let points: Vec<(i64, f64)> = offsets.iter().map(|(k, v)| (*k, *v)).collect();
+    if points.is_empty() { return OffsetFit::default(); }
+    let range_us = (range_us.0.min(points[0].0), range_us.1.max(points[points.len() - 1].0));
+
+    let mut result = OffsetFit::default();
+    match model {
+        OffsetModel::Interpolate => {
+            // The line is only a hint which sync points may be wrong
+            result.samples = offsets.clone();
+            result.fitted = match robust_line(&points) {
+                Some((line, _)) if points.len() > 1 => points.iter().map(|(k, _)| (*k, line.at(*k))).collect(),
+                _ => offsets.clone()
+            };
+            return result;
+        }
+        OffsetModel::Line => {
+            if let Some((line, inliers)) = robust_line(&points) {
+                result.rejected = rejected(&points, &inliers);
+                result.samples = [range_us.0, range_us.1].into_iter().map(|k| (k, line.at(k))).collect();
+            }
+        }
+        OffsetModel::Piecewise => {
+            let (segments, rejected) = piecewise(&points);
+            result.rejected = rejected;
+            let count = segments.len();
+            for (i, (line, from, to)) in segments.into_iter().enumerate() {
+                let from = if i == 0 { range_us.0 } else { from };
+                let to = if i == count - 1 { range_us.1 } else { to };
+                result.samples.insert(from, line.at(from));
+                result.samples.insert(to, line.at(to));
+            }
+        }
+        OffsetModel::Spline => {
+            let inliers = spline_inliers(&points);
+            result.rejected = rejected(&points, &inliers);
+            let knots: Vec<(i64, f64)> = points.iter().zip(&inliers).filter(|(_, inlier)| **inlier).map(|(p, _)| *p).collect();
+            if let Some(spline) = Spline::new(&knots) {
+                let mut ts = range_us.0;
+                while ts < range_us.1 {
+                    result.samples.insert(ts, spline.at(ts));
+                    ts += SPLINE_STEP_US;
+                }
+                result.samples.insert(range_us.1, spline.at(range_us.1));
+                for (k, _) in &knots {
+                    result.samples.insert(*k, spline.at(*k));
+                }
+            }
+        }
+    }
+    if result.samples.is_empty() {
+        result.samples = offsets.clone();
+        result.rejected.clear();
+    }
+    result.fitted = points.iter().map(|(k, _)| (*k, crate::gyro_source::GyroSource::offset_at_timestamp(&result.samples, *k as f64 / 1000.0))).collect();
+    result
+}
+
+fn rejected(points: &[(i64, f64)], inliers: &[bool]) -> BTreeSet<i64> {
+    points.iter().zip(inliers).filter(|(_, inlier)| !**inlier).map(|(p, _)| p.0).collect()
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Line {
+    slope: f64, // ms per us
+    intercept: f64,
+}
+impl Line {
+    fn at(&self, ts: i64) -> f64 { self.slope * ts as f64 + self.intercept }
+
+    /// Least squares fit
+    fn fit<'a>(points: impl Iterator<Item = &'a (i64, f64)> + Clone) -> Option<Self> {
+        let n = points.clone().count() as f64;
+        if n < 1.0 { return None; }
+        let mean_t = points.clone().map(|p| p.0 as f64).sum::<f64>() / n;
+        let mean_o = points.clone().map(|p| p.1).sum::<f64>() / n;
+        let variance = points.clone().map(|p| (p.0 as f64 - mean_t).powi(2)).sum::<f64>();
+        let slope = if variance > 1e-6 {
+            points.map(|p| (p.0 as f64 - mean_t) * (p.1 - mean_o)).sum::<f64>() / variance
+        } else {
+            0.0
+        };
+        Some(Self { slope, intercept: mean_o - slope * mean_t })
+    }
+}
+
+/// Line agreeing with the most points, refitted to them. Returns the line and which points are inliers
+fn robust_line(points: &[(i64, f64)]) -> Option<(Line, Vec<bool>)> {
+    let inliers_of = |line: &Line| -> Vec<bool> { points.iter().map(|p| (line.at(p.0) - p.1).abs() < MAX_FITTING_ERROR).collect() };
+    let residuals = |line: &Line, inliers: &[bool]| -> f64 { points.iter().zip(inliers).filter(|x| *x.1).map(|(p, _)| (line.at(p.0) - p.1).powi(2)).sum() };
+    let refit = |inliers: &[bool]| Line::fit(points.iter().zip(inliers).filter(|x| *x.1).map(|x| x.0));
+
+    // Lines through pairs of points, and a constant offset for each point (i == j)
+    let n = points.len();
+    let candidates: Vec<(usize, usize)> = if n * (n + 1) / 2 <= MAX_LINE_CANDIDATES {
+        (0..n).flat_map(|i| (i..n).map(move |j| (i, j))).collect()
+    } else {
+        // Random pairs, seeded so the result is the same every time
+        let mut rng = fastrand::Rng::with_seed(n as u64);
+        (0..MAX_LINE_CANDIDATES).map(|_| (rng.usize(..n), rng.usize(..n))).collect()
+    };
+
+    let mut best: Option<(Line, Vec<bool>, usize, f64)> = None;
+    for (i, j) in candidates {
+        let Some(candidate) = Line::fit([points[i], points[j]].iter()) else { continue; };
+        let inliers = inliers_of(&candidate);
+        let Some(line) = refit(&inliers) else { continue; };
+        let inliers = inliers_of(&line);
+        let count = inliers.iter().filter(|x| **x).count();
+        let error = residuals(&line, &inliers);
+        let better = match &best {
+            Some(b) => count > b.2 || (count == b.2 && error < b.3),
+            None => true
+        };
+        if better {
+            best = Some((line, inliers, count, error));
+        }
+    }
+    best.filter(|b| b.2 > 0).map(|b| (b.0, b.1))
+}
+
+/// Splits the points at jumps of the offset. A jump has to be confirmed by the next point, otherwise the point is rejected.
+/// Returns the line of each segment with the timestamps of its first and last point
+fn piecewise(points: &[(i64, f64)]) -> (Vec<(Line, i64, i64)>, BTreeSet<i64>) {
+    let mut segments: Vec<Vec<(i64, f64)>> = vec![Vec::new()];
+    let mut rejected = BTreeSet::new();
+    for (i, p) in points.iter().enumerate() {
+        let segment = segments.last_mut().unwrap();
+        let Some(line) = Line::fit(segment.iter()) else { segment.push(*p); continue; };
+        if (line.at(p.0) - p.1).abs() < MAX_FITTING_ERROR {
+            segment.push(*p);
+            continue;
+        }
+        let confirmed = points.get(i + 1).is_some_and(|next| (next.1 - p.1).abs() < MAX_FITTING_ERROR && (line.at(next.0) - next.1).abs() >= MAX_FITTING_ERROR);
+        if confirmed {
+            segments.push(vec![*p]);
+        } else {
+            rejected.insert(p.0);
+        }
+    }
+
+    // A single point isn't enough to tell a jump from a bad sync point
+    if segments.len() > 1 {
+        segments.retain(|s| {
+            if s.len() < 2 { rejected.extend(s.iter().map(|p| p.0)); }
+            s.len() >= 2
+        });
+    }
+
+    let segments = segments.into_iter().filter_map(|s| {
+        let (line, inliers) = robust_line(&s)?;
+        rejected.extend(s.iter().zip(&inliers).filter(|x| !*x.1).map(|x| x.0.0));
+        let kept: Vec<i64> = s.iter().zip(&inliers).filter(|x| *x.1).map(|x| x.0.0).collect();
+        Some((line, *kept.first()?, *kept.last()?))
+    }).collect();
+    (segments, rejected)
+}
+
+/// A point is rejected when it doesn't agree with the line through its neighbors
+fn spline_inliers(points: &[(i64, f64)]) -> Vec<bool> {
+    if points.len() < 3 {
+        return robust_line(points).map(|x| x.1).unwrap_or_else(|| vec![true; points.len()]);
+    }
+    let window = SPLINE_WINDOW.min(points.len());
+    (0..points.len()).map(|i| {
+        let start = i.saturating_sub(window / 2).min(points.len() - window);
+        match robust_line(&points[start..start + window]) {
+            Some((_, inliers)) => inliers[i - start],
+            None => true
+        }
+    }).collect()
+}
+
+/// Natural cubic spline through the points, extrapolated linearly
+struct Spline {
+    knots: Vec<(f64, f64)>, // seconds from the first point, offset
+    second_derivatives: Vec<f64>,
+    origin: i64,
+}
+impl Spline {
+    fn new(points: &[(i64, f64)]) -> Option<Self> {
+        let origin = points.first()?.0;
+        let knots: Vec<(f64, f64)> = points.iter().map(|p| ((p.0 - origin) as f64 / 1_000_000.0, p.1)).collect();
+        let n = knots.len();
+        let mut second_derivatives = vec![0.0; n];
+        if n > 2 {
+            // Tridiagonal system for the inner second derivatives, solved with the Thomas algorithm
+            let h: Vec<f64> = knots.windows(2).map(|w| w[1].0 - w[0].0).collect();
+            let mut diagonal = vec![0.0; n];
+            let mut rhs = vec![0.0; n];
+            for i in 1..n - 1 {
+                diagonal[i] = 2.0 * (h[i - 1] + h[i]);
+                rhs[i] = 6.0 * ((knots[i + 1].1 - knots[i].1) / h[i] - (knots[i].1 - knots[i - 1].1) / h[i - 1]);
+            }
+            for i in 2..n - 1 {
+                let m = h[i - 1] / diagonal[i - 1];
+                diagonal[i] -= m * h[i - 1];
+                rhs[i] -= m * rhs[i - 1];
+            }
+            for i in (1..n - 1).rev() {
+                second_derivatives[i] = (rhs[i] - h[i] * second_derivatives[i + 1]) / diagonal[i];
+            }
+        }
+        Some(Self { knots, second_derivatives, origin })
+    }
+
+    fn at(&self, ts: i64) -> f64 {
+        let t = (ts - self.origin) as f64 / 1_000_000.0;
+        let (k, m) = (&self.knots, &self.second_derivatives);
+        let n = k.len();
+        if n == 1 { return k[0].1; }
+        let slope = |i: usize| -> f64 {
+            // First derivative at the start of interval i
+            let h = k[i + 1].0 - k[i].0;
+            (k[i + 1].1 - k[i].1) / h - h * (2.0 * m[i] + m[i + 1]) / 6.0
+        };
+        if t <= k[0].0 {
+            return k[0].1 + slope(0) * (t - k[0].0);
+        }
+        if t >= k[n - 1].0 {
+            let h = k[n - 1].0 - k[n - 2].0;
+            let end_slope = (k[n - 1].1 - k[n - 2].1) / h + h * (m[n - 2] + 2.0 * m[n - 1]) / 6.0;
+            return k[n - 1].1 + end_slope * (t - k[n - 1].0);
+        }
+        let i = k.partition_point(|x| x.0 <= t).saturating_sub(1).min(n - 2);
+        let h = k[i + 1].0 - k[i].0;
+        let (a, b) = ((k[i + 1].0 - t) / h, (t - k[i].0) / h);
+        a * k[i].1 + b * k[i + 1].1 + ((a.powi(3) - a) * m[i] + (b.powi(3) - b) * m[i + 1]) * h * h / 6.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE: i64 = 60_000_000;
+
+    fn points(f: impl Fn(i64) -> f64) -> BTreeMap<i64, f64> {
+        (0..20).map(|i| { let ts = i * MINUTE / 2; (ts, f(ts)) }).collect()
+    }
+
+    #[test]
+    fn line_rejects_outliers() {
+        let drift = |ts: i64| 12.0 + ts as f64 * 2e-7; // 12 ms per minute
+        let mut offsets = points(drift);
+        offsets.insert(3 * MINUTE, 80.0);
+        offsets.insert(7 * MINUTE, -40.0);
+        let fit = fit(&offsets, OffsetModel::Line, (0, 10 * MINUTE));
+        assert_eq!(fit.rejected, BTreeSet::from([3 * MINUTE, 7 * MINUTE]));
+        for ts in [0, MINUTE, 3 * MINUTE, 10 * MINUTE] {
+            let offset = crate::gyro_source::GyroSource::offset_at_timestamp(&fit.samples, ts as f64 / 1000.0);
+            assert!((offset - drift(ts)).abs() < 1e-6, "{offset} != {}", drift(ts));
+        }
+    }
+
+    #[test]
+    fn line_with_many_points() {
+        // A sync point every second of a long recording, every 10th is wrong
+        let drift = |ts: i64| 12.0 + ts as f64 * 2e-7;
+        let offsets: BTreeMap<i64, f64> = (0..3000).map(|i| {
+            let ts = i * 1_000_000;
+            (ts, if i % 10 == 5 { drift(ts) + 30.0 + (i % 7) as f64 } else { drift(ts) })
+        }).collect();
+        let fit = fit(&offsets, OffsetModel::Line, (0, 3000 * 1_000_000));
+        assert_eq!(fit.rejected, offsets.keys().copied().filter(|ts| ts / 1_000_000 % 10 == 5).collect::<BTreeSet<_>>());
+        let offset = crate::gyro_source::GyroSource::offset_at_timestamp(&fit.samples, 1_500_000.0);
+        assert!((offset - drift(1_500_000_000)).abs() < 1e-6, "{offset} != {}", drift(1_500_000_000));
+    }
+
+    #[test]
+    fn piecewise_finds_jumps() {
+        let model = |ts: i64| if ts < 4 * MINUTE { 10.0 + ts as f64 * 1e-7 } else { 45.0 + ts as f64 * 1e-7 };
+        let mut offsets = points(model);
+        offsets.insert(8 * MINUTE + 1, -30.0);
+        let fit = fit(&offsets, OffsetModel::Piecewise, (0, 10 * MINUTE));
+        assert_eq!(fit.rejected, BTreeSet::from([8 * MINUTE + 1]));
+        for ts in [0, 3 * MINUTE, 5 * MINUTE, 10 * MINUTE] {
+            let offset = crate::gyro_source::GyroSource::offset_at_timestamp(&fit.samples, ts as f64 / 1000.0);
+            assert!((offset - model(ts)).abs() < 1e-6, "{offset} != {}", model(ts));
+        }
+    }
+
+    #[test]
+    fn spline_follows_changing_drift() {
+        let model = |ts: i64| { let t = ts as f64 / MINUTE as f64; 5.0 + 3.0 * t - 0.2 * t * t };
+        let mut offsets = points(model);
+        offsets.insert(5 * MINUTE + MINUTE / 4, 60.0);
+        let fit = fit(&offsets, OffsetModel::Spline, (0, 10 * MINUTE));
+        assert_eq!(fit.rejected, BTreeSet::from([5 * MINUTE + MINUTE / 4]));
+        for ts in [MINUTE / 3, 4 * MINUTE, 9 * MINUTE] {
+            let offset = crate::gyro_source::GyroSource::offset_at_timestamp(&fit.samples, ts as f64 / 1000.0);
+            assert!((offset - model(ts)).abs() < 0.05, "{offset} != {}", model(ts));
+        }
+    }
+}
@@ -0,0 +1,308 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Dense optical flow with phase correlation, for footage with too little texture for feature detection (sky, water, snow).
+// The global shift is found first on the whole frame downscaled to 128x128, then every block of a regular grid is correlated
+// with the block of the next frame moved by the global shift. Phase correlation only uses the phase of the spectrum,
+// so weak gradients count the same as strong ones. Its subpixel part is biased towards whole pixels by the window,
+// so it's refined with a few Lucas-Kanade iterations over the whole block.
+// The block centers and their shifts are returned as matched points, so the pose is estimated from the motion of the whole image.
+
+use super::super::{ OpticalFlowPair, OpticalFlowPoints };
+use super::{ OpticalFlowTrait, OpticalFlowMethod };
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicU32, Ordering::SeqCst };
+use parking_lot::RwLock;
+use rustfft::{ num_complex::Complex, Fft, FftPlanner };
+
+const GRID_COLUMNS: u32 = 12;
+const GLOBAL_SIZE: usize = 128;
+const MIN_BLOCK_SIZE: usize = 32;
+const MAX_BLOCK_SIZE: usize = 128;
+/// Minimum height of the correlation peak, the whole correlation surface sums to 1
+const MIN_PEAK: f32 = 0.08;
+/// Blocks with lower standard deviation of the intensity are just noise
+const MIN_STDDEV: f32 = 0.5;
+const REFINE_ITERATIONS: usize = 10;
+/// Maximum change of the phase correlation result by the refinement, in pixels
+const MAX_REFINEMENT: f32 = 1.5;
+
+#[derive(Clone)]
+pub struct OFDense {
+    features: Vec<(f32, f32)>,
+    img: Arc<image::GrayImage>,
+    matched_points: Arc<RwLock<BTreeMap<i64, (OpticalFlowPoints, OpticalFlowPoints)>>>,
+    timestamp_us: i64,
+    size: (u32, u32),
+    used: Arc<AtomicU32>,
+}
+
+impl OFDense {
+    pub fn detect_features(timestamp_us: i64, img: Arc<image::GrayImage>, width: u32, height: u32) -> Self {
+        Self {
+            features: grid(img.width(), img.height()).0,
+            timestamp_us,
+            size: (width, height),
+            matched_points: Default::default(),
+            img,
+            used: Default::default()
+        }
+    }
+}
+
+impl OpticalFlowTrait for OFDense {
+    fn size(&self) -> (u32, u32) { self.size }
+    fn features(&self) -> &Vec<(f32, f32)> { &self.features }
+
+    fn optical_flow_to(&self, to: &OpticalFlowMethod) -> OpticalFlowPair {
+        if let OpticalFlowMethod::OFDense(next) = to {
+            if let Some(matched) = self.matched_points.read().get(&next.timestamp_us) {
+                return Some(matched.clone());
+            }
+            if self.img.is_empty() || next.img.is_empty() || self.img.dimensions() != next.img.dimensions() { return None; }
+
+            let result = dense_flow(&self.img, &next.img);
+
+            self.used.fetch_add(1, SeqCst);
+            next.used.fetch_add(1, SeqCst);
+
+            self.matched_points.write().insert(next.timestamp_us, result.clone());
+            return Some(result);
+        }
+        None
+    }
+    fn can_cleanup(&self) -> bool {
+        self.used.load(SeqCst) == 2
+    }
+    fn cleanup(&mut self) {
+        self.img = Arc::new(image::GrayImage::default());
+    }
+}
+
+/// Block centers and the block size. Blocks at the edges are moved inside the frame
+fn grid(width: u32, height: u32) -> (Vec<(f32, f32)>, usize) {
+    let step = (width / GRID_COLUMNS).max(1);
+    let block = (step as usize).next_power_of_two().clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE);
+    if (width as usize) < block || (height as usize) < block { return (Vec::new(), block); }
+    let rows = (height / step).max(1);
+    let y_offset = (height - rows * step) / 2;
+    let half = block as u32 / 2;
+    let centers = (0..rows).flat_map(|y| (0..GRID_COLUMNS).map(move |x| {
+        ((x * step + step / 2).clamp(half, width - half) as f32, (y_offset + y * step + step / 2).clamp(half, height - half) as f32)
+    })).collect();
+    (centers, block)
+}
+
+fn dense_flow(img1: &image::GrayImage, img2: &image::GrayImage) -> (OpticalFlowPoints, OpticalFlowPoints) {
+    let (w, h) = (img1.width() as f32, img1.height() as f32);
+    let (centers, block) = grid(img1.width(), img1.height());
+    let mut planner = FftPlanner::<f32>::new();
+
+    // Global shift, scaled back to pixels
+    let global = PhaseCorrelation::new(&mut planner, GLOBAL_SIZE);
+    let (gx, gy) = global.shift(&downscale(img1, GLOBAL_SIZE), &downscale(img2, GLOBAL_SIZE))
+        .map(|(x, y, _)| ((x * w / GLOBAL_SIZE as f32).round(), (y * h / GLOBAL_SIZE as f32).round()))
+        .unwrap_or((0.0, 0.0));
+
+    let correlation = PhaseCorrelation::new(&mut planner, block);
+    let half = block as f32 / 2.0;
+    let mut points_a = Vec::with_capacity(centers.len());
+    let mut points_b = Vec::with_capacity(centers.len());
+    for (cx, cy) in centers {
+        let (x1, y1) = (cx - half, cy - half);
+        let (x2, y2) = (x1 + gx, y1 + gy);
+        if x2 < 0.0 || y2 < 0.0 || x2 + block as f32 > w || y2 + block as f32 > h { continue; }
+
+        let a = crop(img1, x1 as u32, y1 as u32, block);
+        let b = crop(img2, x2 as u32, y2 as u32, block);
+        if let Some((dx, dy, peak)) = correlation.shift(&a, &b) {
+            let (dx, dy) = refine(img1, img2, (x1, y1), block, (gx + dx, gy + dy));
+            let (bx, by) = (cx + dx, cy + dy);
+            if peak >= MIN_PEAK && bx >= 0.0 && bx < w && by >= 0.0 && by < h {
+                points_a.push((cx, cy));
+                points_b.push((bx, by));
+            }
+        }
+    }
+    (points_a, points_b)
+}
+
+/// Lucas-Kanade translation of the inner part of the block at `origin`, starting at `shift`
+fn refine(img1: &image::GrayImage, img2: &image::GrayImage, origin: (f32, f32), block: usize, shift: (f32, f32)) -> (f32, f32) {
+    let margin = block as u32 / 8;
+    let (x0, y0) = (origin.0 as u32 + margin, origin.1 as u32 + margin);
+    let size = block as u32 - margin * 2;
+    let pixel = |x: u32, y: u32| img1.get_pixel(x, y).0[0] as f32;
+
+    let mut samples = Vec::with_capacity((size * size) as usize);
+    let (mut gxx, mut gxy, mut gyy) = (0.0f32, 0.0f32, 0.0f32);
+    for y in y0..y0 + size {
+        for x in x0..x0 + size {
+            let gx = (pixel(x + 1, y) - pixel(x - 1, y)) / 2.0;
+            let gy = (pixel(x, y + 1) - pixel(x, y - 1)) / 2.0;
+            gxx += gx * gx;
+            gxy += gx * gy;
+            gyy += gy * gy;
+            samples.push((x as f32, y as f32, pixel(x, y), gx, gy));
+        }
+    }
+    let det = gxx * gyy - gxy * gxy;
+    if det.abs() < f32::EPSILON { return shift; }
+
+    let mut d = shift;
+    for _ in 0..REFINE_ITERATIONS {
+        let (mut bx, mut by) = (0.0f32, 0.0f32);
+        for &(x, y, i, gx, gy) in &samples {
+            let diff = i - sample(img2, x + d.0, y + d.1);
+            bx += diff * gx;
+            by += diff * gy;
+        }
+        let delta = ((gyy * bx - gxy * by) / det, (gxx * by - gxy * bx) / det);
+        d = (d.0 + delta.0, d.1 + delta.1);
+        if !d.0.is_finite() || !d.1.is_finite() || (d.0 - shift.0).abs() > MAX_REFINEMENT || (d.1 - shift.1).abs() > MAX_REFINEMENT {
+            return shift;
+        }
+        if delta.0 * delta.0 + delta.1 * delta.1 < 1e-4 { break; }
+    }
+    d
+}
+
+/// Bilinear sample with the border replicated
+fn sample(img: &image::GrayImage, x: f32, y: f32) -> f32 {
+    let (w, h) = (img.width() as i32, img.height() as i32);
+    let pixel = |x: i32, y: i32| img.get_pixel(x.clamp(0, w - 1) as u32, y.clamp(0, h - 1) as u32).0[0] as f32;
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let (x0, y0) = (x0 as i32, y0 as i32);
+    let top    = pixel(x0, y0)     * (1.0 - fx) + pixel(x0 + 1, y0)     * fx;
+    let bottom = pixel(x0, y0 + 1) * (1.0 - fx) + pixel(x0 + 1, y0 + 1) * fx;
+    top * (1.0 - fy) + bottom * fy
+}
+
+fn crop(img: &image::GrayImage, x: u32, y: u32, size: usize) -> Vec<f32> {
+    let mut ret = Vec::with_capacity(size * size);
+    for yy in 0..size as u32 {
+        for xx in 0..size as u32 {
+            ret.push(img.get_pixel(x + xx, y + yy).0[0] as f32);
+        }
+    }
+    ret
+}
+
+/// Area average to `size`x`size`, the aspect ratio isn't preserved
+fn downscale(img: &image::GrayImage, size: usize) -> Vec<f32> {
+    let (w, h) = (img.width() as usize, img.height() as usize);
+    let mut ret = vec![0.0; size * size];
+    for y in 0..size {
+        let (sy0, sy1) = (y * h / size, ((y + 1) * h / size).max(y * h / size + 1).min(h));
+        for x in 0..size {
+            let (sx0, sx1) = (x * w / size, ((x + 1) * w / size).max(x * w / size + 1).min(w));
+            let mut sum = 0.0;
+            for sy in sy0..sy1 {
+                for sx in sx0..sx1 {
+                    sum += img.get_pixel(sx as u32, sy as u32).0[0] as f32;
+                }
+            }
+            ret[y * size + x] = sum / ((sy1 - sy0) * (sx1 - sx0)).max(1) as f32;
+        }
+    }
+    ret
+}
+
+struct PhaseCorrelation {
+    size: usize,
+    window: Vec<f32>,
+    forward: Arc<dyn Fft<f32>>,
+    inverse: Arc<dyn Fft<f32>>,
+}
+
+impl PhaseCorrelation {
+    fn new(planner: &mut FftPlanner<f32>, size: usize) -> Self {
+        let hann: Vec<f32> = (0..size).map(|i| 0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / size as f32).cos()).collect();
+        Self {
+            size,
+            window: (0..size * size).map(|i| hann[i / size] * hann[i % size]).collect(),
+            forward: planner.plan_fft_forward(size),
+            inverse: planner.plan_fft_inverse(size),
+        }
+    }
+
+    /// Windowed spectrum of a block without its mean, or `None` if the block is flat
+    fn spectrum(&self, block: &[f32]) -> Option<Vec<Complex<f32>>> {
+        let n = block.len() as f32;
+        let mean = block.iter().sum::<f32>() / n;
+        let stddev = (block.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / n).sqrt();
+        if stddev < MIN_STDDEV { return None; }
+        let mut data: Vec<Complex<f32>> = block.iter().zip(self.window.iter()).map(|(v, w)| Complex::new((v - mean) * w, 0.0)).collect();
+        self.fft_2d(&mut data, &self.forward);
+        Some(data)
+    }
+
+    fn fft_2d(&self, data: &mut [Complex<f32>], fft: &Arc<dyn Fft<f32>>) {
+        let n = self.size;
+        fft.process(data);
+        let mut column = vec![Complex::default(); n];
+        for x in 0..n {
+            for y in 0..n { column[y] = data[y * n + x]; }
+            fft.process(&mut column);
+            for y in 0..n { data[y * n + x] = column[y]; }
+        }
+    }
+
+    /// Shift of `b` relative to `a` with subpixel precision, and the height of the correlation peak
+    fn shift(&self, a: &[f32], b: &[f32]) -> Option<(f32, f32, f32)> {
+        let n = self.size;
+        let (fa, fb) = (self.spectrum(a)?, self.spectrum(b)?);
+        let mut cross: Vec<Complex<f32>> = fb.iter().zip(fa.iter()).map(|(b, a)| {
+            let c = b * a.conj();
+            let norm = c.norm();
+            if norm > 1e-12 { c / norm } else { Complex::default() }
+        }).collect();
+        self.fft_2d(&mut cross, &self.inverse);
+
+        let scale = 1.0 / (n * n) as f32;
+        let surface: Vec<f32> = cross.iter().map(|c| c.re * scale).collect();
+        let (peak_index, &peak) = surface.iter().enumerate().max_by(|a, b| a.1.total_cmp(b.1))?;
+        let (px, py) = (peak_index % n, peak_index / n);
+
+        // Subpixel shift from the ratio of the peak and its bigger neighbor (Foroosh et al.), wrapped around like the correlation
+        let at = |x: usize, y: usize| surface[(y % n) * n + x % n];
+        let subpixel = |minus: f32, plus: f32| {
+            let (side, neighbor) = if plus >= minus { (1.0, plus) } else { (-1.0, minus) };
+            if neighbor > 0.0 { side * neighbor / (neighbor + peak) } else { 0.0 }
+        };
+        let dx = px as f32 + subpixel(at(px + n - 1, py), at(px + 1, py));
+        let dy = py as f32 + subpixel(at(px, py + n - 1), at(px, py + 1));
+        let wrap = |d: f32| if d > n as f32 / 2.0 { d - n as f32 } else { d };
+        Some((wrap(dx), wrap(dy), peak))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Low contrast texture on a gradient, like a cloudy sky
+    fn sky(x: f32, y: f32) -> f32 {
+        140.0 + y * 0.1 + 3.0 * (x * 0.07 + (y * 0.05).sin() * 2.0).sin() + 2.0 * (x * 0.031 - y * 0.043).cos() * (y * 0.11).sin()
+    }
+
+    #[test]
+    fn tracks_low_texture_shift() {
+        let (w, h, shift) = (640u32, 360u32, (23.4f32, -11.6f32));
+        let frame = |dx: f32, dy: f32| Arc::new(image::GrayImage::from_fn(w, h, |x, y| image::Luma([sky(x as f32 - dx, y as f32 - dy).round() as u8])));
+
+        let first = OFDense::detect_features(0, frame(0.0, 0.0), w, h);
+        let second = OpticalFlowMethod::OFDense(OFDense::detect_features(33333, frame(shift.0, shift.1), w, h));
+
+        let (pts1, pts2) = first.optical_flow_to(&second).unwrap();
+        assert!(pts1.len() > first.features().len() * 3 / 4, "matched: {} of {}", pts1.len(), first.features().len());
+        // The texture has only a few levels of contrast, so the rounding to 8 bits leaves some blocks less precise
+        let errors: Vec<f32> = pts1.iter().zip(pts2.iter()).map(|(a, b)| ((b.0 - a.0 - shift.0).powi(2) + (b.1 - a.1 - shift.1).powi(2)).sqrt()).collect();
+        let mean = errors.iter().sum::<f32>() / errors.len() as f32;
+        let max = errors.iter().copied().fold(0.0f32, f32::max);
+        assert!(mean < 0.15 && max < 0.6, "mean error: {mean}, max error: {max}");
+    }
+}
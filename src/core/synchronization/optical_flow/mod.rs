@@ -7,6 +7,8 @@ use std::sync::Arc;
 mod akaze;        pub use self::akaze::*;
 mod opencv_dis;   pub use opencv_dis::*;
 mod opencv_pyrlk; pub use opencv_pyrlk::*;
+mod pyrlk;        pub use pyrlk::*;
+mod dense;        pub use dense::*;
 
 #[enum_delegate::register]
 pub trait OpticalFlowTrait {
@@ -23,13 +25,22 @@ pub enum OpticalFlowMethod {
     OFAkaze(OFAkaze),
     OFOpenCVPyrLK(OFOpenCVPyrLK),
     OFOpenCVDis(OFOpenCVDis),
+    OFPyrLK(OFPyrLK),
+    OFDense(OFDense),
 }
 impl OpticalFlowMethod {
     pub fn detect_features(method: u32, timestamp_us: i64, img: Arc<image::GrayImage>, width: u32, height: u32) -> Self {
         match method {
             0 => Self::OFAkaze(OFAkaze::detect_features(timestamp_us, img, width, height)),
+            #[cfg(feature = "use-opencv")]
             1 => Self::OFOpenCVPyrLK(OFOpenCVPyrLK::detect_features(timestamp_us, img, width, height)),
+            #[cfg(feature = "use-opencv")]
             2 => Self::OFOpenCVDis(OFOpenCVDis::detect_features(timestamp_us, img, width, height)),
+            // Without OpenCV, use the Rust implementation of Lucas-Kanade instead
+            #[cfg(not(feature = "use-opencv"))]
+            1 | 2 => Self::OFPyrLK(OFPyrLK::detect_features(timestamp_us, img, width, height)),
+            3 => Self::OFPyrLK(OFPyrLK::detect_features(timestamp_us, img, width, height)),
+            4 => Self::OFDense(OFDense::detect_features(timestamp_us, img, width, height)),
             _ => { log::error!("Unknown OF method {method}", ); Self::OFAkaze(OFAkaze::detect_features(timestamp_us, img, width, height)) }
         }
     }
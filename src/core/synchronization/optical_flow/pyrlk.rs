@@ -0,0 +1,318 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Pyramidal Lucas-Kanade optical flow without OpenCV, with the same parameters as `OFOpenCVPyrLK`:
+// up to 200 Shi-Tomasi corners at least 10 px apart, tracked with a 21x21 window over 3 pyramid levels above the full resolution.
+// Every track is also followed back to the first frame and dropped if it doesn't return to where it started.
+
+use super::super::{ OpticalFlowPair, OpticalFlowPoints };
+use super::{ OpticalFlowTrait, OpticalFlowMethod };
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicU32, Ordering::SeqCst };
+use parking_lot::RwLock;
+
+const MAX_FEATURES: usize = 200;
+const QUALITY_LEVEL: f32 = 0.01;
+const MIN_DISTANCE: f32 = 10.0;
+const WINDOW_RADIUS: i32 = 10;
+const PYRAMID_LEVELS: usize = 3;
+const MAX_ITERATIONS: usize = 30;
+const EPSILON: f32 = 0.01;
+/// Minimum eigenvalue of the spatial gradient matrix per window pixel, in intensity² per pixel². Equivalent to OpenCV's default `minEigThreshold` of 1e-4
+const MIN_EIGEN_THRESHOLD: f32 = 0.1;
+const MAX_FORWARD_BACKWARD_ERROR: f32 = 1.0;
+
+#[derive(Clone)]
+pub struct OFPyrLK {
+    features: Vec<(f32, f32)>,
+    img: Arc<image::GrayImage>,
+    matched_points: Arc<RwLock<BTreeMap<i64, (OpticalFlowPoints, OpticalFlowPoints)>>>,
+    timestamp_us: i64,
+    size: (u32, u32),
+    used: Arc<AtomicU32>,
+}
+
+impl OFPyrLK {
+    pub fn detect_features(timestamp_us: i64, img: Arc<image::GrayImage>, width: u32, height: u32) -> Self {
+        let features = if img.is_empty() { Vec::new() } else { good_features_to_track(&Image::from_gray(&img)) };
+        Self {
+            features,
+            size: (width, height),
+            img,
+            timestamp_us,
+            matched_points: Default::default(),
+            used: Default::default()
+        }
+    }
+}
+
+impl OpticalFlowTrait for OFPyrLK {
+    fn size(&self) -> (u32, u32) { self.size }
+    fn features(&self) -> &Vec<(f32, f32)> { &self.features }
+
+    fn optical_flow_to(&self, to: &OpticalFlowMethod) -> OpticalFlowPair {
+        if let OpticalFlowMethod::OFPyrLK(next) = to {
+            if let Some(matched) = self.matched_points.read().get(&next.timestamp_us) {
+                return Some(matched.clone());
+            }
+            if self.img.is_empty() || next.img.is_empty() || self.img.dimensions() != next.img.dimensions() { return None; }
+
+            let prev_pyramid = pyramid(&self.img);
+            let next_pyramid = pyramid(&next.img);
+            let (w, h) = (self.img.width() as f32, self.img.height() as f32);
+
+            let forward = track(&prev_pyramid, &next_pyramid, &self.features);
+            let tracked: Vec<(f32, f32)> = forward.iter().map(|p| p.unwrap_or((-1.0, -1.0))).collect();
+            let backward = track(&next_pyramid, &prev_pyramid, &tracked);
+
+            let mut pts1 = Vec::with_capacity(self.features.len());
+            let mut pts2 = Vec::with_capacity(self.features.len());
+            for ((pt1, pt2), back) in self.features.iter().zip(forward.iter()).zip(backward.iter()) {
+                if let (Some(pt2), Some(back)) = (pt2, back) {
+                    let in_frame = pt2.0 >= 0.0 && pt2.0 < w && pt2.1 >= 0.0 && pt2.1 < h;
+                    if in_frame && (back.0 - pt1.0).powi(2) + (back.1 - pt1.1).powi(2) < MAX_FORWARD_BACKWARD_ERROR.powi(2) {
+                        pts1.push(*pt1);
+                        pts2.push(*pt2);
+                    }
+                }
+            }
+
+            self.used.fetch_add(1, SeqCst);
+            next.used.fetch_add(1, SeqCst);
+
+            self.matched_points.write().insert(next.timestamp_us, (pts1.clone(), pts2.clone()));
+            return Some((pts1, pts2));
+        }
+        None
+    }
+    fn can_cleanup(&self) -> bool {
+        self.used.load(SeqCst) == 2
+    }
+    fn cleanup(&mut self) {
+        self.img = Arc::new(image::GrayImage::default());
+    }
+}
+
+struct Image {
+    width: usize,
+    height: usize,
+    data: Vec<f32>,
+}
+
+impl Image {
+    fn from_gray(img: &image::GrayImage) -> Self {
+        Self { width: img.width() as usize, height: img.height() as usize, data: img.as_raw().iter().map(|&v| v as f32).collect() }
+    }
+
+    /// Pixel with the border replicated
+    #[inline]
+    fn get(&self, x: i32, y: i32) -> f32 {
+        let x = x.clamp(0, self.width as i32 - 1) as usize;
+        let y = y.clamp(0, self.height as i32 - 1) as usize;
+        self.data[y * self.width + x]
+    }
+
+    #[inline]
+    fn sample(&self, x: f32, y: f32) -> f32 {
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i32, y0 as i32);
+        let top    = self.get(x0, y0)     * (1.0 - fx) + self.get(x0 + 1, y0)     * fx;
+        let bottom = self.get(x0, y0 + 1) * (1.0 - fx) + self.get(x0 + 1, y0 + 1) * fx;
+        top * (1.0 - fy) + bottom * fy
+    }
+
+    /// Half resolution, filtered with the 5-tap binomial kernel like `cv::pyrDown`
+    fn downsample(&self) -> Self {
+        const KERNEL: [f32; 5] = [1.0 / 16.0, 4.0 / 16.0, 6.0 / 16.0, 4.0 / 16.0, 1.0 / 16.0];
+        let (width, height) = (self.width.div_ceil(2), self.height.div_ceil(2));
+        let mut rows = Image { width, height: self.height, data: vec![0.0; width * self.height] };
+        for y in 0..self.height {
+            for x in 0..width {
+                rows.data[y * width + x] = KERNEL.iter().enumerate().map(|(i, k)| k * self.get(x as i32 * 2 + i as i32 - 2, y as i32)).sum();
+            }
+        }
+        let mut ret = Image { width, height, data: vec![0.0; width * height] };
+        for y in 0..height {
+            for x in 0..width {
+                ret.data[y * width + x] = KERNEL.iter().enumerate().map(|(i, k)| k * rows.get(x as i32, y as i32 * 2 + i as i32 - 2)).sum();
+            }
+        }
+        ret
+    }
+
+    /// Scharr derivatives, scaled to intensity per pixel
+    fn gradients(&self) -> (Image, Image) {
+        let mut ix = Image { width: self.width, height: self.height, data: vec![0.0; self.data.len()] };
+        let mut iy = Image { width: self.width, height: self.height, data: vec![0.0; self.data.len()] };
+        for y in 0..self.height as i32 {
+            for x in 0..self.width as i32 {
+                let i = y as usize * self.width + x as usize;
+                ix.data[i] = (3.0 * (self.get(x + 1, y - 1) - self.get(x - 1, y - 1)) + 10.0 * (self.get(x + 1, y) - self.get(x - 1, y)) + 3.0 * (self.get(x + 1, y + 1) - self.get(x - 1, y + 1))) / 32.0;
+                iy.data[i] = (3.0 * (self.get(x - 1, y + 1) - self.get(x - 1, y - 1)) + 10.0 * (self.get(x, y + 1) - self.get(x, y - 1)) + 3.0 * (self.get(x + 1, y + 1) - self.get(x + 1, y - 1))) / 32.0;
+            }
+        }
+        (ix, iy)
+    }
+}
+
+struct Level {
+    img: Image,
+    ix: Image,
+    iy: Image,
+}
+
+fn pyramid(img: &image::GrayImage) -> Vec<Level> {
+    let mut images = vec![Image::from_gray(img)];
+    for _ in 0..PYRAMID_LEVELS {
+        let last = images.last().unwrap();
+        if last.width < (WINDOW_RADIUS as usize * 2 + 1) * 2 || last.height < (WINDOW_RADIUS as usize * 2 + 1) * 2 { break; }
+        images.push(last.downsample());
+    }
+    images.into_iter().map(|img| {
+        let (ix, iy) = img.gradients();
+        Level { img, ix, iy }
+    }).collect()
+}
+
+/// Shi-Tomasi corners: local maxima of the minimum eigenvalue of the 3x3 gradient matrix, strongest first
+fn good_features_to_track(img: &Image) -> Vec<(f32, f32)> {
+    let (ix, iy) = img.gradients();
+    let (w, h) = (img.width, img.height);
+    if w < 3 || h < 3 { return Vec::new(); }
+
+    let mut response = vec![0.0f32; w * h];
+    for y in 1..h - 1 {
+        for x in 1..w - 1 {
+            let (mut a, mut b, mut c) = (0.0, 0.0, 0.0);
+            for dy in -1..=1 {
+                for dx in -1..=1 {
+                    let i = (y as i32 + dy) as usize * w + (x as i32 + dx) as usize;
+                    a += ix.data[i] * ix.data[i];
+                    b += ix.data[i] * iy.data[i];
+                    c += iy.data[i] * iy.data[i];
+                }
+            }
+            response[y * w + x] = (a + c) / 2.0 - (((a - c) / 2.0).powi(2) + b * b).sqrt();
+        }
+    }
+    let threshold = response.iter().copied().fold(0.0f32, f32::max) * QUALITY_LEVEL;
+    if threshold <= 0.0 { return Vec::new(); }
+
+    let mut candidates = Vec::new();
+    for y in 1..h - 1 {
+        for x in 1..w - 1 {
+            let r = response[y * w + x];
+            if r > threshold && (-1..=1).all(|dy| (-1..=1).all(|dx| response[(y as i32 + dy) as usize * w + (x as i32 + dx) as usize] <= r)) {
+                candidates.push((r, x, y));
+            }
+        }
+    }
+    candidates.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+    // Grid of the accepted corners, with cells of the minimum distance so only the neighboring cells have to be checked
+    let cell = MIN_DISTANCE as usize;
+    let (gw, gh) = (w / cell + 1, h / cell + 1);
+    let mut grid: Vec<Vec<(f32, f32)>> = vec![Vec::new(); gw * gh];
+    let mut ret = Vec::with_capacity(MAX_FEATURES);
+    for (_, x, y) in candidates {
+        let (gx, gy) = (x / cell, y / cell);
+        let pt = (x as f32, y as f32);
+        let too_close = (gy.saturating_sub(1)..=(gy + 1).min(gh - 1)).any(|cy| (gx.saturating_sub(1)..=(gx + 1).min(gw - 1)).any(|cx| {
+            grid[cy * gw + cx].iter().any(|p| (p.0 - pt.0).powi(2) + (p.1 - pt.1).powi(2) < MIN_DISTANCE * MIN_DISTANCE)
+        }));
+        if !too_close {
+            grid[gy * gw + gx].push(pt);
+            ret.push(pt);
+            if ret.len() >= MAX_FEATURES { break; }
+        }
+    }
+    ret
+}
+
+/// Positions of `points` of the `from` image in the `to` image, or `None` for points which couldn't be tracked
+fn track(from: &[Level], to: &[Level], points: &[(f32, f32)]) -> Vec<Option<(f32, f32)>> {
+    let levels = from.len().min(to.len());
+    let window = ((WINDOW_RADIUS * 2 + 1) * (WINDOW_RADIUS * 2 + 1)) as usize;
+    let mut patch = Vec::with_capacity(window);
+
+    points.iter().map(|&(px, py)| {
+        if px < 0.0 || py < 0.0 { return None; }
+        let mut guess = (0.0f32, 0.0f32);
+        for level in (0..levels).rev() {
+            let (prev, next) = (&from[level], &to[level]);
+            let scale = (1 << level) as f32;
+            let (x, y) = (px / scale, py / scale);
+
+            patch.clear();
+            let (mut gxx, mut gxy, mut gyy) = (0.0f32, 0.0f32, 0.0f32);
+            for dy in -WINDOW_RADIUS..=WINDOW_RADIUS {
+                for dx in -WINDOW_RADIUS..=WINDOW_RADIUS {
+                    let (sx, sy) = (x + dx as f32, y + dy as f32);
+                    let (i, gx, gy) = (prev.img.sample(sx, sy), prev.ix.sample(sx, sy), prev.iy.sample(sx, sy));
+                    gxx += gx * gx;
+                    gxy += gx * gy;
+                    gyy += gy * gy;
+                    patch.push((i, gx, gy));
+                }
+            }
+            let det = gxx * gyy - gxy * gxy;
+            let min_eigen = (gxx + gyy - ((gxx - gyy).powi(2) + 4.0 * gxy * gxy).sqrt()) / 2.0;
+            if det.abs() < f32::EPSILON || min_eigen / (window as f32) < MIN_EIGEN_THRESHOLD {
+                return None;
+            }
+
+            let mut v = (0.0f32, 0.0f32);
+            for _ in 0..MAX_ITERATIONS {
+                let (cx, cy) = (x + guess.0 + v.0, y + guess.1 + v.1);
+                let (mut bx, mut by) = (0.0f32, 0.0f32);
+                let mut k = 0;
+                for dy in -WINDOW_RADIUS..=WINDOW_RADIUS {
+                    for dx in -WINDOW_RADIUS..=WINDOW_RADIUS {
+                        let (i, gx, gy) = patch[k];
+                        let diff = i - next.img.sample(cx + dx as f32, cy + dy as f32);
+                        bx += diff * gx;
+                        by += diff * gy;
+                        k += 1;
+                    }
+                }
+                let delta = ((gyy * bx - gxy * by) / det, (gxx * by - gxy * bx) / det);
+                v = (v.0 + delta.0, v.1 + delta.1);
+                if !v.0.is_finite() || !v.1.is_finite() { return None; }
+                if delta.0 * delta.0 + delta.1 * delta.1 < EPSILON * EPSILON { break; }
+            }
+            guess = if level > 0 { ((guess.0 + v.0) * 2.0, (guess.1 + v.1) * 2.0) } else { (guess.0 + v.0, guess.1 + v.1) };
+        }
+        Some((px + guess.0, py + guess.1))
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texture(x: f32, y: f32) -> f32 {
+        128.0 + 50.0 * (x * 0.21).sin() * (y * 0.17).sin() + 40.0 * (x * 0.05 + y * 0.09).sin() + 20.0 * (x * 0.013 - y * 0.031).cos()
+    }
+
+    // The second frame is the first one shifted by a known subpixel offset, every returned track has to match it
+    #[test]
+    fn tracks_shifted_image() {
+        let (w, h, shift) = (320u32, 240u32, (6.4f32, -3.3f32));
+        let frame = |dx: f32, dy: f32| Arc::new(image::GrayImage::from_fn(w, h, |x, y| image::Luma([texture(x as f32 - dx, y as f32 - dy).round() as u8])));
+
+        let first = OFPyrLK::detect_features(0, frame(0.0, 0.0), w, h);
+        let second = OpticalFlowMethod::OFPyrLK(OFPyrLK::detect_features(33333, frame(shift.0, shift.1), w, h));
+        assert!(first.features().len() > 50, "features: {}", first.features().len());
+
+        let (pts1, pts2) = first.optical_flow_to(&second).unwrap();
+        assert!(pts1.len() > first.features().len() / 2, "tracked: {} of {}", pts1.len(), first.features().len());
+        // The window of points near the border covers the replicated border, which isn't shifted
+        for (a, b) in pts1.iter().zip(pts2.iter()).filter(|(a, _)| a.0 > 20.0 && a.1 > 20.0 && a.0 < w as f32 - 20.0 && a.1 < h as f32 - 20.0) {
+            let error = ((b.0 - a.0 - shift.0).powi(2) + (b.1 - a.1 - shift.1).powi(2)).sqrt();
+            assert!(error < 0.2, "{a:?} -> {b:?}, error: {error}");
+        }
+    }
+}
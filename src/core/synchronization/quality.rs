@@ -0,0 +1,153 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Confidence of a found sync offset. It's computed the same way for every offset method,
+// from the tracked features of the sync range and the gyro cost curve around the offset.
+
+use std::collections::BTreeMap;
+use nalgebra::Vector3;
+use crate::filtering::Lowpass;
+use crate::gyro_source::TimeIMU;
+use crate::stabilization::{ ComputeParams, FrameTransform, undistort_points_for_optical_flow };
+use super::PoseEstimator;
+use super::find_offset::essential_matrix::calculate_cost;
+
+/// The cost curve is sampled within this range around the found offset, in ms
+const CURVE_RANGE_MS: f64 = 100.0;
+const CURVE_STEP_MS: f64 = 2.0;
+/// Costs closer than this to the found offset belong to the same minimum, in ms
+const PEAK_WIDTH_MS: f64 = 20.0;
+/// Maximum distance of a tracked point from its position predicted by the frame rotation, in pixels of the processed frame
+const INLIER_THRESHOLD_PX: f64 = 2.0;
+
+const MIN_FEATURES: f64 = 20.0;
+const MIN_INLIER_RATIO: f64 = 0.5;
+const MIN_PEAK_SHARPNESS: f64 = 0.3;
+
+#[derive(Default, Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct SyncQuality {
+    /// (offset in ms, cost) around the found offset
+    pub cost_curve: Vec<(f64, f64)>,
+    /// Cost returned by the offset method
+    pub cost: f64,
+    /// Average number of tracked features per frame
+    pub features: f64,
+    /// Fraction of the tracked features which agree with the estimated camera rotation
+    pub inlier_ratio: f64,
+    /// 0 when another offset matches as well as the found one, 1 when the found offset matches perfectly
+    pub peak_sharpness: f64,
+    pub trustworthy: bool,
+}
+
+impl SyncQuality {
+    /// `range` is the analyzed range of the sync point, `offset` and `cost` are the result of the offset method
+    pub fn compute(estimator: &PoseEstimator, range: (i64, i64), offset: f64, cost: f64, params: &ComputeParams) -> Self {
+        let (features, inlier_ratio) = Self::features(estimator, range, params);
+        let (minimum, cost_curve) = Self::cost_curve(estimator, range, offset, params);
+        let peak_sharpness = peak_sharpness(&cost_curve, offset, minimum);
+
+        Self {
+            cost_curve,
+            cost,
+            features,
+            inlier_ratio,
+            peak_sharpness,
+            trustworthy: features >= MIN_FEATURES && inlier_ratio >= MIN_INLIER_RATIO && peak_sharpness >= MIN_PEAK_SHARPNESS
+        }
+    }
+
+    /// Returns the average number of tracked points per frame and the ratio of points consistent with the frame rotation
+    fn features(estimator: &PoseEstimator, range: (i64, i64), params: &ComputeParams) -> (f64, f64) {
+        let (mut frames, mut points, mut inliers) = (0usize, 0usize, 0usize);
+        for (_, fr) in estimator.sync_results.read().range(range.0..=range.1) {
+            let Ok(of) = fr.optical_flow.try_borrow() else { continue; };
+            let Some(Some(((ts1, pts1), (ts2, pts2)))) = of.get(&1) else { continue; };
+            frames += 1;
+            points += pts1.len().min(pts2.len());
+
+            let Some(rotation) = fr.rotation else { continue; };
+            let pts1 = undistort_points_for_optical_flow(pts1, *ts1, params, fr.frame_size);
+            let pts2 = undistort_points_for_optical_flow(pts2, *ts2, params, fr.frame_size);
+            let threshold = INLIER_THRESHOLD_PX / focal_length(params, fr.frame_size, *ts1);
+            inliers += pts1.iter().zip(pts2.iter()).filter(|(p1, p2)| {
+                let p = rotation * Vector3::new(p1.0 as f64, p1.1 as f64, 1.0);
+                p.z > 0.0 && ((p.x / p.z - p2.0 as f64).powi(2) + (p.y / p.z - p2.1 as f64).powi(2)).sqrt() < threshold
+            }).count();
+        }
+        if frames == 0 || points == 0 { return (0.0, 0.0); }
+        (points as f64 / frames as f64, inliers as f64 / points as f64)
+    }
+
+    /// Returns the cost at `offset` and the cost curve around it
+    fn cost_curve(estimator: &PoseEstimator, range: (i64, i64), offset: f64, params: &ComputeParams) -> (f64, Vec<(f64, f64)>) {
+        let gyro = params.gyro.read();
+        let mut of_item: Vec<TimeIMU> = estimator.estimated_gyro.read().range(range.0..range.1).map(|v| v.1.clone()).collect();
+        if of_item.is_empty() || gyro.duration_ms <= 0.0 || gyro.raw_imu.is_empty() { return (f64::MAX, Vec::new()); }
+
+        let (first, last) = (of_item[0].timestamp_ms, of_item[of_item.len() - 1].timestamp_ms);
+        let margin = CURVE_RANGE_MS + PEAK_WIDTH_MS;
+        let mut gyro_item: Vec<TimeIMU> = gyro.raw_imu.iter().filter(|x| {
+            let ts = x.timestamp_ms + offset;
+            ts >= first - margin && ts <= last + margin
+        }).cloned().collect();
+
+        let sample_rate = gyro.raw_imu.len() as f64 / (gyro.duration_ms / 1000.0);
+        let _ = Lowpass::filter_gyro_forward_backward(20.0, params.scaled_fps, &mut of_item);
+        let _ = Lowpass::filter_gyro_forward_backward(20.0, sample_rate, &mut gyro_item);
+        let gyro_bintree: BTreeMap<usize, TimeIMU> = gyro_item.into_iter().map(|x| ((x.timestamp_ms * 1000.0) as usize, x)).collect();
+
+        let steps = (CURVE_RANGE_MS * 2.0 / CURVE_STEP_MS) as usize;
+        let curve = (0..=steps).filter_map(|i| {
+            let offs = offset - CURVE_RANGE_MS + i as f64 * CURVE_STEP_MS;
+            let cost = calculate_cost(offs, &of_item, &gyro_bintree);
+            (cost < f64::MAX).then_some((offs, cost))
+        }).collect();
+        (calculate_cost(offset, &of_item, &gyro_bintree), curve)
+    }
+}
+
+/// Compares the cost at the found offset with the lowest cost outside of its minimum
+fn peak_sharpness(curve: &[(f64, f64)], offset: f64, minimum: f64) -> f64 {
+    if minimum >= f64::MAX { return 0.0; }
+    let other = curve.iter()
+        .filter(|(offs, _)| (offs - offset).abs() >= PEAK_WIDTH_MS)
+        .map(|(_, cost)| *cost)
+        .min_by(|a, b| a.total_cmp(b));
+    match other {
+        Some(other) if other > 0.0 => (1.0 - minimum / other).clamp(0.0, 1.0),
+        _ => 0.0
+    }
+}
+
+fn focal_length(params: &ComputeParams, size: (u32, u32), timestamp_us: i64) -> f64 {
+    let (camera_matrix, ..) = FrameTransform::get_lens_data_at_timestamp(params, timestamp_us as f64 / 1000.0);
+    let focal = camera_matrix[(0, 0)] * size.0 as f64 / params.video_width.max(1) as f64;
+    if focal > 0.0 { focal } else { size.0.max(1) as f64 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sharpness() {
+        let valley = |center: f64, depth: f64| move |x: f64| 1.0 - depth * (-((x - center) / 10.0).powi(2)).exp();
+        let curve = |f: &dyn Fn(f64) -> f64| (0..=100).map(|i| { let x = -100.0 + i as f64 * 2.0; (x, f(x)) }).collect::<Vec<_>>();
+
+        // Single deep minimum
+        let single = valley(0.0, 0.9);
+        assert!(peak_sharpness(&curve(&single), 0.0, single(0.0)) > 0.85);
+
+        // Flat curve
+        assert!(peak_sharpness(&curve(&|_| 1.0), 0.0, 1.0) < 1e-9);
+
+        // Two minima of the same depth, eg. periodic motion
+        let double = |x: f64| valley(-40.0, 0.9)(x).min(valley(40.0, 0.9)(x));
+        assert!(peak_sharpness(&curve(&double), -40.0, double(-40.0)) < 1e-6);
+
+        // Found offset isn't the lowest one
+        let shifted = valley(60.0, 0.9);
+        assert_eq!(peak_sharpness(&curve(&shifted), 0.0, shifted(0.0)), 0.0);
+    }
+}
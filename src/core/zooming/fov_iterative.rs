@@ -2,7 +2,9 @@
 // Copyright © 2022 Maik <myco at gmx>
 
 use super::*;
-use crate::stabilization::undistort_points_with_rolling_shutter;
+use crate::stabilization::{ undistort_points_with_rolling_shutter, undistort_points };
+use crate::gyro_source::Quat64;
+use nalgebra::Matrix3;
 use crate::keyframes::*;
 use std::collections::BTreeMap;
 use parking_lot::RwLock;
@@ -95,14 +97,49 @@ impl<'a>  FovIterative<'a> {
         let adaptive_zoom_center_x = keyframe_values.0;
         let adaptive_zoom_center_y = keyframe_values.1;
         let lens_correction_amount = keyframe_values.2;
+        let offset = (adaptive_zoom_center_x as f32 * self.input_dim.0, adaptive_zoom_center_y as f32 * self.input_dim.1);
 
-        let mut polygon = undistort_points_with_rolling_shutter(&rect, ts, &self.compute_params, lens_correction_amount, false);
-        for (x, y) in polygon.iter_mut() {
-            *x -= adaptive_zoom_center_x as f32 * self.input_dim.0;
-            *y -= adaptive_zoom_center_y as f32 * self.input_dim.1;
-        }
-        if self.compute_params.zooming_debug_points {
-            self.debug_points.write().insert(ts_us, polygon.iter().map(|(x, y)| ((x / self.input_dim.0) as f64, (y / self.input_dim.1) as f64)).collect());
+        self.fit_in_polygon(rect, center, offset, Some(ts_us), |points| {
+            undistort_points_with_rolling_shutter(points, ts, &self.compute_params, lens_correction_amount, false)
+        })
+    }
+
+    /// FOV at which the output fits inside the source image if the frame at `timestamp_ms` was corrected by `rotation`
+    /// (same as `GyroSource::smoothed_quat_at_timestamp`) instead of the current smoothed orientation.
+    /// `camera_matrix` and `distortion_coeffs` are the lens data at `timestamp_ms` and `new_k` is the output camera matrix at fov 1.
+    /// Doesn't read the gyro data, so it can be used while smoothing. Rolling shutter is not taken into account.
+    pub fn fov_for_rotation(&self, rotation: &Quat64, timestamp_ms: f64, camera_matrix: Matrix3<f64>, distortion_coeffs: &[f64; 12], new_k: Matrix3<f64>) -> f64 {
+        let params = self.compute_params;
+        let rect = points_around_rect(self.input_dim.0, self.input_dim.1, 31, 31);
+        let center = Point2D(self.input_dim.0 / 2.0, self.input_dim.1 / 2.0);
+        let offset = (params.adaptive_zoom_center_offset.0 as f32 * self.input_dim.0, params.adaptive_zoom_center_offset.1 as f32 * self.input_dim.1);
+
+        // Same as in `FrameTransform::at_timestamp_for_points`
+        let image_rotation = Matrix3::new_rotation(params.video_rotation * (std::f64::consts::PI / 180.0));
+        let mut r = image_rotation * *rotation.to_rotation_matrix().matrix();
+        r[(0, 1)] *= -1.0; r[(0, 2)] *= -1.0;
+        r[(1, 0)] *= -1.0; r[(2, 0)] *= -1.0;
+
+        self.fit_in_polygon(&rect, &center, offset, None, |points| {
+            undistort_points(points, camera_matrix, distortion_coeffs, r, Some(new_k), None, params, params.lens_correction_amount, timestamp_ms)
+        })
+    }
+
+    fn fit_in_polygon<F: Fn(&[(f32, f32)]) -> Vec<(f32, f32)>>(&self, rect: &[(f32, f32)], center: &Point2D, offset: (f32, f32), debug_ts_us: Option<i64>, undistort: F) -> f64 {
+        let undistort = |points: &[(f32, f32)]| -> Vec<(f32, f32)> {
+            let mut polygon = undistort(points);
+            for (x, y) in polygon.iter_mut() {
+                *x -= offset.0;
+                *y -= offset.1;
+            }
+            polygon
+        };
+
+        let mut polygon = undistort(rect);
+        if let Some(ts_us) = debug_ts_us {
+            if self.compute_params.zooming_debug_points {
+                self.debug_points.write().insert(ts_us, polygon.iter().map(|(x, y)| ((x / self.input_dim.0) as f64, (y / self.input_dim.1) as f64)).collect());
+            }
         }
 
         let initial = (1000000.0, 1000000.0 * self.output_inv_aspect);
@@ -120,11 +157,7 @@ impl<'a>  FovIterative<'a> {
                 ];
 
                 let distorted = interpolate_points(&relevant, 30);
-                polygon = undistort_points_with_rolling_shutter(&distorted, ts, &self.compute_params, lens_correction_amount, false);
-                for (x, y) in polygon.iter_mut() {
-                    *x -= adaptive_zoom_center_x as f32 * self.input_dim.0;
-                    *y -= adaptive_zoom_center_y as f32 * self.input_dim.1;
-                }
+                polygon = undistort(&distorted);
                 nearest = self.nearest_edge(&polygon, center, nearest.1);
             } else {
                 break;
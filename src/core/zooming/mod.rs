@@ -33,11 +33,8 @@ pub trait FieldOfViewAlgorithm {
     fn get_debug_points(&self) -> BTreeMap<i64, Vec<(f64, f64)>>;
 }
 
-pub fn calculate_fovs(compute_params: &ComputeParams, timestamps: &[f64], keyframes: &KeyframeManager, method: ZoomMethod) -> (Vec<f64>, Vec<f64>, BTreeMap<i64, Vec<(f64, f64)>>)  {
-    if timestamps.is_empty() {
-        return Default::default();
-    }
-
+/// Parameters used by `FovIterative`: original video dimensions and no zoom applied
+pub fn compute_params_for_fov(compute_params: &ComputeParams) -> ComputeParams {
     let mut compute_params = compute_params.clone();
     compute_params.fov_scale = 1.0;
     compute_params.fovs.clear();
@@ -49,6 +46,15 @@ pub fn calculate_fovs(compute_params: &ComputeParams, timestamps: &[f64], keyfra
     compute_params.height = compute_params.video_height;
     compute_params.output_width = compute_params.video_width;
     compute_params.output_height = compute_params.video_height;
+    compute_params
+}
+
+pub fn calculate_fovs(compute_params: &ComputeParams, timestamps: &[f64], keyframes: &KeyframeManager, method: ZoomMethod) -> (Vec<f64>, Vec<f64>, BTreeMap<i64, Vec<(f64, f64)>>)  {
+    if timestamps.is_empty() {
+        return Default::default();
+    }
+
+    let compute_params = compute_params_for_fov(compute_params);
 
     let fov_estimator = fov_iterative::FovIterative::new(&compute_params);
     let mut fov_values = fov_estimator.compute(timestamps, &compute_params.trim_ranges);
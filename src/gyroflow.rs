@@ -182,6 +182,7 @@ fn entry() {
     engine.set_property("isStorePackage".into(), util::is_store_package().into());
     engine.set_property("isMobile".into(), cfg!(any(target_os = "android", target_os = "ios")).into());
     engine.set_property("isSandboxed".into(), gyroflow_core::filesystem::is_sandboxed().into());
+    engine.set_property("hasOpenCV".into(), cfg!(feature = "opencv").into());
 
     // Get smoothing algorithms
     engine.set_property("smoothingAlgorithms".into(), QVariant::from(ctl.borrow().get_smoothing_algs()));
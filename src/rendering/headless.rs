@@ -0,0 +1,494 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+// Rendering entry point which doesn't depend on the Qt event loop or the `RenderQueue`.
+// Loads a .gyroflow project, optionally runs the autosync and renders it with the same code path as the render queue.
+
+use crate::core;
+use crate::core::{ StabilizationManager, GyroflowCoreError, InputFile };
+use crate::core::lens_profile_database::LensProfileDatabase;
+use crate::rendering;
+use super::{ FFmpegError, render_options::RenderOptions };
+use std::sync::{ Arc, atomic::{ AtomicBool, AtomicUsize, Ordering::SeqCst } };
+use parking_lot::RwLock;
+
+#[derive(Debug)]
+pub enum HeadlessError {
+    NotAProject(String),
+    FileExists(String),
+    Conversion(String),
+    Cancelled,
+    DoubtfulSync(usize),
+    Core(GyroflowCoreError),
+    FFmpeg(FFmpegError),
+}
+impl std::fmt::Display for HeadlessError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            HeadlessError::NotAProject(url)      => write!(f, "{url} is not a project file (no video file defined)"),
+            HeadlessError::FileExists(url)       => write!(f, "Output file {url} already exists"),
+            HeadlessError::Conversion(msg)       => write!(f, "Conversion failed: {msg}"),
+            HeadlessError::Cancelled             => write!(f, "Rendering cancelled"),
+            HeadlessError::DoubtfulSync(n)       => write!(f, "Synchronization is doubtful at {n} sync points"),
+            HeadlessError::Core(e)               => write!(f, "{e}"),
+            HeadlessError::FFmpeg(e)             => write!(f, "{e}"),
+        }
+    }
+}
+impl std::error::Error for HeadlessError { }
+impl HeadlessError {
+    /// Stable error identifier for machine-readable output, eg. `ffmpeg.EncoderNotFound` or `core.InvalidData`
+    pub fn kind(&self) -> String {
+        fn variant_name<T: std::fmt::Debug>(v: &T) -> String {
+            let dbg = format!("{v:?}");
+            dbg.split(|c: char| !c.is_alphanumeric() && c != '_').next().unwrap_or_default().to_owned()
+        }
+        match self {
+            HeadlessError::NotAProject(_)      => "not_a_project".into(),
+            HeadlessError::FileExists(_)       => "file_exists".into(),
+            HeadlessError::Conversion(_)       => "conversion".into(),
+            HeadlessError::Cancelled           => "cancelled".into(),
+            HeadlessError::DoubtfulSync(_)     => "doubtful_sync".into(),
+            HeadlessError::Core(e)             => format!("core.{}", variant_name(e)),
+            HeadlessError::FFmpeg(e)           => format!("ffmpeg.{}", variant_name(e)),
+        }
+    }
+}
+impl From<GyroflowCoreError> for HeadlessError {
+    fn from(err: GyroflowCoreError) -> HeadlessError { HeadlessError::Core(err) }
+}
+impl From<FFmpegError> for HeadlessError {
+    fn from(err: FFmpegError) -> HeadlessError { HeadlessError::FFmpeg(err) }
+}
+
+pub struct HeadlessRender {
+    pub stab: Arc<StabilizationManager>,
+    pub render_options: RenderOptions,
+    pub processing_resolution: i32,
+    /// Return `HeadlessError::DoubtfulSync` instead of only warning when the synchronization is doubtful
+    pub block_doubtful_sync: bool,
+}
+
+impl HeadlessRender {
+    /// Loads the project file and prepares the `StabilizationManager` the same way `RenderQueue::add_file` does.
+    /// `additional_data` has the same format as in the render queue (`output` and `synchronization` objects).
+    /// Output options stored in the project take precedence over the ones in `additional_data`.
+    pub fn from_project(url: &str, additional_data: &str, default_suffix: &str, lens_profile_db: Arc<RwLock<LensProfileDatabase>>) -> Result<Self, HeadlessError> {
+        let data = core::filesystem::read(url).map_err(GyroflowCoreError::from)?;
+        let project: serde_json::Value = serde_json::from_slice(&data).map_err(GyroflowCoreError::from)?;
+        if project.get("videofile").and_then(|x| x.as_str()).unwrap_or_default().is_empty() {
+            return Err(HeadlessError::NotAProject(url.to_owned()));
+        }
+
+        if !lens_profile_db.read().loaded {
+            lens_profile_db.write().load_all();
+        }
+        let stab = StabilizationManager {
+            lens_profile_db,
+            ..Default::default()
+        };
+
+        let obj = stab.import_gyroflow_file(url, true, |_|(), Arc::new(AtomicBool::new(false)))?;
+
+        let additional_data: serde_json::Value = serde_json::from_str(additional_data).unwrap_or_default();
+
+        let mut render_options = RenderOptions::default();
+        let mut override_ext = None;
+        for out in [additional_data.get("output"), obj.get("output")].into_iter().flatten() {
+            render_options.update_from_json(out);
+            if let Some(ext) = out.get("output_extension").and_then(|x| x.as_str()) {
+                override_ext = Some(ext.to_owned());
+            }
+        }
+
+        let video_url = stab.input_file.read().url.clone();
+        let video_size = stab.params.read().video_size;
+        if render_options.output_width == 0 || render_options.output_height == 0 {
+            let output_dim = stab.lens.read().output_dimension.clone();
+            (render_options.output_width, render_options.output_height) = output_dim.map(|x| (x.w, x.h)).unwrap_or(video_size);
+        }
+        render_options.output_folder = get_output_folder(&video_url, &render_options.output_folder);
+        if render_options.output_filename.is_empty() {
+            render_options.output_filename = get_output_filename(&video_url, default_suffix, &render_options, override_ext.as_deref());
+        }
+        render_options.input_filename = core::filesystem::get_filename(&video_url);
+
+        if let Some(sync) = additional_data.get("synchronization") {
+            update_sync_settings(&stab, sync);
+        }
+        if let Some(sync) = obj.get("synchronization").and_then(|x| x.as_object()) {
+            if !sync.is_empty() {
+                update_sync_settings(&stab, &serde_json::Value::Object(sync.clone()));
+            }
+        }
+
+        stab.set_render_params(video_size, (render_options.output_width, render_options.output_height));
+
+        Ok(Self {
+            stab: Arc::new(stab),
+            render_options,
+            processing_resolution: 720,
+            block_doubtful_sync: false,
+        })
+    }
+
+    /// Applies a preset, either its JSON content or the path to the preset file
+    pub fn apply_preset(&self, preset: &str) -> Result<(), HeadlessError> {
+        let data = if preset.starts_with('{') { preset.to_owned() } else { std::fs::read_to_string(preset).map_err(GyroflowCoreError::from)? };
+        let mut is_preset = false;
+        self.stab.import_gyroflow_data(data.as_bytes(), true, None, |_|(), Arc::new(AtomicBool::new(false)), &mut is_preset)?;
+        Ok(())
+    }
+
+    pub fn output_url(&self) -> String {
+        core::filesystem::get_file_url(&self.render_options.output_folder, &self.render_options.output_filename, false)
+    }
+
+    pub fn output_exists(&self) -> bool {
+        core::filesystem::exists_in_folder(&self.render_options.output_folder, &self.render_options.output_filename.replace("_%05d", "_00001"))
+    }
+
+    /// Runs the autosync (if enabled in the sync settings) and renders the video.
+    /// `progress` receives `(progress, current_frame, total_frames, finished, is_conversion)`, same as `rendering::render`.
+    /// `processing` receives the autosync progress in the 0-1 range.
+    pub fn render<F, F2>(&self, progress: F, processing: F2, overwrite: bool, cancel_flag: Arc<AtomicBool>, pause_flag: Arc<AtomicBool>) -> Result<(), HeadlessError>
+        where F: Fn((f64, usize, usize, bool, bool)) + Send + Sync + Clone,
+              F2: Fn(f64) + Send + Sync + Clone + 'static
+    {
+        if !overwrite && self.output_exists() {
+            return Err(HeadlessError::FileExists(self.output_url()));
+        }
+
+        let stab = self.stab.clone();
+        let mut input_file = stab.input_file.read().clone();
+
+        do_autosync(stab.clone(), processing, |(msg, arg): (String, String)| {
+            ::log::warn!("Autosync: {}", msg.replace("%1", &arg));
+        }, self.processing_resolution, cancel_flag.clone());
+
+        if cancel_flag.load(SeqCst) { return Err(HeadlessError::Cancelled); }
+
+        let doubtful = stab.gyro.read().doubtful_offsets();
+        if doubtful > 0 {
+            ::log::warn!("Synchronization is doubtful at {doubtful} sync points");
+            if self.block_doubtful_sync { return Err(HeadlessError::DoubtfulSync(doubtful)); }
+        }
+
+        let total_frame_count = stab.params.read().frame_count;
+        let progress2 = progress.clone();
+        convert_r3d(&mut input_file, |(percent, frame)| {
+            progress2((percent * 0.98, frame, total_frame_count + 1, false, true));
+        }, cancel_flag.clone())?;
+
+        let encoder_initialized = |encoder_name: String| {
+            ::log::info!("Encoder initialized: {encoder_name}");
+        };
+        render_ranges(stab, progress, &input_file, &self.render_options, cancel_flag.clone(), pause_flag, encoder_initialized)?;
+
+        if cancel_flag.load(SeqCst) { return Err(HeadlessError::Cancelled); }
+        Ok(())
+    }
+}
+
+/// Renders the video, or each trim range separately if enabled in `render_options`.
+/// If rendering fails before the first frame, it's retried with different GPU decoders and then without GPU decoding.
+pub fn render_ranges<F, F2>(stab: Arc<StabilizationManager>, progress: F, input_file: &InputFile, render_options: &RenderOptions, cancel_flag: Arc<AtomicBool>, pause_flag: Arc<AtomicBool>, encoder_initialized: F2) -> Result<(), FFmpegError>
+    where F: Fn((f64, usize, usize, bool, bool)) + Send + Sync + Clone,
+          F2: Fn(String) + Send + Sync + Clone
+{
+    let rendered_frames = Arc::new(AtomicUsize::new(0));
+    let rendered_frames2 = rendered_frames.clone();
+    let progress = move |params: (f64, usize, usize, bool, bool)| {
+        rendered_frames2.store(params.1, SeqCst);
+        progress(params);
+    };
+
+    let num_ranges = stab.params.read().trim_ranges.len();
+    let ranges_to_render = if render_options.export_trims_separately && num_ranges > 0 {
+        (0..num_ranges).map(Some).collect::<Vec<_>>()
+    } else {
+        vec![None]
+    };
+    for range in ranges_to_render {
+        if cancel_flag.load(SeqCst) { break; }
+        let mut i = 0;
+        loop {
+            let result = rendering::render(stab.clone(), progress.clone(), input_file, render_options, i, range, cancel_flag.clone(), pause_flag.clone(), encoder_initialized.clone());
+            if let Err(e) = result {
+                if let FFmpegError::PixelFormatNotSupported(_) = e {
+                    return Err(e);
+                }
+                if rendered_frames.load(SeqCst) == 0 {
+                    if (0..4).contains(&i) {
+                        // Try 4 times with different GPU decoders
+                        i += 1;
+                        continue;
+                    }
+                    if (0..5).contains(&i) {
+                        // Try without GPU decoder
+                        i = -1;
+                        continue;
+                    }
+                }
+                return Err(e);
+            } else {
+                // Render ok
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Converts an R3D file to ProRes with REDline, using the R3D settings from the GUI, and points `input_file` to the converted file.
+/// Already converted files are used directly. Other files are left unchanged. `progress` receives `(progress, frame)` of the conversion.
+pub fn convert_r3d<F: FnMut((f64, usize))>(input_file: &mut InputFile, mut progress: F, cancel_flag: Arc<AtomicBool>) -> Result<(), HeadlessError> {
+    // Assumes regular filesystem
+    if !core::filesystem::get_filename(&input_file.url).to_ascii_lowercase().ends_with(".r3d") {
+        return Ok(());
+    }
+    let mov_url = core::filesystem::get_file_url(&core::filesystem::get_folder(&input_file.url), &core::filesystem::filename_with_extension(&core::filesystem::get_filename(&input_file.url), "mov"), false);
+    if core::filesystem::exists(&mov_url) {
+        input_file.url = mov_url;
+        return Ok(());
+    }
+    let in_file = input_file.url.clone();
+
+    let mut frame = 0;
+    let mut error = None;
+    let r3d_progress = |(percent, error_str, out_url): (f64, String, String)| {
+        if !error_str.is_empty() {
+            error = Some(error_str);
+        } else {
+            progress((percent, frame));
+            input_file.url = out_url;
+            frame += 1;
+        }
+    };
+    let format = crate::util::get_setting("r3dConvertFormat").parse::<i32>().unwrap_or(0);
+    let force_primary = crate::util::get_setting("r3dColorMode").parse::<i32>().unwrap_or(0);
+
+    let gamma_curves = [-1, 1, 2, 3, 4, 5, 6, 14, 15, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36, 37];
+    let color_spaces = [2, 0, 1, 14, 15, 5, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27];
+    let gamma = gamma_curves[crate::util::get_setting("r3dGammaCurve").parse::<usize>().unwrap_or(7)];
+    let space = color_spaces[crate::util::get_setting("r3dColorSpace").parse::<usize>().unwrap_or(0)];
+    let additional_params = crate::util::get_setting("r3dRedlineParams");
+    crate::external_sdk::r3d::REDSdk::convert_r3d(&in_file, format, force_primary > 0, gamma, space, &additional_params, r3d_progress, cancel_flag.clone());
+    if cancel_flag.load(SeqCst) {
+        std::thread::sleep(std::time::Duration::from_secs(2));
+        let _ = core::filesystem::remove_file(&mov_url);
+        return Err(HeadlessError::Cancelled);
+    }
+    match error {
+        Some(e) => Err(HeadlessError::Conversion(e)),
+        None => Ok(())
+    }
+}
+
+pub fn get_output_folder(input_url: &str, ui_output_folder: &str) -> String {
+    if !ui_output_folder.is_empty() {
+        return ui_output_folder.to_owned();
+    }
+    core::filesystem::get_folder(input_url)
+}
+pub fn get_output_filename(input_url: &str, suffix: &str, render_options: &RenderOptions, override_ext: Option<&str>) -> String {
+    let mut filename = core::filesystem::get_filename(input_url);
+
+    let mut ext = override_ext.unwrap_or(match render_options.codec.as_ref() {
+        "ProRes"        => ".mov",
+        "DNxHD"         => ".mov",
+        "CineForm"      => ".mov",
+        "EXR Sequence"  => "_%05d.exr",
+        "PNG Sequence"  => "_%05d.png",
+        _ => ".mp4"
+    });
+    if ext == ".mp4" && render_options.preserve_other_tracks {
+        ext = ".mov";
+    }
+    if let Some(pos) = filename.rfind('.') {
+        filename = filename[..pos].to_owned();
+    }
+
+    format!("{filename}{suffix}{ext}")
+}
+
+pub fn update_sync_settings(stab: &StabilizationManager, sync_options: &serde_json::Value) {
+    let mut sync_settings = stab.lens.read().sync_settings.clone().unwrap_or(sync_options.clone());
+    if sync_settings.is_object() && sync_options.is_object() {
+        crate::core::util::merge_json(&mut sync_settings, sync_options);
+    }
+    if sync_settings.is_object() && !sync_settings.as_object().unwrap().is_empty() {
+        stab.lens.write().sync_settings = Some(sync_settings);
+    }
+}
+
+pub fn do_autosync<F: Fn(f64) + Send + Sync + Clone + 'static, F2: Fn((String, String)) + Send + Sync + Clone + 'static>(stab: Arc<StabilizationManager>, processing_cb: F, err: F2, proc_height: i32, cancel_flag: Arc<AtomicBool>) {
+    let (url, duration_ms) = {
+        (stab.input_file.read().url.clone(), stab.params.read().duration_ms)
+    };
+
+    let (has_sync_points, has_accurate_timestamps) = {
+        let gyro = stab.gyro.read();
+        (!gyro.get_offsets().is_empty(), gyro.file_metadata.has_accurate_timestamps)
+    };
+    let fps = stab.params.read().fps;
+
+    let sync_settings = stab.lens.read().sync_settings.clone().unwrap_or_default();
+    if !has_sync_points && !has_accurate_timestamps && sync_settings.get("do_autosync").and_then(|v| v.as_bool()).unwrap_or_default() {
+        // ----------------------------------------------------------------------------
+        // --------------------------------- Autosync ---------------------------------
+        processing_cb(0.01);
+        use gyroflow_core::synchronization::AutosyncProcess;
+        use gyroflow_core::synchronization;
+        use crate::rendering::VideoProcessor;
+        use itertools::Either;
+
+        if let Ok(mut sync_params) = serde_json::from_value(sync_settings) as serde_json::Result<synchronization::SyncParams> {
+            if sync_params.max_sync_points > 0 {
+                let chunks = 1.0 / sync_params.max_sync_points as f64;
+                let start = chunks / 2.0;
+                let mut timestamps_fract: Vec<f64> = (0..sync_params.max_sync_points).map(|i| start + (i as f64 * chunks)).collect();
+
+                if !sync_params.custom_sync_pattern.is_null() {
+                    let v = resolve_syncpoint_pattern(&sync_params.custom_sync_pattern, duration_ms, fps);
+                    timestamps_fract = v.into_iter().filter(|v| *v <= duration_ms).map(|v| v / duration_ms).collect();
+                }
+
+                #[cfg(not(any(target_os = "ios", target_os = "android")))]
+                let _prevent_system_sleep = keep_awake::inhibit_system("Gyroflow", "Autosyncing");
+                #[cfg(any(target_os = "ios", target_os = "android"))]
+                let _prevent_system_sleep = keep_awake::inhibit_display("Gyroflow", "Autosyncing");
+
+                sync_params.initial_offset     *= 1000.0; // s to ms
+                sync_params.time_per_syncpoint *= 1000.0; // s to ms
+                sync_params.search_size        *= 1000.0; // s to ms
+
+                let every_nth_frame = sync_params.every_nth_frame.max(1);
+
+                let size = stab.params.read().video_size;
+
+                if let Ok(mut sync) = AutosyncProcess::from_manager(&stab, &timestamps_fract, sync_params, "synchronize".into(), cancel_flag.clone()) {
+                    let processing_cb2 = processing_cb.clone();
+                    sync.on_progress(move |percent, _ready, _total| {
+                        processing_cb2(percent);
+                    });
+                    let stab2 = stab.clone();
+                    sync.on_finished(move |arg| {
+                        if let Either::Left(offsets) = arg {
+                            let mut gyro = stab2.gyro.write();
+                            gyro.prevent_recompute = true;
+                            for x in offsets {
+                                ::log::info!("Setting offset at {:.4}: {:.4} (cost {:.4}, features: {:.1}, inliers: {:.2}, sharpness: {:.2})", x.0, x.1, x.2, x.3.features, x.3.inlier_ratio, x.3.peak_sharpness);
+                                if !x.3.trustworthy { ::log::warn!("Sync point at {:.4} is doubtful", x.0); }
+                                let new_ts = ((x.0 - x.1) * 1000.0) as i64;
+                                // Remove existing offsets within 100ms range
+                                gyro.remove_offsets_near(new_ts, 100.0);
+                                gyro.set_offset(new_ts, x.1);
+                                gyro.set_offset_quality(new_ts, x.3);
+                            }
+                            gyro.prevent_recompute = false;
+                            gyro.adjust_offsets();
+                            stab2.keyframes.write().update_gyro(&gyro);
+                        }
+                    });
+
+                    let (sw, sh) = ((720.0 * (size.0 as f64 / size.1 as f64)).round() as u32, 720);
+
+                    let gpu_decoding = *rendering::GPU_DECODING.read();
+
+                    let mut frame_no = 0;
+                    let mut abs_frame_no = 0;
+                    let sync = Arc::new(sync);
+
+                    let mut decoder_options = ffmpeg_next::Dictionary::new();
+                    if proc_height > 0 {
+                        decoder_options.set("scale", &format!("{}x{}", (proc_height * 16) / 9, proc_height));
+                    }
+                    ::log::debug!("Decoder options: {:?}", decoder_options);
+
+                    let fs_base = gyroflow_core::filesystem::get_engine_base();
+                    match VideoProcessor::from_file(&fs_base, &url, gpu_decoding, 0, Some(decoder_options)) {
+                        Ok(mut proc) => {
+                            let err2 = err.clone();
+                            let sync2 = sync.clone();
+                            proc.on_frame(move |timestamp_us, input_frame, _output_frame, converter, _rate_control| {
+                                if abs_frame_no % every_nth_frame == 0 {
+                                    match converter.scale(input_frame, ffmpeg_next::format::Pixel::GRAY8, sw, sh) {
+                                        Ok(small_frame) => {
+                                            let (width, height, stride, pixels) = (small_frame.plane_width(0), small_frame.plane_height(0), small_frame.stride(0), small_frame.data(0));
+
+                                            sync2.feed_frame(timestamp_us, frame_no, width, height, stride, pixels);
+                                        },
+                                        Err(e) => {
+                                            err2(("An error occured: %1".to_string(), e.to_string()))
+                                        }
+                                    }
+                                    frame_no += 1;
+                                }
+                                abs_frame_no += 1;
+                                Ok(())
+                            });
+                            if let Err(e) = proc.start_decoder_only(sync.get_ranges(), cancel_flag.clone()) {
+                                err(("An error occured: %1".to_string(), e.to_string()));
+                            }
+
+                            sync.finished_feeding_frames();
+                        }
+                        Err(error) => {
+                            err(("An error occured: %1".to_string(), error.to_string()));
+                        }
+                    };
+                } else {
+                    err(("An error occured: %1".to_string(), "Invalid parameters".to_string()));
+                }
+
+                stab.recompute_blocking();
+            }
+        }
+        processing_cb(1.0);
+        // --------------------------------- Autosync ---------------------------------
+        // ----------------------------------------------------------------------------
+    }
+}
+
+// Keep in sync with Synchronization.qml
+fn resolve_syncpoint_pattern(o: &serde_json::Value, duration: f64, fps: f64) -> Vec<f64> {
+    fn resolve_duration_to_ms(d: &serde_json::Value, fps: f64) -> Option<f64> {
+        if !d.is_number() && !d.is_string() { return None; }
+             if d.is_string() && d.as_str()?.ends_with("ms") { d.as_str()?.strip_suffix("ms")?.parse::<f64>().ok() }
+        else if d.is_string() && d.as_str()?.ends_with('s')  { d.as_str()?.strip_suffix('s')?.parse::<f64>().ok().map(|x| x * 1000.0) }
+        else if d.is_string() { d.as_str()?.parse::<f64>().ok().map(|x| (x / fps) * 1000.0) }
+        else { d.as_f64().map(|x| (x / fps) * 1000.0) }
+    }
+    fn resolve_item(x: &serde_json::Value, duration: f64, fps: f64) -> Vec<f64> {
+        if let Some(x) = x.as_object() {
+            let start = x.get("start").and_then(|y| resolve_duration_to_ms(y, fps)).unwrap_or_default();
+            let interval = x.get("interval").and_then(|y| resolve_duration_to_ms(y, fps)).unwrap_or(duration);
+            let gap = x.get("gap").and_then(|y| resolve_duration_to_ms(y, fps)).unwrap_or_default();
+            let mut out = Vec::new();
+            let mut i = start;
+            while i < duration {
+                out.push(i - gap / 2.0);
+                if gap > 0.0 {
+                    out.push(i + gap / 2.0);
+                }
+                i += interval;
+            }
+            out
+        } else {
+            Vec::new()
+        }
+    }
+
+    let mut timestamps = Vec::new();
+    if let Some(array) = o.as_array() {
+        for x in array {
+            timestamps.append(&mut resolve_item(x, duration, fps));
+        }
+    } else if o.is_object() {
+        timestamps.append(&mut resolve_item(o, duration, fps));
+    }
+    timestamps.sort_by(|a, b| a.total_cmp(b));
+
+    timestamps
+}
@@ -8,6 +8,8 @@ mod audio_resampler;
 pub mod ffmpeg_processor;
 pub mod ffmpeg_hw;
 pub mod render_queue;
+pub mod render_options;
+pub mod headless;
 pub mod mdk_processor;
 pub mod video_processor;
 pub mod zero_copy;
@@ -17,7 +19,7 @@ pub mod ffmpeg_android;
 
 pub use self::video_processor::VideoProcessor;
 pub use self::ffmpeg_processor::{ FfmpegProcessor, FFmpegError };
-use render_queue::RenderOptions;
+use render_options::RenderOptions;
 use crate::core::{ StabilizationManager, stabilization::* };
 use ffmpeg_next::{ format::Pixel, frame::Video, codec, Error, ffi };
 use std::cell::RefCell;
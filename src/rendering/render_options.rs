@@ -0,0 +1,114 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use crate::core;
+use regex::Regex;
+
+#[derive(Default, Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct RenderMetadata {
+    pub comment: String,
+}
+
+#[derive(Default, Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct RenderOptions {
+    pub codec: String,
+    pub codec_options: String,
+    pub output_folder: String,
+    pub output_filename: String,
+    pub output_width: usize,
+    pub output_height: usize,
+    pub input_filename: String,
+    pub bitrate: f64,
+    pub use_gpu: bool,
+    pub audio: bool,
+    pub pixel_format: String,
+
+    // Advanced
+    pub encoder_options: String,
+    pub metadata: RenderMetadata,
+    pub keyframe_distance: f64,
+    pub preserve_other_tracks: bool,
+    pub pad_with_black: bool,
+    pub export_trims_separately: bool,
+    pub audio_codec: String,
+    pub interpolation: String,
+}
+impl RenderOptions {
+    pub fn settings_string(&self, fps: f64) -> String {
+        let codec_info = match self.codec.as_ref() {
+            "H.264/AVC" | "H.265/HEVC" | "AV1" => format!("{} {:.0} Mbps", self.codec, self.bitrate),
+            "DNxHD" => self.codec_options.clone(),
+            "ProRes" => format!("{} {}", self.codec, self.codec_options),
+            _ => self.codec.clone()
+        };
+
+        format!("{}x{} {:.3}fps | {}", self.output_width, self.output_height, fps, codec_info)
+    }
+
+    pub fn get_encoder_options_dict(&self) -> ffmpeg_next::Dictionary {
+        let re = Regex::new(r#"-([^\s"]+)\s+("[^"]+"|[^\s"]+)"#).unwrap();
+
+        let mut options = ffmpeg_next::Dictionary::new();
+        for x in re.captures_iter(&self.encoder_options) {
+            if let Some(k) = x.get(1) {
+                if let Some(v) = x.get(2) {
+                    let k = k.as_str();
+                    let v = v.as_str().trim_matches('"');
+                    options.set(k, v);
+                }
+            }
+        }
+        options
+    }
+    pub fn get_metadata_dict(&self) -> ffmpeg_next::Dictionary {
+        let mut metadata = ffmpeg_next::Dictionary::new();
+        metadata.set("comment", format!("Original filename: {}\n{}", self.input_filename, self.metadata.comment).trim());
+        metadata
+    }
+    pub fn update_from_json(&mut self, obj: &serde_json::Value) {
+        if let serde_json::Value::Object(obj) = obj {
+            if let Some(v) = obj.get("codec")          .and_then(|x| x.as_str())  { self.codec = v.to_string(); }
+            if let Some(v) = obj.get("codec_options")  .and_then(|x| x.as_str())  { self.codec_options = v.to_string(); }
+            if let Some(v) = obj.get("output_width")   .and_then(|x| x.as_u64())  { self.output_width = v as usize; }
+            if let Some(v) = obj.get("output_height")  .and_then(|x| x.as_u64())  { self.output_height = v as usize; }
+            if let Some(v) = obj.get("bitrate")        .and_then(|x| x.as_f64())  { self.bitrate = v; }
+            if let Some(v) = obj.get("use_gpu")        .and_then(|x| x.as_bool()) { self.use_gpu = v; }
+            if let Some(v) = obj.get("audio")          .and_then(|x| x.as_bool()) { self.audio = v; }
+            if let Some(v) = obj.get("pixel_format")   .and_then(|x| x.as_str())  { self.pixel_format = v.to_string(); }
+
+            // Advanced
+            if let Some(v) = obj.get("encoder_options")        .and_then(|x| x.as_str())  { self.encoder_options = v.to_string(); }
+            if let Some(v) = obj.get("keyframe_distance")      .and_then(|x| x.as_f64())  { self.keyframe_distance = v; }
+            if let Some(v) = obj.get("preserve_other_tracks")  .and_then(|x| x.as_bool()) { self.preserve_other_tracks = v; }
+            if let Some(v) = obj.get("pad_with_black")         .and_then(|x| x.as_bool()) { self.pad_with_black = v; }
+            if let Some(v) = obj.get("export_trims_separately").and_then(|x| x.as_bool()) { self.export_trims_separately = v; }
+            if let Some(v) = obj.get("audio_codec")            .and_then(|x| x.as_str())  { self.audio_codec = v.to_string(); }
+            if let Some(v) = obj.get("interpolation")          .and_then(|x| x.as_str())  { self.interpolation = v.to_string(); }
+
+            if let Some(v) = obj.get("metadata").and_then(|x| x.as_object())  {
+                if let Some(s) = v.get("comment").and_then(|x| x.as_str()) { self.metadata.comment = s.to_string(); }
+            }
+
+            // Backwards compatibility
+            if let Some(v) = obj.get("output_path").and_then(|x| x.as_str()) {
+                let url = core::filesystem::path_to_url(v);
+                let folder = core::filesystem::get_folder(&url);
+                if !folder.is_empty() {
+                    self.output_folder = folder;
+                }
+                let filename = core::filesystem::get_filename(&url);
+                if !filename.is_empty() {
+                    self.output_filename = filename;
+                }
+            }
+            if let Some(v) = obj.get("output_folder").and_then(|x| x.as_str()).filter(|x| !x.is_empty()) {
+                self.output_folder = v.to_string();
+            }
+            if let Some(v) = obj.get("output_filename").and_then(|x| x.as_str()).filter(|x| !x.is_empty()) {
+                self.output_filename = v.to_string();
+            }
+        }
+    }
+}
@@ -5,11 +5,12 @@ use qmetaobject::*;
 
 use crate::{ core, rendering, util };
 use crate::core::StabilizationManager;
-use std::sync::{ Arc, atomic::{ AtomicBool, AtomicUsize, Ordering::SeqCst } };
+use std::sync::{ Arc, atomic::{ AtomicBool, Ordering::SeqCst } };
 use std::cell::RefCell;
 use std::collections::{ HashMap, HashSet };
 use parking_lot::RwLock;
-use regex::Regex;
+pub use super::render_options::{ RenderOptions, RenderMetadata };
+use super::headless::HeadlessError;
 
 #[derive(Default, Clone, SimpleListItem, Debug)]
 pub struct RenderQueueItem {
@@ -55,115 +56,6 @@ struct Job {
     stab: Arc<StabilizationManager>
 }
 
-#[derive(Default, Clone, Debug, serde::Serialize, serde::Deserialize)]
-#[serde(default)]
-pub struct RenderMetadata {
-    pub comment: String,
-}
-
-#[derive(Default, Clone, Debug, serde::Serialize, serde::Deserialize)]
-#[serde(default)]
-pub struct RenderOptions {
-    pub codec: String,
-    pub codec_options: String,
-    pub output_folder: String,
-    pub output_filename: String,
-    pub output_width: usize,
-    pub output_height: usize,
-    pub input_filename: String,
-    pub bitrate: f64,
-    pub use_gpu: bool,
-    pub audio: bool,
-    pub pixel_format: String,
-
-    // Advanced
-    pub encoder_options: String,
-    pub metadata: RenderMetadata,
-    pub keyframe_distance: f64,
-    pub preserve_other_tracks: bool,
-    pub pad_with_black: bool,
-    pub export_trims_separately: bool,
-    pub audio_codec: String,
-    pub interpolation: String,
-}
-impl RenderOptions {
-    pub fn settings_string(&self, fps: f64) -> String {
-        let codec_info = match self.codec.as_ref() {
-            "H.264/AVC" | "H.265/HEVC" | "AV1" => format!("{} {:.0} Mbps", self.codec, self.bitrate),
-            "DNxHD" => self.codec_options.clone(),
-            "ProRes" => format!("{} {}", self.codec, self.codec_options),
-            _ => self.codec.clone()
-        };
-
-        format!("{}x{} {:.3}fps | {}", self.output_width, self.output_height, fps, codec_info)
-    }
-
-    pub fn get_encoder_options_dict(&self) -> ffmpeg_next::Dictionary {
-        let re = Regex::new(r#"-([^\s"]+)\s+("[^"]+"|[^\s"]+)"#).unwrap();
-
-        let mut options = ffmpeg_next::Dictionary::new();
-        for x in re.captures_iter(&self.encoder_options) {
-            if let Some(k) = x.get(1) {
-                if let Some(v) = x.get(2) {
-                    let k = k.as_str();
-                    let v = v.as_str().trim_matches('"');
-                    options.set(k, v);
-                }
-            }
-        }
-        options
-    }
-    pub fn get_metadata_dict(&self) -> ffmpeg_next::Dictionary {
-        let mut metadata = ffmpeg_next::Dictionary::new();
-        metadata.set("comment", format!("Original filename: {}\n{}", self.input_filename, self.metadata.comment).trim());
-        metadata
-    }
-    pub fn update_from_json(&mut self, obj: &serde_json::Value) {
-        if let serde_json::Value::Object(obj) = obj {
-            if let Some(v) = obj.get("codec")          .and_then(|x| x.as_str())  { self.codec = v.to_string(); }
-            if let Some(v) = obj.get("codec_options")  .and_then(|x| x.as_str())  { self.codec_options = v.to_string(); }
-            if let Some(v) = obj.get("output_width")   .and_then(|x| x.as_u64())  { self.output_width = v as usize; }
-            if let Some(v) = obj.get("output_height")  .and_then(|x| x.as_u64())  { self.output_height = v as usize; }
-            if let Some(v) = obj.get("bitrate")        .and_then(|x| x.as_f64())  { self.bitrate = v; }
-            if let Some(v) = obj.get("use_gpu")        .and_then(|x| x.as_bool()) { self.use_gpu = v; }
-            if let Some(v) = obj.get("audio")          .and_then(|x| x.as_bool()) { self.audio = v; }
-            if let Some(v) = obj.get("pixel_format")   .and_then(|x| x.as_str())  { self.pixel_format = v.to_string(); }
-
-            // Advanced
-            if let Some(v) = obj.get("encoder_options")        .and_then(|x| x.as_str())  { self.encoder_options = v.to_string(); }
-            if let Some(v) = obj.get("keyframe_distance")      .and_then(|x| x.as_f64())  { self.keyframe_distance = v; }
-            if let Some(v) = obj.get("preserve_other_tracks")  .and_then(|x| x.as_bool()) { self.preserve_other_tracks = v; }
-            if let Some(v) = obj.get("pad_with_black")         .and_then(|x| x.as_bool()) { self.pad_with_black = v; }
-            if let Some(v) = obj.get("export_trims_separately").and_then(|x| x.as_bool()) { self.export_trims_separately = v; }
-            if let Some(v) = obj.get("audio_codec")            .and_then(|x| x.as_str())  { self.audio_codec = v.to_string(); }
-            if let Some(v) = obj.get("interpolation")          .and_then(|x| x.as_str())  { self.interpolation = v.to_string(); }
-
-            if let Some(v) = obj.get("metadata").and_then(|x| x.as_object())  {
-                if let Some(s) = v.get("comment").and_then(|x| x.as_str()) { self.metadata.comment = s.to_string(); }
-            }
-
-            // Backwards compatibility
-            if let Some(v) = obj.get("output_path").and_then(|x| x.as_str()) {
-                let url = core::filesystem::path_to_url(v);
-                let folder = core::filesystem::get_folder(&url);
-                if !folder.is_empty() {
-                    self.output_folder = folder;
-                }
-                let filename = core::filesystem::get_filename(&url);
-                if !filename.is_empty() {
-                    self.output_filename = filename;
-                }
-            }
-            if let Some(v) = obj.get("output_folder").and_then(|x| x.as_str()).filter(|x| !x.is_empty()) {
-                self.output_folder = v.to_string();
-            }
-            if let Some(v) = obj.get("output_filename").and_then(|x| x.as_str()).filter(|x| !x.is_empty()) {
-                self.output_filename = v.to_string();
-            }
-        }
-    }
-}
-
 #[derive(Default, QObject)]
 pub struct RenderQueue {
     base: qt_base_class!(trait QObject),
@@ -181,7 +73,7 @@ pub struct RenderQueue {
 
     render_job: qt_method!(fn(&mut self, job_id: u32)),
     cancel_job: qt_method!(fn(&self, job_id: u32)),
-    reset_job: qt_method!(fn(&self, job_id: u32)),
+    reset_job: qt_method!(fn(&mut self, job_id: u32)),
     get_gyroflow_data: qt_method!(fn(&self, job_id: u32) -> QString),
 
     add_file: qt_method!(fn(&mut self, url: String, gyro_url: String, additional_data: String) -> u32),
@@ -237,12 +129,15 @@ pub struct RenderQueue {
     parallel_renders: qt_property!(i32; WRITE set_parallel_renders),
     pub export_project: qt_property!(u32),
     pub overwrite_mode: qt_property!(u32),
+    /// Fail the render instead of only warning when the synchronization is doubtful. Never applies to project exports
+    pub block_doubtful_sync: qt_property!(bool),
 
     pub request_close: qt_signal!(),
 
     pub queue_finished: qt_signal!(),
 
     pub jobs_added: HashSet<u32>,
+    error_kinds: HashMap<u32, String>,
 
     paused_timestamp: Option<u64>,
     start_frame: u64,
@@ -328,6 +223,11 @@ impl RenderQueue {
         }
     }
 
+    /// Stable identifier of the last error of the job, eg. `file_exists` or `ffmpeg.EncoderNotFound`, see `HeadlessError::kind`
+    pub fn get_error_kind(&self, job_id: u32) -> String {
+        self.error_kinds.get(&job_id).cloned().unwrap_or_default()
+    }
+
     pub fn set_error_string(&mut self, job_id: u32, err: QString) {
         update_model!(self, job_id, itm {
             itm.error_string = err;
@@ -466,6 +366,7 @@ impl RenderQueue {
             self.queue_changed();
         }
         self.jobs.remove(&job_id);
+        self.error_kinds.remove(&job_id);
         self.update_queue_indices();
     }
     pub fn clear(&mut self) {
@@ -633,10 +534,11 @@ impl RenderQueue {
             job.cancel_flag.store(true, SeqCst);
         }
     }
-    pub fn reset_job(&self, job_id: u32) {
+    pub fn reset_job(&mut self, job_id: u32) {
         if let Some(job) = self.jobs.get(&job_id) {
             job.cancel_flag.store(true, SeqCst);
         }
+        self.error_kinds.remove(&job_id);
         update_model!(self, job_id, itm {
             itm.error_string = QString::default();
             itm.current_frame = 0;
@@ -743,11 +645,7 @@ impl RenderQueue {
 
             rendering::clear_log();
 
-            let rendered_frames = Arc::new(AtomicUsize::new(0));
-            let rendered_frames2 = rendered_frames.clone();
             let progress = util::qt_queued_callback_mut(self, move |this, (progress, current_frame, total_frames, finished, is_conversion): (f64, usize, usize, bool, bool)| {
-                rendered_frames2.store(current_frame, SeqCst);
-
                 let mut start_time = 0;
 
                 update_model!(this, job_id, itm {
@@ -806,7 +704,7 @@ impl RenderQueue {
                 this.encoder_initialized(job_id, encoder_name);
             });
 
-            let err = util::qt_queued_callback_mut(self, move |this, (msg, mut arg): (String, String)| {
+            let err = util::qt_queued_callback_mut(self, move |this, (kind, msg, mut arg): (String, String, String)| {
                 arg.push_str("\n\n");
                 arg.push_str(&rendering::get_log());
 
@@ -814,6 +712,7 @@ impl RenderQueue {
                     itm.error_string = QString::from(arg.clone());
                     itm.status = JobStatus::Error;
                 });
+                this.error_kinds.insert(job_id, kind);
 
                 this.error(job_id, QString::from(msg), QString::from(arg), QString::default());
                 this.render_progress(job_id, 1.0, 0, 0, true, 0.0, false);
@@ -857,7 +756,6 @@ impl RenderQueue {
             let total_frame_count = params.frame_count;
             drop(params);
             let mut input_file = stab.input_file.read().clone();
-            let filename = core::filesystem::get_filename(&input_file.url);
             let render_options = job.render_options.clone();
 
             progress((0.0, 0, (total_frame_count as f64 * trim_ratio).round() as usize, false, false));
@@ -866,13 +764,24 @@ impl RenderQueue {
             let cancel_flag = job.cancel_flag.clone();
             let pause_flag = self.pause_flag.clone();
             let export_project = self.export_project;
+            let block_doubtful_sync = self.block_doubtful_sync;
             let default_suffix = self.default_suffix.to_string();
             let mut additional_data = job.additional_data.clone();
             let proc_height = self.processing_resolution;
             let err2 = err.clone();
+            let sync_err = move |(msg, arg): (String, String)| err2(("sync".to_string(), msg, arg));
 
             core::run_threaded(move || {
-                Self::do_autosync(stab.clone(), processing, err2, proc_height);
+                rendering::headless::do_autosync(stab.clone(), processing, sync_err, proc_height, cancel_flag.clone());
+
+                let doubtful = stab.gyro.read().doubtful_offsets();
+                if doubtful > 0 {
+                    ::log::warn!("Synchronization is doubtful at {doubtful} sync points");
+                    if block_doubtful_sync && export_project == 0 {
+                        err(("doubtful_sync".to_string(), "Synchronization is doubtful at %1 sync points. Check them before rendering.".to_string(), doubtful.to_string()));
+                        return;
+                    }
+                }
 
                 if export_project > 0 {
                     if let Ok(serde_json::Value::Object(mut obj)) = serde_json::from_str(&additional_data) as serde_json::Result<serde_json::Value> {
@@ -893,7 +802,8 @@ impl RenderQueue {
                     };
                     if export_project != 4 {
                         if let Err(e) = result {
-                            err((e.to_string(), String::new()));
+                            let e = HeadlessError::from(e);
+                            err((e.kind(), e.to_string(), String::new()));
                         } else {
                             progress((1.0, 1, 1, true, false));
                         }
@@ -901,120 +811,48 @@ impl RenderQueue {
                     }
                 }
 
-                // Assumes regular filesystem
-                if filename.to_ascii_lowercase().ends_with(".r3d") {
-                    let mov_url = core::filesystem::get_file_url(&core::filesystem::get_folder(&input_file.url), &core::filesystem::filename_with_extension(&core::filesystem::get_filename(&input_file.url), "mov"), false);
-                    if core::filesystem::exists(&mov_url) {
-                        input_file.url = mov_url.clone();
-                    } else {
-                        let in_file = input_file.url.clone();
-
-                        let mut frame = 0;
-                        let r3d_progress = |(percent, error_str, out_url): (f64, String, String)| {
-                            if !error_str.is_empty() {
-                                err(("An error occured: %1".to_string(), error_str));
-                            } else {
-                                progress((percent * 0.98, frame, total_frame_count + 1, false, true));
-                                input_file.url = out_url;
-                                frame += 1;
-                            }
-                        };
-                        let format = crate::util::get_setting("r3dConvertFormat").parse::<i32>().unwrap_or(0);
-                        let force_primary = crate::util::get_setting("r3dColorMode").parse::<i32>().unwrap_or(0);
-
-                        let gamma_curves = [-1, 1, 2, 3, 4, 5, 6, 14, 15, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36, 37];
-                        let color_spaces = [2, 0, 1, 14, 15, 5, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27];
-                        let gamma = gamma_curves[crate::util::get_setting("r3dGammaCurve").parse::<usize>().unwrap_or(7)];
-                        let space = color_spaces[crate::util::get_setting("r3dColorSpace").parse::<usize>().unwrap_or(0)];
-                        let additional_params = crate::util::get_setting("r3dRedlineParams");
-                        crate::external_sdk::r3d::REDSdk::convert_r3d(&in_file, format, force_primary > 0, gamma, space, &additional_params, r3d_progress, cancel_flag.clone());
-                        if cancel_flag.load(SeqCst) {
-                            std::thread::sleep(std::time::Duration::from_secs(2));
-                            let _ = core::filesystem::remove_file(&mov_url);
-                            err(("Conversion cancelled%1".to_string(), "".to_string()));
-                            return;
-                        }
+                let progress2 = progress.clone();
+                let r3d_progress = |(percent, frame): (f64, usize)| {
+                    progress2((percent * 0.98, frame, total_frame_count + 1, false, true));
+                };
+                match rendering::headless::convert_r3d(&mut input_file, r3d_progress, cancel_flag.clone()) {
+                    Err(HeadlessError::Cancelled) => {
+                        err((HeadlessError::Cancelled.kind(), "Conversion cancelled%1".to_string(), "".to_string()));
+                        return;
                     }
+                    Err(e) => {
+                        err((e.kind(), "An error occured: %1".to_string(), e.to_string()));
+                        return;
+                    }
+                    Ok(()) => { }
                 }
 
-                let num_ranges = stab.params.read().trim_ranges.len();
-                let ranges_to_render = if render_options.export_trims_separately && num_ranges > 0 {
-                    (0..num_ranges).map(Some).collect::<Vec<_>>()
-                } else {
-                    vec![None]
-                };
-                'ranges: for range in ranges_to_render {
-                    if cancel_flag.load(SeqCst) { break; }
-                    let mut i = 0;
-                    loop {
-                        let result = rendering::render(stab.clone(), progress.clone(), &input_file, &render_options, i, range, cancel_flag.clone(), pause_flag.clone(), encoder_initialized.clone());
-                        if let Err(e) = result {
-                            if let rendering::FFmpegError::PixelFormatNotSupported((fmt, supported)) = e {
-                                convert_format((format!("{:?}", fmt), supported.into_iter().map(|v| format!("{:?}", v)).collect::<Vec<String>>().join(",")));
-                                break 'ranges;
-                            }
-                            if rendered_frames.load(SeqCst) == 0 {
-                                if (0..4).contains(&i) {
-                                    // Try 4 times with different GPU decoders
-                                    i += 1;
-                                    continue;
-                                }
-                                if (0..5).contains(&i) {
-                                    // Try without GPU decoder
-                                    i = -1;
-                                    continue;
-                                }
-                            }
-                            err(("An error occured: %1".to_string(), e.to_string()));
-                            break 'ranges;
-                        } else {
-                            // Render ok
-                            break;
-                        }
+                match rendering::headless::render_ranges(stab, progress, &input_file, &render_options, cancel_flag, pause_flag, encoder_initialized) {
+                    Err(rendering::FFmpegError::PixelFormatNotSupported((fmt, supported))) => {
+                        convert_format((format!("{:?}", fmt), supported.into_iter().map(|v| format!("{:?}", v)).collect::<Vec<String>>().join(",")));
                     }
+                    Err(e) => {
+                        let e = HeadlessError::from(e);
+                        err((e.kind(), "An error occured: %1".to_string(), e.to_string()));
+                    }
+                    Ok(()) => { }
                 }
             });
         }
     }
 
-    fn get_output_folder(input_url: &str, ui_output_folder: &str) -> String {
-        if !ui_output_folder.is_empty() {
-            return ui_output_folder.to_owned();
-        }
-        core::filesystem::get_folder(input_url)
-    }
-    fn get_output_filename(input_url: &str, suffix: &str, render_options: &RenderOptions, override_ext: Option<&str>) -> String {
-        let mut filename = core::filesystem::get_filename(input_url);
-
-        let mut ext = override_ext.unwrap_or(match render_options.codec.as_ref() {
-            "ProRes"        => ".mov",
-            "DNxHD"         => ".mov",
-            "CineForm"      => ".mov",
-            "EXR Sequence"  => "_%05d.exr",
-            "PNG Sequence"  => "_%05d.png",
-            _ => ".mp4"
-        });
-        if ext == ".mp4" && render_options.preserve_other_tracks {
-            ext = ".mov";
-        }
-        if let Some(pos) = filename.rfind('.') {
-            filename = filename[..pos].to_owned();
-        }
-
-        format!("{filename}{suffix}{ext}")
-    }
-
     pub fn add_file(&mut self, url: String, gyro_url: String, additional_data: String) -> u32 {
         let job_id = fastrand::u32(1..);
 
         let is_gf_data = url.starts_with('{');
 
-        let err = util::qt_queued_callback_mut(self, move |this, (msg, arg): (String, String)| {
+        let err = util::qt_queued_callback_mut(self, move |this, (kind, msg, arg): (String, String, String)| {
             ::log::warn!("[add_file]: {}", arg);
             update_model!(this, job_id, itm {
                 itm.error_string = QString::from(arg.clone());
                 itm.status = JobStatus::Error;
             });
+            this.error_kinds.insert(job_id, kind);
             this.error(job_id, QString::from(msg), QString::from(arg), QString::default());
         });
         let processing_done = util::qt_queued_callback_mut(self, move |this, _: ()| {
@@ -1025,6 +863,7 @@ impl RenderQueue {
                         itm.error_string = msg.clone();
                         itm.status = JobStatus::Error;
                     });
+                    this.error_kinds.insert(job_id, HeadlessError::FileExists(String::new()).kind());
                     this.error(job_id, msg, QString::default(), QString::default());
                 }
             }
@@ -1050,10 +889,10 @@ impl RenderQueue {
                 let override_ext = out.get("output_extension").and_then(|x| x.as_str()).map(|x| x.to_owned());
                 if let Ok(mut render_options) = serde_json::from_value(out.clone()) as serde_json::Result<RenderOptions> {
                     render_options.update_from_json(out);
-                    let (smoothing_name, smoothing_params) = {
+                    let (smoothing_id, smoothing_params) = {
                         let smoothing_lock = stabilizer.smoothing.read();
                         let smoothing = smoothing_lock.current();
-                        (smoothing.get_name(), smoothing.get_parameters_json())
+                        (smoothing.get_id(), smoothing.get_parameters_json())
                     };
                     let params = stabilizer.params.read();
 
@@ -1080,14 +919,8 @@ impl RenderQueue {
                     };
 
                     {
-                        let method_idx = stab.get_smoothing_algs()
-                            .iter().enumerate()
-                            .find(|(_, m)| smoothing_name == m.as_str())
-                            .map(|(idx, _)| idx)
-                            .unwrap_or_default();
-
                         let mut smoothing = stab.smoothing.write();
-                        smoothing.set_current(method_idx);
+                        smoothing.set_current_id(&smoothing_id);
 
                         for param in smoothing_params.as_array().unwrap() {
                             (|| -> Option<()> {
@@ -1173,21 +1006,23 @@ impl RenderQueue {
                                         };
 
                                         if let Err(e) = fetch_thumb(out, ratio) {
-                                            err(("An error occured: %1".to_string(), e.to_string()));
+                                            let e = HeadlessError::from(e);
+                                            err((e.kind(), "An error occured: %1".to_string(), e.to_string()));
                                         }
                                     }
 
-                                    Self::update_sync_settings(&stab, &sync_options);
+                                    rendering::headless::update_sync_settings(&stab, &sync_options);
                                     if let Some(sync) = obj.get("synchronization").and_then(|x| x.as_object()) {
                                         if !sync.is_empty() {
-                                            Self::update_sync_settings(&stab, &serde_json::Value::Object(sync.clone()));
+                                            rendering::headless::update_sync_settings(&stab, &serde_json::Value::Object(sync.clone()));
                                         }
                                     }
 
                                     processing_done(());
                                 },
                                 Err(e) => {
-                                    err(("An error occured: %1".to_string(), format!("Error loading {}: {:?}", url, e)));
+                                    let msg = format!("Error loading {}: {:?}", url, e);
+                                    err((HeadlessError::from(e).kind(), "An error occured: %1".to_string(), msg));
                                 }
                             }
                         } else if let Ok(info) = rendering::VideoProcessor::get_video_info(&url) {
@@ -1200,8 +1035,8 @@ impl RenderQueue {
                             if !has_output_height {
                                 render_options.output_height = info.height as usize;
                             }
-                            render_options.output_folder = Self::get_output_folder(&url, &render_options.output_folder);
-                            render_options.output_filename = Self::get_output_filename(&url, &suffix, &render_options, override_ext.as_deref());
+                            render_options.output_folder = rendering::headless::get_output_folder(&url, &render_options.output_folder);
+                            render_options.output_filename = rendering::headless::get_output_filename(&url, &suffix, &render_options, override_ext.as_deref());
 
                             let ratio = info.width as f64 / info.height as f64;
 
@@ -1232,7 +1067,8 @@ impl RenderQueue {
                                                 }
                                             }
                                             Err(e) => {
-                                                err(("An error occured: %1".to_string(), e.to_string()));
+                                                let e = HeadlessError::from(e);
+                                                err((e.kind(), "An error occured: %1".to_string(), e.to_string()));
                                                 return;
                                             }
                                         }
@@ -1256,7 +1092,7 @@ impl RenderQueue {
 
                                 loaded(render_options);
 
-                                Self::update_sync_settings(&stab, &sync_options);
+                                rendering::headless::update_sync_settings(&stab, &sync_options);
 
                                 let default_preset = gyroflow_core::lens_profile_database::LensProfileDatabase::get_path().join("default.gyroflow");
                                 if let Ok(data) = std::fs::read_to_string(default_preset) {
@@ -1265,13 +1101,14 @@ impl RenderQueue {
                                 }
 
                                 if let Err(e) = fetch_thumb(&url, ratio) {
-                                    err(("An error occured: %1".to_string(), e.to_string()));
+                                    let e = HeadlessError::from(e);
+                                    err((e.kind(), "An error occured: %1".to_string(), e.to_string()));
                                 }
 
                                 processing_done(());
                             }
                         } else {
-                            err(("An error occured: %1".to_string(), "Unable to read the video file.".to_string()));
+                            err(("invalid_input".to_string(), "An error occured: %1".to_string(), "Unable to read the video file.".to_string()));
                         }
                     });
                 }
@@ -1282,134 +1119,6 @@ impl RenderQueue {
         job_id
     }
 
-    fn do_autosync<F: Fn(f64) + Send + Sync + Clone + 'static, F2: Fn((String, String)) + Send + Sync + Clone + 'static>(stab: Arc<StabilizationManager>, processing_cb: F, err: F2, proc_height: i32) {
-        let (url, duration_ms) = {
-            (stab.input_file.read().url.clone(), stab.params.read().duration_ms)
-        };
-
-        let (has_sync_points, has_accurate_timestamps) = {
-            let gyro = stab.gyro.read();
-            (!gyro.get_offsets().is_empty(), gyro.file_metadata.has_accurate_timestamps)
-        };
-        let fps = stab.params.read().fps;
-
-        let sync_settings = stab.lens.read().sync_settings.clone().unwrap_or_default();
-        if !has_sync_points && !has_accurate_timestamps && sync_settings.get("do_autosync").and_then(|v| v.as_bool()).unwrap_or_default() {
-            // ----------------------------------------------------------------------------
-            // --------------------------------- Autosync ---------------------------------
-            processing_cb(0.01);
-            use gyroflow_core::synchronization::AutosyncProcess;
-            use gyroflow_core::synchronization;
-            use crate::rendering::VideoProcessor;
-            use itertools::Either;
-
-            if let Ok(mut sync_params) = serde_json::from_value(sync_settings) as serde_json::Result<synchronization::SyncParams> {
-                if sync_params.max_sync_points > 0 {
-                    let chunks = 1.0 / sync_params.max_sync_points as f64;
-                    let start = chunks / 2.0;
-                    let mut timestamps_fract: Vec<f64> = (0..sync_params.max_sync_points).map(|i| start + (i as f64 * chunks)).collect();
-
-                    if !sync_params.custom_sync_pattern.is_null() {
-                        let v = Self::resolve_syncpoint_pattern(&sync_params.custom_sync_pattern, duration_ms, fps);
-                        timestamps_fract = v.into_iter().filter(|v| *v <= duration_ms).map(|v| v / duration_ms).collect();
-                    }
-
-                    #[cfg(not(any(target_os = "ios", target_os = "android")))]
-                    let _prevent_system_sleep = keep_awake::inhibit_system("Gyroflow", "Autosyncing");
-                    #[cfg(any(target_os = "ios", target_os = "android"))]
-                    let _prevent_system_sleep = keep_awake::inhibit_display("Gyroflow", "Autosyncing");
-
-                    let cancel_flag = Arc::new(AtomicBool::new(false));
-                    sync_params.initial_offset     *= 1000.0; // s to ms
-                    sync_params.time_per_syncpoint *= 1000.0; // s to ms
-                    sync_params.search_size        *= 1000.0; // s to ms
-
-                    let every_nth_frame = sync_params.every_nth_frame.max(1);
-
-                    let size = stab.params.read().video_size;
-
-                    if let Ok(mut sync) = AutosyncProcess::from_manager(&stab, &timestamps_fract, sync_params, "synchronize".into(), cancel_flag.clone()) {
-                        let processing_cb2 = processing_cb.clone();
-                        sync.on_progress(move |percent, _ready, _total| {
-                            processing_cb2(percent);
-                        });
-                        let stab2 = stab.clone();
-                        sync.on_finished(move |arg| {
-                            if let Either::Left(offsets) = arg {
-                                let mut gyro = stab2.gyro.write();
-                                gyro.prevent_recompute = true;
-                                for x in offsets {
-                                    ::log::info!("Setting offset at {:.4}: {:.4} (cost {:.4})", x.0, x.1, x.2);
-                                    let new_ts = ((x.0 - x.1) * 1000.0) as i64;
-                                    // Remove existing offsets within 100ms range
-                                    gyro.remove_offsets_near(new_ts, 100.0);
-                                    gyro.set_offset(new_ts, x.1);
-                                }
-                                gyro.prevent_recompute = false;
-                                gyro.adjust_offsets();
-                                stab2.keyframes.write().update_gyro(&gyro);
-                            }
-                        });
-
-                        let (sw, sh) = ((720.0 * (size.0 as f64 / size.1 as f64)).round() as u32, 720);
-
-                        let gpu_decoding = *rendering::GPU_DECODING.read();
-
-                        let mut frame_no = 0;
-                        let mut abs_frame_no = 0;
-                        let sync = Arc::new(sync);
-
-                        let mut decoder_options = ffmpeg_next::Dictionary::new();
-                        if proc_height > 0 {
-                            decoder_options.set("scale", &format!("{}x{}", (proc_height * 16) / 9, proc_height));
-                        }
-                        ::log::debug!("Decoder options: {:?}", decoder_options);
-
-                        let fs_base = gyroflow_core::filesystem::get_engine_base();
-                        match VideoProcessor::from_file(&fs_base, &url, gpu_decoding, 0, Some(decoder_options)) {
-                            Ok(mut proc) => {
-                                let err2 = err.clone();
-                                let sync2 = sync.clone();
-                                proc.on_frame(move |timestamp_us, input_frame, _output_frame, converter, _rate_control| {
-                                    if abs_frame_no % every_nth_frame == 0 {
-                                        match converter.scale(input_frame, ffmpeg_next::format::Pixel::GRAY8, sw, sh) {
-                                            Ok(small_frame) => {
-                                                let (width, height, stride, pixels) = (small_frame.plane_width(0), small_frame.plane_height(0), small_frame.stride(0), small_frame.data(0));
-
-                                                sync2.feed_frame(timestamp_us, frame_no, width, height, stride, pixels);
-                                            },
-                                            Err(e) => {
-                                                err2(("An error occured: %1".to_string(), e.to_string()))
-                                            }
-                                        }
-                                        frame_no += 1;
-                                    }
-                                    abs_frame_no += 1;
-                                    Ok(())
-                                });
-                                if let Err(e) = proc.start_decoder_only(sync.get_ranges(), cancel_flag) {
-                                    err(("An error occured: %1".to_string(), e.to_string()));
-                                }
-
-                                sync.finished_feeding_frames();
-                            }
-                            Err(error) => {
-                                err(("An error occured: %1".to_string(), error.to_string()));
-                            }
-                        };
-                    } else {
-                        err(("An error occured: %1".to_string(), "Invalid parameters".to_string()));
-                    }
-
-                    stab.recompute_blocking();
-                }
-            }
-            processing_cb(1.0);
-            // --------------------------------- Autosync ---------------------------------
-            // ----------------------------------------------------------------------------
-        }
-    }
-
     pub fn apply_to_all(&mut self, data: String, additional_data: String, to_job_id: u32) {
         ::log::debug!("Applying preset {}", &data);
         let data_parsed: serde_json::Result<serde_json::Value> = serde_json::from_str(&data);
@@ -1423,6 +1132,7 @@ impl RenderQueue {
             this.processing_done(job_id, true);
         });
         let err = util::qt_queued_callback_mut(self, move |this, (job_id, msg): (u32, String)| {
+            this.error_kinds.insert(job_id, HeadlessError::FileExists(String::new()).kind());
             this.error(job_id, QString::from(msg), QString::default(), QString::default());
         });
         ::log::debug!("new_output_options: {:?}", &new_output_options);
@@ -1450,8 +1160,8 @@ impl RenderQueue {
                     if let Some(ref new_output_options) = new_output_options {
                         let override_ext = new_output_options.get("output_extension").and_then(|x| x.as_str());
                         job.render_options.update_from_json(new_output_options);
-                        job.render_options.output_folder = Self::get_output_folder(&itm.input_file.to_string(), &job.render_options.output_folder);
-                        job.render_options.output_filename = Self::get_output_filename(&itm.input_file.to_string(), &self.default_suffix.to_string(), &job.render_options, override_ext);
+                        job.render_options.output_folder = rendering::headless::get_output_folder(&itm.input_file.to_string(), &job.render_options.output_folder);
+                        job.render_options.output_filename = rendering::headless::get_output_filename(&itm.input_file.to_string(), &self.default_suffix.to_string(), &job.render_options, override_ext);
                         itm.export_settings = QString::from(job.render_options.settings_string(job.stab.params.read().fps));
                         itm.output_filename = QString::from(job.render_options.output_filename.as_str());
                         itm.output_folder   = QString::from(job.render_options.output_folder.as_str());
@@ -1470,7 +1180,7 @@ impl RenderQueue {
                         ::log::error!("Failed to update queue stab data: {:?}", e);
                     }
 
-                    Self::update_sync_settings(&stab, &sync_options);
+                    rendering::headless::update_sync_settings(&stab, &sync_options);
                     processing_done(job_id);
 
                     q.change_line(job.queue_index, itm);
@@ -1518,56 +1228,4 @@ impl RenderQueue {
         }
         0
     }
-
-    // Keep in sync with Synchronization.qml
-    fn resolve_syncpoint_pattern(o: &serde_json::Value, duration: f64, fps: f64) -> Vec<f64> {
-        fn resolve_duration_to_ms(d: &serde_json::Value, fps: f64) -> Option<f64> {
-            if !d.is_number() && !d.is_string() { return None; }
-                 if d.is_string() && d.as_str()?.ends_with("ms") { d.as_str()?.strip_suffix("ms")?.parse::<f64>().ok() }
-            else if d.is_string() && d.as_str()?.ends_with('s')  { d.as_str()?.strip_suffix('s')?.parse::<f64>().ok().map(|x| x * 1000.0) }
-            else if d.is_string() { d.as_str()?.parse::<f64>().ok().map(|x| (x / fps) * 1000.0) }
-            else { d.as_f64().map(|x| (x / fps) * 1000.0) }
-        }
-        fn resolve_item(x: &serde_json::Value, duration: f64, fps: f64) -> Vec<f64> {
-            if let Some(x) = x.as_object() {
-                let start = x.get("start").and_then(|y| resolve_duration_to_ms(y, fps)).unwrap_or_default();
-                let interval = x.get("interval").and_then(|y| resolve_duration_to_ms(y, fps)).unwrap_or(duration);
-                let gap = x.get("gap").and_then(|y| resolve_duration_to_ms(y, fps)).unwrap_or_default();
-                let mut out = Vec::new();
-                let mut i = start;
-                while i < duration {
-                    out.push(i - gap / 2.0);
-                    if gap > 0.0 {
-                        out.push(i + gap / 2.0);
-                    }
-                    i += interval;
-                }
-                out
-            } else {
-                Vec::new()
-            }
-        }
-
-        let mut timestamps = Vec::new();
-        if let Some(array) = o.as_array() {
-            for x in array {
-                timestamps.append(&mut resolve_item(x, duration, fps));
-            }
-        } else if o.is_object() {
-            timestamps.append(&mut resolve_item(o, duration, fps));
-        }
-        timestamps.sort_by(|a, b| a.total_cmp(b));
-
-        timestamps
-    }
-
-    fn update_sync_settings(stab: &StabilizationManager, sync_options: &serde_json::Value) {
-        let mut sync_settings = stab.lens.read().sync_settings.clone().unwrap_or(sync_options.clone());
-        if sync_settings.is_object() && sync_options.is_object() {
-            crate::core::util::merge_json(&mut sync_settings, sync_options);
-        }
-        if sync_settings.is_object() && !sync_settings.as_object().unwrap().is_empty() {
-            stab.lens.write().sync_settings = Some(sync_settings);
-        }
-    }
 }
@@ -50,6 +50,19 @@ Window {
     title: qsTr("Lens calibrator");
 
     Component.onCompleted: {
+        // Warnings of the calibration report
+        QT_TR_NOOP("Only %1 frames were used for the calibration, at least %2 are recommended");
+        QT_TR_NOOP("The calibration target covers only %1% of the image");
+        QT_TR_NOOP("The top left corner of the image isn't covered");
+        QT_TR_NOOP("The top right corner of the image isn't covered");
+        QT_TR_NOOP("The bottom left corner of the image isn't covered");
+        QT_TR_NOOP("The bottom right corner of the image isn't covered");
+        QT_TR_NOOP("The top edge of the image is poorly covered");
+        QT_TR_NOOP("The bottom edge of the image is poorly covered");
+        QT_TR_NOOP("The left edge of the image is poorly covered");
+        QT_TR_NOOP("The right edge of the image is poorly covered");
+        QT_TR_NOOP("Frame %1 has a high reprojection error (%2 px)");
+
         ui_tools.set_icon(calibrator_window);
         if (!isMobile) {
             Qt.callLater(() => {
@@ -88,8 +101,19 @@ Window {
                 Qt.callLater(controller.recompute_threaded);
                 let model = [];
                 model[QT_TRANSLATE_NOOP("TableList", "Reprojection error")] = rms == 0? "---" : rms.toLocaleString(Qt.locale(), "f", 5);
+                const models = controller.get_calibration_models();
+                if (models.length > 1) {
+                    model[QT_TRANSLATE_NOOP("TableList", "Error per model")] = models.map(x => x.name + ": " + x.rms.toLocaleString(Qt.locale(), "f", 3)).join("<br>");
+                }
                 model[QT_TRANSLATE_NOOP("TableList", "Good frames")] = good;
                 model[QT_TRANSLATE_NOOP("TableList", "Average pattern sharpness")] = sharpness.toLocaleString(Qt.locale(), "f", 2) + " px";
+                const report = rms > 0 && rms < 100? controller.get_calibration_report() : ({});
+                if (report.frames) {
+                    model[QT_TRANSLATE_NOOP("TableList", "Image coverage")] = (report.coverage_ratio * 100).toFixed(0) + "%";
+                    if (report.warnings.length > 0) {
+                        model[QT_TRANSLATE_NOOP("TableList", "Warnings")] = report.warnings.map(x => x.args.reduce((text, arg) => text.arg(arg), qsTr(x.text))).join("<br>");
+                    }
+                }
                 lensCalib.infoList.model = model;
                 if (rms > 5) {
                     window.play_sound("error");
@@ -32,7 +32,7 @@ Modal {
             "Integration method": ["integration_method"],
         },
         "Trim range": ["trim_ranges_ms"],
-        "Offsets":    ["offsets"],
+        "Offsets":    ["offsets", "offsets_quality"],
         "Keyframes":  ["keyframes"]
     },
     { // Right column
@@ -66,7 +66,7 @@ Modal {
         }
     }];
 
-    property var defaultOff: ["trim_ranges_ms", "offsets", "video_infofps_scale", "video_inforotation", "synchronizationdo_autosync"];
+    property var defaultOff: ["trim_ranges_ms", "offsets", "offsets_quality", "video_infofps_scale", "video_inforotation", "synchronizationdo_autosync"];
 
     text: isPreset? qsTr("Select settings you want to include in the preset")
                   : qsTr("Select settings you want to apply to all items in the render queue");
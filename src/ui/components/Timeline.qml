@@ -645,6 +645,23 @@ Item {
                         ]);
                     }
                 }
+                Action {
+                    iconName: "lens";
+                    text: qsTr("Estimate lens profile here");
+                    onTriggered: {
+                        const pos = root.position; // (root.mapFromVisibleArea(timelineContextMenu.pressedX / ma.width));
+
+                        const text = qsTr("Your video needs to be already synced properly and you should use this function\non a part of your video where the camera rotates a lot (ideally around all axes) and doesn't move.\n\n" +
+                                        "The lens profile is estimated from the video motion and is less accurate than a calibration with a chessboard.\n" +
+                                        "Are you sure you want to continue?");
+                        messageBox(Modal.Warning, text, [
+                            { text: qsTr("Yes"), clicked: function() {
+                                controller.start_autosync(pos.toString(), window.sync.getSettingsJson(), "estimate_lens");
+                            }},
+                            { text: qsTr("No"), accent: true },
+                        ]);
+                    }
+                }
                 Action {
                     iconName: "bias";
                     text: qsTr("Estimate gyro bias here");
@@ -830,8 +847,9 @@ Item {
                 unit: qsTr("ms");
                 isCalibPoint: false;
                 property real badSyncpointDistance: window.videoArea.detectedCamera.toLowerCase().includes("runcam")? 300.0 : 30.0;
-                property real validness: (Math.min(badSyncpointDistance, Math.abs(offset_ms - linear_offset_ms)) / badSyncpointDistance); // 0 - valid (point near the line), 1 - invalid (30ms or more deviation from the line)
+                property real validness: doubtful || rejected? 1.0 : (Math.min(badSyncpointDistance, Math.abs(offset_ms - linear_offset_ms)) / badSyncpointDistance); // 0 - valid (point near the line), 1 - invalid (30ms or more deviation from the line or doubtful autosync result)
                 color: Qt.hsva((112 * (1.0 - validness)) / 360, 0.84, 0.86, 1.0);
+                opacity: rejected? 0.5 : 1.0;
                 onEdit: (ts_us, val) => {
                     root.editingSyncPoint = true;
                     syncPointSlider.timestamp_us = ts_us;
@@ -122,6 +122,22 @@ MenuItem {
             shutterCb.checked = Math.abs(rolling_shutter) > 0;
             bottomToTop.checked = rolling_shutter < 0;
         }
+        function onImu_calibrated(result: var): void {
+            shutter.value = Math.abs(result.frame_readout_time);
+            shutterCb.checked = Math.abs(result.frame_readout_time) > 0;
+            bottomToTop.checked = result.frame_readout_time < 0;
+            infoList.rms = result.rms;
+
+            const lines = [
+                qsTr("Reprojection error: %1 px").arg(result.rms.toFixed(3)),
+                qsTr("Gyro error: %1°").arg(result.gyro_rms.toFixed(3)),
+                qsTr("IMU orientation: %1").arg(result.imu_orientation),
+                qsTr("IMU rotation: %1").arg(result.imu_rotation.map(x => x.toFixed(2) + "°").join(", ")),
+                qsTr("Gyro offset: %1 ms").arg(result.offset.toFixed(2)),
+                qsTr("Frame readout time: %1 ms").arg(result.frame_readout_time.toFixed(2))
+            ];
+            messageBox(Modal.Info, qsTr("IMU calibrated from %1 frames.").arg(result.views) + "\n\n" + lines.join("\n"), [ { text: qsTr("Ok") } ]);
+        }
     }
 
     Settings {
@@ -138,7 +154,7 @@ MenuItem {
         defaultSuffix: "json";
 
         title: qsTr("Export lens profile");
-        nameFilters: Qt.platform.os == "android"? undefined : [qsTr("Lens profiles") + " (*.json)"];
+        nameFilters: Qt.platform.os == "android"? undefined : [qsTr("Lens profiles") + " (*.json)", qsTr("Lensfun database") + " (*.xml)", qsTr("Adobe lens profile") + " (*.lcp)"];
         type: "output-preset";
         onAccepted: {
             if (uploadProfile.checked) {
@@ -152,6 +168,17 @@ MenuItem {
         }
     }
 
+    FileDialog {
+        id: reportDialog;
+        fileMode: FileDialog.SaveFile;
+        defaultSuffix: "html";
+
+        title: qsTr("Export quality report");
+        nameFilters: Qt.platform.os == "android"? undefined : [qsTr("HTML report") + " (*.html)", qsTr("JSON report") + " (*.json)"];
+        type: "output-preset";
+        onAccepted: controller.export_calibration_report(selectedFile);
+    }
+
     InfoMessageSmall {
         show: infoList.rms > 5 && infoList.rms < 100;
         text: qsTr("For a good lens calibration, this value should be less than 5, ideally less than 1.");
@@ -297,6 +324,32 @@ MenuItem {
             }
         }
     }
+    Button {
+        text: qsTr("Calibrate with gyro");
+        iconName: "spinner";
+        enabled: infoList.rms > 0 && infoList.rms < 100 && calibrator_window.videoArea.vid.loaded;
+        anchors.horizontalCenter: parent.horizontalCenter;
+        tooltip: qsTr("Refines the lens and estimates the IMU orientation, gyro offset and rolling shutter from all detected frames.\nThe camera has to be rotated around all axes in the calibration video.");
+        onClicked: controller.calibrate_imu();
+    }
+    Button {
+        text: qsTr("Reject outlier frames");
+        iconName: "bin";
+        enabled: infoList.rms > 0 && infoList.rms < 100 && calibrator_window.videoArea.vid.loaded;
+        anchors.horizontalCenter: parent.horizontalCenter;
+        tooltip: qsTr("Removes the frames with much higher reprojection error than the others and calibrates again.");
+        onClicked: controller.reject_calibration_outliers();
+    }
+    Button {
+        text: qsTr("Export quality report");
+        iconName: "save";
+        enabled: infoList.rms > 0 && infoList.rms < 100;
+        anchors.horizontalCenter: parent.horizontalCenter;
+        onClicked: {
+            reportDialog.selectedFile = "calibration_report.html";
+            reportDialog.open2();
+        }
+    }
     Item { width: 1; height: 1; }
     Button {
         text: qsTr("Export lens profile");
@@ -358,6 +411,71 @@ MenuItem {
             }
         }
 
+        Label {
+            position: Label.LeftPosition;
+            text: qsTr("Calibration target");
+
+            ComboBox {
+                id: calibrationTarget;
+                property var targets: [
+                    [QT_TR_NOOP("Chessboard"), "chessboard"],
+                    [QT_TR_NOOP("Partial chessboard"), "partial_chessboard"],
+                    [QT_TR_NOOP("Asymmetric circle grid"), "asymmetric_circles"]
+                ];
+                model: targets.map(x => qsTr(x[0]));
+                font.pixelSize: 12 * dpiScale;
+                width: parent.width;
+                currentIndex: 0;
+                onCurrentIndexChanged: controller.set_calibration_target(targets[currentIndex][1]);
+            }
+        }
+        Label {
+            position: Label.LeftPosition;
+            text: calibrationTarget.currentIndex == 2? qsTr("Circles (columns x rows)") : qsTr("Inner corners (columns x rows)");
+
+            Row {
+                width: parent.width;
+                spacing: 5 * dpiScale;
+                NumberField {
+                    id: gridColumns;
+                    width: (parent.width - parent.spacing) / 2;
+                    height: 25 * dpiScale;
+                    value: 14;
+                    from: 2;
+                    onValueChanged: controller.set_calibration_grid_size(gridColumns.value, gridRows.value);
+                }
+                NumberField {
+                    id: gridRows;
+                    width: (parent.width - parent.spacing) / 2;
+                    height: 25 * dpiScale;
+                    value: 8;
+                    from: 2;
+                    onValueChanged: controller.set_calibration_grid_size(gridColumns.value, gridRows.value);
+                }
+            }
+        }
+        Label {
+            position: Label.LeftPosition;
+            text: qsTr("Distortion model");
+
+            ComboBox {
+                id: distortionModel;
+                property var models: [
+                    ["OpenCV Fisheye",  "opencv_fisheye"],
+                    ["OpenCV Standard", "opencv_standard"],
+                    ["Poly3",           "poly3"],
+                    ["Poly5",           "poly5"],
+                    ["PTLens",          "ptlens"],
+                    ["Insta360",        "insta360"],
+                    ["Sony",            "sony"]
+                ];
+                model: models.map(x => x[0]);
+                font.pixelSize: 12 * dpiScale;
+                width: parent.width;
+                currentIndex: 0;
+                onCurrentIndexChanged: controller.set_calibration_model(models[currentIndex][1]);
+            }
+        }
         Label {
             position: Label.LeftPosition;
             text: qsTr("Digital lens");
@@ -623,6 +741,7 @@ MenuItem {
             text: qsTr("Plain chessboard pattern (previous version without dots in the middle)");
             checked: false;
             width: parent.width;
+            visible: calibrationTarget.currentIndex == 0;
             Component.onCompleted: contentItem.wrapMode = Text.WordWrap;
         }
     }
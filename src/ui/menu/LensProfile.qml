@@ -34,10 +34,10 @@ MenuItem {
 
     FileDialog {
         id: fileDialog;
-        property var extensions: ["json"];
+        property var extensions: ["json", "lcp", "xml", "pto"];
 
         title: qsTr("Choose a lens profile")
-        nameFilters: [qsTr("Lens profiles") + " (*.json" + (Qt.platform.os == "ios"? " *.txt" : "") + ")"];
+        nameFilters: [qsTr("Lens profiles") + " (*.json *.lcp *.xml *.pto" + (Qt.platform.os == "ios"? " *.txt" : "") + ")"];
         type: "lens";
         onAccepted: loadFile(fileDialog.selectedFile);
     }
@@ -145,6 +145,10 @@ MenuItem {
                         window.sync.loadGyroflow({
                             synchronization: obj.sync_settings
                         });
+                        // From the IMU calibration, only when selected by the user so it doesn't override the orientation of the video or project
+                        if (root.selected_manually && (obj.sync_settings.imu_orientation || obj.sync_settings.imu_rotation)) {
+                            Qt.callLater(controller.apply_lens_imu_transform);
+                        }
                     }
 
                     root.input_horizontal_stretch = obj.input_horizontal_stretch > 0.01? obj.input_horizontal_stretch : 1.0;
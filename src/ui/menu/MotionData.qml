@@ -134,6 +134,9 @@ MenuItem {
         function onOrientation_guessed(value: string): void {
              orientation.text = value;
         }
+        function onImu_transform_loaded(imu_orientation: string, rotation: var): void {
+            root.loadGyroflow({ gyro_source: { imu_orientation: imu_orientation, rotation: rotation } });
+        }
         function onChart_data_changed(): void {
             Qt.callLater(orientationIndicator.requestPaint);
         }
@@ -35,6 +35,20 @@ MenuItem {
         property alias zoomingMethod: zoomingMethod.currentIndex;
     }
 
+    FileDialog {
+        id: pluginDialog;
+        title: qsTr("Choose a smoothing plugin");
+        nameFilters: [qsTr("Smoothing plugins") + (Qt.platform.os == "windows"? " (*.dll)" : Qt.platform.os == "osx"? " (*.dylib)" : " (*.so)")];
+        type: "smoothing-plugin";
+        onAccepted: {
+            const index = controller.load_smoothing_plugin(selectedFile);
+            if (index > -1) {
+                smoothingMethod.model = controller.get_smoothing_algs();
+                smoothingMethod.currentIndex = index;
+            }
+        }
+    }
+
     function loadGyroflow(obj: var): void {
         const stab = obj.stabilization || { };
         if (stab && Object.keys(stab).length > 0) {
@@ -306,6 +320,13 @@ MenuItem {
         }
     }
 
+    LinkButton {
+        text: qsTr("Load smoothing plugin");
+        anchors.horizontalCenter: parent.horizontalCenter;
+        visible: Qt.platform.os != "ios" && Qt.platform.os != "android";
+        onClicked: pluginDialog.open2();
+    }
+
     Column {
         id: smoothingOptions;
         x: 5 * dpiScale;
@@ -23,6 +23,7 @@ MenuItem {
         property alias sync_lpf: lpf.value;
         property alias checkNegativeInitialOffset: checkNegativeInitialOffset.checked;
         property alias experimentalAutoSyncPoints: experimentalAutoSyncPoints.checked;
+        property alias offsetModel: offsetModel.currentIndex;
         // property alias syncMethod: syncMethod.currentIndex;
         // property alias offsetMethod: offsetMethod.currentIndex;
         // property alias poseMethod: poseMethod.currentIndex;
@@ -55,6 +56,8 @@ MenuItem {
             if (o.hasOwnProperty("auto_sync_points")) experimentalAutoSyncPoints.checked    = !!o.auto_sync_points;
             if (o.hasOwnProperty("do_autosync") && o.do_autosync) autosyncTimer.doRun = true;
         }
+        const gyro = obj.gyro_source || { };
+        if (gyro.hasOwnProperty("offset_model")) offsetModel.currentIndex = Math.max(0, offsetModel.names.indexOf(gyro.offset_model));
     }
     Timer {
         id: autosyncTimer;
@@ -331,7 +334,13 @@ MenuItem {
         }
         InfoMessageSmall {
             show: syncMethod.currentValue == "AKAZE";
-            text: qsTr("The AKAZE method may be more accurate but is significantly slower than OpenCV. Use only if OpenCV doesn't produce good results");
+            text: hasOpenCV? qsTr("The AKAZE method may be more accurate but is significantly slower than OpenCV. Use only if OpenCV doesn't produce good results")
+                           : qsTr("The AKAZE method may be more accurate but is significantly slower than PyrLK. Use only if PyrLK doesn't produce good results");
+        }
+        InfoMessageSmall {
+            show: syncMethod.currentIndex == 4;
+            type: InfoMessage.Info;
+            text: qsTr("Phase correlation tracks the whole image instead of individual features. Use it for footage with little texture, like sky, water or snow.");
         }
         Label {
             position: Label.LeftPosition;
@@ -339,10 +348,12 @@ MenuItem {
 
             ComboBox {
                 id: syncMethod;
-                model: ["AKAZE", "OpenCV (PyrLK)", "OpenCV (DIS)"];
+                // Without OpenCV, the OpenCV methods fall back to the pure-Rust PyrLK
+                model: hasOpenCV? ["AKAZE", "OpenCV (PyrLK)", "OpenCV (DIS)", "PyrLK", QT_TRANSLATE_NOOP("Popup", "Phase correlation")]
+                                : ["AKAZE", "PyrLK", "PyrLK", "PyrLK", QT_TRANSLATE_NOOP("Popup", "Phase correlation")];
                 font.pixelSize: 12 * dpiScale;
                 width: parent.width;
-                currentIndex: 2;
+                currentIndex: hasOpenCV? 2 : 3;
                 onCurrentIndexChanged: controller.set_of_method(currentIndex);
                 Component.onCompleted: currentIndexChanged();
             }
@@ -353,7 +364,9 @@ MenuItem {
 
             ComboBox {
                 id: poseMethod;
-                model: ["findEssentialMat", "Almeida", "EightPoint", "findHomography"];
+                // Without OpenCV, findEssentialMat and findHomography fall back to the pure-Rust RANSAC estimators
+                model: hasOpenCV? ["findEssentialMat", "Almeida", "EightPoint", "findHomography", "RANSAC EssentialMat", "RANSAC Homography"]
+                                : ["RANSAC EssentialMat", "Almeida", "EightPoint", "RANSAC Homography", "RANSAC EssentialMat", "RANSAC Homography"];
                 font.pixelSize: 12 * dpiScale;
                 width: parent.width;
                 currentIndex: 0;
@@ -378,6 +391,27 @@ MenuItem {
                 tooltip: tooltips[currentIndex];
             }
         }
+        Label {
+            text: qsTr("Offset model");
+            position: Label.LeftPosition;
+
+            ComboBox {
+                id: offsetModel;
+                property var names: ["Interpolate", "Line", "Piecewise", "Spline"];
+                model: [QT_TRANSLATE_NOOP("Popup", "Interpolate"), QT_TRANSLATE_NOOP("Popup", "Line"), QT_TRANSLATE_NOOP("Popup", "Piecewise linear"), QT_TRANSLATE_NOOP("Popup", "Spline")];
+                font.pixelSize: 12 * dpiScale;
+                width: parent.width;
+                currentIndex: 0;
+                property var tooltips: ([
+                    qsTr("Interpolate between all sync points."),
+                    qsTr("Fit a single line to the sync points, for a constant clock drift.\nSync points which don't fit the line are ignored."),
+                    qsTr("Fit lines between jumps of the offset, for example caused by dropped IMU samples.\nSync points which don't fit the lines are ignored."),
+                    qsTr("Fit a smooth curve to the sync points, for a clock drift which changes over time.\nSync points which don't fit the curve are ignored.")
+                ]);
+                tooltip: tooltips[currentIndex];
+                onCurrentIndexChanged: controller.set_offset_model(currentIndex);
+            }
+        }
         CheckBoxWithContent {
             id: lpfcb;
             text: qsTr("Low pass filter");